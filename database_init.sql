@@ -1,36 +1,295 @@
 alter table if exists access_tokens drop constraint unique_uid_cid;
+drop table if exists schema_migrations;
+drop table if exists login_failures;
+drop table if exists federation_states;
+drop table if exists federated_identities;
+drop table if exists group_roles;
+drop table if exists user_roles;
+drop table if exists group_members;
+drop table if exists groups;
+drop table if exists roles;
+drop table if exists session_clients;
+drop table if exists login_sessions;
 drop table if exists user_data;
+drop table if exists attributes;
 drop table if exists access_tokens;
 drop table if exists authorization_codes;
+drop table if exists consents;
+drop table if exists client_scopes;
+drop table if exists scope_grants;
+drop table if exists scope_implications;
+drop table if exists scopes;
+drop table if exists client_secrets;
 drop table if exists clients;
+drop table if exists webauthn_challenges;
+drop table if exists webauthn_credentials;
+drop table if exists recovery_codes;
+drop table if exists totp_credentials;
+drop table if exists email_tokens;
+drop table if exists invitations;
 drop table if exists users;
 
 create table if not exists users (
   id UUID primary key DEFAULT gen_random_uuid(),
   username varchar(50) not null unique,
   email varchar(100) not null unique,
-  password varchar(512) not null
+  password varchar(512) not null,
+  email_verified boolean not null default true,
+  disabled boolean not null default false,
+  creation_time timestamp with time zone not null default now(),
+  -- the user has to log in with a second factor, see totp_credentials
+  mfa_required boolean not null default false,
+  -- where the password is checked, here (local) or in the directory
+  source varchar(16) not null default 'local'
 );
 
-create table if not exists user_data (
+-- the user's TOTP authenticator (RFC 6238). The secret has to be readable to check codes, so unlike
+-- passwords it is stored as is. last_used_step keeps a code from being used twice
+create table if not exists totp_credentials (
+  user_id UUID primary key,
+  secret varchar(64) not null,
+  creation_time timestamp with time zone not null default now(),
+  confirmed_time timestamp with time zone,
+  last_used_step bigint,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+-- single-use codes for when the authenticator is lost, only a sha256 of a code is stored
+create table if not exists recovery_codes (
   id serial primary key,
+  user_id UUID not null,
+  code_hash varchar(64) not null,
+  used_time timestamp with time zone,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+-- passkeys (WebAuthn). credential_id is base64url, public_key the COSE key from the authenticator.
+-- sign_count only goes up, a lower count from an authenticator means the key was copied
+create table if not exists webauthn_credentials (
+  id serial primary key,
+  user_id UUID not null,
+  credential_id varchar(1400) not null unique,
+  public_key bytea not null,
+  sign_count bigint not null default 0,
+  name varchar(100) not null,
+  creation_time timestamp with time zone not null default now(),
+  last_used_time timestamp with time zone,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+-- accounts at upstream OpenID Connect providers (SERVER.UPSTREAM.*) linked to users. provider is the
+-- provider's name in the configuration, subject its sub claim
+create table if not exists federated_identities (
+  provider varchar(64) not null,
+  subject varchar(255) not null,
+  user_id UUID not null,
+  email varchar(100),
+  creation_time timestamp with time zone not null default now(),
+  last_login_time timestamp with time zone,
+  primary key (provider, subject),
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+-- logins at an upstream provider that are under way, by a sha256 of the state handed to the provider.
+-- request is the query of the authorization request that continues once the user is back
+create table if not exists federation_states (
+  state varchar(64) primary key,
+  provider varchar(64) not null,
+  nonce varchar(64) not null,
+  code_verifier varchar(128) not null,
+  request varchar(4096) not null,
+  next varchar(20),
+  expire_time timestamp with time zone not null
+);
+
+-- challenges handed to the browser for a WebAuthn ceremony, stored as a sha256 and used once
+create table if not exists webauthn_challenges (
+  challenge varchar(64) primary key,
+  purpose varchar(20) not null,
   user_id UUID,
-  key varchar(512),
+  expire_time timestamp with time zone not null,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+-- failed logins per username and per client ip address, a subject with too many of them is locked until
+-- locked_until. The username is not a reference to users, guesses for unknown users count as well
+create table if not exists login_failures (
+  kind varchar(10) not null,
+  subject varchar(100) not null,
+  failures integer not null,
+  last_failure timestamp with time zone not null,
+  locked_until timestamp with time zone,
+  primary key (kind, subject)
+);
+
+-- invitation codes for registration, only a sha256 of the code is stored. An invitation with an email
+-- can only be used to register that address
+create table if not exists invitations (
+  id serial primary key,
+  code_hash varchar(64) not null unique,
+  email varchar(100),
+  creation_time timestamp with time zone not null default now(),
+  expire_time timestamp with time zone,
+  used_time timestamp with time zone,
+  used_by UUID,
+  foreign key (used_by) references users(id) on delete set null
+);
 
-  foreign key (user_id) references users(id)
+-- tokens in the links of verification and password reset mails, only a sha256 of the token is stored.
+-- A token can be used once, email is the address it was sent to
+create table if not exists email_tokens (
+  id serial primary key,
+  token_hash varchar(64) not null unique,
+  user_id UUID not null,
+  purpose varchar(20) not null,
+  email varchar(100) not null,
+  creation_time timestamp with time zone not null default now(),
+  expire_time timestamp with time zone not null,
+  used_time timestamp with time zone,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+-- the custom attributes users can have. type is string, integer or boolean, a string can be limited to
+-- max_length characters and has to match pattern as a whole. The attribute is released as a claim of the
+-- same name when scope is granted, without a scope it isn't released
+create table if not exists attributes (
+  name varchar(64) primary key,
+  type varchar(10) not null,
+  description varchar(512),
+  max_length integer,
+  pattern varchar(512),
+  scope varchar(255)
+);
+
+-- the values of a user's attributes as text, key is the name in attributes
+create table if not exists user_data (
+  id serial primary key,
+  user_id UUID not null,
+  key varchar(512) not null,
+  value varchar(2048) not null,
+  unique (user_id, key),
+  foreign key (user_id) references users(id) on delete cascade
 );
 
 create table if not exists clients (
   id UUID primary key DEFAULT gen_random_uuid(),
   display_name varchar(50),
   client_id varchar(50) not null unique,
-  client_secret varchar(512) not null
+  redirect_uris varchar(2048) not null default '',
+  post_logout_redirect_uris varchar(2048) not null default '',
+  frontchannel_logout_uri varchar(512),
+  backchannel_logout_uri varchar(512),
+  first_party boolean not null default false,
+  -- space separated, the grant types the client may use at the token endpoint
+  grant_types varchar(255) not null default 'authorization_code refresh_token',
+  -- seconds, without a lifetime access tokens last 30 days and refresh tokens don't expire
+  access_token_lifetime integer,
+  refresh_token_lifetime integer,
+  disabled boolean not null default false,
+  creation_time timestamp with time zone not null default now(),
+  -- users of this client have to log in with a second factor
+  mfa_required boolean not null default false,
+  -- requests per minute on the rate limited routes, instead of SERVER.RATE_LIMIT. 0 is no limit
+  rate_limit integer,
+  -- access tokens are signed jwts (RFC 9068) instead of opaque strings
+  jwt_access_tokens boolean not null default false
+);
+
+-- only a sha256 of a secret is stored, the prefix helps to tell secrets apart. A client can have several
+-- secrets at once so a secret can be replaced without downtime
+create table if not exists client_secrets (
+  id serial primary key,
+  client_id UUID not null,
+  secret_hash varchar(64) not null unique,
+  prefix varchar(8) not null,
+  creation_time timestamp with time zone not null default now(),
+  expire_time timestamp with time zone,
+  foreign key (client_id) references clients(id) on delete cascade
+);
+
+create table if not exists scopes (
+  id serial primary key,
+  name varchar(255) not null unique,
+  description varchar(512),
+  requires_consent boolean not null default true
+);
+
+create table if not exists client_scopes (
+  client_id UUID not null,
+  scope_id integer not null,
+  is_default boolean not null default false,
+  primary key (client_id, scope_id),
+  foreign key (client_id) references clients(id) on delete cascade,
+  foreign key (scope_id) references scopes(id)
+);
+
+-- projects:write:{project} implies projects:read:{project}, parameters carry over
+create table if not exists scope_implications (
+  id serial primary key,
+  scope varchar(255) not null,
+  implies varchar(255) not null
+);
+
+-- grants for parameterized scopes, to a user or to a client
+-- a grant is for a user at a client, or for the client itself when user_id is null
+create table if not exists scope_grants (
+  id serial primary key,
+  scope varchar(255) not null,
+  user_id UUID,
+  client_id UUID,
+  check (user_id is not null or client_id is not null),
+  foreign key (user_id) references users(id) on delete cascade,
+  foreign key (client_id) references clients(id) on delete cascade
+);
+
+-- roles without a client_id are global, the others are the client's own. A user has the roles assigned to
+-- them and those of their groups, tokens carry the global ones and those of the token's client
+create table if not exists roles (
+  id serial primary key,
+  name varchar(64) not null,
+  description varchar(512),
+  client_id UUID,
+  foreign key (client_id) references clients(id) on delete cascade
+);
+create unique index if not exists roles_global_name_key on roles (name) where client_id is null;
+create unique index if not exists roles_client_name_key on roles (client_id, name) where client_id is not null;
+
+create table if not exists groups (
+  id serial primary key,
+  name varchar(64) not null unique,
+  description varchar(512)
+);
+
+create table if not exists group_members (
+  group_id integer not null,
+  user_id UUID not null,
+  primary key (group_id, user_id),
+  foreign key (group_id) references groups(id) on delete cascade,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table if not exists user_roles (
+  user_id UUID not null,
+  role_id integer not null,
+  primary key (user_id, role_id),
+  foreign key (user_id) references users(id) on delete cascade,
+  foreign key (role_id) references roles(id) on delete cascade
+);
+
+create table if not exists group_roles (
+  group_id integer not null,
+  role_id integer not null,
+  primary key (group_id, role_id),
+  foreign key (group_id) references groups(id) on delete cascade,
+  foreign key (role_id) references roles(id) on delete cascade
 );
 
 create table if not exists access_tokens (
   id serial primary key,
-  access_token varchar(128) not null,
+  access_token varchar(4096) not null,
+  refresh_token_hash varchar(64) unique,
   expire_time timestamp with time zone not null,
+  refresh_expire_time timestamp with time zone,
   creation_time timestamp with time zone not null,
   scope varchar(255),
   token_type varchar(50) not null,
@@ -38,8 +297,12 @@ create table if not exists access_tokens (
   client_id UUID not null,
   device varchar(255) not null,
   issuer varchar(255) not null,
-  foreign key (user_id) references users(id),
-  foreign key (client_id) references clients(id)
+  auth_time timestamp with time zone,
+  -- space separated authentication methods (RFC 8176)
+  amr varchar(64),
+  sid varchar(64),
+  foreign key (user_id) references users(id) on delete cascade,
+  foreign key (client_id) references clients(id) on delete cascade
 );
 
 create table if not exists authorization_codes (
@@ -49,22 +312,86 @@ create table if not exists authorization_codes (
   code varchar(255) not null,
   device varchar(255) not null,
   pcke_hash varchar(255),
+  scope varchar(255),
+  redirect_uri varchar(512),
+  nonce varchar(255),
+  auth_time timestamp with time zone,
+  amr varchar(64),
+  sid varchar(64),
   creation_time timestamp with time zone not null,
   expire_time timestamp with time zone not null,
-  foreign key (user_id) references users(id),
-  foreign key (client_id) references clients(id)
+  foreign key (user_id) references users(id) on delete cascade,
+  foreign key (client_id) references clients(id) on delete cascade
 );
 
+-- scopes a user approved for a client, approved scopes are not asked again
+create table if not exists consents (
+  id serial primary key,
+  user_id UUID not null,
+  client_id UUID not null,
+  scope varchar(255) not null,
+  creation_time timestamp with time zone not null,
+  unique (user_id, client_id, scope),
+  foreign key (user_id) references users(id) on delete cascade,
+  foreign key (client_id) references clients(id) on delete cascade
+);
 
 create table if not exists login_sessions (
   id serial primary key,
   session_token varchar(255) not null,
+  sid varchar(64),
   user_id UUID,
+  -- pwd, and otp once the second factor was given
+  amr varchar(64) not null default 'pwd',
   creation_time timestamp with time zone not null,
-  expire_time timestamp with time zone not null
+  expire_time timestamp with time zone not null,
+  foreign key (user_id) references users(id) on delete cascade
 );
 
+-- the clients a login session handed out codes to, they are told when the session ends
+create table if not exists session_clients (
+  session_id integer not null,
+  client_id UUID not null,
+  primary key (session_id, client_id),
+  foreign key (session_id) references login_sessions(id) on delete cascade,
+  foreign key (client_id) references clients(id) on delete cascade
+);
+
+-- migrations applied to the database, this script has the full schema so it records all of them
+create table if not exists schema_migrations (
+  version varchar(255) primary key,
+  applied_time timestamp with time zone not null default now()
+);
+insert into schema_migrations (version) values ('0000_baseline'), ('0001_client_cascades'), ('0002_email_tokens'), ('0003_mfa'), ('0004_passkeys'), ('0005_login_failures'), ('0006_client_rate_limit'), ('0007_user_attributes'), ('0008_roles'), ('0009_federation'), ('0010_refresh_token_hash'), ('0011_user_source'), ('0012_client_secret_hashes');
+
 alter table access_tokens add constraint unique_uid_cid unique (user_id, client_id, device);
-insert into clients (display_name, client_id, client_secret) values ('Mijn Client', 'top', 'top_321');
-insert into users (username, email, password) values ('test', 'test@test.nl', 'test');
+
+-- demo data for a development database, `tenants init` only runs the script up to here
+insert into clients (display_name, client_id, redirect_uris, post_logout_redirect_uris, grant_types) values ('Mijn Client', 'top', 'http://localhost:8082/callback', 'http://localhost:8082/', 'authorization_code refresh_token password client_credentials');
+-- secret 'top_321'
+insert into client_secrets (client_id, secret_hash, prefix) select id, '7f7246765959797b2bb70f1642bd50bb085b35a7a84ef1fd09d56606f66a719f', 'top_' from clients where client_id = 'top';
+insert into scopes (name, description, requires_consent) values ('openid', 'Sign you in', false), ('read', 'Read your data', true), ('write', 'Modify your data', true);
+insert into scopes (name, description, requires_consent) values ('projects:{action}:{project}', 'Access project {project}', true);
+insert into scopes (name, description, requires_consent) values ('admin', 'Manage users and clients', true);
+insert into scopes (name, description, requires_consent) values ('profile', 'Your department and language', true), ('employee', 'Your employee number', true);
+insert into attributes (name, type, description, max_length, pattern, scope) values
+  ('department', 'string', 'Department', 100, null, 'profile'),
+  ('employee_id', 'string', 'Employee number', 20, '[A-Z0-9-]+', 'employee'),
+  ('locale', 'string', 'Preferred language as a BCP 47 tag', 35, '[a-z]{2,3}(-[A-Za-z0-9]{2,8})*', 'profile');
+insert into scope_implications (scope, implies) values ('projects:*', 'projects:read:*'), ('projects:*', 'projects:write:*'), ('projects:write:{project}', 'projects:read:{project}');
+insert into client_scopes (client_id, scope_id, is_default) select c.id, s.id, s.name = 'read' from clients c, scopes s where c.client_id = 'top' and s.name != 'admin';
+-- the admin api is used with a client_credentials token of this client, secret 'admin_321'
+insert into clients (display_name, client_id, first_party, grant_types) values ('Admin', 'admin', true, 'client_credentials');
+insert into client_secrets (client_id, secret_hash, prefix) select id, '04a0763cc3a9ed0d9cc15eef566190d4f23632c44795574f7078322e9d8245ef', 'admin_' from clients where client_id = 'admin';
+insert into client_scopes (client_id, scope_id, is_default) select c.id, s.id, true from clients c, scopes s where c.client_id = 'admin' and s.name = 'admin';
+-- password 'test', an Argon2id PHC string
+insert into users (username, email, password) values ('test', 'test@test.nl', '$argon2id$v=19$m=19456,t=2,p=1$sed6TfCInZCEngqU+gYYMA$GC0EMolARZrPEUG91/avuf0ue9mWbzmu3Yejgyaar9w');
+insert into scope_grants (scope, user_id, client_id)
+    select 'projects:write:1234', u.id, c.id from users u, clients c where u.username = 'test' and c.client_id = 'top';
+insert into roles (name, description) values ('staff', 'Employees');
+insert into roles (name, description, client_id) select 'editor', 'Edits content', id from clients where client_id = 'top';
+insert into groups (name, description) values ('developers', 'The development team');
+insert into group_roles (group_id, role_id) select g.id, r.id from groups g, roles r where g.name = 'developers' and r.name = 'staff';
+insert into group_members (group_id, user_id) select g.id, u.id from groups g, users u where g.name = 'developers' and u.username = 'test';
+insert into user_roles (user_id, role_id) select u.id, r.id from users u, roles r where u.username = 'test' and r.name = 'editor';
 
@@ -0,0 +1,124 @@
+-- takes a database made by the first database_init.sql, from before there were migrations, to the schema
+-- 0001 starts from. Databases bootstrapped since then have it all and already record their migrations
+do $$
+begin
+  if exists (select 1 from schema_migrations) then
+    return;
+  end if;
+
+  alter table users add column if not exists email_verified boolean not null default true,
+    add column if not exists disabled boolean not null default false,
+    add column if not exists creation_time timestamp with time zone not null default now();
+  -- passwords were stored as is, marked so they are taken once and replaced by a hash, see password.rs
+  update users set password = 'plain$' || password;
+
+  create table if not exists invitations (
+    id serial primary key,
+    code_hash varchar(64) not null unique,
+    email varchar(100),
+    creation_time timestamp with time zone not null default now(),
+    expire_time timestamp with time zone,
+    used_time timestamp with time zone,
+    used_by UUID,
+    foreign key (used_by) references users(id) on delete set null
+  );
+
+  alter table user_data drop constraint if exists user_data_user_id_fkey,
+    add constraint user_data_user_id_fkey foreign key (user_id) references users(id) on delete cascade;
+
+  -- every client could use every grant, they keep them
+  alter table clients add column if not exists redirect_uris varchar(2048) not null default '',
+    add column if not exists post_logout_redirect_uris varchar(2048) not null default '',
+    add column if not exists frontchannel_logout_uri varchar(512),
+    add column if not exists backchannel_logout_uri varchar(512),
+    add column if not exists first_party boolean not null default false,
+    add column if not exists grant_types varchar(255) not null default 'authorization_code refresh_token',
+    add column if not exists access_token_lifetime integer,
+    add column if not exists refresh_token_lifetime integer,
+    add column if not exists disabled boolean not null default false,
+    add column if not exists creation_time timestamp with time zone not null default now();
+  update clients set grant_types = 'authorization_code refresh_token password client_credentials';
+
+  create table if not exists client_secrets (
+    id serial primary key,
+    client_id UUID not null,
+    secret_hash varchar(64) not null unique,
+    prefix varchar(8) not null,
+    creation_time timestamp with time zone not null default now(),
+    expire_time timestamp with time zone,
+    foreign key (client_id) references clients(id) on delete cascade
+  );
+
+  create table if not exists scopes (
+    id serial primary key,
+    name varchar(255) not null unique,
+    description varchar(512),
+    requires_consent boolean not null default true
+  );
+
+  create table if not exists client_scopes (
+    client_id UUID not null,
+    scope_id integer not null,
+    is_default boolean not null default false,
+    primary key (client_id, scope_id),
+    foreign key (client_id) references clients(id),
+    foreign key (scope_id) references scopes(id)
+  );
+
+  create table if not exists scope_implications (
+    id serial primary key,
+    scope varchar(255) not null,
+    implies varchar(255) not null
+  );
+
+  create table if not exists scope_grants (
+    id serial primary key,
+    scope varchar(255) not null,
+    user_id UUID,
+    client_id UUID,
+    check (user_id is not null or client_id is not null),
+    foreign key (user_id) references users(id) on delete cascade,
+    foreign key (client_id) references clients(id)
+  );
+
+  alter table access_tokens add column if not exists refresh_token varchar(128) unique,
+    add column if not exists refresh_expire_time timestamp with time zone,
+    add column if not exists auth_time timestamp with time zone,
+    add column if not exists sid varchar(64),
+    drop constraint if exists access_tokens_user_id_fkey,
+    add constraint access_tokens_user_id_fkey foreign key (user_id) references users(id) on delete cascade;
+
+  alter table authorization_codes add column if not exists scope varchar(255),
+    add column if not exists redirect_uri varchar(512),
+    add column if not exists nonce varchar(255),
+    add column if not exists auth_time timestamp with time zone,
+    add column if not exists sid varchar(64),
+    drop constraint if exists authorization_codes_user_id_fkey,
+    add constraint authorization_codes_user_id_fkey foreign key (user_id) references users(id) on delete cascade;
+
+  create table if not exists consents (
+    id serial primary key,
+    user_id UUID not null,
+    client_id UUID not null,
+    scope varchar(255) not null,
+    creation_time timestamp with time zone not null,
+    unique (user_id, client_id, scope),
+    foreign key (user_id) references users(id) on delete cascade,
+    foreign key (client_id) references clients(id)
+  );
+
+  -- sessions had no reference to their user, those of users that are gone can't be used
+  delete from login_sessions where user_id is not null and user_id not in (select id from users);
+  alter table login_sessions add column if not exists sid varchar(64),
+    drop constraint if exists login_sessions_user_id_fkey,
+    add constraint login_sessions_user_id_fkey foreign key (user_id) references users(id) on delete cascade;
+
+  create table if not exists session_clients (
+    session_id integer not null,
+    client_id UUID not null,
+    primary key (session_id, client_id),
+    foreign key (session_id) references login_sessions(id) on delete cascade,
+    foreign key (client_id) references clients(id)
+  );
+end
+$$;
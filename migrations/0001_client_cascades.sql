@@ -0,0 +1,13 @@
+-- deleting a client takes its scopes, grants, consents, codes, tokens and session links with it
+alter table client_scopes drop constraint if exists client_scopes_client_id_fkey,
+  add constraint client_scopes_client_id_fkey foreign key (client_id) references clients(id) on delete cascade;
+alter table scope_grants drop constraint if exists scope_grants_client_id_fkey,
+  add constraint scope_grants_client_id_fkey foreign key (client_id) references clients(id) on delete cascade;
+alter table access_tokens drop constraint if exists access_tokens_client_id_fkey,
+  add constraint access_tokens_client_id_fkey foreign key (client_id) references clients(id) on delete cascade;
+alter table authorization_codes drop constraint if exists authorization_codes_client_id_fkey,
+  add constraint authorization_codes_client_id_fkey foreign key (client_id) references clients(id) on delete cascade;
+alter table consents drop constraint if exists consents_client_id_fkey,
+  add constraint consents_client_id_fkey foreign key (client_id) references clients(id) on delete cascade;
+alter table session_clients drop constraint if exists session_clients_client_id_fkey,
+  add constraint session_clients_client_id_fkey foreign key (client_id) references clients(id) on delete cascade;
@@ -0,0 +1,11 @@
+create table if not exists email_tokens (
+  id serial primary key,
+  token_hash varchar(64) not null unique,
+  user_id UUID not null,
+  purpose varchar(20) not null,
+  email varchar(100) not null,
+  creation_time timestamp with time zone not null default now(),
+  expire_time timestamp with time zone not null,
+  used_time timestamp with time zone,
+  foreign key (user_id) references users(id) on delete cascade
+);
@@ -0,0 +1,22 @@
+alter table users add column if not exists mfa_required boolean not null default false;
+alter table clients add column if not exists mfa_required boolean not null default false;
+alter table login_sessions add column if not exists amr varchar(64) not null default 'pwd';
+alter table authorization_codes add column if not exists amr varchar(64);
+alter table access_tokens add column if not exists amr varchar(64);
+
+create table if not exists totp_credentials (
+  user_id UUID primary key,
+  secret varchar(64) not null,
+  creation_time timestamp with time zone not null default now(),
+  confirmed_time timestamp with time zone,
+  last_used_step bigint,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table if not exists recovery_codes (
+  id serial primary key,
+  user_id UUID not null,
+  code_hash varchar(64) not null,
+  used_time timestamp with time zone,
+  foreign key (user_id) references users(id) on delete cascade
+);
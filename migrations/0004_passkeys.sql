@@ -0,0 +1,19 @@
+create table if not exists webauthn_credentials (
+  id serial primary key,
+  user_id UUID not null,
+  credential_id varchar(1400) not null unique,
+  public_key bytea not null,
+  sign_count bigint not null default 0,
+  name varchar(100) not null,
+  creation_time timestamp with time zone not null default now(),
+  last_used_time timestamp with time zone,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table if not exists webauthn_challenges (
+  challenge varchar(64) primary key,
+  purpose varchar(20) not null,
+  user_id UUID,
+  expire_time timestamp with time zone not null,
+  foreign key (user_id) references users(id) on delete cascade
+);
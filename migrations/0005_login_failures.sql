@@ -0,0 +1,8 @@
+create table if not exists login_failures (
+  kind varchar(10) not null,
+  subject varchar(100) not null,
+  failures integer not null,
+  last_failure timestamp with time zone not null,
+  locked_until timestamp with time zone,
+  primary key (kind, subject)
+);
@@ -0,0 +1 @@
+alter table clients add column if not exists rate_limit integer;
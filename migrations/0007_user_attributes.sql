@@ -0,0 +1,14 @@
+create table if not exists attributes (
+  name varchar(64) primary key,
+  type varchar(10) not null,
+  description varchar(512),
+  max_length integer,
+  pattern varchar(512),
+  scope varchar(255)
+);
+
+-- user_data only had keys so far, there is nothing to keep from rows without a user
+alter table user_data add column if not exists value varchar(2048);
+delete from user_data where user_id is null or key is null or value is null;
+alter table user_data alter column user_id set not null, alter column key set not null, alter column value set not null;
+create unique index if not exists user_data_user_id_key_key on user_data (user_id, key);
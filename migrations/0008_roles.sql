@@ -0,0 +1,43 @@
+create table if not exists roles (
+  id serial primary key,
+  name varchar(64) not null,
+  description varchar(512),
+  client_id UUID,
+  foreign key (client_id) references clients(id) on delete cascade
+);
+create unique index if not exists roles_global_name_key on roles (name) where client_id is null;
+create unique index if not exists roles_client_name_key on roles (client_id, name) where client_id is not null;
+
+create table if not exists groups (
+  id serial primary key,
+  name varchar(64) not null unique,
+  description varchar(512)
+);
+
+create table if not exists group_members (
+  group_id integer not null,
+  user_id UUID not null,
+  primary key (group_id, user_id),
+  foreign key (group_id) references groups(id) on delete cascade,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table if not exists user_roles (
+  user_id UUID not null,
+  role_id integer not null,
+  primary key (user_id, role_id),
+  foreign key (user_id) references users(id) on delete cascade,
+  foreign key (role_id) references roles(id) on delete cascade
+);
+
+create table if not exists group_roles (
+  group_id integer not null,
+  role_id integer not null,
+  primary key (group_id, role_id),
+  foreign key (group_id) references groups(id) on delete cascade,
+  foreign key (role_id) references roles(id) on delete cascade
+);
+
+-- a jwt doesn't fit the opaque tokens' column
+alter table clients add column if not exists jwt_access_tokens boolean not null default false;
+alter table access_tokens alter column access_token type varchar(4096);
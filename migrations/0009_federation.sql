@@ -0,0 +1,20 @@
+create table if not exists federated_identities (
+  provider varchar(64) not null,
+  subject varchar(255) not null,
+  user_id UUID not null,
+  email varchar(100),
+  creation_time timestamp with time zone not null default now(),
+  last_login_time timestamp with time zone,
+  primary key (provider, subject),
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table if not exists federation_states (
+  state varchar(64) primary key,
+  provider varchar(64) not null,
+  nonce varchar(64) not null,
+  code_verifier varchar(128) not null,
+  request varchar(4096) not null,
+  next varchar(20),
+  expire_time timestamp with time zone not null
+);
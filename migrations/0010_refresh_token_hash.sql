@@ -0,0 +1,5 @@
+-- refresh tokens are kept as their sha-256 in hex, like the other secrets, the ones issued before stay valid
+alter table access_tokens rename column refresh_token to refresh_token_hash;
+update access_tokens set refresh_token_hash = encode(sha256(convert_to(refresh_token_hash, 'UTF8')), 'hex')
+  where refresh_token_hash is not null;
+alter table access_tokens alter column refresh_token_hash type varchar(64);
@@ -0,0 +1,4 @@
+-- where a user's password is checked, here (local) or in the directory. Users linked to the directory
+-- came from there
+alter table users add column source varchar(16) not null default 'local';
+update users set source = 'directory' where id in (select user_id from federated_identities where provider = 'ldap');
@@ -0,0 +1,12 @@
+-- clients of the first release have their secret in clients.client_secret, it moves to client_secrets as a
+-- sha256 so the clients can go on using it
+do $$
+begin
+  if exists (select 1 from information_schema.columns
+             where table_schema = current_schema() and table_name = 'clients' and column_name = 'client_secret') then
+    insert into client_secrets (client_id, secret_hash, prefix)
+      select id, encode(sha256(convert_to(client_secret, 'UTF8')), 'hex'), left(client_secret, 6) from clients;
+    alter table clients drop column client_secret;
+  end if;
+end
+$$;
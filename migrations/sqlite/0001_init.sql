@@ -0,0 +1,298 @@
+-- The tables of database_init.sql for SQLite. Ids are uuids as text, booleans 0 and 1, and times are text in
+-- UTC like strftime('%Y-%m-%d %H:%M:%f', 'now') has them, so they compare as text. A change to the schema
+-- goes in database_init.sql, a new file in migrations/ and a new file in migrations/sqlite/.
+
+create table users (
+  id text primary key default (lower(hex(randomblob(4))) || '-' || lower(hex(randomblob(2))) || '-4' ||
+    substr(lower(hex(randomblob(2))), 2) || '-' || substr('89ab', 1 + (abs(random()) % 4), 1) ||
+    substr(lower(hex(randomblob(2))), 2) || '-' || lower(hex(randomblob(6)))),
+  username varchar(50) not null unique,
+  email varchar(100) not null unique,
+  password varchar(512) not null,
+  email_verified boolean not null default true,
+  disabled boolean not null default false,
+  creation_time timestamp not null default (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+  mfa_required boolean not null default false
+);
+
+create table totp_credentials (
+  user_id text primary key,
+  secret varchar(64) not null,
+  creation_time timestamp not null default (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+  confirmed_time timestamp,
+  last_used_step integer,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table recovery_codes (
+  id integer primary key autoincrement,
+  user_id text not null,
+  code_hash varchar(64) not null,
+  used_time timestamp,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table webauthn_credentials (
+  id integer primary key autoincrement,
+  user_id text not null,
+  credential_id varchar(1400) not null unique,
+  public_key blob not null,
+  sign_count integer not null default 0,
+  name varchar(100) not null,
+  creation_time timestamp not null default (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+  last_used_time timestamp,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table federated_identities (
+  provider varchar(64) not null,
+  subject varchar(255) not null,
+  user_id text not null,
+  email varchar(100),
+  creation_time timestamp not null default (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+  last_login_time timestamp,
+  primary key (provider, subject),
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table federation_states (
+  state varchar(64) primary key,
+  provider varchar(64) not null,
+  nonce varchar(64) not null,
+  code_verifier varchar(128) not null,
+  request varchar(4096) not null,
+  next varchar(20),
+  expire_time timestamp not null
+);
+
+create table webauthn_challenges (
+  challenge varchar(64) primary key,
+  purpose varchar(20) not null,
+  user_id text,
+  expire_time timestamp not null,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table login_failures (
+  kind varchar(10) not null,
+  subject varchar(100) not null,
+  failures integer not null,
+  last_failure timestamp not null,
+  locked_until timestamp,
+  primary key (kind, subject)
+);
+
+create table invitations (
+  id integer primary key autoincrement,
+  code_hash varchar(64) not null unique,
+  email varchar(100),
+  creation_time timestamp not null default (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+  expire_time timestamp,
+  used_time timestamp,
+  used_by text,
+  foreign key (used_by) references users(id) on delete set null
+);
+
+create table email_tokens (
+  id integer primary key autoincrement,
+  token_hash varchar(64) not null unique,
+  user_id text not null,
+  purpose varchar(20) not null,
+  email varchar(100) not null,
+  creation_time timestamp not null default (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+  expire_time timestamp not null,
+  used_time timestamp,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table attributes (
+  name varchar(64) primary key,
+  type varchar(10) not null,
+  description varchar(512),
+  max_length integer,
+  pattern varchar(512),
+  scope varchar(255)
+);
+
+create table user_data (
+  id integer primary key autoincrement,
+  user_id text not null,
+  key varchar(512) not null,
+  value varchar(2048) not null,
+  unique (user_id, key),
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table clients (
+  id text primary key default (lower(hex(randomblob(4))) || '-' || lower(hex(randomblob(2))) || '-4' ||
+    substr(lower(hex(randomblob(2))), 2) || '-' || substr('89ab', 1 + (abs(random()) % 4), 1) ||
+    substr(lower(hex(randomblob(2))), 2) || '-' || lower(hex(randomblob(6)))),
+  display_name varchar(50),
+  client_id varchar(50) not null unique,
+  redirect_uris varchar(2048) not null default '',
+  post_logout_redirect_uris varchar(2048) not null default '',
+  frontchannel_logout_uri varchar(512),
+  backchannel_logout_uri varchar(512),
+  first_party boolean not null default false,
+  grant_types varchar(255) not null default 'authorization_code refresh_token',
+  access_token_lifetime integer,
+  refresh_token_lifetime integer,
+  disabled boolean not null default false,
+  creation_time timestamp not null default (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+  mfa_required boolean not null default false,
+  rate_limit integer,
+  jwt_access_tokens boolean not null default false
+);
+
+create table client_secrets (
+  id integer primary key autoincrement,
+  client_id text not null,
+  secret_hash varchar(64) not null unique,
+  prefix varchar(8) not null,
+  creation_time timestamp not null default (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+  expire_time timestamp,
+  foreign key (client_id) references clients(id) on delete cascade
+);
+
+create table scopes (
+  id integer primary key autoincrement,
+  name varchar(255) not null unique,
+  description varchar(512),
+  requires_consent boolean not null default true
+);
+
+create table client_scopes (
+  client_id text not null,
+  scope_id integer not null,
+  is_default boolean not null default false,
+  primary key (client_id, scope_id),
+  foreign key (client_id) references clients(id) on delete cascade,
+  foreign key (scope_id) references scopes(id)
+);
+
+create table scope_implications (
+  id integer primary key autoincrement,
+  scope varchar(255) not null,
+  implies varchar(255) not null
+);
+
+create table scope_grants (
+  id integer primary key autoincrement,
+  scope varchar(255) not null,
+  user_id text,
+  client_id text,
+  check (user_id is not null or client_id is not null),
+  foreign key (user_id) references users(id) on delete cascade,
+  foreign key (client_id) references clients(id) on delete cascade
+);
+
+create table roles (
+  id integer primary key autoincrement,
+  name varchar(64) not null,
+  description varchar(512),
+  client_id text,
+  foreign key (client_id) references clients(id) on delete cascade
+);
+create unique index roles_global_name_key on roles (name) where client_id is null;
+create unique index roles_client_name_key on roles (client_id, name) where client_id is not null;
+
+create table groups (
+  id integer primary key autoincrement,
+  name varchar(64) not null unique,
+  description varchar(512)
+);
+
+create table group_members (
+  group_id integer not null,
+  user_id text not null,
+  primary key (group_id, user_id),
+  foreign key (group_id) references groups(id) on delete cascade,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table user_roles (
+  user_id text not null,
+  role_id integer not null,
+  primary key (user_id, role_id),
+  foreign key (user_id) references users(id) on delete cascade,
+  foreign key (role_id) references roles(id) on delete cascade
+);
+
+create table group_roles (
+  group_id integer not null,
+  role_id integer not null,
+  primary key (group_id, role_id),
+  foreign key (group_id) references groups(id) on delete cascade,
+  foreign key (role_id) references roles(id) on delete cascade
+);
+
+-- a user has one token per client and device, a new token replaces the old one (see insert_token)
+create table access_tokens (
+  id integer primary key autoincrement,
+  access_token varchar(4096) not null,
+  refresh_token varchar(128) unique,
+  expire_time timestamp not null,
+  refresh_expire_time timestamp,
+  creation_time timestamp not null,
+  scope varchar(255),
+  token_type varchar(50) not null,
+  user_id text,
+  client_id text not null,
+  device varchar(255) not null,
+  issuer varchar(255) not null,
+  auth_time timestamp,
+  amr varchar(64),
+  sid varchar(64),
+  constraint unique_uid_cid unique (user_id, client_id, device),
+  foreign key (user_id) references users(id) on delete cascade,
+  foreign key (client_id) references clients(id) on delete cascade
+);
+
+create table authorization_codes (
+  id integer primary key autoincrement,
+  client_id text,
+  user_id text,
+  code varchar(255) not null,
+  device varchar(255) not null,
+  pcke_hash varchar(255),
+  scope varchar(255),
+  redirect_uri varchar(512),
+  nonce varchar(255),
+  auth_time timestamp,
+  amr varchar(64),
+  sid varchar(64),
+  creation_time timestamp not null,
+  expire_time timestamp not null,
+  foreign key (user_id) references users(id) on delete cascade,
+  foreign key (client_id) references clients(id) on delete cascade
+);
+
+create table consents (
+  id integer primary key autoincrement,
+  user_id text not null,
+  client_id text not null,
+  scope varchar(255) not null,
+  creation_time timestamp not null,
+  unique (user_id, client_id, scope),
+  foreign key (user_id) references users(id) on delete cascade,
+  foreign key (client_id) references clients(id) on delete cascade
+);
+
+create table login_sessions (
+  id integer primary key autoincrement,
+  session_token varchar(255) not null,
+  sid varchar(64),
+  user_id text,
+  amr varchar(64) not null default 'pwd',
+  creation_time timestamp not null,
+  expire_time timestamp not null,
+  foreign key (user_id) references users(id) on delete cascade
+);
+
+create table session_clients (
+  session_id integer not null,
+  client_id text not null,
+  primary key (session_id, client_id),
+  foreign key (session_id) references login_sessions(id) on delete cascade,
+  foreign key (client_id) references clients(id) on delete cascade
+);
@@ -0,0 +1,3 @@
+-- refresh tokens are kept as their sha-256 in hex, like the other secrets, the ones issued before stay valid
+alter table access_tokens rename column refresh_token to refresh_token_hash;
+update access_tokens set refresh_token_hash = sha256(refresh_token_hash) where refresh_token_hash is not null;
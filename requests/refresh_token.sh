@@ -0,0 +1,3 @@
+#!/bin/bash
+REFRESH_TOKEN=$1
+curl --user top:top_321 -d "grant_type=refresh_token&refresh_token=$REFRESH_TOKEN&scope=read" -X POST http://localhost:8081/oauth2/token
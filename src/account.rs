@@ -0,0 +1,285 @@
+use crate::handlers::{check_csrf, form_page, get_db_client, hash_password};
+use crate::mail::{self, Mailer};
+use crate::models::{EmailForm, EmailTokenPurpose, EmailTokenQuery, PasswordResetForm, ServerConfig, User};
+use crate::pages;
+use crate::password;
+use crate::storage::{Database, Storage};
+use chrono::Duration;
+use std::sync::Arc;
+use warp::{Rejection, Reply};
+
+// Email verification and forgotten passwords. Both send a link with a token that works once and expires,
+// only a hash of the token is stored. The forms asking for a mail answer the same whether the address is
+// known or not.
+
+const INVALID_LINK: &str = "This link is invalid or has expired.";
+
+fn notice(title: &str, message: &str) -> warp::reply::Response {
+    warp::reply::html(pages::notice(title, message)).into_response()
+}
+
+// Mails the user a link to verify their current address
+pub(crate) async fn send_verification(
+    client: &dyn Storage,
+    mailer: &Arc<dyn Mailer>,
+    user: &User,
+    server_config: &ServerConfig,
+) {
+    let lifetime = server_config.mail.verification_lifetime;
+    let token = client
+        .create_email_token(user.id, EmailTokenPurpose::VerifyEmail, &user.email, Duration::seconds(lifetime))
+        .await;
+    let link = format!("{}/verify_email?token={}", server_config.public_url(), token);
+    mail::send_in_background(
+        mailer.clone(),
+        mail::verification_mail(&user.email, &user.username, &link, lifetime),
+    );
+}
+
+pub async fn verify_email(
+    query: EmailTokenQuery,
+    database: Arc<dyn Database>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = get_db_client(&database).await?;
+    match client.use_email_token(&query.token, EmailTokenPurpose::VerifyEmail).await {
+        Some((user_id, email)) if client.set_email_verified(user_id, &email).await => Ok(notice(
+            "Email address verified",
+            "Thank you, your email address has been verified. You can log in now.",
+        )),
+        _ => Ok(notice("Verify your email address", INVALID_LINK)),
+    }
+}
+
+pub async fn get_resend_verification(
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    Ok(form_page(&server_config, |csrf| {
+        pages::email_form(
+            "/verify_email/resend",
+            "Verify your email address",
+            "Enter your email address and we'll send you a new verification link.",
+            csrf,
+        )
+    }))
+}
+
+pub async fn post_resend_verification(
+    form: EmailForm,
+    csrf: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+    mailer: Arc<dyn Mailer>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    check_csrf(csrf, &form.csrf_token)?;
+    let client = get_db_client(&database).await?;
+
+    if let Some(user) = client.get_user_by_email(form.email.trim()).await {
+        if !user.email_verified && !user.disabled {
+            send_verification(&*client, &mailer, &user, &server_config).await;
+        }
+    }
+    Ok(notice(
+        "Check your mail",
+        "If an account with that address still needs to be verified, a new link is on its way.",
+    ))
+}
+
+pub async fn get_forgot_password(
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    Ok(form_page(&server_config, |csrf| {
+        pages::email_form(
+            "/forgot_password",
+            "Forgot your password?",
+            "Enter the email address of your account and we'll send you a link to choose a new password.",
+            csrf,
+        )
+    }))
+}
+
+pub async fn post_forgot_password(
+    form: EmailForm,
+    csrf: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+    mailer: Arc<dyn Mailer>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    check_csrf(csrf, &form.csrf_token)?;
+    let client = get_db_client(&database).await?;
+
+    if let Some(user) = client.get_user_by_email(form.email.trim()).await {
+        if !user.disabled {
+            let lifetime = server_config.mail.reset_lifetime;
+            let token = client
+                .create_email_token(user.id, EmailTokenPurpose::ResetPassword, &user.email, Duration::seconds(lifetime))
+                .await;
+            let link = format!("{}/reset_password?token={}", server_config.public_url(), token);
+            mail::send_in_background(mailer, mail::reset_mail(&user.email, &user.username, &link, lifetime));
+        }
+    }
+    Ok(notice(
+        "Check your mail",
+        "If an account with that address exists, we've sent it a link to choose a new password.",
+    ))
+}
+
+pub async fn get_reset_password(
+    query: EmailTokenQuery,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = get_db_client(&database).await?;
+    match client.find_email_token(&query.token, EmailTokenPurpose::ResetPassword).await {
+        Some(_) => Ok(form_page(&server_config, |csrf| {
+            pages::reset_password(&query.token, csrf, None)
+        })),
+        None => Ok(notice("Reset password", INVALID_LINK)),
+    }
+}
+
+// The token is only used up once the new password is accepted, a password that doesn't meet the policy
+// shows the form again. Following the link proves the address, so it counts as verified.
+pub async fn post_reset_password(
+    form: PasswordResetForm,
+    csrf: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    check_csrf(csrf, &form.csrf_token)?;
+    let client = get_db_client(&database).await?;
+
+    let user = match client.find_email_token(&form.token, EmailTokenPurpose::ResetPassword).await {
+        Some((user_id, _)) => client.get_user(user_id).await,
+        None => None,
+    };
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(notice("Reset password", INVALID_LINK)),
+    };
+
+    if let Err(message) = password::check_policy(&form.password, &[&user.username, &user.email], &server_config.password) {
+        return Ok(form_page(&server_config, |csrf| {
+            pages::reset_password(&form.token, csrf, Some(&message))
+        }));
+    }
+    let hash = hash_password(form.password, &server_config).await;
+
+    match client.use_email_token(&form.token, EmailTokenPurpose::ResetPassword).await {
+        Some((user_id, email)) => {
+            client.set_password(user_id, &hash).await;
+            client.set_email_verified(user_id, &email).await;
+            client.revoke_user(user_id).await;
+            Ok(notice(
+                "Password changed",
+                "Your password has been changed, you can log in with it now.",
+            ))
+        }
+        None => Ok(notice("Reset password", INVALID_LINK)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use crate::storage::UserStore;
+    use crate::testing::{self, add_client, add_user, basic_auth, password_grant, Outbox, CLIENT_ID, PASSWORD, USERNAME};
+    use warp::filters::BoxedFilter;
+
+    type App = BoxedFilter<(warp::reply::Response,)>;
+
+    const EMAIL: &str = "test@example.com";
+
+    // Posts the form with the csrf cookie it repeats, answers the status and the page
+    async fn post(app: &App, path: &str, form: &str) -> (u16, String) {
+        let response = warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("cookie", "csrf=token")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(format!("{}&csrf_token=token", form))
+            .reply(app)
+            .await;
+        (response.status().as_u16(), String::from_utf8_lossy(response.body()).to_string())
+    }
+
+    async fn get(app: &App, path: &str) -> String {
+        let response = warp::test::request().path(path).reply(app).await;
+        String::from_utf8_lossy(response.body()).to_string()
+    }
+
+    fn token(link: &str) -> &str {
+        link.split("token=").nth(1).expect("The link has no token")
+    }
+
+    #[tokio::test]
+    async fn a_verification_link_works_once() {
+        let mut memory = Memory::new();
+        let hash = password::hash(PASSWORD, &testing::config().server.password);
+        memory.register_user(USERNAME, EMAIL, &hash, false, None).await.unwrap();
+        let outbox = Arc::new(Outbox::default());
+        let app = testing::app_with_mailer(&memory, testing::config(), outbox.clone());
+
+        post(&app, "/verify_email/resend", &format!("email={}", EMAIL)).await;
+        let link = outbox.link(EMAIL).await.expect("No verification mail was sent");
+        let path = format!("/verify_email?token={}", token(&link));
+        assert!(!get(&app, &path).await.contains(INVALID_LINK));
+        assert!(memory.get_user_by_email(EMAIL).await.unwrap().email_verified);
+        assert!(get(&app, &path).await.contains(INVALID_LINK));
+
+        // a verified address gets no more links
+        post(&app, "/verify_email/resend", &format!("email={}", EMAIL)).await;
+        assert!(outbox.link(EMAIL).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_links_and_links_for_something_else_are_refused() {
+        let mut memory = Memory::new();
+        let user = add_user(&mut memory, USERNAME, PASSWORD).await;
+        let expired = Duration::seconds(-1);
+        let verification = memory.create_email_token(user.id, EmailTokenPurpose::VerifyEmail, EMAIL, expired).await;
+        let reset = memory.create_email_token(user.id, EmailTokenPurpose::ResetPassword, EMAIL, expired).await;
+        let app = testing::app(&memory, testing::config());
+
+        assert!(get(&app, &format!("/verify_email?token={}", verification)).await.contains(INVALID_LINK));
+        assert!(get(&app, &format!("/reset_password?token={}", reset)).await.contains(INVALID_LINK));
+        let (_, page) = post(&app, "/reset_password", &format!("token={}&password=a+new+password", reset)).await;
+        assert!(page.contains(INVALID_LINK));
+
+        let verification =
+            memory.create_email_token(user.id, EmailTokenPurpose::VerifyEmail, EMAIL, Duration::hours(1)).await;
+        let form = format!("token={}&password=a+new+password", verification);
+        let (_, page) = post(&app, "/reset_password", &form).await;
+        assert!(page.contains(INVALID_LINK));
+    }
+
+    #[tokio::test]
+    async fn a_reset_link_is_only_sent_to_known_addresses_and_works_once() {
+        let mut memory = Memory::new();
+        let (_, secret) = add_client(&mut memory, CLIENT_ID, &[]).await;
+        add_user(&mut memory, USERNAME, PASSWORD).await;
+        let outbox = Arc::new(Outbox::default());
+        let app = testing::app_with_mailer(&memory, testing::config(), outbox.clone());
+        let authorization = basic_auth(CLIENT_ID, &secret);
+
+        // the answer doesn't tell whether the address is known
+        let known = post(&app, "/forgot_password", &format!("email={}", EMAIL)).await;
+        let unknown = post(&app, "/forgot_password", "email=nobody@example.com").await;
+        assert_eq!(known, unknown);
+        assert!(outbox.link("nobody@example.com").await.is_none());
+        let link = outbox.link(EMAIL).await.expect("No reset mail was sent");
+
+        // a password the policy refuses leaves the link as it was
+        let (_, page) = post(&app, "/reset_password", &format!("token={}&password=short", token(&link))).await;
+        assert!(page.contains("at least 8 characters"));
+        let (_, page) = post(&app, "/reset_password", &format!("token={}&password=a+new+password", token(&link))).await;
+        assert!(!page.contains(INVALID_LINK));
+        assert_eq!(password_grant(&app, &authorization, USERNAME, "a new password").await.0, 200);
+        assert_eq!(password_grant(&app, &authorization, USERNAME, PASSWORD).await.0, 400);
+
+        let form = format!("token={}&password=another+password", token(&link));
+        let (_, page) = post(&app, "/reset_password", &form).await;
+        assert!(page.contains(INVALID_LINK));
+        assert_eq!(password_grant(&app, &authorization, USERNAME, "another password").await.0, 400);
+    }
+}
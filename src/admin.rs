@@ -0,0 +1,855 @@
+use crate::attributes;
+use crate::errors::Error::{self, *};
+use crate::handlers::{check_new_user, get_db_client, hash_password, valid_email, valid_username};
+use crate::lockout;
+use crate::models::{
+    AttributeDefinition, ClientUpdate, Group, NewClient, NewClientSecret, NewUser, PasswordReset, Role,
+    RoleDescription, RoleQuery, SecretRequest, ServerConfig, UserList, UserQuery, UserRoles, UserUpdate,
+};
+use crate::password;
+use crate::roles;
+use crate::scopes;
+use crate::session;
+use crate::storage::{ClientStore, Database, RoleStore, Storage};
+use chrono::{Duration, Local};
+use serde_json::{Map, Value};
+use std::net::IpAddr;
+use std::sync::Arc;
+use url::Url;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{reply::json, Rejection, Reply};
+
+// The /admin api for users and clients. Requests carry a bearer token that was granted the admin scope
+// (SERVER.ADMIN_SCOPE).
+
+async fn require_admin(
+    client: &dyn Storage,
+    authorization: &str,
+    server_config: &ServerConfig,
+) -> Result<(), Rejection> {
+    let token = authorization.strip_prefix("Bearer ").unwrap_or("");
+    let scope = match client.get_active_token_scope(token).await {
+        Some(scope) => scope,
+        None => {
+            return Err(warp::reject::custom(AuthorizationError(
+                "A valid bearer token is required".to_string(),
+            )))
+        }
+    };
+
+    if scopes::parse(&scope).contains(&server_config.admin_scope) {
+        Ok(())
+    } else {
+        Err(warp::reject::custom(ForbiddenError(format!(
+            "The token needs the {} scope",
+            server_config.admin_scope
+        ))))
+    }
+}
+
+async fn admin_client(
+    authorization: &str,
+    database: &Arc<dyn Database>,
+    server_config: &ServerConfig,
+) -> Result<Box<dyn Storage>, Rejection> {
+    let client = get_db_client(database).await?;
+    require_admin(&*client, authorization, server_config).await?;
+    Ok(client)
+}
+
+fn user_not_found() -> Rejection {
+    warp::reject::custom(NotFoundError("User not found".to_string()))
+}
+
+pub async fn list_users(
+    query: UserQuery,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(50).clamp(1, 200);
+    let (users, total) = client
+        .get_users(query.username.as_deref(), query.email.as_deref(), per_page, (page - 1) * per_page)
+        .await;
+
+    Ok(json(&UserList {
+        users,
+        page,
+        per_page,
+        total,
+    })
+    .into_response())
+}
+
+pub async fn get_user(
+    user_id: Uuid,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    match client.get_user(user_id).await {
+        Some(user) => Ok(json(&user).into_response()),
+        None => Err(user_not_found()),
+    }
+}
+
+pub async fn create_user(
+    params: NewUser,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let mut client = admin_client(&authorization, &database, &server_config).await?;
+
+    let username = params.username.trim();
+    let email = params.email.trim();
+    let hash = check_new_user(username, email, params.password, &server_config)
+        .await
+        .map_err(warp::reject::custom)?;
+    let email_verified = params.email_verified.unwrap_or(true);
+    let user = client.register_user(username, email, &hash, email_verified, None)
+        .await
+        .map_err(warp::reject::custom)?;
+
+    Ok(warp::reply::with_status(json(&user), StatusCode::CREATED).into_response())
+}
+
+pub async fn update_user(
+    user_id: Uuid,
+    update: UserUpdate,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+
+    let update = UserUpdate {
+        username: update.username.map(|u| u.trim().to_string()),
+        email: update.email.map(|e| e.trim().to_string()),
+        ..update
+    };
+    if update.username.as_deref().is_some_and(|u| !valid_username(u)) {
+        return Err(warp::reject::custom(InvalidRequestError(
+            "Username must be 3 to 50 letters, digits or . _ - @".to_string(),
+        )));
+    }
+    if update.email.as_deref().is_some_and(|e| !valid_email(e)) {
+        return Err(warp::reject::custom(InvalidRequestError(
+            "Invalid email address".to_string(),
+        )));
+    }
+
+    match client.update_user(user_id, &update)
+        .await
+        .map_err(warp::reject::custom)?
+    {
+        Some(user) => Ok(json(&user).into_response()),
+        None => Err(user_not_found()),
+    }
+}
+
+// A disabled user can't log in, their sessions and tokens are revoked right away
+pub async fn set_user_disabled(
+    user_id: Uuid,
+    disabled: bool,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let user = client.set_user_disabled(user_id, disabled)
+        .await
+        .ok_or_else(user_not_found)?;
+    if disabled {
+        client.revoke_user(user_id).await;
+    }
+    Ok(json(&user).into_response())
+}
+
+pub async fn delete_user(
+    user_id: Uuid,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    if client.delete_user(user_id).await {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(user_not_found())
+    }
+}
+
+// Removes the user's authenticator and recovery codes, for a user that lost them. When MFA is required
+// the next login enrolls a new authenticator.
+pub async fn reset_mfa(
+    user_id: Uuid,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    client.get_user(user_id).await.ok_or_else(user_not_found)?;
+    client.delete_mfa(user_id).await;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// Lifts a lockout of the user's password logins and forgets their failed attempts
+pub async fn unlock_user(
+    user_id: Uuid,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let user = client.get_user(user_id).await.ok_or_else(user_not_found)?;
+    client.clear_login_failures(lockout::USER, &lockout::user_subject(&user.username)).await;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+pub async fn unlock_ip(
+    ip: IpAddr,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    if client.clear_login_failures(lockout::IP, &ip.to_string()).await {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(warp::reject::custom(NotFoundError("No failed logins from this address".to_string())))
+    }
+}
+
+pub async fn get_user_attributes(
+    user_id: Uuid,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    client.get_user(user_id).await.ok_or_else(user_not_found)?;
+    Ok(json(&attributes::values(&*client, user_id).await).into_response())
+}
+
+// Sets the attributes in the body and removes the ones that are null, nothing changes when one of them
+// doesn't fit its definition
+pub async fn update_user_attributes(
+    user_id: Uuid,
+    update: Map<String, Value>,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let mut client = admin_client(&authorization, &database, &server_config).await?;
+    client.get_user(user_id).await.ok_or_else(user_not_found)?;
+    let changes = check_attributes(&*client, update).await.map_err(warp::reject::custom)?;
+    client.set_user_attributes(user_id, &changes)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(json(&attributes::values(&*client, user_id).await).into_response())
+}
+
+pub(crate) async fn check_attributes(
+    client: &dyn Storage,
+    update: Map<String, Value>,
+) -> Result<Vec<(String, Option<String>)>, Error> {
+    let definitions = client.get_attribute_definitions().await;
+    let mut changes = Vec::with_capacity(update.len());
+    for (name, value) in update {
+        let definition = definitions
+            .iter()
+            .find(|d| d.name == name)
+            .ok_or_else(|| invalid(&format!("Unknown attribute {}", name)))?;
+        let value = match value {
+            Value::Null => None,
+            value => Some(attributes::check_value(definition, &value)?),
+        };
+        changes.push((name, value));
+    }
+    Ok(changes)
+}
+
+pub async fn list_passkeys(
+    user_id: Uuid,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    client.get_user(user_id).await.ok_or_else(user_not_found)?;
+    Ok(json(&client.get_passkeys(user_id).await).into_response())
+}
+
+pub async fn delete_passkey(
+    user_id: Uuid,
+    passkey_id: i32,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    if client.delete_passkey(user_id, passkey_id).await {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(warp::reject::custom(NotFoundError("Unknown passkey".to_string())))
+    }
+}
+
+// The user's accounts at upstream providers
+pub async fn list_identities(
+    user_id: Uuid,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    client.get_user(user_id).await.ok_or_else(user_not_found)?;
+    Ok(json(&client.get_federated_identities(user_id).await).into_response())
+}
+
+// Unlinks the user's account at the provider, the next login there links or creates an account again
+pub async fn delete_identity(
+    user_id: Uuid,
+    provider: String,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    if client.delete_federated_identities(user_id, &provider).await {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(warp::reject::custom(NotFoundError(format!("No account at {}", provider))))
+    }
+}
+
+// Sets the given password or generates one, a generated password is returned once. The user is logged
+// out everywhere.
+pub async fn reset_password(
+    user_id: Uuid,
+    params: PasswordReset,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let user = client.get_user(user_id).await.ok_or_else(user_not_found)?;
+
+    let (new_password, generated) = match params.password {
+        Some(new_password) => {
+            password::check_policy(&new_password, &[&user.username, &user.email], &server_config.password)
+                .map_err(|e| warp::reject::custom(InvalidRequestError(e)))?;
+            (new_password, None)
+        }
+        None => {
+            let generated = session::generate_secret(20);
+            (generated.clone(), Some(generated))
+        }
+    };
+
+    let hash = hash_password(new_password, &server_config).await;
+    client.set_password(user_id, &hash).await;
+    client.revoke_user(user_id).await;
+
+    Ok(json(&PasswordReset { password: generated }).into_response())
+}
+
+const GRANT_TYPES: [&str; 4] = ["authorization_code", "refresh_token", "password", "client_credentials"];
+
+fn client_not_found() -> Rejection {
+    warp::reject::custom(NotFoundError("Client not found".to_string()))
+}
+
+fn invalid(message: &str) -> Error {
+    InvalidRequestError(message.to_string())
+}
+
+// Redirect uris are compared as strings, they have to be absolute and can't have a fragment
+fn valid_uri(uri: &str) -> bool {
+    Url::parse(uri).is_ok_and(|u| u.fragment().is_none()) && !uri.contains(char::is_whitespace)
+}
+
+// Checks the settings of a new or changed client, the admin api and the command line share this
+pub(crate) fn check_client_settings<'a>(
+    display_name: &Option<String>,
+    uris: impl IntoIterator<Item = &'a String>,
+    grant_types: &[String],
+    lifetimes: &[Option<i32>],
+    rate_limit: Option<i32>,
+) -> Result<(), Error> {
+    if display_name.as_ref().is_some_and(|d| d.chars().count() > 50) {
+        return Err(invalid("The display name can be at most 50 characters"));
+    }
+    if let Some(uri) = uris.into_iter().find(|u| !valid_uri(u)) {
+        return Err(invalid(&format!("Invalid uri {}", uri)));
+    }
+    if let Some(grant_type) = grant_types.iter().find(|g| !GRANT_TYPES.contains(&g.as_str())) {
+        return Err(invalid(&format!("Unsupported grant type {}", grant_type)));
+    }
+    if lifetimes.iter().flatten().any(|l| *l <= 0) {
+        return Err(invalid("Token lifetimes are in seconds and have to be positive"));
+    }
+    if rate_limit.is_some_and(|r| r < 0) {
+        return Err(invalid("The rate limit is in requests per minute, 0 for no limit"));
+    }
+    Ok(())
+}
+
+async fn find_client(client: &dyn ClientStore, client_id: &str) -> Result<uuid::Uuid, Rejection> {
+    client.get_client_details(client_id)
+        .await
+        .map(|(id, _)| id)
+        .ok_or_else(client_not_found)
+}
+
+pub async fn list_attributes(
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    Ok(json(&client.get_attribute_definitions().await).into_response())
+}
+
+// Adds an attribute or replaces its definition. The values users already have must fit the new one.
+pub async fn put_attribute(
+    name: String,
+    mut definition: AttributeDefinition,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    definition.name = name;
+    check_definition(&*client, &definition).await.map_err(warp::reject::custom)?;
+    client.put_attribute_definition(&definition).await;
+    Ok(json(&definition).into_response())
+}
+
+pub(crate) async fn check_definition(client: &dyn Storage, definition: &AttributeDefinition) -> Result<(), Error> {
+    attributes::check_definition(definition)?;
+    let misfits = client.get_attribute_values(&definition.name)
+        .await
+        .iter()
+        .filter(|text| attributes::check_value(definition, &attributes::typed_value(definition, text)).is_err())
+        .count();
+    if misfits > 0 {
+        return Err(ConflictError(format!(
+            "{} users have a value for {} that doesn't fit this definition",
+            misfits, definition.name
+        )));
+    }
+    Ok(())
+}
+
+// Removes the attribute and every user's value for it
+pub async fn delete_attribute(
+    name: String,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let mut client = admin_client(&authorization, &database, &server_config).await?;
+    if client.delete_attribute_definition(&name)
+        .await
+        .map_err(warp::reject::custom)?
+    {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(warp::reject::custom(NotFoundError("Unknown attribute".to_string())))
+    }
+}
+
+pub async fn list_clients(
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    Ok(json(&client.get_clients().await).into_response())
+}
+
+pub async fn get_client(
+    client_id: String,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    match client.get_client_details(&client_id).await {
+        Some((_, details)) => Ok(json(&details).into_response()),
+        None => Err(client_not_found()),
+    }
+}
+
+// The client_id and a first secret are generated, the secret is only shown in this response
+pub async fn create_client(
+    params: NewClient,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let mut client = admin_client(&authorization, &database, &server_config).await?;
+
+    check_client_settings(
+        &params.display_name,
+        params
+            .redirect_uris
+            .iter()
+            .chain(&params.post_logout_redirect_uris)
+            .chain(&params.frontchannel_logout_uri)
+            .chain(&params.backchannel_logout_uri),
+        params.grant_types.as_deref().unwrap_or_default(),
+        &[params.access_token_lifetime, params.refresh_token_lifetime],
+        params.rate_limit,
+    )
+    .map_err(warp::reject::custom)?;
+
+    let client_id = session::generate_secret(24);
+    let client_db_id = client.create_client(&client_id, &params)
+        .await
+        .map_err(warp::reject::custom)?;
+    let (_, secret) = client.add_client_secret(client_db_id, None).await;
+
+    let (_, mut details) = client.get_client_details(&client_id)
+        .await
+        .ok_or_else(client_not_found)?;
+    details.client_secret = Some(secret);
+    Ok(warp::reply::with_status(json(&details), StatusCode::CREATED).into_response())
+}
+
+pub async fn update_client(
+    client_id: String,
+    update: ClientUpdate,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let mut client = admin_client(&authorization, &database, &server_config).await?;
+    let client_db_id = find_client(&*client, &client_id).await?;
+
+    check_client_settings(
+        &update.display_name,
+        update
+            .redirect_uris
+            .iter()
+            .flatten()
+            .chain(update.post_logout_redirect_uris.iter().flatten())
+            .chain(&update.frontchannel_logout_uri)
+            .chain(&update.backchannel_logout_uri),
+        update.grant_types.as_deref().unwrap_or_default(),
+        &[update.access_token_lifetime, update.refresh_token_lifetime],
+        update.rate_limit,
+    )
+    .map_err(warp::reject::custom)?;
+
+    client.update_client(client_db_id, &update)
+        .await
+        .map_err(warp::reject::custom)?;
+    let (_, details) = client.get_client_details(&client_id)
+        .await
+        .ok_or_else(client_not_found)?;
+    Ok(json(&details).into_response())
+}
+
+// A disabled client can't authenticate or start the authorization flow, its tokens are revoked
+pub async fn set_client_disabled(
+    client_id: String,
+    disabled: bool,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let client_db_id = find_client(&*client, &client_id).await?;
+
+    client.set_client_disabled(client_db_id, disabled).await;
+    if disabled {
+        client.revoke_client(client_db_id).await;
+    }
+    let (_, details) = client.get_client_details(&client_id)
+        .await
+        .ok_or_else(client_not_found)?;
+    Ok(json(&details).into_response())
+}
+
+pub async fn list_client_secrets(
+    client_id: String,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let client_db_id = find_client(&*client, &client_id).await?;
+    Ok(json(&client.get_client_secrets(client_db_id).await).into_response())
+}
+
+// Rotating a secret: add a new one and let the old ones expire once the client has switched over
+pub async fn add_client_secret(
+    client_id: String,
+    params: SecretRequest,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let client_db_id = find_client(&*client, &client_id).await?;
+
+    if params.expires_in.is_some_and(|e| e <= 0) {
+        return Err(warp::reject::custom(invalid("expires_in has to be positive")));
+    }
+    let expire_time = params.expires_in.map(|e| Local::now() + Duration::seconds(e));
+    let (metadata, secret) = client.add_client_secret(client_db_id, expire_time).await;
+
+    if let Some(previous) = params.previous_expires_in {
+        let previous = Local::now() + Duration::seconds(previous.max(0));
+        client.expire_other_client_secrets(client_db_id, metadata.id, previous).await;
+    }
+
+    let response = NewClientSecret {
+        metadata,
+        client_secret: secret,
+    };
+    Ok(warp::reply::with_status(json(&response), StatusCode::CREATED).into_response())
+}
+
+pub async fn expire_client_secret(
+    client_id: String,
+    secret_id: i32,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let client_db_id = find_client(&*client, &client_id).await?;
+
+    if client.expire_client_secret(client_db_id, secret_id, Local::now()).await {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(warp::reject::custom(NotFoundError("Secret not found".to_string())))
+    }
+}
+
+pub async fn list_client_tokens(
+    client_id: String,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let client_db_id = find_client(&*client, &client_id).await?;
+    Ok(json(&client.get_client_tokens(client_db_id).await).into_response())
+}
+
+fn role_not_found() -> Rejection {
+    warp::reject::custom(NotFoundError("Unknown role".to_string()))
+}
+
+fn group_not_found() -> Rejection {
+    warp::reject::custom(NotFoundError("Unknown group".to_string()))
+}
+
+// The client a client role belongs to, None for a global role
+async fn role_client(client: &dyn Storage, client_id: Option<&str>) -> Result<Option<Uuid>, Rejection> {
+    match client_id {
+        Some(client_id) => Ok(Some(find_client(client, client_id).await?)),
+        None => Ok(None),
+    }
+}
+
+async fn find_role(client: &dyn Storage, name: &str, query: &RoleQuery) -> Result<i32, Rejection> {
+    let client_db_id = role_client(client, query.client_id.as_deref()).await?;
+    client.find_role(client_db_id, name).await.ok_or_else(role_not_found)
+}
+
+async fn find_group(client: &dyn RoleStore, name: &str) -> Result<(i32, Group), Rejection> {
+    client.get_group(name).await.ok_or_else(group_not_found)
+}
+
+// The global roles at /admin/roles, those of a client at /admin/clients/{client_id}/roles
+pub async fn list_roles(
+    client_id: Option<String>,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let client_db_id = role_client(&*client, client_id.as_deref()).await?;
+    Ok(json(&client.get_roles(client_db_id).await).into_response())
+}
+
+// Adds a role or changes its description
+pub async fn put_role(
+    client_id: Option<String>,
+    name: String,
+    params: RoleDescription,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let client_db_id = role_client(&*client, client_id.as_deref()).await?;
+    roles::check_name(&name).map_err(warp::reject::custom)?;
+    roles::check_description(&params.description).map_err(warp::reject::custom)?;
+
+    client.put_role(client_db_id, &name, &params.description).await;
+    Ok(json(&Role {
+        name,
+        client_id,
+        description: params.description,
+    })
+    .into_response())
+}
+
+// Removes the role from the users and groups that have it
+pub async fn delete_role(
+    client_id: Option<String>,
+    name: String,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let client_db_id = role_client(&*client, client_id.as_deref()).await?;
+    if client.delete_role(client_db_id, &name).await {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(role_not_found())
+    }
+}
+
+pub async fn get_user_roles(
+    user_id: Uuid,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    client.get_user(user_id).await.ok_or_else(user_not_found)?;
+    Ok(json(&UserRoles {
+        roles: client.get_user_roles(user_id).await,
+        groups: client.get_user_groups(user_id).await,
+        effective: client.get_effective_roles(user_id).await,
+    })
+    .into_response())
+}
+
+// Assigns a role to the user or takes it away, ?client_id= names a client role
+pub async fn set_user_role(
+    user_id: Uuid,
+    name: String,
+    assigned: bool,
+    query: RoleQuery,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    client.get_user(user_id).await.ok_or_else(user_not_found)?;
+    let role_id = find_role(&*client, &name, &query).await?;
+
+    if assigned {
+        client.add_user_role(user_id, role_id).await;
+    } else if !client.remove_user_role(user_id, role_id).await {
+        return Err(warp::reject::custom(NotFoundError("The user doesn't have this role".to_string())));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+pub async fn list_groups(
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    Ok(json(&client.get_groups().await).into_response())
+}
+
+pub async fn get_group(
+    name: String,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    Ok(json(&find_group(&*client, &name).await?.1).into_response())
+}
+
+// Adds a group or changes its description
+pub async fn put_group(
+    name: String,
+    params: RoleDescription,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    roles::check_name(&name).map_err(warp::reject::custom)?;
+    roles::check_description(&params.description).map_err(warp::reject::custom)?;
+
+    client.put_group(&name, &params.description).await;
+    Ok(json(&find_group(&*client, &name).await?.1).into_response())
+}
+
+// The members lose the group's roles, the roles themselves stay
+pub async fn delete_group(
+    name: String,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    if client.delete_group(&name).await {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(group_not_found())
+    }
+}
+
+pub async fn set_group_member(
+    name: String,
+    user_id: Uuid,
+    member: bool,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let (group_id, _) = find_group(&*client, &name).await?;
+    client.get_user(user_id).await.ok_or_else(user_not_found)?;
+
+    if member {
+        client.add_group_member(group_id, user_id).await;
+    } else if !client.remove_group_member(group_id, user_id).await {
+        return Err(warp::reject::custom(NotFoundError("The user isn't a member".to_string())));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// Gives the group's members a role or takes it away, ?client_id= names a client role
+pub async fn set_group_role(
+    name: String,
+    role: String,
+    assigned: bool,
+    query: RoleQuery,
+    authorization: String,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = admin_client(&authorization, &database, &server_config).await?;
+    let (group_id, _) = find_group(&*client, &name).await?;
+    let role_id = find_role(&*client, &role, &query).await?;
+
+    if assigned {
+        client.add_group_role(group_id, role_id).await;
+    } else if !client.remove_group_role(group_id, role_id).await {
+        return Err(warp::reject::custom(NotFoundError("The group doesn't have this role".to_string())));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
@@ -0,0 +1,162 @@
+use crate::errors::Error::{self, InvalidRequestError};
+use crate::models::AttributeDefinition;
+use crate::scopes;
+use crate::storage::{AttributeStore, Storage};
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Custom user attributes like department or locale. Values are kept as text in user_data and checked
+// against their definition in attributes when they are written. Claims carry them with their type, the
+// scope of the definition releases them.
+
+pub const TYPES: &[&str] = &["string", "integer", "boolean"];
+const MAX_LENGTH: i32 = 2048;
+
+// Names of the claims and fields the attributes end up next to
+const RESERVED: &[&str] = &[
+    "iss", "sub", "aud", "exp", "iat", "nbf", "jti", "auth_time", "amr", "acr", "azp", "nonce", "sid", "at_hash",
+    "active", "client_id", "username", "user_id", "scope", "effective_scope", "token_type", "issuer", "roles", "groups",
+];
+
+fn invalid(message: String) -> Error {
+    InvalidRequestError(message)
+}
+
+// A pattern has to match the whole value
+fn pattern(pattern: &str) -> Result<Regex, Error> {
+    Regex::new(&format!("^(?:{})$", pattern)).map_err(|_| invalid(format!("Invalid pattern {}", pattern)))
+}
+
+pub fn check_definition(definition: &AttributeDefinition) -> Result<(), Error> {
+    let name = &definition.name;
+    let valid_name = name.len() <= 64
+        && name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid_name {
+        return Err(invalid(
+            "Attribute names start with a letter and have lowercase letters, digits and _, up to 64".to_string(),
+        ));
+    }
+    if RESERVED.contains(&name.as_str()) {
+        return Err(invalid(format!("{} is a reserved claim", name)));
+    }
+    if !TYPES.contains(&definition.kind.as_str()) {
+        return Err(invalid(format!("The type is one of {}", TYPES.join(", "))));
+    }
+    if definition.kind != "string" && (definition.max_length.is_some() || definition.pattern.is_some()) {
+        return Err(invalid("Only string attributes have a max_length or pattern".to_string()));
+    }
+    if definition.max_length.is_some_and(|m| m <= 0 || m > MAX_LENGTH) {
+        return Err(invalid(format!("The max_length is between 1 and {}", MAX_LENGTH)));
+    }
+    if let Some(p) = &definition.pattern {
+        if p.len() > 512 {
+            return Err(invalid("The pattern can be at most 512 characters".to_string()));
+        }
+        pattern(p)?;
+    }
+    if definition.description.as_ref().is_some_and(|d| d.chars().count() > 512) {
+        return Err(invalid("The description can be at most 512 characters".to_string()));
+    }
+    if definition.scope.as_ref().is_some_and(|s| s.is_empty() || s.len() > 255 || s.contains(' ')) {
+        return Err(invalid("The scope is a single scope of at most 255 characters".to_string()));
+    }
+    Ok(())
+}
+
+// Checks a value against the definition and returns the text that is stored
+pub fn check_value(definition: &AttributeDefinition, value: &Value) -> Result<String, Error> {
+    let text = match (definition.kind.as_str(), value) {
+        ("string", Value::String(s)) => s.clone(),
+        ("integer", Value::Number(n)) if n.is_i64() => n.to_string(),
+        ("boolean", Value::Bool(b)) => b.to_string(),
+        _ => return Err(invalid(format!("{} has to be of type {}", definition.name, definition.kind))),
+    };
+    let max_length = definition.max_length.unwrap_or(MAX_LENGTH);
+    if text.chars().count() > max_length as usize {
+        return Err(invalid(format!("{} can be at most {} characters", definition.name, max_length)));
+    }
+    if let Some(p) = &definition.pattern {
+        if !pattern(p)?.is_match(&text) {
+            return Err(invalid(format!("{} doesn't have the right format", definition.name)));
+        }
+    }
+    Ok(text)
+}
+
+// The stored text as a value of the attribute's type. Text that doesn't fit the type stays a string, so
+// check_value refuses it.
+pub fn typed_value(definition: &AttributeDefinition, text: &str) -> Value {
+    match (definition.kind.as_str(), text) {
+        ("integer", _) => text.parse::<i64>().map(Value::from).unwrap_or_else(|_| Value::from(text)),
+        ("boolean", "true") => Value::Bool(true),
+        ("boolean", "false") => Value::Bool(false),
+        _ => Value::from(text),
+    }
+}
+
+// All of the user's attributes, for the admin api
+pub async fn values(client: &dyn AttributeStore, user_id: Uuid) -> Map<String, Value> {
+    let definitions = client.get_attribute_definitions().await;
+    client.get_user_attributes(user_id)
+        .await
+        .into_iter()
+        .filter_map(|(name, text)| {
+            let definition = definitions.iter().find(|d| d.name == name)?;
+            Some((name, typed_value(definition, &text)))
+        })
+        .collect()
+}
+
+// The attributes the granted scope releases, implied scopes included
+pub async fn claims(client: &dyn Storage, user_id: Uuid, scope: Option<&str>) -> Map<String, Value> {
+    let granted = scope.map(scopes::parse).unwrap_or_default();
+    if granted.is_empty() {
+        return Map::new();
+    }
+    let granted = scopes::expand(&granted, &client.get_scope_rules().await);
+    let definitions = client.get_attribute_definitions().await;
+
+    client.get_user_attributes(user_id)
+        .await
+        .into_iter()
+        .filter_map(|(name, text)| {
+            let definition = definitions.iter().find(|d| d.name == name)?;
+            let scope = definition.scope.as_deref()?;
+            if !granted.iter().any(|g| scopes::covers(g, scope)) {
+                return None;
+            }
+            Some((name, typed_value(definition, &text)))
+        })
+        .collect()
+}
+
+// Copies values to the user's attributes, mapping is attribute name to the key of its value in source. Values
+// that don't fit the definition are skipped, as are missing ones. Used for the claims of an upstream provider
+// and the attributes of a directory.
+pub async fn copy(client: &mut dyn Storage, user_id: Uuid, mapping: &HashMap<String, String>, source: &Map<String, Value>) {
+    if mapping.is_empty() {
+        return;
+    }
+    let definitions = client.get_attribute_definitions().await;
+    let mut changes = Vec::new();
+    for (name, key) in mapping {
+        let (definition, value) = match (definitions.iter().find(|d| &d.name == name), source.get(key)) {
+            (Some(definition), Some(value)) => (definition, value),
+            (None, _) => {
+                println!("Attribute {} mapped from {} is not defined", name, key);
+                continue;
+            }
+            (_, None) => continue,
+        };
+        match check_value(definition, value) {
+            Ok(text) => changes.push((name.clone(), Some(text))),
+            Err(e) => println!("{} not copied to {}: {}", key, name, e),
+        }
+    }
+    if let Err(e) = client.set_user_attributes(user_id, &changes).await {
+        println!("Updating the attributes of user {} failed: {}", user_id, e);
+    }
+}
@@ -0,0 +1,998 @@
+use crate::admin::{check_attributes, check_client_settings, check_definition};
+use crate::attributes;
+use crate::db;
+use crate::errors::Error::{self, *};
+use crate::handlers::{check_new_user, hash_password, valid_email, valid_username};
+use crate::lockout;
+use crate::migrations;
+use crate::models::{
+    AttributeDefinition, ClientDetails, ClientUpdate, Config, Group, NewClient, Role, User, UserRoles, UserUpdate,
+};
+use crate::password;
+use crate::roles;
+use crate::session;
+use crate::storage::{ClientStore, Database, RoleStore, Storage, UserStore};
+use crate::tenants::{self, Tenant};
+use chrono::{Duration, Local, TimeZone};
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::net::IpAddr;
+use uuid::Uuid;
+
+// Administration from the command line, with the same configuration (environment, .env) as the server.
+// Users are named by username and clients by client_id.
+
+#[derive(Parser)]
+#[command(version, about = "OAuth2 and OpenID Connect server, serves when no command is given")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Run the command for a tenant instead of the default tenant
+    #[arg(long, global = true)]
+    pub tenant: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the server
+    Serve,
+    /// Manage users
+    #[command(subcommand)]
+    Users(UserCommand),
+    /// Manage clients and their secrets
+    #[command(subcommand)]
+    Clients(ClientCommand),
+    /// Revoke access and refresh tokens
+    #[command(subcommand)]
+    Tokens(TokenCommand),
+    /// Manage the custom attributes users can have
+    #[command(subcommand)]
+    Attributes(AttributeCommand),
+    /// Manage the global roles and those of clients
+    #[command(subcommand)]
+    Roles(RoleCommand),
+    /// Manage groups, their members and roles
+    #[command(subcommand)]
+    Groups(GroupCommand),
+    /// Lift a lockout of the password logins from a client address
+    UnlockIp {
+        ip: IpAddr,
+    },
+    /// List the tenants and create their schemas
+    #[command(subcommand)]
+    Tenants(TenantCommand),
+    /// Apply the database migrations that haven't run yet, for every tenant
+    Migrate,
+    /// Print the configuration, passwords are redacted
+    Config,
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// List users, the filters match part of the username or email
+    List {
+        #[arg(long)]
+        username: Option<String>,
+        #[arg(long)]
+        email: Option<String>,
+    },
+    Show {
+        username: String,
+    },
+    /// Create a user, a password is generated when none is given
+    Create {
+        username: String,
+        email: String,
+        #[arg(long)]
+        password: Option<String>,
+        /// The user has to verify the email address before logging in
+        #[arg(long)]
+        unverified: bool,
+    },
+    Update {
+        username: String,
+        #[arg(long)]
+        new_username: Option<String>,
+        #[arg(long)]
+        email: Option<String>,
+        #[arg(long)]
+        email_verified: Option<bool>,
+        /// The user has to log in with a second factor
+        #[arg(long)]
+        mfa_required: Option<bool>,
+    },
+    /// Disable a user and revoke their sessions and tokens
+    Disable {
+        username: String,
+    },
+    Enable {
+        username: String,
+    },
+    /// Delete a user with everything they hold
+    Delete {
+        username: String,
+    },
+    /// Set or generate a new password, the user is logged out everywhere
+    ResetPassword {
+        username: String,
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Remove the user's authenticator and recovery codes
+    ResetMfa {
+        username: String,
+    },
+    Passkeys {
+        username: String,
+    },
+    RemovePasskey {
+        username: String,
+        id: i32,
+    },
+    /// The user's accounts at upstream providers
+    Identities {
+        username: String,
+    },
+    /// Unlink the user's account at an upstream provider
+    Unlink {
+        username: String,
+        provider: String,
+    },
+    /// Lift a lockout of the user's password logins
+    Unlock {
+        username: String,
+    },
+    Attributes {
+        username: String,
+    },
+    /// Set an attribute of the user, without a value it is removed
+    SetAttribute {
+        username: String,
+        name: String,
+        value: Option<String>,
+    },
+    /// The user's roles, groups and the roles they have through either
+    Roles {
+        username: String,
+    },
+    GrantRole {
+        username: String,
+        role: String,
+        /// The client of a client role
+        #[arg(long)]
+        client: Option<String>,
+    },
+    RevokeRole {
+        username: String,
+        role: String,
+        #[arg(long)]
+        client: Option<String>,
+    },
+}
+
+// Client settings, list options can be repeated
+#[derive(Args)]
+pub struct ClientSettings {
+    #[arg(long)]
+    display_name: Option<String>,
+    #[arg(long = "redirect-uri")]
+    redirect_uris: Option<Vec<String>>,
+    #[arg(long = "post-logout-redirect-uri")]
+    post_logout_redirect_uris: Option<Vec<String>>,
+    #[arg(long)]
+    frontchannel_logout_uri: Option<String>,
+    #[arg(long)]
+    backchannel_logout_uri: Option<String>,
+    #[arg(long = "grant-type")]
+    grant_types: Option<Vec<String>>,
+    #[arg(long = "scope")]
+    scopes: Option<Vec<String>>,
+    #[arg(long = "default-scope")]
+    default_scopes: Option<Vec<String>>,
+    /// Seconds
+    #[arg(long)]
+    access_token_lifetime: Option<i32>,
+    /// Seconds
+    #[arg(long)]
+    refresh_token_lifetime: Option<i32>,
+    #[arg(long)]
+    first_party: Option<bool>,
+    /// Users of the client have to log in with a second factor
+    #[arg(long)]
+    mfa_required: Option<bool>,
+    /// Requests per minute on the rate limited routes, 0 for no limit
+    #[arg(long)]
+    rate_limit: Option<i32>,
+    /// Issue access tokens as signed jwts
+    #[arg(long)]
+    jwt_access_tokens: Option<bool>,
+}
+
+#[derive(Subcommand)]
+pub enum ClientCommand {
+    List,
+    Show {
+        client_id: String,
+    },
+    /// Create a client, the client_id and first secret are generated
+    Create {
+        #[command(flatten)]
+        settings: ClientSettings,
+    },
+    /// Change the given settings, lists (uris, grant types, scopes) are replaced as a whole
+    Update {
+        client_id: String,
+        #[command(flatten)]
+        settings: ClientSettings,
+    },
+    /// Disable a client and revoke its tokens
+    Disable {
+        client_id: String,
+    },
+    Enable {
+        client_id: String,
+    },
+    /// Delete a client with its secrets, consents and tokens
+    Delete {
+        client_id: String,
+    },
+    /// List the client's secrets
+    Secrets {
+        client_id: String,
+    },
+    /// Add a secret, optionally phasing out the existing ones
+    AddSecret {
+        client_id: String,
+        /// Seconds until the new secret expires
+        #[arg(long)]
+        expires_in: Option<i64>,
+        /// Seconds until the client's other secrets expire
+        #[arg(long)]
+        previous_expires_in: Option<i64>,
+    },
+    /// Expire a secret right away
+    ExpireSecret {
+        client_id: String,
+        secret_id: i32,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// Revoke the tokens matching all given filters, at least one is required
+    Revoke {
+        /// Username
+        #[arg(long)]
+        user: Option<String>,
+        /// Client id
+        #[arg(long)]
+        client: Option<String>,
+        #[arg(long)]
+        device: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AttributeCommand {
+    List,
+    /// Add an attribute or replace its definition
+    Define {
+        name: String,
+        /// string, integer or boolean
+        #[arg(long = "type", default_value = "string")]
+        kind: String,
+        #[arg(long)]
+        description: Option<String>,
+        /// Characters, for strings
+        #[arg(long)]
+        max_length: Option<i32>,
+        /// A regular expression the whole value has to match, for strings
+        #[arg(long)]
+        pattern: Option<String>,
+        /// The scope that releases the attribute as a claim
+        #[arg(long)]
+        scope: Option<String>,
+    },
+    /// Remove an attribute and every user's value for it
+    Remove {
+        name: String,
+    },
+}
+
+// Roles are global unless --client names the client they belong to
+#[derive(Subcommand)]
+pub enum RoleCommand {
+    List {
+        #[arg(long)]
+        client: Option<String>,
+    },
+    /// Add a role or change its description
+    Define {
+        name: String,
+        #[arg(long)]
+        client: Option<String>,
+        #[arg(long)]
+        description: Option<String>,
+    },
+    /// Remove a role from everyone that has it
+    Remove {
+        name: String,
+        #[arg(long)]
+        client: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TenantCommand {
+    List,
+    /// Create the tenant's schema from database_init.sql, without its demo data, and an admin client
+    Init {
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum GroupCommand {
+    List,
+    /// A group with its roles and members
+    Show {
+        name: String,
+    },
+    /// Add a group or change its description
+    Define {
+        name: String,
+        #[arg(long)]
+        description: Option<String>,
+    },
+    Remove {
+        name: String,
+    },
+    AddMember {
+        name: String,
+        username: String,
+    },
+    RemoveMember {
+        name: String,
+        username: String,
+    },
+    GrantRole {
+        name: String,
+        role: String,
+        /// The client of a client role
+        #[arg(long)]
+        client: Option<String>,
+    },
+    RevokeRole {
+        name: String,
+        role: String,
+        #[arg(long)]
+        client: Option<String>,
+    },
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+fn timestamp(seconds: i64) -> String {
+    Local.timestamp_opt(seconds, 0).unwrap().format("%Y-%m-%d %H:%M").to_string()
+}
+
+async fn find_user(client: &dyn UserStore, username: &str) -> Result<User, Error> {
+    client.get_user_by_username(username)
+        .await
+        .ok_or_else(|| NotFoundError(format!("User {}", username)))
+}
+
+async fn find_client(client: &dyn ClientStore, client_id: &str) -> Result<(Uuid, ClientDetails), Error> {
+    client.get_client_details(client_id)
+        .await
+        .ok_or_else(|| NotFoundError(format!("Client {}", client_id)))
+}
+
+async fn role_client(client: &dyn Storage, client_id: Option<&str>) -> Result<Option<Uuid>, Error> {
+    match client_id {
+        Some(client_id) => Ok(Some(find_client(client, client_id).await?.0)),
+        None => Ok(None),
+    }
+}
+
+async fn find_role(client: &dyn Storage, name: &str, client_id: Option<&str>) -> Result<i32, Error> {
+    let client_db_id = role_client(client, client_id).await?;
+    client.find_role(client_db_id, name)
+        .await
+        .ok_or_else(|| NotFoundError(format!("Role {}", name)))
+}
+
+async fn find_group(client: &dyn RoleStore, name: &str) -> Result<(i32, Group), Error> {
+    client.get_group(name)
+        .await
+        .ok_or_else(|| NotFoundError(format!("Group {}", name)))
+}
+
+fn role_name(role: &Role) -> String {
+    match &role.client_id {
+        Some(client_id) => format!("{}/{}", client_id, role.name),
+        None => role.name.clone(),
+    }
+}
+
+// The tenants are those of the configuration, none when the command runs for a single tenant
+pub async fn run(
+    command: Command,
+    config: &Config,
+    database: &dyn Database,
+    tenants: &[Tenant],
+) -> Result<(), Error> {
+    if let Command::Config = command {
+        let mut config = config.clone();
+        let secrets = IntoIterator::into_iter([&mut config.pg.password, &mut config.server.mail.smtp_password]);
+        let upstream_secrets = config.server.upstream.values_mut().map(|u| &mut u.client_secret);
+        let ldap_secrets = config.server.ldap.iter_mut().map(|l| &mut l.bind_password);
+        for secret in secrets.chain(upstream_secrets).chain(ldap_secrets) {
+            if secret.is_some() {
+                *secret = Some("****".to_string());
+            }
+        }
+        println!("{:#?}", config);
+        return Ok(());
+    }
+
+    let mut client = database.connect().await?;
+    match command {
+        Command::Users(command) => users(command, &mut *client, config).await,
+        Command::Clients(command) => clients(command, &mut *client).await,
+        Command::Tokens(command) => tokens(command, &*client).await,
+        Command::Attributes(command) => attribute_definitions(command, &mut *client).await,
+        Command::Roles(command) => role_definitions(command, &*client).await,
+        Command::Groups(command) => groups(command, &*client).await,
+        Command::UnlockIp { ip } => {
+            if client.clear_login_failures(lockout::IP, &ip.to_string()).await {
+                println!("Unlocked {}", ip);
+            } else {
+                println!("No failed logins from {}", ip);
+            }
+            Ok(())
+        }
+        Command::Tenants(command) => tenant_schemas(command, tenants).await,
+        Command::Migrate => {
+            print_migrations("The database", database.migrate().await?);
+            for tenant in tenants {
+                let mut client = tenant_client(tenant).await?;
+                if !db::schema_exists(&client, &tenant.schema).await {
+                    println!("Tenant {} has no schema yet", tenant.name);
+                    continue;
+                }
+                print_migrations(&format!("Tenant {}", tenant.name), migrations::migrate(&mut client).await?);
+            }
+            Ok(())
+        }
+        Command::Serve | Command::Config => Ok(()),
+    }
+}
+
+async fn users(command: UserCommand, client: &mut dyn Storage, config: &Config) -> Result<(), Error> {
+    let server_config = &config.server;
+    match command {
+        UserCommand::List { username, email } => {
+            let (users, _) = client.get_users(username.as_deref(), email.as_deref(), i64::MAX, 0).await;
+            for user in users {
+                println!(
+                    "{}  {:<20} {:<30} {}{}",
+                    user.id,
+                    user.username,
+                    user.email,
+                    timestamp(user.created),
+                    match (user.disabled, user.email_verified) {
+                        (true, _) => "  disabled",
+                        (false, false) => "  unverified",
+                        _ => "",
+                    }
+                );
+            }
+        }
+        UserCommand::Show { username } => print_json(&find_user(client, &username).await?),
+        UserCommand::Create {
+            username,
+            email,
+            password,
+            unverified,
+        } => {
+            let generated = password.is_none();
+            let password = password.unwrap_or_else(|| session::generate_secret(20));
+            let hash = check_new_user(&username, &email, password.clone(), server_config).await?;
+            let user = client.register_user(&username, &email, &hash, !unverified, None).await?;
+            print_json(&user);
+            if generated {
+                println!("Password: {}", password);
+            }
+        }
+        UserCommand::Update {
+            username,
+            new_username,
+            email,
+            email_verified,
+            mfa_required,
+        } => {
+            let user = find_user(client, &username).await?;
+            if new_username.as_deref().is_some_and(|u| !valid_username(u)) {
+                return Err(InvalidRequestError(
+                    "Username must be 3 to 50 letters, digits or . _ - @".to_string(),
+                ));
+            }
+            if email.as_deref().is_some_and(|e| !valid_email(e)) {
+                return Err(InvalidRequestError("Invalid email address".to_string()));
+            }
+            let update = UserUpdate {
+                username: new_username,
+                email,
+                email_verified,
+                mfa_required,
+            };
+            if let Some(user) = client.update_user(user.id, &update).await? {
+                print_json(&user);
+            }
+        }
+        UserCommand::Disable { username } => {
+            let user = find_user(client, &username).await?;
+            client.set_user_disabled(user.id, true).await;
+            client.revoke_user(user.id).await;
+            println!("Disabled {}", username);
+        }
+        UserCommand::Enable { username } => {
+            let user = find_user(client, &username).await?;
+            client.set_user_disabled(user.id, false).await;
+            println!("Enabled {}", username);
+        }
+        UserCommand::Delete { username } => {
+            let user = find_user(client, &username).await?;
+            client.delete_user(user.id).await;
+            println!("Deleted {}", username);
+        }
+        UserCommand::ResetPassword { username, password } => {
+            let user = find_user(client, &username).await?;
+            let generated = password.is_none();
+            let password = match password {
+                Some(password) => {
+                    password::check_policy(&password, &[&user.username, &user.email], &server_config.password)
+                        .map_err(InvalidRequestError)?;
+                    password
+                }
+                None => session::generate_secret(20),
+            };
+            let hash = hash_password(password.clone(), server_config).await;
+            client.set_password(user.id, &hash).await;
+            client.revoke_user(user.id).await;
+            if generated {
+                println!("Password: {}", password);
+            } else {
+                println!("Password of {} changed", username);
+            }
+        }
+        UserCommand::ResetMfa { username } => {
+            let user = find_user(client, &username).await?;
+            client.delete_mfa(user.id).await;
+            println!("Removed the authenticator of {}", username);
+        }
+        UserCommand::Passkeys { username } => {
+            let user = find_user(client, &username).await?;
+            for passkey in client.get_passkeys(user.id).await {
+                println!(
+                    "{:<6} {:<30} {}  {}",
+                    passkey.id,
+                    passkey.name,
+                    timestamp(passkey.created),
+                    passkey.last_used.map(timestamp).unwrap_or_default()
+                );
+            }
+        }
+        UserCommand::RemovePasskey { username, id } => {
+            let user = find_user(client, &username).await?;
+            if !client.delete_passkey(user.id, id).await {
+                return Err(NotFoundError("Unknown passkey".to_string()));
+            }
+            println!("Removed passkey {} of {}", id, username);
+        }
+        UserCommand::Identities { username } => {
+            let user = find_user(client, &username).await?;
+            for identity in client.get_federated_identities(user.id).await {
+                println!(
+                    "{:<16} {:<40} {:<30} {}  {}",
+                    identity.provider,
+                    identity.subject,
+                    identity.email.unwrap_or_default(),
+                    timestamp(identity.created),
+                    identity.last_login.map(timestamp).unwrap_or_default()
+                );
+            }
+        }
+        UserCommand::Unlink { username, provider } => {
+            let user = find_user(client, &username).await?;
+            if !client.delete_federated_identities(user.id, &provider).await {
+                return Err(NotFoundError(format!("{} has no account at {}", username, provider)));
+            }
+            println!("Unlinked {} from {}", username, provider);
+        }
+        UserCommand::Attributes { username } => {
+            let user = find_user(client, &username).await?;
+            print_json(&attributes::values(client, user.id).await);
+        }
+        UserCommand::SetAttribute { username, name, value } => {
+            let user = find_user(client, &username).await?;
+            let definitions = client.get_attribute_definitions().await;
+            // the value is typed in as text, it is read as the attribute's type
+            let value = match (definitions.iter().find(|d| d.name == name), value) {
+                (Some(definition), Some(value)) => attributes::typed_value(definition, &value),
+                _ => Value::Null,
+            };
+            let mut update = Map::new();
+            update.insert(name, value);
+            let changes = check_attributes(client, update).await?;
+            client.set_user_attributes(user.id, &changes).await?;
+            print_json(&attributes::values(client, user.id).await);
+        }
+        UserCommand::Roles { username } => {
+            let user = find_user(client, &username).await?;
+            print_json(&UserRoles {
+                roles: client.get_user_roles(user.id).await,
+                groups: client.get_user_groups(user.id).await,
+                effective: client.get_effective_roles(user.id).await,
+            });
+        }
+        UserCommand::GrantRole { username, role, client: client_id } => {
+            let user = find_user(client, &username).await?;
+            let role_id = find_role(client, &role, client_id.as_deref()).await?;
+            client.add_user_role(user.id, role_id).await;
+            println!("Granted {} to {}", role, username);
+        }
+        UserCommand::RevokeRole { username, role, client: client_id } => {
+            let user = find_user(client, &username).await?;
+            let role_id = find_role(client, &role, client_id.as_deref()).await?;
+            if !client.remove_user_role(user.id, role_id).await {
+                return Err(NotFoundError(format!("{} doesn't have role {}", username, role)));
+            }
+            println!("Revoked {} from {}", role, username);
+        }
+        UserCommand::Unlock { username } => {
+            // failures are counted for usernames that don't exist as well
+            if client.clear_login_failures(lockout::USER, &lockout::user_subject(&username)).await {
+                println!("Unlocked {}", username);
+            } else {
+                println!("No failed logins for {}", username);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_settings(settings: &ClientSettings) -> Result<(), Error> {
+    check_client_settings(
+        &settings.display_name,
+        settings
+            .redirect_uris
+            .iter()
+            .flatten()
+            .chain(settings.post_logout_redirect_uris.iter().flatten())
+            .chain(&settings.frontchannel_logout_uri)
+            .chain(&settings.backchannel_logout_uri),
+        settings.grant_types.as_deref().unwrap_or_default(),
+        &[settings.access_token_lifetime, settings.refresh_token_lifetime],
+        settings.rate_limit,
+    )
+}
+
+async fn clients(command: ClientCommand, client: &mut dyn Storage) -> Result<(), Error> {
+    match command {
+        ClientCommand::List => {
+            for details in client.get_clients().await {
+                println!(
+                    "{:<32} {:<30} {}{}",
+                    details.client_id,
+                    details.display_name.as_deref().unwrap_or(""),
+                    details.grant_types.join(","),
+                    if details.disabled { "  disabled" } else { "" }
+                );
+            }
+        }
+        ClientCommand::Show { client_id } => print_json(&find_client(client, &client_id).await?.1),
+        ClientCommand::Create { settings } => {
+            check_settings(&settings)?;
+            let params = NewClient {
+                display_name: settings.display_name,
+                redirect_uris: settings.redirect_uris.unwrap_or_default(),
+                post_logout_redirect_uris: settings.post_logout_redirect_uris.unwrap_or_default(),
+                frontchannel_logout_uri: settings.frontchannel_logout_uri,
+                backchannel_logout_uri: settings.backchannel_logout_uri,
+                grant_types: settings.grant_types,
+                scopes: settings.scopes.unwrap_or_default(),
+                default_scopes: settings.default_scopes.unwrap_or_default(),
+                access_token_lifetime: settings.access_token_lifetime,
+                refresh_token_lifetime: settings.refresh_token_lifetime,
+                first_party: settings.first_party.unwrap_or(false),
+                mfa_required: settings.mfa_required.unwrap_or(false),
+                rate_limit: settings.rate_limit,
+                jwt_access_tokens: settings.jwt_access_tokens.unwrap_or(false),
+            };
+            let client_id = session::generate_secret(24);
+            let client_db_id = client.create_client(&client_id, &params).await?;
+            let (_, secret) = client.add_client_secret(client_db_id, None).await;
+            let (_, mut details) = find_client(client, &client_id).await?;
+            details.client_secret = Some(secret);
+            print_json(&details);
+        }
+        ClientCommand::Update { client_id, settings } => {
+            let (client_db_id, _) = find_client(client, &client_id).await?;
+            check_settings(&settings)?;
+            let update = ClientUpdate {
+                display_name: settings.display_name,
+                redirect_uris: settings.redirect_uris,
+                post_logout_redirect_uris: settings.post_logout_redirect_uris,
+                frontchannel_logout_uri: settings.frontchannel_logout_uri,
+                backchannel_logout_uri: settings.backchannel_logout_uri,
+                grant_types: settings.grant_types,
+                scopes: settings.scopes,
+                default_scopes: settings.default_scopes,
+                access_token_lifetime: settings.access_token_lifetime,
+                refresh_token_lifetime: settings.refresh_token_lifetime,
+                first_party: settings.first_party,
+                mfa_required: settings.mfa_required,
+                rate_limit: settings.rate_limit,
+                jwt_access_tokens: settings.jwt_access_tokens,
+            };
+            client.update_client(client_db_id, &update).await?;
+            print_json(&find_client(client, &client_id).await?.1);
+        }
+        ClientCommand::Disable { client_id } => {
+            let (client_db_id, _) = find_client(client, &client_id).await?;
+            client.set_client_disabled(client_db_id, true).await;
+            client.revoke_client(client_db_id).await;
+            println!("Disabled {}", client_id);
+        }
+        ClientCommand::Enable { client_id } => {
+            let (client_db_id, _) = find_client(client, &client_id).await?;
+            client.set_client_disabled(client_db_id, false).await;
+            println!("Enabled {}", client_id);
+        }
+        ClientCommand::Delete { client_id } => {
+            let (client_db_id, _) = find_client(client, &client_id).await?;
+            client.delete_client(client_db_id).await;
+            println!("Deleted {}", client_id);
+        }
+        ClientCommand::Secrets { client_id } => {
+            let (client_db_id, _) = find_client(client, &client_id).await?;
+            for secret in client.get_client_secrets(client_db_id).await {
+                println!(
+                    "{:<6} {:<10} created {}  expires {}",
+                    secret.id,
+                    format!("{}...", secret.prefix),
+                    timestamp(secret.created),
+                    secret.expires.map(timestamp).unwrap_or_else(|| "never".to_string())
+                );
+            }
+        }
+        ClientCommand::AddSecret {
+            client_id,
+            expires_in,
+            previous_expires_in,
+        } => {
+            let (client_db_id, _) = find_client(client, &client_id).await?;
+            if expires_in.is_some_and(|e| e <= 0) {
+                return Err(InvalidRequestError("expires_in has to be positive".to_string()));
+            }
+            let expire_time = expires_in.map(|e| Local::now() + Duration::seconds(e));
+            let (metadata, secret) = client.add_client_secret(client_db_id, expire_time).await;
+            if let Some(previous) = previous_expires_in {
+                let previous = Local::now() + Duration::seconds(previous.max(0));
+                client.expire_other_client_secrets(client_db_id, metadata.id, previous).await;
+            }
+            println!("Secret {}: {}", metadata.id, secret);
+        }
+        ClientCommand::ExpireSecret { client_id, secret_id } => {
+            let (client_db_id, _) = find_client(client, &client_id).await?;
+            if !client.expire_client_secret(client_db_id, secret_id, Local::now()).await {
+                return Err(NotFoundError(format!("Secret {}", secret_id)));
+            }
+            println!("Expired secret {}", secret_id);
+        }
+    }
+    Ok(())
+}
+
+fn print_migrations(name: &str, migrated: Vec<&str>) {
+    if migrated.is_empty() {
+        println!("{} is up to date", name);
+    }
+    for version in migrated {
+        println!("{}: applied {}", name, version);
+    }
+}
+
+// Tenants are schemas of the Postgres database, a connection of the tenant only sees its own
+async fn tenant_client(tenant: &Tenant) -> Result<deadpool_postgres::Client, Error> {
+    crate::create_pool(&tenant.config).get().await.map_err(DBPoolError)
+}
+
+async fn tenant_schemas(command: TenantCommand, tenants: &[Tenant]) -> Result<(), Error> {
+    match command {
+        TenantCommand::List => {
+            for tenant in tenants {
+                let client = tenant_client(tenant).await?;
+                let state = if db::schema_exists(&client, &tenant.schema).await { "" } else { ", no schema yet" };
+                println!(
+                    "{}\t{}\t{}\t(schema {}{})",
+                    tenant.name, tenant.host, tenant.config.server.name, tenant.schema, state
+                );
+            }
+            Ok(())
+        }
+        TenantCommand::Init { name } => {
+            let tenant = tenants
+                .iter()
+                .find(|t| t.name == name)
+                .ok_or_else(|| NotFoundError(format!("Tenant {}", name)))?;
+            let script = std::fs::read_to_string("database_init.sql")
+                .map_err(|e| InvalidRequestError(format!("Can't read database_init.sql: {}", e)))?;
+            let mut client = tenant_client(tenant).await?;
+            let secret = tenants::init(&mut client, tenant, &script).await?;
+            println!("Created schema {} for tenant {}", tenant.schema, tenant.name);
+            println!("Client admin has secret {}", secret);
+            Ok(())
+        }
+    }
+}
+
+async fn attribute_definitions(command: AttributeCommand, client: &mut dyn Storage) -> Result<(), Error> {
+    match command {
+        AttributeCommand::List => {
+            for definition in client.get_attribute_definitions().await {
+                println!(
+                    "{:<24} {:<8} {:<16} {}",
+                    definition.name,
+                    definition.kind,
+                    definition.scope.as_deref().unwrap_or(""),
+                    definition.description.as_deref().unwrap_or("")
+                );
+            }
+        }
+        AttributeCommand::Define {
+            name,
+            kind,
+            description,
+            max_length,
+            pattern,
+            scope,
+        } => {
+            let definition = AttributeDefinition {
+                name,
+                kind,
+                description,
+                max_length,
+                pattern,
+                scope,
+            };
+            check_definition(client, &definition).await?;
+            client.put_attribute_definition(&definition).await;
+            print_json(&definition);
+        }
+        AttributeCommand::Remove { name } => {
+            if !client.delete_attribute_definition(&name).await? {
+                return Err(NotFoundError("Unknown attribute".to_string()));
+            }
+            println!("Removed {}", name);
+        }
+    }
+    Ok(())
+}
+
+async fn role_definitions(command: RoleCommand, client: &dyn Storage) -> Result<(), Error> {
+    match command {
+        RoleCommand::List { client: client_id } => {
+            let client_db_id = role_client(client, client_id.as_deref()).await?;
+            for role in client.get_roles(client_db_id).await {
+                println!("{:<40} {}", role_name(&role), role.description.as_deref().unwrap_or(""));
+            }
+        }
+        RoleCommand::Define {
+            name,
+            client: client_id,
+            description,
+        } => {
+            let client_db_id = role_client(client, client_id.as_deref()).await?;
+            roles::check_name(&name)?;
+            roles::check_description(&description)?;
+            client.put_role(client_db_id, &name, &description).await;
+            print_json(&Role {
+                name,
+                client_id,
+                description,
+            });
+        }
+        RoleCommand::Remove { name, client: client_id } => {
+            let client_db_id = role_client(client, client_id.as_deref()).await?;
+            if !client.delete_role(client_db_id, &name).await {
+                return Err(NotFoundError(format!("Role {}", name)));
+            }
+            println!("Removed {}", name);
+        }
+    }
+    Ok(())
+}
+
+async fn groups(command: GroupCommand, client: &dyn Storage) -> Result<(), Error> {
+    match command {
+        GroupCommand::List => {
+            for group in client.get_groups().await {
+                println!(
+                    "{:<24} {:<40} {}",
+                    group.name,
+                    group.roles.iter().map(role_name).collect::<Vec<_>>().join(","),
+                    group.description.as_deref().unwrap_or("")
+                );
+            }
+        }
+        GroupCommand::Show { name } => print_json(&find_group(client, &name).await?.1),
+        GroupCommand::Define { name, description } => {
+            roles::check_name(&name)?;
+            roles::check_description(&description)?;
+            client.put_group(&name, &description).await;
+            print_json(&find_group(client, &name).await?.1);
+        }
+        GroupCommand::Remove { name } => {
+            if !client.delete_group(&name).await {
+                return Err(NotFoundError(format!("Group {}", name)));
+            }
+            println!("Removed {}", name);
+        }
+        GroupCommand::AddMember { name, username } => {
+            let (group_id, _) = find_group(client, &name).await?;
+            let user = find_user(client, &username).await?;
+            client.add_group_member(group_id, user.id).await;
+            println!("Added {} to {}", username, name);
+        }
+        GroupCommand::RemoveMember { name, username } => {
+            let (group_id, _) = find_group(client, &name).await?;
+            let user = find_user(client, &username).await?;
+            if !client.remove_group_member(group_id, user.id).await {
+                return Err(NotFoundError(format!("{} isn't a member of {}", username, name)));
+            }
+            println!("Removed {} from {}", username, name);
+        }
+        GroupCommand::GrantRole { name, role, client: client_id } => {
+            let (group_id, _) = find_group(client, &name).await?;
+            let role_id = find_role(client, &role, client_id.as_deref()).await?;
+            client.add_group_role(group_id, role_id).await;
+            println!("Granted {} to {}", role, name);
+        }
+        GroupCommand::RevokeRole { name, role, client: client_id } => {
+            let (group_id, _) = find_group(client, &name).await?;
+            let role_id = find_role(client, &role, client_id.as_deref()).await?;
+            if !client.remove_group_role(group_id, role_id).await {
+                return Err(NotFoundError(format!("{} doesn't have role {}", name, role)));
+            }
+            println!("Revoked {} from {}", role, name);
+        }
+    }
+    Ok(())
+}
+
+async fn tokens(command: TokenCommand, client: &dyn Storage) -> Result<(), Error> {
+    let TokenCommand::Revoke { user, client: client_id, device } = command;
+    if user.is_none() && client_id.is_none() && device.is_none() {
+        return Err(InvalidRequestError(
+            "Give at least one of --user, --client or --device".to_string(),
+        ));
+    }
+
+    let user_id = match &user {
+        Some(username) => Some(find_user(client, username).await?.id),
+        None => None,
+    };
+    let client_db_id = match &client_id {
+        Some(client_id) => Some(find_client(client, client_id).await?.0),
+        None => None,
+    };
+    let revoked = client.revoke_tokens(user_id, client_db_id, device.as_deref()).await;
+    println!("Revoked {} tokens", revoked);
+    Ok(())
+}
@@ -1,8 +1,22 @@
-use crate::models::{AccessToken, Introspection, User};
+use crate::errors::Error::{
+    self, ConflictError, DBInitError, DBQueryError, InvalidRequestError, InvalidScopeError,
+};
+use crate::models::{
+    AccessToken, AttributeDefinition, AuthorizationCode, AuthorizationParams, ChallengePurpose, ClientDetails,
+    ClientInfo, ClientScope, ClientSecret, ClientUpdate, EmailTokenPurpose, FederatedIdentity,
+    FederationState, Group, GroupMember, Introspection, LoginSession,
+    LogoutClient, NewClient, Passkey, PasskeyCredential, Role, Scope, ScopePolicy, ScopeRule, TokenGrant,
+    TokenInfo, TotpCredential, User, UserSource, UserUpdate,
+};
+use crate::federation;
+use crate::ldap;
+use crate::session;
+use crate::webauthn::NewCredential;
 use chrono::{DateTime, Duration, Local};
 use deadpool_postgres::Client;
 use tokio_pg_mapper::FromTokioPostgresRow;
-use sha2::{Sha256, Digest};
+use tokio_postgres::error::{DbError, SqlState};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 // validate in 1 go?
 pub async fn validate_access_token(
@@ -10,167 +24,2295 @@ pub async fn validate_access_token(
     access_token: String,
     client_db_id: Uuid,
 ) -> Option<Introspection> {
-    let statement = client.prepare("select a.scope, a.expire_time, a.creation_time, c.username, c.id, b.client_id, b.display_name, a.token_type, a.issuer
-                                   from access_tokens as a join clients as b on a.client_id = b.id left join users as c on a.user_id = c.id 
+    let statement = client.prepare("select a.scope, a.expire_time, a.creation_time, c.username, c.id, b.client_id, b.display_name, a.token_type, a.issuer, a.auth_time, a.amr
+                                   from access_tokens as a join clients as b on a.client_id = b.id left join users as c on a.user_id = c.id
                                    where a.access_token = $1 and b.id = $2").await.unwrap();
     let response = client
         .query(&statement, &[&access_token, &client_db_id])
         .await
         .expect("Error executing query on access token/clients table");
 
-    //TODO check if response is not empty
-
     if response.is_empty() {
         return None;
     }
 
     let expire_time: DateTime<Local> = response[0].get(1);
-    let is_active = if expire_time < Local::now() {
-        false
-    } else {
-        true
-    };
+    let is_active = expire_time >= Local::now();
 
     let creation_time: DateTime<Local> = response[0].get(2);
+    let auth_time: Option<DateTime<Local>> = response[0].get(9);
 
-    return Some(Introspection {
+    Some(Introspection {
         active: is_active,
         client_id: response[0].get(5),
         username: response[0].get(3),
         user_id: response[0].get(4),
         scope: response[0].get(0),
+        effective_scope: None,
         token_type: response[0].get(7),
         issuer: response[0].get(8),
         exp: expire_time.timestamp(),
         iat: creation_time.timestamp(),
+        auth_time: auth_time.map(|t| t.timestamp()),
+        amr: response[0].get::<_, Option<String>>(10).map(split_list).unwrap_or_default(),
+        roles: Vec::new(),
+        groups: Vec::new(),
+        claims: serde_json::Map::new(),
     })
 }
 
-pub async fn validate_password_credentials(
-    client: &Client,
-    username: String,
-    password: String,
-) -> Option<Uuid> {
+// The password hash of a user that can log in with a password, not disabled and with a verified email
+pub async fn get_password_hash(client: &Client, username: &str) -> Option<(Uuid, String)> {
     let statement = client
-        .prepare("select id from users where username = $1 and password = $2")
+        .prepare("select id, password from users where username = $1 and email_verified and not disabled")
         .await
         .unwrap();
 
     let user = client
-        .query(&statement, &[&username, &password])
+        .query(&statement, &[&username])
         .await
         .expect("Error executing query on users table");
 
     if user.len() == 1 {
-        return Some(user.get(0).unwrap().get(0));
+        Some((user[0].get(0), user[0].get(1)))
     } else {
-        return None;
+        None
     }
 }
 
+pub async fn get_user_source(client: &Client, username: &str) -> Option<UserSource> {
+    let statement = client
+        .prepare("select source from users where username = $1")
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&username])
+        .await
+        .expect("Error executing query on users table")
+        .map(|row| UserSource::parse(row.get(0)))
+}
+
 pub async fn validate_client_credentials(
     client: &Client,
     client_id: String,
     secret: String,
 ) -> Option<Uuid> {
     let statement = client
-        .prepare("select id from clients where client_id = $1 and client_secret = $2")
+        .prepare(
+            "select c.id from clients as c join client_secrets as s on s.client_id = c.id
+             where c.client_id = $1 and not c.disabled and s.secret_hash = $2
+             and (s.expire_time is null or s.expire_time > now())",
+        )
         .await
         .unwrap();
 
     let client_response = client
-        .query(&statement, &[&client_id, &secret])
+        .query(&statement, &[&client_id, &session::hash_token(&secret)])
         .await
         .expect("Error executing query on clients table");
 
     if client_response.len() == 1 {
-        return Some(client_response.get(0).unwrap().get(0));
+        Some(client_response[0].get(0))
     } else {
-        return None;
+        None
     }
 }
 
-pub async fn validate_code(client: &Client, code: &String, pcke: &String) -> Option<Uuid> {
-    let mut hasher = Sha256::new();
-    hasher.update(pcke);
-    let pcke_result = format!("{:X}", hasher.finalize()).to_lowercase();
+// Stores a new secret for the client. The secret is returned here and never again, only its hash is kept.
+pub async fn add_client_secret(
+    client: &Client,
+    client_db_id: Uuid,
+    expire_time: Option<DateTime<Local>>,
+) -> (ClientSecret, String) {
+    let secret = session::generate_secret(48);
+    let statement = client
+        .prepare(
+            "insert into client_secrets (client_id, secret_hash, prefix, expire_time) values ($1, $2, $3, $4)
+             returning id, prefix, creation_time, expire_time",
+        )
+        .await
+        .unwrap();
+
+    let row = client
+        .query_one(
+            &statement,
+            &[&client_db_id, &session::hash_token(&secret), &&secret[..6], &expire_time],
+        )
+        .await
+        .expect("Error inserting client secret");
+    (client_secret(&row), secret)
+}
+
+fn client_secret(row: &tokio_postgres::Row) -> ClientSecret {
+    let creation_time: DateTime<Local> = row.get(2);
+    let expire_time: Option<DateTime<Local>> = row.get(3);
+    ClientSecret {
+        id: row.get(0),
+        prefix: row.get(1),
+        created: creation_time.timestamp(),
+        expires: expire_time.map(|t| t.timestamp()),
+    }
+}
 
-    println!("Looking for code {}", code);
-    println!("Looking for pcke {}", pcke_result);
+pub async fn get_client_secrets(client: &Client, client_db_id: Uuid) -> Vec<ClientSecret> {
+    let statement = client
+        .prepare(
+            "select id, prefix, creation_time, expire_time from client_secrets
+             where client_id = $1 order by creation_time",
+        )
+        .await
+        .unwrap();
 
+    client
+        .query(&statement, &[&client_db_id])
+        .await
+        .expect("Error executing query on client_secrets table")
+        .iter()
+        .map(client_secret)
+        .collect()
+}
+
+// Sets when a secret stops working, used to phase out the old secret after a new one was added
+pub async fn expire_client_secret(
+    client: &Client,
+    client_db_id: Uuid,
+    secret_id: i32,
+    expire_time: DateTime<Local>,
+) -> bool {
     let statement = client
-        .prepare("select * from authorization_codes where code = $1 and pcke_hash = $2")
+        .prepare("update client_secrets set expire_time = $3 where client_id = $1 and id = $2")
         .await
         .unwrap();
 
-    let code_response = client
-        .query(&statement, &[&code, &pcke_result])
+    client
+        .execute(&statement, &[&client_db_id, &secret_id, &expire_time])
         .await
-        .expect("Error executing query on authorization_codes table");
+        .expect("Error updating client_secrets table")
+        == 1
+}
 
-    if code_response.len() == 1 {
-        // TODO check tijd op token
-        return Some(code_response.get(0).unwrap().get(2));
-    } else {
-        return None;
+// Phases out the client's other secrets, secrets that already expire sooner are left alone
+pub async fn expire_other_client_secrets(
+    client: &Client,
+    client_db_id: Uuid,
+    keep_id: i32,
+    expire_time: DateTime<Local>,
+) {
+    let statement = client
+        .prepare(
+            "update client_secrets set expire_time = $3
+             where client_id = $1 and id != $2 and (expire_time is null or expire_time > $3)",
+        )
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&client_db_id, &keep_id, &expire_time])
+        .await
+        .expect("Error updating client_secrets table");
+}
+
+const CLIENT_COLUMNS: &str = "id, client_id, display_name, redirect_uris, post_logout_redirect_uris, frontchannel_logout_uri,
+    backchannel_logout_uri, grant_types, access_token_lifetime, refresh_token_lifetime, first_party, disabled, creation_time, mfa_required, rate_limit,
+    jwt_access_tokens";
+
+async fn client_details(client: &Client, row: &tokio_postgres::Row) -> ClientDetails {
+    let creation_time: DateTime<Local> = row.get(12);
+    ClientDetails {
+        client_id: row.get(1),
+        display_name: row.get(2),
+        redirect_uris: split_list(row.get(3)),
+        post_logout_redirect_uris: split_list(row.get(4)),
+        frontchannel_logout_uri: row.get(5),
+        backchannel_logout_uri: row.get(6),
+        grant_types: split_list(row.get(7)),
+        scopes: get_client_scopes(client, row.get(0)).await,
+        access_token_lifetime: row.get(8),
+        refresh_token_lifetime: row.get(9),
+        first_party: row.get(10),
+        mfa_required: row.get(13),
+        rate_limit: row.get(14),
+        jwt_access_tokens: row.get(15),
+        disabled: row.get(11),
+        created: creation_time.timestamp(),
+        client_secret: None,
     }
 }
 
-pub async fn delete_code(client: &Client, code: &String) {
+// Disabled clients included, unlike get_client_by_client_id
+pub async fn get_client_details(client: &Client, client_id: &str) -> Option<(Uuid, ClientDetails)> {
     let statement = client
-        .prepare("delete from authorization_codes where code = $1")
+        .prepare(&format!("select {} from clients where client_id = $1", CLIENT_COLUMNS))
         .await
         .unwrap();
 
-    let code_response = client
-        .query(&statement, &[&code])
+    let row = client
+        .query_opt(&statement, &[&client_id])
         .await
-        .expect("Error deleting query on authorization_codes table");
+        .expect("Error executing query on clients table")?;
+    Some((row.get(0), client_details(client, &row).await))
 }
 
-pub async fn create_tables(client: &Client, script: &str) {
-    let res = client.batch_execute(script).await;
+pub async fn get_clients(client: &Client) -> Vec<ClientDetails> {
+    let statement = client
+        .prepare(&format!("select {} from clients order by client_id", CLIENT_COLUMNS))
+        .await
+        .unwrap();
 
-    match res {
-        Ok(_) => println!("Database created"),
-        Err(msg) => println!("Error creating database, continuing startup. Message: ${0}", msg)
-    };
+    let rows = client
+        .query(&statement, &[])
+        .await
+        .expect("Error executing query on clients table");
+
+    let mut clients = Vec::with_capacity(rows.len());
+    for row in &rows {
+        clients.push(client_details(client, row).await);
+    }
+    clients
 }
 
-pub async fn insert_token(
+pub async fn create_client(client: &mut Client, client_id: &str, params: &NewClient) -> Result<Uuid, Error> {
+    let transaction = client.transaction().await?;
+    let grant_types = params
+        .grant_types
+        .as_ref()
+        .map(|g| g.join(" "))
+        .unwrap_or_else(|| "authorization_code refresh_token".to_string());
+
+    let row = transaction
+        .query_one(
+            "insert into clients (client_id, display_name, redirect_uris, post_logout_redirect_uris, frontchannel_logout_uri,
+             backchannel_logout_uri, grant_types, access_token_lifetime, refresh_token_lifetime, first_party, mfa_required,
+             rate_limit, jwt_access_tokens) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) returning id",
+            &[
+                &client_id,
+                &params.display_name,
+                &params.redirect_uris.join(" "),
+                &params.post_logout_redirect_uris.join(" "),
+                &params.frontchannel_logout_uri,
+                &params.backchannel_logout_uri,
+                &grant_types,
+                &params.access_token_lifetime,
+                &params.refresh_token_lifetime,
+                &params.first_party,
+                &params.mfa_required,
+                &params.rate_limit,
+                &params.jwt_access_tokens,
+            ],
+        )
+        .await?;
+    let client_db_id: Uuid = row.get(0);
+
+    set_client_scopes(&transaction, client_db_id, &params.scopes, &params.default_scopes).await?;
+    transaction.commit().await?;
+    Ok(client_db_id)
+}
+
+pub async fn update_client(client: &mut Client, client_db_id: Uuid, update: &ClientUpdate) -> Result<(), Error> {
+    let transaction = client.transaction().await?;
+
+    transaction
+        .execute(
+            "update clients set display_name = coalesce($2, display_name), redirect_uris = coalesce($3, redirect_uris),
+             post_logout_redirect_uris = coalesce($4, post_logout_redirect_uris),
+             frontchannel_logout_uri = coalesce($5, frontchannel_logout_uri),
+             backchannel_logout_uri = coalesce($6, backchannel_logout_uri), grant_types = coalesce($7, grant_types),
+             access_token_lifetime = coalesce($8, access_token_lifetime),
+             refresh_token_lifetime = coalesce($9, refresh_token_lifetime), first_party = coalesce($10, first_party),
+             mfa_required = coalesce($11, mfa_required), rate_limit = coalesce($12, rate_limit),
+             jwt_access_tokens = coalesce($13, jwt_access_tokens)
+             where id = $1",
+            &[
+                &client_db_id,
+                &update.display_name,
+                &update.redirect_uris.as_ref().map(|u| u.join(" ")),
+                &update.post_logout_redirect_uris.as_ref().map(|u| u.join(" ")),
+                &update.frontchannel_logout_uri,
+                &update.backchannel_logout_uri,
+                &update.grant_types.as_ref().map(|g| g.join(" ")),
+                &update.access_token_lifetime,
+                &update.refresh_token_lifetime,
+                &update.first_party,
+                &update.mfa_required,
+                &update.rate_limit,
+                &update.jwt_access_tokens,
+            ],
+        )
+        .await?;
+
+    if update.scopes.is_some() || update.default_scopes.is_some() {
+        // a missing list keeps what the client has
+        let current = transaction
+            .query(
+                "select s.name, cs.is_default from client_scopes as cs join scopes as s on cs.scope_id = s.id
+                 where cs.client_id = $1",
+                &[&client_db_id],
+            )
+            .await?;
+        let scopes = update.scopes.clone().unwrap_or_else(|| current.iter().map(|r| r.get(0)).collect());
+        let defaults = update.default_scopes.clone().unwrap_or_else(|| {
+            current
+                .iter()
+                .filter(|r| r.get::<_, bool>(1))
+                .map(|r| r.get(0))
+                .collect()
+        });
+        set_client_scopes(&transaction, client_db_id, &scopes, &defaults).await?;
+    }
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+// Replaces the scopes of the client, default scopes have to be among the scopes
+async fn set_client_scopes(
+    transaction: &deadpool_postgres::Transaction<'_>,
+    client_db_id: Uuid,
+    scopes: &[String],
+    defaults: &[String],
+) -> Result<(), Error> {
+    if let Some(scope) = defaults.iter().find(|d| !scopes.contains(d)) {
+        return Err(InvalidScopeError(format!("Default scope {} is not one of the client's scopes", scope)));
+    }
+
+    transaction
+        .execute("delete from client_scopes where client_id = $1", &[&client_db_id])
+        .await?;
+    let inserted = transaction
+        .execute(
+            "insert into client_scopes (client_id, scope_id, is_default)
+             select $1, id, name = any($3) from scopes where name = any($2)",
+            &[&client_db_id, &scopes, &defaults],
+        )
+        .await?;
+
+    if inserted as usize != scopes.len() {
+        return Err(InvalidScopeError("Unknown scope".to_string()));
+    }
+    Ok(())
+}
+
+pub async fn set_client_disabled(client: &Client, client_db_id: Uuid, disabled: bool) {
+    let statement = client
+        .prepare("update clients set disabled = $2 where id = $1")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&client_db_id, &disabled])
+        .await
+        .expect("Error updating clients table");
+}
+
+// Removes the client's tokens and pending codes
+pub async fn revoke_client(client: &Client, client_db_id: Uuid) {
+    for table in ["authorization_codes", "access_tokens"] {
+        let statement = client
+            .prepare(&format!("delete from {} where client_id = $1", table))
+            .await
+            .unwrap();
+        client
+            .execute(&statement, &[&client_db_id])
+            .await
+            .expect("Error revoking client");
+    }
+}
+
+// Deletes the client with its secrets, scopes, grants, consents and tokens, see the on delete cascade
+// constraints
+pub async fn delete_client(client: &Client, client_db_id: Uuid) -> bool {
+    let statement = client
+        .prepare("delete from clients where id = $1")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&client_db_id])
+        .await
+        .expect("Error deleting from clients table")
+        == 1
+}
+
+// Tokens of the client that can still be used, either the access token or its refresh token
+pub async fn get_client_tokens(client: &Client, client_db_id: Uuid) -> Vec<TokenInfo> {
+    let statement = client
+        .prepare(
+            "select a.id, a.user_id, u.username, a.scope, a.device, a.creation_time, a.expire_time, a.refresh_token_hash is not null
+             from access_tokens as a left join users as u on a.user_id = u.id
+             where a.client_id = $1 and (a.expire_time > now()
+               or (a.refresh_token_hash is not null and (a.refresh_expire_time is null or a.refresh_expire_time > now())))
+             order by a.creation_time desc",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&client_db_id])
+        .await
+        .expect("Error executing query on access_tokens table")
+        .iter()
+        .map(|row| {
+            let creation_time: DateTime<Local> = row.get(5);
+            let expire_time: DateTime<Local> = row.get(6);
+            TokenInfo {
+                id: row.get(0),
+                user_id: row.get(1),
+                username: row.get(2),
+                scope: row.get(3),
+                device: row.get(4),
+                created: creation_time.timestamp(),
+                expires: expire_time.timestamp(),
+                refresh_token: row.get(7),
+            }
+        })
+        .collect()
+}
+
+pub async fn get_client_scopes(client: &Client, client_db_id: Uuid) -> Vec<ClientScope> {
+    let statement = client
+        .prepare(
+            "select s.name, cs.is_default from client_scopes as cs join scopes as s on cs.scope_id = s.id
+             where cs.client_id = $1 order by s.name",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&client_db_id])
+        .await
+        .expect("Error executing query on client_scopes table")
+        .iter()
+        .map(|row| ClientScope {
+            name: row.get(0),
+            is_default: row.get(1),
+        })
+        .collect()
+}
+
+// Space separated lists as stored in the text columns
+pub fn split_list(value: String) -> Vec<String> {
+    value.split_whitespace().map(String::from).collect()
+}
+
+// The client's own limit in requests per minute, None for unknown clients and clients without one
+pub async fn get_client_rate_limit(client: &Client, client_id: &str) -> Option<i32> {
+    let statement = client
+        .prepare("select rate_limit from clients where client_id = $1")
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&client_id])
+        .await
+        .expect("Error executing query on clients table")
+        .and_then(|row| row.get(0))
+}
+
+// Looks up a client by its public client id, used where the client does not authenticate
+pub async fn get_client_by_client_id(client: &Client, client_id: &str) -> Option<ClientInfo> {
+    let statement = client
+        .prepare(
+            "select id, client_id, display_name, redirect_uris, first_party, post_logout_redirect_uris, grant_types,
+             access_token_lifetime, refresh_token_lifetime, mfa_required, jwt_access_tokens from clients
+             where client_id = $1 and not disabled",
+        )
+        .await
+        .unwrap();
+
+    let response = client
+        .query(&statement, &[&client_id])
+        .await
+        .expect("Error executing query on clients table");
+
+    response.first().map(|row| ClientInfo {
+        id: row.get(0),
+        client_id: row.get(1),
+        display_name: row.get(2),
+        redirect_uris: split_list(row.get(3)),
+        post_logout_redirect_uris: split_list(row.get(5)),
+        first_party: row.get(4),
+        grant_types: split_list(row.get(6)),
+        access_token_lifetime: row.get(7),
+        refresh_token_lifetime: row.get(8),
+        mfa_required: row.get(9),
+        jwt_access_tokens: row.get(10),
+    })
+}
+
+pub async fn get_client_by_id(client: &Client, client_db_id: Uuid) -> Option<ClientInfo> {
+    let statement = client
+        .prepare("select client_id from clients where id = $1")
+        .await
+        .unwrap();
+
+    let response = client
+        .query(&statement, &[&client_db_id])
+        .await
+        .expect("Error executing query on clients table");
+
+    match response.first() {
+        Some(row) => get_client_by_client_id(client, row.get(0)).await,
+        None => None,
+    }
+}
+
+pub async fn get_scopes(client: &Client) -> Vec<Scope> {
+    let statement = client
+        .prepare("select name, description, requires_consent from scopes")
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[])
+        .await
+        .expect("Error executing query on scopes table")
+        .iter()
+        .map(|row| Scope::from_row_ref(row).unwrap())
+        .collect()
+}
+
+pub async fn get_consented_scopes(client: &Client, user_id: Uuid, client_db_id: Uuid) -> Vec<String> {
+    let statement = client
+        .prepare("select scope from consents where user_id = $1 and client_id = $2")
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&user_id, &client_db_id])
+        .await
+        .expect("Error executing query on consents table")
+        .iter()
+        .map(|row| row.get(0))
+        .collect()
+}
+
+pub async fn insert_consents(client: &Client, user_id: Uuid, client_db_id: Uuid, scopes: &[String]) {
+    let statement = client
+        .prepare(
+            "insert into consents (user_id, client_id, scope, creation_time) values ($1, $2, $3, NOW())
+             on conflict (user_id, client_id, scope) do nothing",
+        )
+        .await
+        .unwrap();
+
+    for scope in scopes {
+        client
+            .execute(&statement, &[&user_id, &client_db_id, scope])
+            .await
+            .expect("Error inserting into consents table");
+    }
+}
+
+pub async fn create_session(
     client: &Client,
-    generated_token: String,
-    _scope: Option<String>,
-    uid: Option<Uuid>,
-    cid: Uuid,
-    issuer: String,
-    device: Option<String>,
-) -> AccessToken {
-    let statement = client.prepare("insert into access_tokens (access_token, expire_time, user_id, client_id, scope, creation_time, token_type, issuer, device) 
-                                   values($1, $2, $3, $4, $5, NOW(), 'bearer', $6, $7) 
-                                   on conflict on constraint unique_uid_cid do 
-                                   update set access_token = $1, expire_time = $2, creation_time = NOW(), scope = $5, issuer = $6, device = $7").await.unwrap();
-    let token_duration = Duration::days(30);
-    let local: DateTime<chrono::Local> = Local::now() + token_duration;
-    let device_str: String = match device {
-        Some(x) => x,
-        None => "unknown".to_string()
-    };
+    session_hash: &str,
+    sid: &str,
+    user_id: Uuid,
+    amr: &[String],
+    lifetime: Duration,
+) {
+    let statement = client
+        .prepare(
+            "insert into login_sessions (session_token, sid, user_id, amr, creation_time, expire_time) values ($1, $2, $3, $4, NOW(), $5)",
+        )
+        .await
+        .unwrap();
+    let expire_time: DateTime<Local> = Local::now() + lifetime;
 
-    let _result = client
-        .query(
-            &statement,
-            &[&generated_token, &local, &uid, &cid, &_scope, &issuer, &device_str],
+    client
+        .execute(&statement, &[&session_hash, &sid, &user_id, &amr.join(" "), &expire_time])
+        .await
+        .expect("Error inserting into login_sessions table");
+}
+
+// Returns the user behind a login session, expired sessions are ignored
+pub async fn find_session(client: &Client, session_hash: &str) -> Option<LoginSession> {
+    let statement = client
+        .prepare("select id, sid, user_id, creation_time, amr from login_sessions where session_token = $1 and expire_time > NOW()")
+        .await
+        .unwrap();
+
+    let response = client
+        .query(&statement, &[&session_hash])
+        .await
+        .expect("Error executing query on login_sessions table");
+
+    response.first().map(|row| LoginSession {
+        id: row.get(0),
+        sid: row.get(1),
+        user_id: row.get(2),
+        auth_time: row.get(3),
+        amr: split_list(row.get(4)),
+    })
+}
+
+// Records the methods the user logged in with, after the second factor
+pub async fn set_session_amr(client: &Client, session_id: i32, amr: &[String]) {
+    let statement = client
+        .prepare("update login_sessions set amr = $2 where id = $1")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&session_id, &amr.join(" ")])
+        .await
+        .expect("Error updating login_sessions table");
+}
+
+pub async fn delete_session(client: &Client, session_id: i32) {
+    let statement = client
+        .prepare("delete from login_sessions where id = $1")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&session_id])
+        .await
+        .expect("Error deleting from login_sessions table");
+}
+
+pub async fn add_session_client(client: &Client, session_id: i32, client_db_id: Uuid) {
+    let statement = client
+        .prepare("insert into session_clients (session_id, client_id) values ($1, $2) on conflict do nothing")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&session_id, &client_db_id])
+        .await
+        .expect("Error inserting into session_clients table");
+}
+
+pub async fn get_session_clients(client: &Client, session_id: i32) -> Vec<LogoutClient> {
+    let statement = client
+        .prepare(
+            "select c.client_id, c.frontchannel_logout_uri, c.backchannel_logout_uri from session_clients as s
+             join clients as c on s.client_id = c.id where s.session_id = $1",
         )
         .await
-        .expect("Error creating access token");
+        .unwrap();
 
-    AccessToken {
-        access_token: String::from(generated_token),
-        token_type: "bearer".to_string(),
-        expires_in: token_duration.num_seconds(),
-        scope: _scope,
+    client
+        .query(&statement, &[&session_id])
+        .await
+        .expect("Error executing query on session_clients table")
+        .iter()
+        .map(|row| LogoutClient {
+            client_id: row.get(0),
+            frontchannel_logout_uri: row.get(1),
+            backchannel_logout_uri: row.get(2),
+        })
+        .collect()
+}
+
+pub async fn get_scope_rules(client: &Client) -> Vec<ScopeRule> {
+    let statement = client
+        .prepare("select scope, implies from scope_implications")
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[])
+        .await
+        .expect("Error executing query on scope_implications table")
+        .iter()
+        .map(|row| ScopeRule {
+            scope: row.get(0),
+            implies: row.get(1),
+        })
+        .collect()
+}
+
+// Grants for parameterized scopes, those of the user at this client or, without a user, of the client itself
+pub async fn get_scope_grants(client: &Client, client_db_id: Uuid, user_id: Option<Uuid>) -> Vec<String> {
+    let statement = client
+        .prepare("select scope from scope_grants where client_id = $1 and user_id is not distinct from $2")
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&client_db_id, &user_id])
+        .await
+        .expect("Error executing query on scope_grants table")
+        .iter()
+        .map(|row| row.get(0))
+        .collect()
+}
+
+pub async fn get_scope_policy(client: &Client, client_db_id: Uuid, user_id: Option<Uuid>) -> ScopePolicy {
+    ScopePolicy {
+        allowed: get_client_scopes(client, client_db_id).await,
+        grants: get_scope_grants(client, client_db_id, user_id).await,
+        rules: get_scope_rules(client).await,
     }
 }
 
-pub async fn register_user() {}
+// Codes are single use and bound to the client and redirect uri they were issued for, a code that checks
+// out is deleted in the same statement so two requests can't both redeem it.
+// The pcke hash is optional, confidential clients can do without.
+pub async fn validate_code(
+    client: &Client,
+    code: &str,
+    pcke: &str,
+    client_db_id: Uuid,
+    redirect_uri: &str,
+) -> Option<AuthorizationCode> {
+    let mut hasher = Sha256::new();
+    hasher.update(pcke);
+    let pcke_result = format!("{:X}", hasher.finalize()).to_lowercase();
+
+    let statement = client
+        .prepare(
+            "delete from authorization_codes where code = $1 and client_id = $2
+             and (pcke_hash is null or pcke_hash = $3) and (redirect_uri is null or redirect_uri = $4)
+             and expire_time > NOW()
+             returning user_id, scope, auth_time, nonce, sid, amr",
+        )
+        .await
+        .unwrap();
+
+    let code_response = client
+        .query(&statement, &[&code, &client_db_id, &pcke_result, &redirect_uri])
+        .await
+        .expect("Error executing query on authorization_codes table");
+
+    if code_response.len() == 1 {
+        Some(AuthorizationCode {
+            user_id: code_response[0].get(0),
+            scope: code_response[0].get(1),
+            auth_time: code_response[0].get(2),
+            amr: code_response[0].get::<_, Option<String>>(5).map(split_list).unwrap_or_default(),
+            nonce: code_response[0].get(3),
+            sid: code_response[0].get(4),
+        })
+    } else {
+        None
+    }
+}
+
+pub async fn insert_code(
+    client: &Client,
+    code: &str,
+    client_db_id: Uuid,
+    authorization: &AuthorizationCode,
+    request: &AuthorizationParams,
+) {
+    let statement = client
+        .prepare(
+            "insert into authorization_codes (client_id, user_id, code, device, pcke_hash, scope, redirect_uri, nonce, auth_time, sid, creation_time, expire_time, amr)
+             values ($1, $2, $3, 'unknown', $4, $5, $6, $7, $8, $9, NOW(), $10, $11)",
+        )
+        .await
+        .unwrap();
+    let expire_time: DateTime<Local> = Local::now() + Duration::minutes(10);
+
+    client
+        .execute(
+            &statement,
+            &[
+                &client_db_id,
+                &authorization.user_id,
+                &code,
+                &request.code_challenge,
+                &authorization.scope,
+                &request.redirect_uri,
+                &authorization.nonce,
+                &authorization.auth_time,
+                &authorization.sid,
+                &expire_time,
+                &authorization.amr.join(" "),
+            ],
+        )
+        .await
+        .expect("Error inserting into authorization_codes table");
+}
+
+pub async fn create_tables(client: &Client, script: &str) -> Result<(), Error> {
+    client.batch_execute(script).await.map_err(DBInitError)
+}
+
+pub async fn schema_exists(client: &Client, schema: &str) -> bool {
+    let statement = client
+        .prepare("select 1 from information_schema.schemata where schema_name = $1")
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&schema])
+        .await
+        .expect("Error executing query on information_schema")
+        .is_some()
+}
+
+// The schema and its tables are created together, a failing script leaves nothing behind. The script runs
+// in the new schema whatever the connection's search_path is, its drops can't reach other schemas.
+pub async fn create_schema(client: &mut Client, schema: &str, script: &str) -> Result<(), Error> {
+    let transaction = client.transaction().await?;
+    transaction
+        .batch_execute(&format!("create schema {0};\nset local search_path to {0};\n{1}", schema, script))
+        .await
+        .map_err(DBInitError)?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+pub async fn add_scope(client: &Client, name: &str, description: &str, requires_consent: bool) {
+    let statement = client
+        .prepare("insert into scopes (name, description, requires_consent) values ($1, $2, $3) on conflict (name) do nothing")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&name, &description, &requires_consent])
+        .await
+        .expect("Error updating scopes table");
+}
+
+// The versions in schema_migrations, the table is created when it isn't there yet
+pub async fn applied_migrations(client: &Client) -> Result<Vec<String>, Error> {
+    client
+        .batch_execute(
+            "create table if not exists schema_migrations (
+               version varchar(255) primary key,
+               applied_time timestamp with time zone not null default now()
+             )",
+        )
+        .await
+        .map_err(DBInitError)?;
+
+    let rows = client
+        .query("select version from schema_migrations", &[])
+        .await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+// Runs a migration script and records its version in one transaction
+pub async fn apply_migration(client: &mut Client, version: &str, script: &str) -> Result<(), Error> {
+    let transaction = client.transaction().await?;
+    transaction.batch_execute(script).await.map_err(DBInitError)?;
+    transaction
+        .execute("insert into schema_migrations (version) values ($1)", &[&version])
+        .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+// Looks up the grant behind a refresh token, refresh tokens are bound to the client they were issued to and
+// stored as a hash
+pub async fn find_refresh_token(
+    client: &Client,
+    refresh_token: &str,
+    client_db_id: Uuid,
+) -> Option<TokenGrant> {
+    let statement = client
+        .prepare(
+            "select user_id, scope, device, auth_time, sid, amr from access_tokens where refresh_token_hash = $1 and client_id = $2
+             and (refresh_expire_time is null or refresh_expire_time > now())",
+        )
+        .await
+        .unwrap();
+
+    let response = client
+        .query(&statement, &[&session::hash_token(refresh_token), &client_db_id])
+        .await
+        .expect("Error executing query on access_tokens table");
+
+    response.first().map(|row| TokenGrant {
+        user_id: row.get(0),
+        client_id: client_db_id,
+        scope: row.get(1),
+        device: row.get(2),
+        auth_time: row.get(3),
+        amr: row.get::<_, Option<String>>(5).map(split_list).unwrap_or_default(),
+        nonce: None,
+        sid: row.get(4),
+    })
+}
+
+// Access tokens last 30 days unless the client says otherwise
+pub const DEFAULT_TOKEN_LIFETIME: i64 = 60 * 60 * 24 * 30;
+
+pub async fn insert_token(
+    client: &Client,
+    generated_token: String,
+    refresh_token: Option<String>,
+    grant: TokenGrant,
+    issuer: String,
+    client_info: &ClientInfo,
+) -> AccessToken {
+    let statement = client.prepare("insert into access_tokens (access_token, expire_time, user_id, client_id, scope, creation_time, token_type, issuer, device, refresh_token_hash, auth_time, sid, refresh_expire_time, amr)
+                                   values($1, $2, $3, $4, $5, NOW(), 'bearer', $6, $7, $8, $9, $10, $11, $12)
+                                   on conflict on constraint unique_uid_cid do
+                                   update set access_token = $1, expire_time = $2, creation_time = NOW(), scope = $5, issuer = $6, device = $7, refresh_token_hash = $8, auth_time = $9, sid = $10, refresh_expire_time = $11, amr = $12").await.unwrap();
+    let token_duration = Duration::seconds(
+        client_info
+            .access_token_lifetime
+            .map(i64::from)
+            .unwrap_or(DEFAULT_TOKEN_LIFETIME),
+    );
+    let local: DateTime<chrono::Local> = Local::now() + token_duration;
+    let refresh_expire_time: Option<DateTime<Local>> = match (&refresh_token, client_info.refresh_token_lifetime) {
+        (Some(_), Some(lifetime)) => Some(Local::now() + Duration::seconds(lifetime.into())),
+        _ => None,
+    };
+    let amr = Some(grant.amr.join(" ")).filter(|a| !a.is_empty());
+    let refresh_token_hash = refresh_token.as_deref().map(session::hash_token);
+    let device_str: String = match grant.device {
+        Some(x) => x,
+        None => "unknown".to_string()
+    };
+
+    client
+        .query(
+            &statement,
+            &[&generated_token, &local, &grant.user_id, &grant.client_id, &grant.scope, &issuer, &device_str, &refresh_token_hash, &grant.auth_time, &grant.sid, &refresh_expire_time, &amr],
+        )
+        .await
+        .expect("Error creating access token");
+
+    AccessToken {
+        access_token: generated_token,
+        token_type: "bearer".to_string(),
+        expires_in: token_duration.num_seconds(),
+        scope: grant.scope,
+        refresh_token,
+        id_token: None,
+    }
+}
+
+// Creates the user, uniqueness of the username and email is left to the constraints on the users table.
+// The invitation (a hash of the code) is claimed in the same transaction, a failed registration leaves it unused.
+pub async fn register_user(
+    client: &mut Client,
+    username: &str,
+    email: &str,
+    password_hash: &str,
+    email_verified: bool,
+    invitation: Option<String>,
+) -> Result<User, Error> {
+    let transaction = client.transaction().await?;
+
+    let row = transaction
+        .query_one(
+            format!(
+                "insert into users (username, email, password, email_verified) values ($1, $2, $3, $4) returning {}",
+                USER_COLUMNS
+            )
+            .as_str(),
+            &[&username, &email, &password_hash, &email_verified],
+        )
+        .await
+        .map_err(unique_violation)?;
+    let user = user(&row);
+
+    if let Some(code_hash) = invitation {
+        let claimed = transaction
+            .execute(
+                "update invitations set used_time = now(), used_by = $1
+                 where code_hash = $2 and used_time is null and (expire_time is null or expire_time > now())
+                 and (email is null or lower(email) = lower($3))",
+                &[&user.id, &code_hash, &email],
+            )
+            .await?;
+        if claimed != 1 {
+            return Err(InvalidRequestError("Invalid invitation code".to_string()));
+        }
+    }
+
+    transaction.commit().await?;
+    Ok(user)
+}
+
+// A directory user has no password here and their address is the directory's, the identity links them to
+// their entry
+pub async fn register_directory_user(
+    client: &mut Client,
+    username: &str,
+    email: &str,
+    subject: &str,
+) -> Result<User, Error> {
+    let transaction = client.transaction().await?;
+    let row = transaction
+        .query_one(
+            format!(
+                "insert into users (username, email, password, email_verified, source) values ($1, $2, $3, true, $4)
+                 returning {}",
+                USER_COLUMNS
+            )
+            .as_str(),
+            &[&username, &email, &federation::NO_PASSWORD, &UserSource::Directory.as_str()],
+        )
+        .await
+        .map_err(unique_violation)?;
+    let user = user(&row);
+    transaction
+        .execute(
+            "insert into federated_identities (provider, subject, user_id, email, last_login_time)
+             values ($1, $2, $3, $4, now())",
+            &[&ldap::PROVIDER, &subject, &user.id, &email],
+        )
+        .await?;
+    transaction.commit().await?;
+    Ok(user)
+}
+
+const USER_COLUMNS: &str = "id, username, email, email_verified, disabled, creation_time, mfa_required";
+
+fn user(row: &tokio_postgres::Row) -> User {
+    let creation_time: DateTime<Local> = row.get(5);
+    User {
+        id: row.get(0),
+        username: row.get(1),
+        email: row.get(2),
+        email_verified: row.get(3),
+        mfa_required: row.get(6),
+        disabled: row.get(4),
+        created: creation_time.timestamp(),
+    }
+}
+
+// A page of users and the total number of matches, the filters match on a part of the username or email
+pub async fn get_users(
+    client: &Client,
+    username: Option<&str>,
+    email: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> (Vec<User>, i64) {
+    let filter = "where ($1::varchar is null or username ilike '%' || $1 || '%')
+                  and ($2::varchar is null or email ilike '%' || $2 || '%')";
+    let statement = client
+        .prepare(&format!(
+            "select {} from users {} order by username limit $3 offset $4",
+            USER_COLUMNS, filter
+        ))
+        .await
+        .unwrap();
+    let users = client
+        .query(&statement, &[&username, &email, &limit, &offset])
+        .await
+        .expect("Error executing query on users table")
+        .iter()
+        .map(user)
+        .collect();
+
+    let statement = client
+        .prepare(&format!("select count(*) from users {}", filter))
+        .await
+        .unwrap();
+    let total: i64 = client
+        .query_one(&statement, &[&username, &email])
+        .await
+        .expect("Error executing query on users table")
+        .get(0);
+
+    (users, total)
+}
+
+pub async fn get_user(client: &Client, user_id: Uuid) -> Option<User> {
+    let statement = client
+        .prepare(&format!("select {} from users where id = $1", USER_COLUMNS))
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&user_id])
+        .await
+        .expect("Error executing query on users table")
+        .map(|row| user(&row))
+}
+
+pub async fn get_user_by_username(client: &Client, username: &str) -> Option<User> {
+    let statement = client
+        .prepare(&format!("select {} from users where username = $1", USER_COLUMNS))
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&username])
+        .await
+        .expect("Error executing query on users table")
+        .map(|row| user(&row))
+}
+
+pub async fn get_user_by_email(client: &Client, email: &str) -> Option<User> {
+    let statement = client
+        .prepare(&format!("select {} from users where lower(email) = lower($1)", USER_COLUMNS))
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&email])
+        .await
+        .expect("Error executing query on users table")
+        .map(|row| user(&row))
+}
+
+// A token for a link in a mail to the given address. Earlier unused tokens of the user for the same
+// purpose stop working, only the link in the latest mail does.
+pub async fn create_email_token(
+    client: &Client,
+    user_id: Uuid,
+    purpose: EmailTokenPurpose,
+    email: &str,
+    lifetime: Duration,
+) -> String {
+    let statement = client
+        .prepare("delete from email_tokens where user_id = $1 and purpose = $2 and used_time is null")
+        .await
+        .unwrap();
+    client
+        .execute(&statement, &[&user_id, &purpose.as_str()])
+        .await
+        .expect("Error deleting from email_tokens table");
+
+    let token = session::generate_secret(48);
+    let statement = client
+        .prepare(
+            "insert into email_tokens (token_hash, user_id, purpose, email, expire_time) values ($1, $2, $3, $4, $5)",
+        )
+        .await
+        .unwrap();
+    client
+        .execute(
+            &statement,
+            &[
+                &session::hash_token(&token),
+                &user_id,
+                &purpose.as_str(),
+                &email,
+                &(Local::now() + lifetime),
+            ],
+        )
+        .await
+        .expect("Error inserting into email_tokens table");
+    token
+}
+
+// The user and address of a token that can still be used, without using it
+pub async fn find_email_token(client: &Client, token: &str, purpose: EmailTokenPurpose) -> Option<(Uuid, String)> {
+    let statement = client
+        .prepare(
+            "select user_id, email from email_tokens
+             where token_hash = $1 and purpose = $2 and used_time is null and expire_time > now()",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&session::hash_token(token), &purpose.as_str()])
+        .await
+        .expect("Error executing query on email_tokens table")
+        .map(|row| (row.get(0), row.get(1)))
+}
+
+// Marks the token used and returns its user and address, a token only works once
+pub async fn use_email_token(client: &Client, token: &str, purpose: EmailTokenPurpose) -> Option<(Uuid, String)> {
+    let statement = client
+        .prepare(
+            "update email_tokens set used_time = now()
+             where token_hash = $1 and purpose = $2 and used_time is null and expire_time > now()
+             returning user_id, email",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&session::hash_token(token), &purpose.as_str()])
+        .await
+        .expect("Error updating email_tokens table")
+        .map(|row| (row.get(0), row.get(1)))
+}
+
+// Only verifies the address the mail went to, a user that changed their email since has to verify again
+pub async fn set_email_verified(client: &Client, user_id: Uuid, email: &str) -> bool {
+    let statement = client
+        .prepare("update users set email_verified = true where id = $1 and email = $2")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&user_id, &email])
+        .await
+        .expect("Error updating users table")
+        == 1
+}
+
+pub async fn update_user(client: &Client, user_id: Uuid, update: &UserUpdate) -> Result<Option<User>, Error> {
+    let statement = client
+        .prepare(&format!(
+            "update users set username = coalesce($2, username), email = coalesce($3, email),
+             email_verified = coalesce($4, email_verified), mfa_required = coalesce($5, mfa_required)
+             where id = $1 returning {}",
+            USER_COLUMNS
+        ))
+        .await?;
+
+    let row = client
+        .query_opt(&statement, &[&user_id, &update.username, &update.email, &update.email_verified, &update.mfa_required])
+        .await
+        .map_err(unique_violation)?;
+    Ok(row.map(|row| user(&row)))
+}
+
+pub async fn set_user_disabled(client: &Client, user_id: Uuid, disabled: bool) -> Option<User> {
+    let statement = client
+        .prepare(&format!(
+            "update users set disabled = $2 where id = $1 returning {}",
+            USER_COLUMNS
+        ))
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&user_id, &disabled])
+        .await
+        .expect("Error updating users table")
+        .map(|row| user(&row))
+}
+
+pub async fn set_password(client: &Client, user_id: Uuid, password_hash: &str) -> bool {
+    let statement = client
+        .prepare("update users set password = $2 where id = $1")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&user_id, &password_hash])
+        .await
+        .expect("Error updating users table")
+        == 1
+}
+
+// Everything the user holds (data, tokens, codes, sessions, consents, grants) goes with it, see the
+// on delete cascade constraints
+pub async fn delete_user(client: &Client, user_id: Uuid) -> bool {
+    let statement = client
+        .prepare("delete from users where id = $1")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&user_id])
+        .await
+        .expect("Error deleting from users table")
+        == 1
+}
+
+// Logs the user out everywhere: login sessions, pending codes and access and refresh tokens
+pub async fn revoke_user(client: &Client, user_id: Uuid) {
+    for table in ["login_sessions", "authorization_codes", "access_tokens"] {
+        let statement = client
+            .prepare(&format!("delete from {} where user_id = $1", table))
+            .await
+            .unwrap();
+        client
+            .execute(&statement, &[&user_id])
+            .await
+            .expect("Error revoking user");
+    }
+}
+
+pub async fn get_totp(client: &Client, user_id: Uuid) -> Option<TotpCredential> {
+    let statement = client
+        .prepare("select secret, confirmed_time is not null from totp_credentials where user_id = $1")
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&user_id])
+        .await
+        .expect("Error executing query on totp_credentials table")
+        .map(|row| TotpCredential {
+            secret: row.get(0),
+            confirmed: row.get(1),
+        })
+}
+
+// Starts enrolling an authenticator, a confirmed one is never replaced this way
+pub async fn set_pending_totp(client: &Client, user_id: Uuid, secret: &str) {
+    let statement = client
+        .prepare(
+            "insert into totp_credentials (user_id, secret) values ($1, $2)
+             on conflict (user_id) do update set secret = $2, creation_time = now()
+             where totp_credentials.confirmed_time is null",
+        )
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&user_id, &secret])
+        .await
+        .expect("Error inserting into totp_credentials table");
+}
+
+pub async fn confirm_totp(client: &Client, user_id: Uuid, step: i64) {
+    let statement = client
+        .prepare("update totp_credentials set confirmed_time = now(), last_used_step = $2 where user_id = $1")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&user_id, &step])
+        .await
+        .expect("Error updating totp_credentials table");
+}
+
+// Accepts the time step of a valid code once, a code can't be used again by someone looking over a shoulder
+pub async fn use_totp_step(client: &Client, user_id: Uuid, step: i64) -> bool {
+    let statement = client
+        .prepare(
+            "update totp_credentials set last_used_step = $2 where user_id = $1 and confirmed_time is not null
+             and (last_used_step is null or last_used_step < $2)",
+        )
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&user_id, &step])
+        .await
+        .expect("Error updating totp_credentials table")
+        == 1
+}
+
+// Replaces the user's recovery codes, only hashes are stored
+pub async fn set_recovery_codes(client: &Client, user_id: Uuid, code_hashes: &[String]) {
+    let statement = client
+        .prepare("delete from recovery_codes where user_id = $1")
+        .await
+        .unwrap();
+    client
+        .execute(&statement, &[&user_id])
+        .await
+        .expect("Error deleting from recovery_codes table");
+
+    let statement = client
+        .prepare("insert into recovery_codes (user_id, code_hash) select $1, unnest($2::varchar[])")
+        .await
+        .unwrap();
+    client
+        .execute(&statement, &[&user_id, &code_hashes])
+        .await
+        .expect("Error inserting into recovery_codes table");
+}
+
+pub async fn use_recovery_code(client: &Client, user_id: Uuid, code_hash: &str) -> bool {
+    let statement = client
+        .prepare(
+            "update recovery_codes set used_time = now() where user_id = $1 and code_hash = $2 and used_time is null",
+        )
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&user_id, &code_hash])
+        .await
+        .expect("Error updating recovery_codes table")
+        == 1
+}
+
+// For a user that lost their authenticator, the next login enrolls a new one if MFA is required
+pub async fn delete_mfa(client: &Client, user_id: Uuid) -> bool {
+    let statement = client
+        .prepare("delete from recovery_codes where user_id = $1")
+        .await
+        .unwrap();
+    client
+        .execute(&statement, &[&user_id])
+        .await
+        .expect("Error deleting from recovery_codes table");
+
+    let statement = client
+        .prepare("delete from totp_credentials where user_id = $1")
+        .await
+        .unwrap();
+    client
+        .execute(&statement, &[&user_id])
+        .await
+        .expect("Error deleting from totp_credentials table")
+        == 1
+}
+
+// Stores the challenge of a WebAuthn ceremony, expired ones are cleaned up on the way
+pub async fn create_challenge(
+    client: &Client,
+    challenge: &str,
+    purpose: ChallengePurpose,
+    user_id: Option<Uuid>,
+    lifetime: Duration,
+) {
+    let statement = client
+        .prepare("delete from webauthn_challenges where expire_time < now()")
+        .await
+        .unwrap();
+    client
+        .execute(&statement, &[])
+        .await
+        .expect("Error deleting from webauthn_challenges table");
+
+    let statement = client
+        .prepare("insert into webauthn_challenges (challenge, purpose, user_id, expire_time) values ($1, $2, $3, $4)")
+        .await
+        .unwrap();
+    client
+        .execute(
+            &statement,
+            &[&session::hash_token(challenge), &purpose.as_str(), &user_id, &(Local::now() + lifetime)],
+        )
+        .await
+        .expect("Error inserting into webauthn_challenges table");
+}
+
+// Removes the challenge and returns the user it was made for, a challenge only works once
+pub async fn use_challenge(client: &Client, challenge: &str, purpose: ChallengePurpose) -> Option<Option<Uuid>> {
+    let statement = client
+        .prepare(
+            "delete from webauthn_challenges where challenge = $1 and purpose = $2 and expire_time > now()
+             returning user_id",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&session::hash_token(challenge), &purpose.as_str()])
+        .await
+        .expect("Error deleting from webauthn_challenges table")
+        .map(|row| row.get(0))
+}
+
+// False when the credential is already registered
+pub async fn add_passkey(client: &Client, user_id: Uuid, credential: &NewCredential, name: &str) -> bool {
+    let statement = client
+        .prepare(
+            "insert into webauthn_credentials (user_id, credential_id, public_key, sign_count, name)
+             values ($1, $2, $3, $4, $5) on conflict (credential_id) do nothing",
+        )
+        .await
+        .unwrap();
+
+    client
+        .execute(
+            &statement,
+            &[&user_id, &credential.credential_id, &credential.public_key, &credential.sign_count, &name],
+        )
+        .await
+        .expect("Error inserting into webauthn_credentials table")
+        == 1
+}
+
+pub async fn get_passkeys(client: &Client, user_id: Uuid) -> Vec<Passkey> {
+    let statement = client
+        .prepare(
+            "select id, name, creation_time, last_used_time from webauthn_credentials where user_id = $1 order by id",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&user_id])
+        .await
+        .expect("Error executing query on webauthn_credentials table")
+        .iter()
+        .map(|row| {
+            let created: DateTime<Local> = row.get(2);
+            let last_used: Option<DateTime<Local>> = row.get(3);
+            Passkey {
+                id: row.get(0),
+                name: row.get(1),
+                created: created.timestamp(),
+                last_used: last_used.map(|t| t.timestamp()),
+            }
+        })
+        .collect()
+}
+
+// The base64url ids of the user's passkeys, for the browser to pick from
+pub async fn get_passkey_ids(client: &Client, user_id: Uuid) -> Vec<String> {
+    let statement = client
+        .prepare("select credential_id from webauthn_credentials where user_id = $1")
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&user_id])
+        .await
+        .expect("Error executing query on webauthn_credentials table")
+        .iter()
+        .map(|row| row.get(0))
+        .collect()
+}
+
+// Passkeys of disabled and unverified users are not found, like their passwords
+pub async fn find_passkey(client: &Client, credential_id: &str) -> Option<PasskeyCredential> {
+    let statement = client
+        .prepare(
+            "select w.id, w.user_id, w.public_key, w.sign_count from webauthn_credentials as w
+             join users as u on w.user_id = u.id where w.credential_id = $1 and u.email_verified and not u.disabled",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&credential_id])
+        .await
+        .expect("Error executing query on webauthn_credentials table")
+        .map(|row| PasskeyCredential {
+            id: row.get(0),
+            user_id: row.get(1),
+            public_key: row.get(2),
+            sign_count: row.get(3),
+        })
+}
+
+pub async fn use_passkey(client: &Client, passkey_id: i32, sign_count: i64) {
+    let statement = client
+        .prepare("update webauthn_credentials set sign_count = $2, last_used_time = now() where id = $1")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&passkey_id, &sign_count])
+        .await
+        .expect("Error updating webauthn_credentials table");
+}
+
+pub async fn delete_passkey(client: &Client, user_id: Uuid, passkey_id: i32) -> bool {
+    let statement = client
+        .prepare("delete from webauthn_credentials where id = $1 and user_id = $2")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&passkey_id, &user_id])
+        .await
+        .expect("Error deleting from webauthn_credentials table")
+        == 1
+}
+
+pub async fn is_locked(client: &Client, kind: &str, subject: &str) -> bool {
+    let statement = client
+        .prepare("select 1 from login_failures where kind = $1 and subject = $2 and locked_until > now()")
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&kind, &subject])
+        .await
+        .expect("Error selecting from login_failures table")
+        .is_some()
+}
+
+// Counts a failed login and returns the failures so far, when the last one was before window_start the
+// count starts over
+pub async fn add_login_failure(
+    client: &Client,
+    kind: &str,
+    subject: &str,
+    window_start: DateTime<Local>,
+) -> i32 {
+    let statement = client
+        .prepare(
+            "insert into login_failures as f (kind, subject, failures, last_failure) values ($1, $2, 1, now())
+             on conflict (kind, subject) do update set
+               failures = case when f.last_failure < $3 then 1 else f.failures + 1 end,
+               last_failure = now()
+             returning failures",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query_one(&statement, &[&kind, &subject, &window_start])
+        .await
+        .expect("Error inserting into login_failures table")
+        .get(0)
+}
+
+pub async fn lock_login(client: &Client, kind: &str, subject: &str, locked_until: DateTime<Local>) {
+    let statement = client
+        .prepare("update login_failures set locked_until = $3 where kind = $1 and subject = $2")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&kind, &subject, &locked_until])
+        .await
+        .expect("Error updating login_failures table");
+}
+
+// Forgets the failures and lifts a lock, false when there were none
+pub async fn clear_login_failures(client: &Client, kind: &str, subject: &str) -> bool {
+    let statement = client
+        .prepare("delete from login_failures where kind = $1 and subject = $2")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&kind, &subject])
+        .await
+        .expect("Error deleting from login_failures table")
+        == 1
+}
+
+// Deletes the access and refresh tokens matching all of the given filters, returns how many were deleted
+pub async fn revoke_tokens(
+    client: &Client,
+    user_id: Option<Uuid>,
+    client_db_id: Option<Uuid>,
+    device: Option<&str>,
+) -> u64 {
+    let statement = client
+        .prepare(
+            "delete from access_tokens where ($1::uuid is null or user_id = $1)
+             and ($2::uuid is null or client_id = $2) and ($3::varchar is null or device = $3)",
+        )
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&user_id, &client_db_id, &device])
+        .await
+        .expect("Error revoking tokens")
+}
+
+// The scope of an access token that is still valid, tokens of any client are accepted
+pub async fn get_active_token_scope(client: &Client, access_token: &str) -> Option<String> {
+    let statement = client
+        .prepare("select scope from access_tokens where access_token = $1 and expire_time > now()")
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&access_token])
+        .await
+        .expect("Error executing query on access_tokens table")
+        .and_then(|row| row.get(0))
+}
+
+// The user and scope of an access token that hasn't expired, for userinfo
+pub async fn get_active_token_user(client: &Client, access_token: &str) -> Option<(Option<Uuid>, Option<String>)> {
+    let statement = client
+        .prepare("select user_id, scope from access_tokens where access_token = $1 and expire_time > now()")
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&access_token])
+        .await
+        .expect("Error executing query on access_tokens table")
+        .map(|row| (row.get(0), row.get(1)))
+}
+
+pub async fn get_attribute_definitions(client: &Client) -> Vec<AttributeDefinition> {
+    let statement = client
+        .prepare("select name, type, description, max_length, pattern, scope from attributes order by name")
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[])
+        .await
+        .expect("Error executing query on attributes table")
+        .iter()
+        .map(|row| AttributeDefinition {
+            name: row.get(0),
+            kind: row.get(1),
+            description: row.get(2),
+            max_length: row.get(3),
+            pattern: row.get(4),
+            scope: row.get(5),
+        })
+        .collect()
+}
+
+// Adds the attribute or replaces its definition
+pub async fn put_attribute_definition(client: &Client, definition: &AttributeDefinition) {
+    let statement = client
+        .prepare(
+            "insert into attributes (name, type, description, max_length, pattern, scope) values ($1, $2, $3, $4, $5, $6)
+             on conflict (name) do update set type = $2, description = $3, max_length = $4, pattern = $5, scope = $6",
+        )
+        .await
+        .unwrap();
+
+    client
+        .execute(
+            &statement,
+            &[
+                &definition.name,
+                &definition.kind,
+                &definition.description,
+                &definition.max_length,
+                &definition.pattern,
+                &definition.scope,
+            ],
+        )
+        .await
+        .expect("Error inserting into attributes table");
+}
+
+// Removes the attribute with the values users have for it
+pub async fn delete_attribute_definition(client: &mut Client, name: &str) -> Result<bool, Error> {
+    let transaction = client.transaction().await?;
+    transaction.execute("delete from user_data where key = $1", &[&name]).await?;
+    let deleted = transaction.execute("delete from attributes where name = $1", &[&name]).await?;
+    transaction.commit().await?;
+    Ok(deleted == 1)
+}
+
+// Every user's value of the attribute, to check them against a new definition
+pub async fn get_attribute_values(client: &Client, name: &str) -> Vec<String> {
+    let statement = client
+        .prepare("select value from user_data where key = $1")
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&name])
+        .await
+        .expect("Error executing query on user_data table")
+        .iter()
+        .map(|row| row.get(0))
+        .collect()
+}
+
+pub async fn get_user_attributes(client: &Client, user_id: Uuid) -> Vec<(String, String)> {
+    let statement = client
+        .prepare("select key, value from user_data where user_id = $1 order by key")
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&user_id])
+        .await
+        .expect("Error executing query on user_data table")
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect()
+}
+
+// Sets the given attributes of the user at once, None removes one
+pub async fn set_user_attributes(
+    client: &mut Client,
+    user_id: Uuid,
+    changes: &[(String, Option<String>)],
+) -> Result<(), Error> {
+    let transaction = client.transaction().await?;
+    for (name, value) in changes {
+        match value {
+            Some(value) => {
+                transaction
+                    .execute(
+                        "insert into user_data (user_id, key, value) values ($1, $2, $3)
+                         on conflict (user_id, key) do update set value = $3",
+                        &[&user_id, name, value],
+                    )
+                    .await?
+            }
+            None => {
+                transaction
+                    .execute("delete from user_data where user_id = $1 and key = $2", &[&user_id, name])
+                    .await?
+            }
+        };
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+fn role(row: &tokio_postgres::Row) -> Role {
+    Role {
+        name: row.get(0),
+        client_id: row.get(1),
+        description: row.get(2),
+    }
+}
+
+// The global roles without a client, otherwise the client's own
+pub async fn get_roles(client: &Client, client_db_id: Option<Uuid>) -> Vec<Role> {
+    let statement = client
+        .prepare(
+            "select r.name, c.client_id, r.description from roles as r left join clients as c on r.client_id = c.id
+             where r.client_id is not distinct from $1 order by r.name",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&client_db_id])
+        .await
+        .expect("Error executing query on roles table")
+        .iter()
+        .map(role)
+        .collect()
+}
+
+pub async fn find_role(client: &Client, client_db_id: Option<Uuid>, name: &str) -> Option<i32> {
+    let statement = client
+        .prepare("select id from roles where client_id is not distinct from $1 and name = $2")
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&client_db_id, &name])
+        .await
+        .expect("Error executing query on roles table")
+        .map(|row| row.get(0))
+}
+
+// Adds the role or changes its description
+pub async fn put_role(client: &Client, client_db_id: Option<Uuid>, name: &str, description: &Option<String>) {
+    // each kind of role has its own unique index
+    let conflict = match client_db_id {
+        Some(_) => "(client_id, name) where client_id is not null",
+        None => "(name) where client_id is null",
+    };
+    let statement = client
+        .prepare(&format!(
+            "insert into roles (name, description, client_id) values ($1, $2, $3)
+             on conflict {} do update set description = $2",
+            conflict
+        ))
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&name, description, &client_db_id])
+        .await
+        .expect("Error updating roles table");
+}
+
+// The assignments of the role go with it
+pub async fn delete_role(client: &Client, client_db_id: Option<Uuid>, name: &str) -> bool {
+    let statement = client
+        .prepare("delete from roles where client_id is not distinct from $1 and name = $2")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&client_db_id, &name])
+        .await
+        .expect("Error deleting from roles table")
+        == 1
+}
+
+pub async fn get_groups(client: &Client) -> Vec<Group> {
+    let statement = client
+        .prepare("select id, name, description from groups order by name")
+        .await
+        .unwrap();
+
+    let rows = client
+        .query(&statement, &[])
+        .await
+        .expect("Error executing query on groups table");
+
+    let mut groups = Vec::with_capacity(rows.len());
+    for row in &rows {
+        groups.push(Group {
+            name: row.get(1),
+            description: row.get(2),
+            roles: get_group_roles(client, row.get(0)).await,
+            members: None,
+        });
+    }
+    groups
+}
+
+// The group with its roles and members
+pub async fn get_group(client: &Client, name: &str) -> Option<(i32, Group)> {
+    let statement = client
+        .prepare("select id, name, description from groups where name = $1")
+        .await
+        .unwrap();
+
+    let row = client
+        .query_opt(&statement, &[&name])
+        .await
+        .expect("Error executing query on groups table")?;
+    let group_id: i32 = row.get(0);
+    Some((
+        group_id,
+        Group {
+            name: row.get(1),
+            description: row.get(2),
+            roles: get_group_roles(client, group_id).await,
+            members: Some(get_group_members(client, group_id).await),
+        },
+    ))
+}
+
+pub async fn put_group(client: &Client, name: &str, description: &Option<String>) {
+    let statement = client
+        .prepare("insert into groups (name, description) values ($1, $2) on conflict (name) do update set description = $2")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&name, description])
+        .await
+        .expect("Error updating groups table");
+}
+
+pub async fn delete_group(client: &Client, name: &str) -> bool {
+    let statement = client
+        .prepare("delete from groups where name = $1")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&name])
+        .await
+        .expect("Error deleting from groups table")
+        == 1
+}
+
+async fn get_group_roles(client: &Client, group_id: i32) -> Vec<Role> {
+    let statement = client
+        .prepare(
+            "select r.name, c.client_id, r.description from group_roles as gr join roles as r on gr.role_id = r.id
+             left join clients as c on r.client_id = c.id where gr.group_id = $1 order by c.client_id nulls first, r.name",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&group_id])
+        .await
+        .expect("Error executing query on group_roles table")
+        .iter()
+        .map(role)
+        .collect()
+}
+
+async fn get_group_members(client: &Client, group_id: i32) -> Vec<GroupMember> {
+    let statement = client
+        .prepare(
+            "select u.id, u.username from group_members as gm join users as u on gm.user_id = u.id
+             where gm.group_id = $1 order by u.username",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&group_id])
+        .await
+        .expect("Error executing query on group_members table")
+        .iter()
+        .map(|row| GroupMember {
+            user_id: row.get(0),
+            username: row.get(1),
+        })
+        .collect()
+}
+
+pub async fn add_group_member(client: &Client, group_id: i32, user_id: Uuid) {
+    let statement = client
+        .prepare("insert into group_members (group_id, user_id) values ($1, $2) on conflict do nothing")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&group_id, &user_id])
+        .await
+        .expect("Error updating group_members table");
+}
+
+pub async fn remove_group_member(client: &Client, group_id: i32, user_id: Uuid) -> bool {
+    let statement = client
+        .prepare("delete from group_members where group_id = $1 and user_id = $2")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&group_id, &user_id])
+        .await
+        .expect("Error deleting from group_members table")
+        == 1
+}
+
+pub async fn add_group_role(client: &Client, group_id: i32, role_id: i32) {
+    let statement = client
+        .prepare("insert into group_roles (group_id, role_id) values ($1, $2) on conflict do nothing")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&group_id, &role_id])
+        .await
+        .expect("Error updating group_roles table");
+}
+
+pub async fn remove_group_role(client: &Client, group_id: i32, role_id: i32) -> bool {
+    let statement = client
+        .prepare("delete from group_roles where group_id = $1 and role_id = $2")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&group_id, &role_id])
+        .await
+        .expect("Error deleting from group_roles table")
+        == 1
+}
+
+pub async fn add_user_role(client: &Client, user_id: Uuid, role_id: i32) {
+    let statement = client
+        .prepare("insert into user_roles (user_id, role_id) values ($1, $2) on conflict do nothing")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&user_id, &role_id])
+        .await
+        .expect("Error updating user_roles table");
+}
+
+pub async fn remove_user_role(client: &Client, user_id: Uuid, role_id: i32) -> bool {
+    let statement = client
+        .prepare("delete from user_roles where user_id = $1 and role_id = $2")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&user_id, &role_id])
+        .await
+        .expect("Error deleting from user_roles table")
+        == 1
+}
+
+// The roles assigned to the user directly
+pub async fn get_user_roles(client: &Client, user_id: Uuid) -> Vec<Role> {
+    let statement = client
+        .prepare(
+            "select r.name, c.client_id, r.description from user_roles as ur join roles as r on ur.role_id = r.id
+             left join clients as c on r.client_id = c.id where ur.user_id = $1 order by c.client_id nulls first, r.name",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&user_id])
+        .await
+        .expect("Error executing query on user_roles table")
+        .iter()
+        .map(role)
+        .collect()
+}
+
+pub async fn get_user_groups(client: &Client, user_id: Uuid) -> Vec<String> {
+    let statement = client
+        .prepare(
+            "select g.name from group_members as gm join groups as g on gm.group_id = g.id
+             where gm.user_id = $1 order by g.name",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&user_id])
+        .await
+        .expect("Error executing query on group_members table")
+        .iter()
+        .map(|row| row.get(0))
+        .collect()
+}
+
+// Makes the user a member of the local groups with the names (lowercase) and of no others, groups of
+// the directory that don't exist here are left out
+pub async fn set_directory_groups(client: &mut Client, user_id: Uuid, names: &[String]) {
+    let transaction = client.transaction().await.expect("Error starting a transaction");
+    transaction
+        .execute(
+            "delete from group_members where user_id = $1
+             and group_id not in (select id from groups where lower(name) = any($2))",
+            &[&user_id, &names],
+        )
+        .await
+        .expect("Error deleting from group_members table");
+    transaction
+        .execute(
+            "insert into group_members (group_id, user_id) select id, $1 from groups where lower(name) = any($2)
+             on conflict do nothing",
+            &[&user_id, &names],
+        )
+        .await
+        .expect("Error inserting into group_members table");
+    transaction.commit().await.expect("Error updating group_members table");
+}
+
+// The roles assigned to the user and those of their groups
+pub async fn get_effective_roles(client: &Client, user_id: Uuid) -> Vec<Role> {
+    let statement = client
+        .prepare(
+            "select distinct r.name, c.client_id, r.description from roles as r left join clients as c on r.client_id = c.id
+             where r.id in (select role_id from user_roles where user_id = $1
+                            union select gr.role_id from group_roles as gr
+                            join group_members as gm on gr.group_id = gm.group_id where gm.user_id = $1)
+             order by c.client_id nulls first, r.name",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&user_id])
+        .await
+        .expect("Error executing query on roles table")
+        .iter()
+        .map(role)
+        .collect()
+}
+
+// Stores a login at an upstream provider until the user comes back, expired ones are cleaned up on the way
+pub async fn create_federation_state(client: &Client, state: &str, params: &FederationState, lifetime: Duration) {
+    let statement = client
+        .prepare("delete from federation_states where expire_time < now()")
+        .await
+        .unwrap();
+    client
+        .execute(&statement, &[])
+        .await
+        .expect("Error deleting from federation_states table");
+
+    let statement = client
+        .prepare(
+            "insert into federation_states (state, provider, nonce, code_verifier, request, next, expire_time)
+             values ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .await
+        .unwrap();
+    client
+        .execute(
+            &statement,
+            &[
+                &session::hash_token(state),
+                &params.provider,
+                &params.nonce,
+                &params.code_verifier,
+                &params.request,
+                &params.next,
+                &(Local::now() + lifetime),
+            ],
+        )
+        .await
+        .expect("Error inserting into federation_states table");
+}
+
+// Removes the login of the state and returns it, a state only works once
+pub async fn use_federation_state(client: &Client, state: &str) -> Option<FederationState> {
+    let statement = client
+        .prepare(
+            "delete from federation_states where state = $1 and expire_time > now()
+             returning provider, nonce, code_verifier, request, next",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&session::hash_token(state)])
+        .await
+        .expect("Error deleting from federation_states table")
+        .map(|row| FederationState {
+            provider: row.get(0),
+            nonce: row.get(1),
+            code_verifier: row.get(2),
+            request: row.get(3),
+            next: row.get(4),
+        })
+}
+
+// The user the account at the provider is linked to, the login is recorded on the way
+pub async fn find_federated_user(client: &Client, provider: &str, subject: &str) -> Option<Uuid> {
+    let statement = client
+        .prepare(
+            "update federated_identities set last_login_time = now() where provider = $1 and subject = $2
+             returning user_id",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query_opt(&statement, &[&provider, &subject])
+        .await
+        .expect("Error updating federated_identities table")
+        .map(|row| row.get(0))
+}
+
+pub async fn link_federated_identity(
+    client: &Client,
+    provider: &str,
+    subject: &str,
+    user_id: Uuid,
+    email: Option<&str>,
+) {
+    let statement = client
+        .prepare(
+            "insert into federated_identities (provider, subject, user_id, email, last_login_time)
+             values ($1, $2, $3, $4, now()) on conflict (provider, subject) do nothing",
+        )
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&provider, &subject, &user_id, &email])
+        .await
+        .expect("Error inserting into federated_identities table");
+}
+
+pub async fn get_federated_identities(client: &Client, user_id: Uuid) -> Vec<FederatedIdentity> {
+    let statement = client
+        .prepare(
+            "select provider, subject, email, creation_time, last_login_time from federated_identities
+             where user_id = $1 order by provider, subject",
+        )
+        .await
+        .unwrap();
+
+    client
+        .query(&statement, &[&user_id])
+        .await
+        .expect("Error executing query on federated_identities table")
+        .iter()
+        .map(|row| {
+            let creation_time: DateTime<Local> = row.get(3);
+            let last_login_time: Option<DateTime<Local>> = row.get(4);
+            FederatedIdentity {
+                provider: row.get(0),
+                subject: row.get(1),
+                email: row.get(2),
+                created: creation_time.timestamp(),
+                last_login: last_login_time.map(|t| t.timestamp()),
+            }
+        })
+        .collect()
+}
+
+// Unlinks the user's accounts at the provider, false when there were none
+pub async fn delete_federated_identities(client: &Client, user_id: Uuid, provider: &str) -> bool {
+    let statement = client
+        .prepare("delete from federated_identities where user_id = $1 and provider = $2")
+        .await
+        .unwrap();
+
+    client
+        .execute(&statement, &[&user_id, &provider])
+        .await
+        .expect("Error deleting from federated_identities table")
+        > 0
+}
+
+// Turns a violated unique constraint on users into a message that says which value is taken
+fn unique_violation(e: tokio_postgres::Error) -> Error {
+    if e.code() != Some(&SqlState::UNIQUE_VIOLATION) {
+        return DBQueryError(e);
+    }
+    let constraint = std::error::Error::source(&e)
+        .and_then(|source| source.downcast_ref::<DbError>())
+        .and_then(|db_error| db_error.constraint());
+    match constraint {
+        Some("users_username_key") => ConflictError("Username is already taken".to_string()),
+        Some("users_email_key") => ConflictError("Email address is already registered".to_string()),
+        _ => DBQueryError(e),
+    }
+}
@@ -1,8 +1,10 @@
 use serde::Serialize;
 use std::convert::Infallible;
 use thiserror::Error;
+use warp::http::header::RETRY_AFTER;
 use warp::{http::StatusCode, Rejection, Reply};
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Error getting connection from the database pool: {0}")]
@@ -11,16 +13,37 @@ pub enum Error {
     DBQueryError(#[from] tokio_postgres::Error),
     #[error("Error initializing database: {0}")]
     DBInitError(tokio_postgres::Error),
+    #[error("Error executing query: {0}")]
+    SqliteError(#[from] rusqlite::Error),
     #[error("Error authorizing: {0}")]
     AuthorizationError(String),
     #[error("Not found: {0}")]
     NotFoundError(String),
     #[error("Get request not allowed {0}")]
     GetRouteFailed(bool),
+    #[error("Invalid scope: {0}")]
+    InvalidScopeError(String),
+    #[error("Invalid grant: {0}")]
+    InvalidGrantError(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequestError(String),
+    #[error("Conflict: {0}")]
+    ConflictError(String),
+    #[error("Forbidden: {0}")]
+    ForbiddenError(String),
+    #[error("Unauthorized client: {0}")]
+    UnauthorizedClientError(String),
+    #[error("Invalid client: {0}")]
+    InvalidClientError(String),
+    // seconds until the request can be retried
+    #[error("Too many requests, retry in {0} seconds")]
+    TooManyRequestsError(u64),
 }
 
 #[derive(Serialize)]
 struct ErrorResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
     message: String,
 }
 
@@ -28,16 +51,12 @@ impl warp::reject::Reject for Error {}
 
 pub async fn handle_get_notallowed(err: Rejection) -> std::result::Result<impl Reply, Rejection> {
     println!("{:?}", err);
-    if let Some(_) = err.find::<warp::reject::MethodNotAllowed>() {
+    if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         return Err(warp::reject::custom(Error::GetRouteFailed(false)));
         // return Err(warp::reject::custom(AuthorizationError("Client credentials invalid".to_string())));
     } else if err.is_not_found() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&ErrorResponse {
-                message: "Not Found".into(),
-            }),
-            StatusCode::NOT_FOUND,
-        ));
+        // leave it to the other routes, handle_rejection turns it into a 404 when none of them match
+        return Err(err);
     }
     Ok(warp::reply::with_status(
         warp::reply::json(&""),
@@ -49,13 +68,11 @@ pub async fn handle_get_notallowed(err: Rejection) -> std::result::Result<impl R
 pub async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Infallible> {
     let code;
     let message;
+    let mut error = None;
+    let mut retry_after = None;
 
     println!("{:?}", err);
 
-    // else if let Some(_) = err.find::<warp::reject::MethodNotAllowed>() {
-    //         code = StatusCode::NOT_FOUND; // this should be a 405...?
-    //         message = "Not Found";
-    //     }
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
         message = "Not Found";
@@ -70,15 +87,58 @@ pub async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply,
                 code = StatusCode::NOT_FOUND;
                 message = e;
             }
-            Error::GetRouteFailed(e) => {
+            Error::GetRouteFailed(_) => {
                 code = StatusCode::METHOD_NOT_ALLOWED;
                 message = "Method not allowed";
             }
+            Error::InvalidScopeError(e) => {
+                code = StatusCode::BAD_REQUEST;
+                error = Some("invalid_scope");
+                message = e;
+            }
+            Error::InvalidGrantError(e) => {
+                code = StatusCode::BAD_REQUEST;
+                error = Some("invalid_grant");
+                message = e;
+            }
+            Error::InvalidRequestError(e) => {
+                code = StatusCode::BAD_REQUEST;
+                error = Some("invalid_request");
+                message = e;
+            }
+            Error::ConflictError(e) => {
+                code = StatusCode::CONFLICT;
+                message = e;
+            }
+            Error::UnauthorizedClientError(e) => {
+                code = StatusCode::BAD_REQUEST;
+                error = Some("unauthorized_client");
+                message = e;
+            }
+            Error::InvalidClientError(e) => {
+                code = StatusCode::UNAUTHORIZED;
+                error = Some("invalid_client");
+                message = e;
+            }
+            Error::ForbiddenError(e) => {
+                code = StatusCode::FORBIDDEN;
+                error = Some("insufficient_scope");
+                message = e;
+            }
+            Error::TooManyRequestsError(seconds) => {
+                code = StatusCode::TOO_MANY_REQUESTS;
+                message = "Too many requests";
+                retry_after = Some(*seconds);
+            }
             _ => {
                 code = StatusCode::UNAUTHORIZED;
                 message = "Unauthorized";
             }
         }
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        // a route with the same path but another method rejected, none of the routes matched
+        code = StatusCode::NOT_FOUND;
+        message = "Not Found";
     } else {
         eprintln!("unhandled error: {:?}", err);
         code = StatusCode::INTERNAL_SERVER_ERROR;
@@ -86,8 +146,13 @@ pub async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply,
     }
 
     let json = warp::reply::json(&ErrorResponse {
+        error: error.map(String::from),
         message: message.into(),
     });
 
-    Ok(warp::reply::with_status(json, code))
+    let mut response = warp::reply::with_status(json, code).into_response();
+    if let Some(seconds) = retry_after {
+        response.headers_mut().insert(RETRY_AFTER, seconds.into());
+    }
+    Ok(response)
 }
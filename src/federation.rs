@@ -0,0 +1,663 @@
+use crate::attributes;
+use crate::errors::Error::InvalidRequestError;
+use crate::handlers::{
+    continue_uri, form_page, found, get_db_client, start_session, valid_email, valid_username, without_prompt,
+};
+use crate::models::{AuthorizationParams, FederationCallback, FederationState, Next, ServerConfig, UpstreamConfig};
+use crate::pages;
+use crate::session;
+use crate::storage::{Database, Storage};
+use chrono::Duration;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use url::Url;
+use uuid::Uuid;
+use warp::Rejection;
+
+// Logging in at an upstream OpenID Connect provider like Azure AD or Google. The user is sent there with
+// the authorization code flow and PKCE and comes back with a code, the provider's ID token says who they
+// are. Their account is the one linked at an earlier login, the one with the same verified email address,
+// or a new one.
+
+const STATE_LIFETIME: i64 = 10 * 60;
+// what federation_states has room for
+const MAX_REQUEST: usize = 4096;
+// binds a login at a provider to the browser that started it
+pub const STATE_COOKIE: &str = "federation";
+// the amr of a session that started at a provider
+pub const FEDERATED: &str = "fed";
+// how long discovery documents and keys of the providers are kept
+const METADATA_LIFETIME: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+// federated users have no password, no hash scheme recognizes this
+pub(crate) const NO_PASSWORD: &str = "!";
+// asymmetric algorithms only, the client secret is no key for ID tokens
+const ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Deserialize, Clone)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+}
+
+fn http() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .expect("Could not create an http client")
+}
+
+async fn fetch<T: serde::de::DeserializeOwned>(uri: &str, what: &str) -> Result<T, String> {
+    http()
+        .get(uri)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("fetching the {} at {} failed: {}", what, uri, e))?
+        .json()
+        .await
+        .map_err(|e| format!("invalid {} at {}: {}", what, uri, e))
+}
+
+fn find_key(jwks: &JwkSet, kid: Option<&str>) -> Option<Jwk> {
+    match kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .cloned()
+}
+
+// The discovery documents and keys of the providers, fetched when they are first needed and again after
+// an hour. Keys are fetched early as well when a token is signed with one that isn't known, the provider
+// rotated its keys.
+pub struct ProviderCache {
+    // by issuer
+    discovery: Mutex<HashMap<String, (Discovery, Instant)>>,
+    // by jwks uri
+    keys: Mutex<HashMap<String, (JwkSet, Instant)>>,
+}
+
+impl ProviderCache {
+    pub fn new() -> Self {
+        ProviderCache {
+            discovery: Mutex::new(HashMap::new()),
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The configured endpoints, the missing ones from the provider's discovery document
+    async fn endpoints(&self, upstream: &UpstreamConfig) -> Result<Discovery, String> {
+        if let (Some(authorization), Some(token), Some(jwks)) =
+            (&upstream.authorization_endpoint, &upstream.token_endpoint, &upstream.jwks_uri)
+        {
+            return Ok(Discovery {
+                authorization_endpoint: authorization.clone(),
+                token_endpoint: token.clone(),
+                jwks_uri: jwks.clone(),
+            });
+        }
+        let cached = self.discovery.lock().unwrap().get(&upstream.issuer).cloned();
+        let discovered = match cached {
+            Some((discovered, fetched)) if fetched.elapsed() < METADATA_LIFETIME => discovered,
+            _ => {
+                let uri = format!("{}/.well-known/openid-configuration", upstream.issuer.trim_end_matches('/'));
+                let discovered: Discovery = fetch(&uri, "discovery document").await?;
+                let mut discovery = self.discovery.lock().unwrap();
+                discovery.insert(upstream.issuer.clone(), (discovered.clone(), Instant::now()));
+                discovered
+            }
+        };
+        Ok(Discovery {
+            authorization_endpoint: upstream
+                .authorization_endpoint
+                .clone()
+                .unwrap_or(discovered.authorization_endpoint),
+            token_endpoint: upstream.token_endpoint.clone().unwrap_or(discovered.token_endpoint),
+            jwks_uri: upstream.jwks_uri.clone().unwrap_or(discovered.jwks_uri),
+        })
+    }
+
+    async fn key(&self, jwks_uri: &str, kid: Option<&str>) -> Result<Jwk, String> {
+        let cached = self.keys.lock().unwrap().get(jwks_uri).cloned();
+        if let Some((jwks, fetched)) = cached {
+            if let Some(jwk) = find_key(&jwks, kid).filter(|_| fetched.elapsed() < METADATA_LIFETIME) {
+                return Ok(jwk);
+            }
+        }
+        // not fetched yet, too old or the provider rotated its keys
+        let jwks: JwkSet = fetch(jwks_uri, "keys").await?;
+        let jwk = find_key(&jwks, kid);
+        self.keys.lock().unwrap().insert(jwks_uri.to_string(), (jwks, Instant::now()));
+        jwk.ok_or_else(|| "the id_token's key is not in the jwks".to_string())
+    }
+}
+
+// Where the provider sends the user back to, register it with the provider
+pub fn redirect_uri(server_config: &ServerConfig, provider: &str) -> String {
+    format!("{}/oauth2/federated/{}/callback", server_config.public_url(), provider)
+}
+
+fn display_name<'a>(provider: &'a str, upstream: &'a UpstreamConfig) -> &'a str {
+    upstream.display_name.as_deref().unwrap_or(provider)
+}
+
+fn login_failed(
+    server_config: &ServerConfig,
+    request: &AuthorizationParams,
+    next: Option<&str>,
+    message: &str,
+) -> warp::reply::Response {
+    form_page(server_config, |csrf| {
+        pages::login(request, next, csrf, &server_config.upstream, Some(message))
+    })
+}
+
+// The login page's link to a provider, sends the user there
+pub async fn get_federated_login(
+    provider: String,
+    request: AuthorizationParams,
+    next: Next,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+    providers: Arc<ProviderCache>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let upstream = server_config.upstream.get(&provider).ok_or_else(warp::reject::not_found)?;
+    let client = get_db_client(&database).await?;
+    // the passkeys page is the only other place a login continues to, see continue_uri
+    let next = next.next.filter(|n| n == "passkeys");
+    let query = serde_urlencoded::to_string(&request).unwrap();
+    if query.len() > MAX_REQUEST {
+        return Err(warp::reject::custom(InvalidRequestError("The authorization request is too long".to_string())));
+    }
+
+    let endpoints = match providers.endpoints(upstream).await {
+        Ok(endpoints) => endpoints,
+        Err(e) => {
+            println!("Upstream provider {}: {}", provider, e);
+            let message = format!("{} can't be reached, try again later", display_name(&provider, upstream));
+            return Ok(login_failed(&server_config, &request, next.as_deref(), &message));
+        }
+    };
+    let mut url = Url::parse(&endpoints.authorization_endpoint).map_err(|_| {
+        warp::reject::custom(InvalidRequestError(format!("Invalid authorization endpoint of {}", provider)))
+    })?;
+
+    let state = session::generate_secret(32);
+    let pending = FederationState {
+        provider: provider.clone(),
+        nonce: session::generate_secret(32),
+        code_verifier: session::generate_secret(64),
+        request: query,
+        next,
+    };
+    client.create_federation_state(&state, &pending, Duration::seconds(STATE_LIFETIME)).await;
+
+    let challenge = base64::encode_config(Sha256::digest(pending.code_verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &upstream.client_id)
+        .append_pair("redirect_uri", &redirect_uri(&server_config, &provider))
+        .append_pair("scope", &upstream.scope)
+        .append_pair("state", &state)
+        .append_pair("nonce", &pending.nonce)
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256");
+    // a client that asks for a fresh login gets one at the provider as well
+    if request.prompt.as_deref().unwrap_or("").split_whitespace().any(|p| p == "login") {
+        url.query_pairs_mut().append_pair("prompt", "login");
+    }
+
+    let mut response = found(url.as_str());
+    session::set_cookie(
+        &mut response,
+        session::cookie(&server_config, STATE_COOKIE, &state, STATE_LIFETIME),
+    );
+    Ok(response)
+}
+
+// Trades the code for the provider's tokens and returns the claims of its ID token once it checks out
+async fn id_token_claims(
+    providers: &ProviderCache,
+    upstream: &UpstreamConfig,
+    redirect_uri: &str,
+    pending: &FederationState,
+    code: &str,
+) -> Result<Map<String, Value>, String> {
+    let endpoints = providers.endpoints(upstream).await?;
+    let mut token_request = http().post(&endpoints.token_endpoint).form(&[
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri),
+        ("code_verifier", &pending.code_verifier),
+        ("client_id", &upstream.client_id),
+    ]);
+    if let Some(secret) = &upstream.client_secret {
+        // client_secret_basic, the id and secret are form encoded first (RFC 6749 section 2.3.1)
+        let encode = |value: &str| url::form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+        token_request = token_request.basic_auth(encode(&upstream.client_id), Some(encode(secret)));
+    }
+    let tokens: TokenResponse = token_request
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("token request failed: {}", e))?
+        .json()
+        .await
+        .map_err(|e| format!("invalid token response: {}", e))?;
+    let id_token = tokens.id_token.ok_or("no id_token in the token response")?;
+
+    let header = jsonwebtoken::decode_header(&id_token).map_err(|e| format!("invalid id_token: {}", e))?;
+    if !ALGORITHMS.contains(&header.alg) {
+        return Err(format!("id_token signed with {:?}", header.alg));
+    }
+    let jwk = providers.key(&endpoints.jwks_uri, header.kid.as_deref()).await?;
+    let key = DecodingKey::from_jwk(&jwk).map_err(|e| format!("unusable key: {}", e))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&upstream.issuer]);
+    validation.set_audience(&[&upstream.client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    let claims = jsonwebtoken::decode::<Map<String, Value>>(&id_token, &key, &validation)
+        .map_err(|e| format!("id_token rejected: {}", e))?
+        .claims;
+
+    let nonce = claims.get("nonce").and_then(Value::as_str).unwrap_or("");
+    if !session::constant_time_eq(nonce, &pending.nonce) {
+        return Err("the id_token has the wrong nonce".to_string());
+    }
+    Ok(claims)
+}
+
+fn claim<'a>(claims: &'a Map<String, Value>, name: &str) -> Option<&'a str> {
+    claims.get(name).and_then(Value::as_str)
+}
+
+// Some providers send email_verified as a string
+fn email_verified(upstream: &UpstreamConfig, claims: &Map<String, Value>) -> bool {
+    match claims.get("email_verified") {
+        Some(Value::Bool(verified)) => *verified,
+        Some(Value::String(verified)) => verified == "true",
+        _ => upstream.trust_email,
+    }
+}
+
+// The first of the candidates that is free, or one made up
+pub(crate) async fn new_username(client: &dyn Storage, provider: &str, candidates: &[&str]) -> String {
+    for candidate in candidates.iter().copied() {
+        if valid_username(candidate) && client.get_user_by_username(candidate).await.is_none() {
+            return candidate.to_string();
+        }
+    }
+    let prefix: String = provider.chars().take(30).collect();
+    format!("{}-{}", prefix, session::generate_secret(8).to_lowercase())
+}
+
+// The user the provider's account belongs to. Errors are shown to the user.
+async fn federated_user(
+    client: &mut dyn Storage,
+    provider: &str,
+    upstream: &UpstreamConfig,
+    claims: &Map<String, Value>,
+) -> Result<Uuid, String> {
+    let subject = claim(claims, "sub").ok_or("The provider didn't say who you are")?;
+    if let Some(user_id) = client.find_federated_user(provider, subject).await {
+        return Ok(user_id);
+    }
+
+    let email = claim(claims, "email").filter(|e| valid_email(e));
+    let verified = email.is_some() && email_verified(upstream, claims);
+    if let (Some(email), true) = (email, verified && upstream.link_by_email) {
+        // an address nobody verified here could belong to anyone
+        if let Some(user) = client.get_user_by_email(email).await.filter(|u| u.email_verified) {
+            client.link_federated_identity(provider, subject, user.id, Some(email)).await;
+            println!("Linked {} account {} to user {}", provider, subject, user.username);
+            return Ok(user.id);
+        }
+    }
+
+    if !upstream.provision {
+        return Err("There is no account for you here, ask for one to be made".to_string());
+    }
+    let email = email.ok_or("The provider didn't share your email address, it's needed for an account")?;
+    if client.get_user_by_email(email).await.is_some() {
+        return Err("There is an account with your email address already, log in with it instead".to_string());
+    }
+    let candidates: Vec<&str> = claim(claims, &upstream.username_claim).into_iter().chain([email]).collect();
+    let username = new_username(client, provider, &candidates).await;
+    let user = client.register_user(&username, email, NO_PASSWORD, verified, None)
+        .await
+        .map_err(|e| e.to_string())?;
+    client.link_federated_identity(provider, subject, user.id, Some(email)).await;
+    println!("Created user {} for {} account {}", user.username, provider, subject);
+    Ok(user.id)
+}
+
+// The provider sends the user back here. With a valid ID token the user is logged in and the
+// authorization request continues.
+pub async fn get_federated_callback(
+    provider: String,
+    params: FederationCallback,
+    state_cookie: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+    providers: Arc<ProviderCache>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let upstream = server_config.upstream.get(&provider).ok_or_else(warp::reject::not_found)?;
+    let mut client = get_db_client(&database).await?;
+
+    // a code sent to another browser than the one that started the login is refused, it would log the
+    // user in as someone else
+    let state = params.state.as_deref().unwrap_or("");
+    let expired = || {
+        warp::reject::custom(InvalidRequestError(
+            "The login expired or was started in another browser, try again".to_string(),
+        ))
+    };
+    if !session::csrf_valid(state_cookie.as_deref(), state) {
+        return Err(expired());
+    }
+    let pending = client.use_federation_state(state)
+        .await
+        .filter(|p| p.provider == provider)
+        .ok_or_else(expired)?;
+    let request: AuthorizationParams = serde_urlencoded::from_str(&pending.request).map_err(|_| expired())?;
+    let next = pending.next.as_deref();
+    let name = display_name(&provider, upstream);
+
+    let claims = match (&params.error, &params.code) {
+        (None, Some(code)) => {
+            let redirect_uri = redirect_uri(&server_config, &provider);
+            id_token_claims(&providers, upstream, &redirect_uri, &pending, code).await
+        }
+        (Some(error), _) => Err(format!("{} {}", error, params.error_description.as_deref().unwrap_or(""))),
+        (None, None) => Err("no code".to_string()),
+    };
+    let claims = match claims {
+        Ok(claims) => claims,
+        Err(e) => {
+            println!("Login at upstream provider {} failed: {}", provider, e);
+            let message = format!("Logging in with {} didn't work", name);
+            return Ok(login_failed(&server_config, &request, next, &message));
+        }
+    };
+
+    let user_id = match federated_user(&mut *client, &provider, upstream, &claims).await {
+        Ok(user_id) => user_id,
+        Err(message) => return Ok(login_failed(&server_config, &request, next, &message)),
+    };
+    if client.get_user(user_id).await.is_none_or(|u| u.disabled) {
+        return Ok(login_failed(&server_config, &request, next, "Your account is disabled"));
+    }
+    attributes::copy(&mut *client, user_id, &upstream.attributes, &claims).await;
+
+    let location = continue_uri(&without_prompt(&request, "login"), next);
+    let mut response = start_session(&*client, user_id, &[FEDERATED.to_string()], &location, &server_config).await;
+    session::set_cookie(&mut response, session::cookie(&server_config, STATE_COOKIE, "", 0));
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use crate::storage::{FederationStore, SessionStore, UserStore};
+    use crate::testing::{self, add_client, add_user, CLIENT_ID, PASSWORD, USERNAME};
+    use serde_json::json;
+    use std::net::SocketAddr;
+    use warp::filters::BoxedFilter;
+    use warp::http::header::{LOCATION, SET_COOKIE};
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    type App = BoxedFilter<(warp::reply::Response,)>;
+
+    const UPSTREAM_CLIENT: &str = "down";
+    const UPSTREAM_SECRET: &str = "s3cret";
+
+    // An OpenID Connect provider that signs with the test key. The tests hand it the codes it accepts, each
+    // with the PKCE challenge it was issued for and the claims of its ID token.
+    #[derive(Default)]
+    struct Provider {
+        codes: HashMap<String, (String, Value)>,
+        // the kid the key is listed under, a rotated key when it isn't the one tokens are signed with
+        listed_kid: Option<String>,
+        discoveries: usize,
+        key_fetches: usize,
+    }
+
+    async fn start_provider() -> (String, Arc<Mutex<Provider>>) {
+        let provider = Arc::new(Mutex::new(Provider::default()));
+        let issuer = Arc::new(Mutex::new(String::new()));
+
+        let (state, base) = (provider.clone(), issuer.clone());
+        let discovery = warp::path!(".well-known" / "openid-configuration").map(move || {
+            state.lock().unwrap().discoveries += 1;
+            let issuer = base.lock().unwrap().clone();
+            warp::reply::json(&json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer),
+            }))
+        });
+        let state = provider.clone();
+        let jwks = warp::path!("jwks").map(move || {
+            let mut provider = state.lock().unwrap();
+            provider.key_fetches += 1;
+            let mut jwks = serde_json::to_value(testing::keys().jwks()).unwrap();
+            if let Some(kid) = &provider.listed_kid {
+                jwks["keys"][0]["kid"] = kid.clone().into();
+            }
+            warp::reply::json(&jwks)
+        });
+        let state = provider.clone();
+        let token = warp::path!("token")
+            .and(warp::header::<String>("authorization"))
+            .and(warp::body::form::<HashMap<String, String>>())
+            .map(move |authorization: String, form: HashMap<String, String>| {
+                let issued = state.lock().unwrap().codes.remove(&form["code"]);
+                let verified = Sha256::digest(form["code_verifier"].as_bytes());
+                let verified = base64::encode_config(verified, base64::URL_SAFE_NO_PAD);
+                let client = format!("Basic {}", base64::encode(format!("{}:{}", UPSTREAM_CLIENT, UPSTREAM_SECRET)));
+                match issued {
+                    Some((challenge, claims)) if challenge == verified && authorization == client => {
+                        let id_token = testing::keys().sign(&claims);
+                        warp::reply::with_status(warp::reply::json(&json!({ "id_token": id_token })), StatusCode::OK)
+                    }
+                    _ => warp::reply::with_status(
+                        warp::reply::json(&json!({ "error": "invalid_grant" })),
+                        StatusCode::BAD_REQUEST,
+                    ),
+                }
+            });
+        let routes = warp::get().and(discovery.or(jwks)).or(warp::post().and(token));
+        let (address, server): (SocketAddr, _) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        *issuer.lock().unwrap() = format!("http://{}", address);
+        let issuer = issuer.lock().unwrap().clone();
+        (issuer, provider)
+    }
+
+    fn config(issuer: &str) -> crate::models::Config {
+        let mut config = testing::config();
+        let upstream = json!({
+            "issuer": issuer,
+            "client_id": UPSTREAM_CLIENT,
+            "client_secret": UPSTREAM_SECRET,
+        });
+        config.server.upstream.insert("idp".to_string(), serde_json::from_value(upstream).unwrap());
+        config
+    }
+
+    fn cookie(response: &warp::http::Response<warp::hyper::body::Bytes>, name: &str) -> Option<String> {
+        response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|c| c.to_str().ok()?.strip_prefix(&format!("{}=", name))?.split(';').next())
+            .find(|value| !value.is_empty())
+            .map(String::from)
+    }
+
+    // Starts a login at the provider, returns the state and the parameters of the redirect to it
+    async fn start_login(app: &App) -> (String, HashMap<String, String>) {
+        let response = warp::test::request()
+            .path(&format!(
+                "/oauth2/federated/idp?client_id={}&response_type=code&redirect_uri=http://localhost:3000/callback",
+                CLIENT_ID
+            ))
+            .reply(app)
+            .await;
+        assert_eq!(response.status(), 302);
+        let location = Url::parse(response.headers()[LOCATION].to_str().unwrap()).unwrap();
+        (cookie(&response, STATE_COOKIE).unwrap(), location.query_pairs().into_owned().collect())
+    }
+
+    async fn callback(
+        app: &App,
+        state_cookie: &str,
+        state: &str,
+        code: &str,
+    ) -> warp::http::Response<warp::hyper::body::Bytes> {
+        warp::test::request()
+            .path(&format!("/oauth2/federated/idp/callback?code={}&state={}", code, state))
+            .header("cookie", format!("{}={}", STATE_COOKIE, state_cookie))
+            .reply(app)
+            .await
+    }
+
+    // The claims of an ID token for the login, the subject's and those given
+    fn id_token(issuer: &str, login: &HashMap<String, String>, subject: &str, extra: Value) -> Value {
+        let mut claims = json!({
+            "iss": issuer,
+            "aud": UPSTREAM_CLIENT,
+            "sub": subject,
+            "exp": chrono::Local::now().timestamp() + 300,
+            "nonce": login["nonce"],
+        });
+        claims.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        claims
+    }
+
+    // Logs in at the provider as the subject, returns the user of the session that started
+    async fn log_in(
+        app: &App,
+        memory: &Memory,
+        issuer: &str,
+        provider: &Mutex<Provider>,
+        subject: &str,
+        extra: Value,
+    ) -> Option<Uuid> {
+        let (state, login) = start_login(app).await;
+        let claims = id_token(issuer, &login, subject, extra);
+        provider.lock().unwrap().codes.insert(subject.to_string(), (login["code_challenge"].clone(), claims));
+        let response = callback(app, &state, &login["state"], subject).await;
+        let token = cookie(&response, crate::session::SESSION_COOKIE)?;
+        memory.find_session(&crate::session::hash_token(&token)).await.map(|s| s.user_id)
+    }
+
+    #[tokio::test]
+    async fn provider_logins_provision_link_and_find_users() {
+        let (issuer, provider) = start_provider().await;
+        let mut memory = Memory::new();
+        add_client(&mut memory, CLIENT_ID, &[]).await;
+        let local = add_user(&mut memory, USERNAME, PASSWORD).await;
+        let app = testing::app(&memory, config(&issuer));
+
+        // a new user, named after the provider's username
+        let extra = json!({ "email": "fed@example.com", "email_verified": true, "preferred_username": "feduser" });
+        let provisioned = log_in(&app, &memory, &issuer, &provider, "u-1", extra).await.unwrap();
+        let user = memory.get_user(provisioned).await.unwrap();
+        assert_eq!((user.username.as_str(), user.email_verified), ("feduser", true));
+        assert_eq!(memory.find_federated_user("idp", "u-1").await, Some(provisioned));
+
+        // found by subject the next time, even with another address
+        let extra = json!({ "email": "moved@example.com", "email_verified": true });
+        assert_eq!(log_in(&app, &memory, &issuer, &provider, "u-1", extra).await, Some(provisioned));
+
+        // linked to the local account with the same verified address
+        let extra = json!({ "email": local.email, "email_verified": true });
+        assert_eq!(log_in(&app, &memory, &issuer, &provider, "u-2", extra).await, Some(local.id));
+        assert_eq!(memory.find_federated_user("idp", "u-2").await, Some(local.id));
+
+        // the provider is discovered and its keys fetched once
+        let provider = provider.lock().unwrap();
+        assert_eq!((provider.discoveries, provider.key_fetches), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn logins_need_the_state_nonce_and_code_verifier_they_started_with() {
+        let (issuer, provider) = start_provider().await;
+        let mut memory = Memory::new();
+        add_client(&mut memory, CLIENT_ID, &[]).await;
+        let app = testing::app(&memory, config(&issuer));
+        let extra = json!({ "email": "fed@example.com", "email_verified": true });
+
+        // a state that isn't the browser's
+        let (state, login) = start_login(&app).await;
+        let (_, other) = start_login(&app).await;
+        let claims = id_token(&issuer, &login, "u-1", extra.clone());
+        provider.lock().unwrap().codes.insert("code".to_string(), (login["code_challenge"].clone(), claims));
+        assert_eq!(callback(&app, &state, &other["state"], "code").await.status(), 400);
+
+        // an ID token for another login's nonce
+        let (state, login) = start_login(&app).await;
+        let claims = id_token(&issuer, &other, "u-1", extra.clone());
+        provider.lock().unwrap().codes.insert("code".to_string(), (login["code_challenge"].clone(), claims));
+        let response = callback(&app, &state, &login["state"], "code").await;
+        assert_eq!(response.status(), 200);
+        assert!(cookie(&response, crate::session::SESSION_COOKIE).is_none());
+
+        // a code that was issued for another login's challenge, the provider refuses the verifier
+        let (state, login) = start_login(&app).await;
+        let claims = id_token(&issuer, &login, "u-1", extra.clone());
+        provider.lock().unwrap().codes.insert("code".to_string(), (other["code_challenge"].clone(), claims));
+        let response = callback(&app, &state, &login["state"], "code").await;
+        assert_eq!(response.status(), 200);
+        assert!(cookie(&response, crate::session::SESSION_COOKIE).is_none());
+
+        // and a state works once
+        assert!(log_in(&app, &memory, &issuer, &provider, "u-1", extra).await.is_some());
+        assert!(memory.get_user_by_email("fed@example.com").await.is_some());
+        assert_eq!(callback(&app, &state, &login["state"], "code").await.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn keys_are_fetched_again_for_an_unknown_kid() {
+        let (issuer, provider) = start_provider().await;
+        let mut memory = Memory::new();
+        add_client(&mut memory, CLIENT_ID, &[]).await;
+        let app = testing::app(&memory, config(&issuer));
+        let extra = json!({ "email": "fed@example.com", "email_verified": true });
+
+        provider.lock().unwrap().listed_kid = Some("retired".to_string());
+        assert!(log_in(&app, &memory, &issuer, &provider, "u-1", extra.clone()).await.is_none());
+        // the provider rotated to the key the tokens are signed with
+        provider.lock().unwrap().listed_kid = None;
+        assert!(log_in(&app, &memory, &issuer, &provider, "u-1", extra.clone()).await.is_some());
+        assert!(log_in(&app, &memory, &issuer, &provider, "u-1", extra).await.is_some());
+        assert_eq!(provider.lock().unwrap().key_fetches, 2);
+    }
+}
@@ -1,16 +1,41 @@
+use crate::account;
+use crate::attributes;
 use crate::db;
 use crate::errors::Error::*;
-use crate::models::{AuthorizationParams, ServerConfig, TokenParams};
+use crate::jwt::SigningKeys;
+use crate::ldap;
+use crate::lockout;
+use crate::logout;
+use crate::mail::Mailer;
+use crate::mfa;
+use crate::models::{
+    AccessTokenClaims, AuthorizationCode, AuthorizationParams, ClientInfo, ConsentParams, EndSessionParams, IdTokenClaims,
+    LoginParams, LoginSession, Next, RegistrationForm, RegistrationParams, ServerConfig, TokenGrant, TokenParams,
+    User, UserInfo, UserSource,
+};
+use crate::password::{self, Verification};
+use crate::pages;
 use crate::response::Response;
-use deadpool_postgres::Client;
+use crate::roles;
+use crate::scopes;
+use crate::session::{self, CSRF_COOKIE, SESSION_COOKIE};
+use crate::storage::{ClientStore, Database, SessionStore, Storage};
+use chrono::{Duration, Local};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use serde_json::{Map, Value};
+use std::net::IpAddr;
 use std::str;
-use std::str::FromStr;
-use warp::http::Uri;
+use std::sync::Arc;
+use url::Url;
+use warp::http::header::{HeaderValue, LOCATION};
+use warp::http::StatusCode;
 use warp::{reply::json, Rejection, Reply};
 use uuid::Uuid;
 
+// The same answer for an unknown user, a wrong password and a locked login
+pub(crate) const INVALID_CREDENTIALS: &str = "Invalid username or password";
+
 fn generate_token() -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -19,200 +44,1168 @@ fn generate_token() -> String {
         .collect()
 }
 
-fn decode_client_auth(client_authorization: String) -> Vec<String> {
-    let f1: Vec<&str> = client_authorization.split(" ").collect();
-    let split = base64::decode(f1[1]).unwrap();
-    str::from_utf8(&split)
-        .unwrap()
-        .split(":")
-        .map(|c: &str| c.to_string())
-        .collect()
+// The client id and secret of a Basic authorization header, None when it isn't one
+fn decode_client_auth(client_authorization: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = client_authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::decode(credentials.trim()).ok()?;
+    let (client_id, secret) = str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some((client_id.to_string(), secret.to_string()))
 }
 
-pub async fn validate_client(client_authorization: String, client: &Client) -> Option<Uuid> {
-    let client_credentials = decode_client_auth(client_authorization);
-    let client_db_id = db::validate_client_credentials(
-        &client,
-        client_credentials[0].to_string(),
-        client_credentials[1].to_string(),
-    )
-    .await;
+pub(crate) async fn get_db_client(database: &Arc<dyn Database>) -> Result<Box<dyn Storage>, Rejection> {
+    database.connect().await.map_err(warp::reject::custom)
+}
 
-    client_db_id
+pub async fn validate_client(client_authorization: String, client: &dyn ClientStore) -> Option<Uuid> {
+    let (client_id, secret) = decode_client_auth(&client_authorization)?;
+    client.validate_client_credentials(client_id, secret).await
 }
 
-// Returns the user id if valid, 0 if invalid
-pub async fn validate_code(client: &Client, code: &String, pcke: &String) -> Option<Uuid> {
-    let user_id = db::validate_code(&client, code, pcke).await;
-    user_id
+// With a directory configured, users that aren't in the users table or came from the directory are checked
+// against it, the others here
+pub(crate) async fn validate_password_credentials(
+    client: &mut dyn Storage,
+    username: String,
+    password: String,
+    server_config: &ServerConfig,
+) -> Option<Uuid> {
+    if let Some(directory) = &server_config.ldap {
+        if client.get_user_source(&username).await != Some(UserSource::Local) {
+            return ldap::validate(client, directory, &username, &password).await;
+        }
+    }
+    let config = server_config.password.clone();
+
+    let (user_id, hash) = match client.get_password_hash(&username).await {
+        Some(stored) => stored,
+        None => {
+            let _ = tokio::task::spawn_blocking(move || password::verify_dummy(&password, &config)).await;
+            return None;
+        }
+    };
+
+    // hashing is slow on purpose, keep it off the async workers
+    let verification = tokio::task::spawn_blocking(move || password::verify(&password, &hash, &config))
+        .await
+        .expect("Password verification panicked");
+
+    match verification {
+        Verification::Valid => Some(user_id),
+        Verification::Rehash(hash) => {
+            // a legacy hash or weaker settings, replaced now that the password is known
+            client.set_password(user_id, &hash).await;
+            Some(user_id)
+        }
+        Verification::Invalid => None,
+    }
+}
+
+pub(crate) fn found(location: &str) -> warp::reply::Response {
+    let mut response = warp::reply().into_response();
+    *response.status_mut() = StatusCode::FOUND;
+    response
+        .headers_mut()
+        .insert(LOCATION, HeaderValue::from_str(location).unwrap());
+    response
+}
+
+// A 302 to the given uri with extra query parameters
+fn redirect_to(uri: &str, params: &[(&str, &str)]) -> warp::reply::Response {
+    let mut url = Url::parse(uri).expect("Redirect uri is not a valid url");
+    if !params.is_empty() {
+        url.query_pairs_mut().extend_pairs(params);
+    }
+    found(url.as_str())
+}
+
+// Sends the result of an authorization request back to the client, see RFC-6749 section 4.1.2
+fn authorization_response(request: &AuthorizationParams, params: &[(&str, &str)]) -> warp::reply::Response {
+    let mut params = params.to_vec();
+    if let Some(state) = request.state.as_deref() {
+        params.push(("state", state));
+    }
+    redirect_to(&request.redirect_uri, &params)
+}
+
+fn authorization_error(request: &AuthorizationParams, error: &str, description: &str) -> warp::reply::Response {
+    authorization_response(request, &[("error", error), ("error_description", description)])
+}
+
+// prompt is a space delimited list, see OpenID Connect core section 3.1.2.1
+fn has_prompt(request: &AuthorizationParams, prompt: &str) -> bool {
+    request
+        .prompt
+        .as_deref()
+        .map(|p| p.split_whitespace().any(|x| x == prompt))
+        .unwrap_or(false)
+}
+
+// The client and redirect uri are checked before anything else, when these are wrong the error is
+// shown to the user instead of being redirected somewhere we can't trust
+pub(crate) async fn validate_authorization_request(
+    client: &dyn Storage,
+    request: &AuthorizationParams,
+) -> Result<ClientInfo, Rejection> {
+    let client_info = match client.get_client_by_client_id(&request.client_id).await {
+        Some(info) => info,
+        None => {
+            return Err(warp::reject::custom(InvalidRequestError(
+                "Unknown client".to_string(),
+            )))
+        }
+    };
+
+    if !client_info.redirect_uris.contains(&request.redirect_uri) {
+        return Err(warp::reject::custom(InvalidRequestError(
+            "redirect_uri is not registered for this client".to_string(),
+        )));
+    }
+
+    if !client_info.grant_types.iter().any(|g| g == "authorization_code") {
+        return Err(warp::reject::custom(UnauthorizedClientError(
+            "The client may not use the authorization code flow".to_string(),
+        )));
+    }
+
+    Ok(client_info)
+}
+
+pub(crate) async fn current_session(client: &dyn SessionStore, session: Option<String>) -> Option<LoginSession> {
+    match session {
+        Some(token) => client.find_session(&session::hash_token(&token)).await,
+        None => None,
+    }
+}
+
+// max_age asks for a login that is at most that many seconds old, see OpenID Connect core section 3.1.2.1
+fn within_max_age(login_session: &LoginSession, request: &AuthorizationParams) -> bool {
+    match request.max_age.as_deref().and_then(|m| m.parse::<i64>().ok()) {
+        Some(max_age) => login_session.auth_time + Duration::seconds(max_age) >= Local::now(),
+        None => true,
+    }
+}
+
+// The same request without the given prompt value, used to resume the request once it has been honored
+pub(crate) fn without_prompt(request: &AuthorizationParams, prompt: &str) -> AuthorizationParams {
+    let remaining: Vec<&str> = request
+        .prompt
+        .as_deref()
+        .unwrap_or("")
+        .split_whitespace()
+        .filter(|p| *p != prompt)
+        .collect();
+    AuthorizationParams {
+        prompt: if remaining.is_empty() {
+            None
+        } else {
+            Some(remaining.join(" "))
+        },
+        ..request.clone()
+    }
+}
+
+pub(crate) fn redirect_to_login(request: &AuthorizationParams) -> warp::reply::Response {
+    let query = serde_urlencoded::to_string(request).unwrap();
+    found(&format!("/oauth2/login?{}", query))
+}
+
+// Renders a page with a form, every form gets a fresh csrf token
+pub(crate) fn form_page(
+    server_config: &ServerConfig,
+    render: impl FnOnce(&str) -> String,
+) -> warp::reply::Response {
+    let csrf_token = session::generate_secret(32);
+    let mut response = warp::reply::html(render(&csrf_token)).into_response();
+    session::set_cookie(
+        &mut response,
+        session::cookie(server_config, CSRF_COOKIE, &csrf_token, 60 * 60),
+    );
+    response
+}
+
+pub(crate) fn check_csrf(cookie: Option<String>, form: &str) -> Result<(), Rejection> {
+    if session::csrf_valid(cookie.as_deref(), form) {
+        Ok(())
+    } else {
+        Err(warp::reject::custom(InvalidRequestError(
+            "Invalid csrf token, reload the page and try again".to_string(),
+        )))
+    }
+}
+
+async fn issue_code(
+    client: &dyn Storage,
+    client_info: &ClientInfo,
+    login_session: &LoginSession,
+    request: &AuthorizationParams,
+    scope: Option<String>,
+) -> warp::reply::Response {
+    let code = generate_token();
+    let authorization = AuthorizationCode {
+        user_id: login_session.user_id,
+        scope,
+        auth_time: Some(login_session.auth_time),
+        amr: login_session.amr.clone(),
+        nonce: request.nonce.clone(),
+        sid: Some(login_session.sid.clone()),
+    };
+    client.insert_code(&code, client_info.id, &authorization, request).await;
+    // remembered so logging out of the session can reach this client
+    client.add_session_client(login_session.id, client_info.id).await;
+    authorization_response(request, &[("code", &code)])
+}
+
+// Works out the scope for an authenticated user and either asks for consent or hands out the code.
+// First party clients and scopes the user approved before skip the consent page, unless the client
+// asks for it with prompt=consent.
+async fn authorize(
+    client: &dyn Storage,
+    client_info: &ClientInfo,
+    login_session: &LoginSession,
+    request: &AuthorizationParams,
+    server_config: &ServerConfig,
+) -> warp::reply::Response {
+    let user_id = login_session.user_id;
+    let policy = client.get_scope_policy(client_info.id, Some(user_id)).await;
+    let scope = match scopes::resolve(request.scope.as_deref(), &policy) {
+        Ok(scope) => scope,
+        Err(e) => return authorization_error(request, "invalid_scope", &e.to_string()),
+    };
+    let granted = scope.as_deref().map(scopes::parse).unwrap_or_default();
+
+    let registry = client.get_scopes().await;
+    let consented = client.get_consented_scopes(user_id, client_info.id).await;
+    let forced = has_prompt(request, "consent");
+    let pending: Vec<(String, String)> = granted
+        .iter()
+        .filter_map(|s| {
+            let (description, requires_consent) = scopes::describe(s, &registry);
+            let needed = requires_consent && !client_info.first_party && !consented.contains(s);
+            if forced || needed {
+                Some((s.clone(), description))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if pending.is_empty() {
+        return issue_code(client, client_info, login_session, request, scope).await;
+    }
+
+    if has_prompt(request, "none") {
+        return authorization_error(request, "consent_required", "The user has to approve the requested scopes");
+    }
+
+    let client_name = client_info
+        .display_name
+        .as_deref()
+        .unwrap_or(&client_info.client_id);
+    form_page(server_config, |csrf| pages::consent(client_name, &pending, request, csrf))
+}
+
+fn id_token(
+    grant: &TokenGrant,
+    user_id: Uuid,
+    claims: Map<String, Value>,
+    audience: &str,
+    issuer: &str,
+    keys: &SigningKeys,
+) -> String {
+    let now = Local::now();
+    keys.sign(&IdTokenClaims {
+        iss: issuer.to_string(),
+        sub: user_id.to_string(),
+        aud: audience.to_string(),
+        exp: (now + Duration::hours(1)).timestamp(),
+        iat: now.timestamp(),
+        auth_time: grant.auth_time.map(|t| t.timestamp()),
+        amr: grant.amr.clone(),
+        nonce: grant.nonce.clone(),
+        sid: grant.sid.clone(),
+        claims,
+    })
+}
+
+// The access token of a client with jwt_access_tokens. It is stored like an opaque one, so revocation and
+// introspection work the same. Roles and claims are those at the time it is issued.
+async fn jwt_access_token(
+    client: &dyn Storage,
+    grant: &TokenGrant,
+    client_info: &ClientInfo,
+    issuer: &str,
+    keys: &SigningKeys,
+) -> String {
+    let (roles, groups, claims) = match grant.user_id {
+        Some(user_id) => {
+            let (roles, groups) = roles::claims(client, user_id, &client_info.client_id).await;
+            (roles, groups, attributes::claims(client, user_id, grant.scope.as_deref()).await)
+        }
+        None => (Vec::new(), Vec::new(), Map::new()),
+    };
+    let lifetime = client_info
+        .access_token_lifetime
+        .map(i64::from)
+        .unwrap_or(db::DEFAULT_TOKEN_LIFETIME);
+    let now = Local::now();
+    keys.sign_typed(
+        "at+jwt",
+        &AccessTokenClaims {
+            iss: issuer.to_string(),
+            sub: grant
+                .user_id
+                .map(|u| u.to_string())
+                .unwrap_or_else(|| client_info.client_id.clone()),
+            aud: client_info.client_id.clone(),
+            client_id: client_info.client_id.clone(),
+            exp: (now + Duration::seconds(lifetime)).timestamp(),
+            iat: now.timestamp(),
+            jti: Uuid::new_v4().to_string(),
+            scope: grant.scope.clone(),
+            auth_time: grant.auth_time.map(|t| t.timestamp()),
+            amr: grant.amr.clone(),
+            roles,
+            groups,
+            claims,
+        },
+    )
+}
+
+// Issues a new access token for the grant. Refresh tokens are only handed out when a user is involved
+// and the client may use them, an ID token when the user is involved and the openid scope was granted.
+async fn issue_token(
+    client: &dyn Storage,
+    grant: TokenGrant,
+    server_config: ServerConfig,
+    keys: &SigningKeys,
+) -> std::result::Result<warp::reply::Json, Rejection> {
+    let client_info = match client.get_client_by_id(grant.client_id).await {
+        Some(info) => info,
+        None => {
+            return Err(warp::reject::custom(AuthorizationError(
+                "client id not found".to_string(),
+            )))
+        }
+    };
+    let refresh_token = grant
+        .user_id
+        .filter(|_| client_info.grant_types.iter().any(|g| g == "refresh_token"))
+        .map(|_| generate_token());
+    let openid = grant
+        .scope
+        .as_deref()
+        .map(|s| scopes::parse(s).iter().any(|x| x == "openid"))
+        .unwrap_or(false);
+    let id_token = match grant.user_id {
+        Some(user_id) if openid => {
+            let claims = attributes::claims(client, user_id, grant.scope.as_deref()).await;
+            Some(id_token(&grant, user_id, claims, &client_info.client_id, &server_config.name, keys))
+        }
+        _ => None,
+    };
+
+    let access_token = if client_info.jwt_access_tokens {
+        jwt_access_token(client, &grant, &client_info, &server_config.name, keys).await
+    } else {
+        generate_token()
+    };
+    let mut res = client.insert_token(access_token, refresh_token, grant, server_config.name, &client_info).await;
+    res.id_token = id_token;
+    Ok(json(&res))
 }
 
 // Introspect a token
 pub async fn introspect_token(
     client_authorization: String,
     access_token: String,
-    db_pool: deadpool_postgres::Pool,
+    database: Arc<dyn Database>,
 ) -> std::result::Result<impl Reply, Rejection> {
-    let client: Client = db_pool.get().await.expect("Error connecting to database");
-    let client_db_id = validate_client(client_authorization, &client).await;
+    let client = get_db_client(&database).await?;
+    let client_db_id = match validate_client(client_authorization, &*client).await {
+        Some(id) => id,
+        None => {
+            return Err(warp::reject::custom(AuthorizationError(
+                "Client credentials invalid".to_string(),
+            )))
+        }
+    };
 
-    if let None = client_db_id {
-        return Err(warp::reject::custom(AuthorizationError(
-            "Client credentials invalid".to_string(),
-        )));
+    match client.validate_access_token(access_token, client_db_id).await {
+        None => Err(warp::reject::custom(NotFoundError(
+            "Unknown token".to_string(),
+        ))),
+        Some(mut x) => {
+            let rules = client.get_scope_rules().await;
+            let granted = x.scope.as_deref().map(scopes::parse).unwrap_or_default();
+            x.effective_scope = scopes::join(&scopes::expand(&granted, &rules));
+            if let (true, Some(user_id)) = (x.active, x.user_id) {
+                x.claims = attributes::claims(&*client, user_id, x.scope.as_deref()).await;
+                (x.roles, x.groups) = roles::claims(&*client, user_id, &x.client_id).await;
+            }
+            Ok(json(&x))
+        }
     }
+}
 
-    let result = db::validate_access_token(&client, access_token, client_db_id.unwrap());
-
-    match result.await {
-        None => {
-            return Err(warp::reject::custom(NotFoundError(
-                "Unknown token".to_string(),
+// OpenID Connect userinfo, for an access token of a user with the openid scope
+pub async fn get_userinfo(
+    authorization: String,
+    database: Arc<dyn Database>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = get_db_client(&database).await?;
+    let token = authorization.strip_prefix("Bearer ").unwrap_or("");
+    let (user_id, scope) = match client.get_active_token_user(token).await {
+        Some((Some(user_id), scope)) => (user_id, scope),
+        _ => {
+            return Err(warp::reject::custom(AuthorizationError(
+                "A valid bearer token of a user is required".to_string(),
             )))
         }
-        Some(x) => return Ok(json(&x)),
+    };
+    if !scope.as_deref().map(scopes::parse).unwrap_or_default().iter().any(|s| s == "openid") {
+        return Err(warp::reject::custom(ForbiddenError("The token needs the openid scope".to_string())));
     }
+
+    let claims = attributes::claims(&*client, user_id, scope.as_deref()).await;
+    Ok(json(&UserInfo {
+        sub: user_id.to_string(),
+        claims,
+    })
+    .into_response())
 }
 
 pub async fn get_authorization(
-    authorization_params: AuthorizationParams,
-    db_pool: deadpool_postgres::Pool,
-) -> std::result::Result<impl Reply, Rejection> {
-    println!("Redirecting to login page");
-    let url = format!(
-        "http://localhost:8082/auth?client_id={}&response_type={}&redirect_uri={}&scope={}",
-        authorization_params.client_id,
-        authorization_params.response_type,
-        authorization_params.redirect_uri,
-        authorization_params.scope
+    request: AuthorizationParams,
+    session: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = get_db_client(&database).await?;
+    let client_info = validate_authorization_request(&*client, &request).await?;
+
+    if request.response_type != "code" {
+        return Ok(authorization_error(
+            &request,
+            "unsupported_response_type",
+            "Only the code response type is supported",
+        ));
+    }
+
+    // an existing session is reused unless the client wants a fresh login, prompt=none never shows a page
+    let login_session = current_session(&*client, session)
+        .await
+        .filter(|s| !has_prompt(&request, "login") && within_max_age(s, &request));
+
+    match login_session {
+        Some(login_session) if mfa::needs_second_factor(&*client, &client_info, &login_session).await => {
+            if has_prompt(&request, "none") {
+                Ok(authorization_error(
+                    &request,
+                    "interaction_required",
+                    "The user has to give a second factor",
+                ))
+            } else {
+                Ok(mfa::redirect_to_mfa(&request, None))
+            }
+        }
+        Some(login_session) => {
+            Ok(authorize(&*client, &client_info, &login_session, &request, &server_config).await)
+        }
+        None if has_prompt(&request, "none") => Ok(authorization_error(
+            &request,
+            "login_required",
+            "The user is not logged in",
+        )),
+        None => Ok(redirect_to_login(&request)),
+    }
+}
+
+// The user approved or denied the consent page
+pub async fn post_consent(
+    params: ConsentParams,
+    session: Option<String>,
+    csrf: Option<String>,
+    database: Arc<dyn Database>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    check_csrf(csrf, &params.csrf_token)?;
+    let client = get_db_client(&database).await?;
+    let request = params.request;
+    let client_info = validate_authorization_request(&*client, &request).await?;
+
+    let login_session = match current_session(&*client, session).await {
+        Some(login_session) => login_session,
+        None => return Ok(redirect_to_login(&request)),
+    };
+    if mfa::needs_second_factor(&*client, &client_info, &login_session).await {
+        return Ok(mfa::redirect_to_mfa(&request, None));
+    }
+    let user_id = login_session.user_id;
+
+    if params.decision != "allow" {
+        return Ok(authorization_error(
+            &request,
+            "access_denied",
+            "The user denied the request",
+        ));
+    }
+
+    let policy = client.get_scope_policy(client_info.id, Some(user_id)).await;
+    let scope = match scopes::resolve(request.scope.as_deref(), &policy) {
+        Ok(scope) => scope,
+        Err(e) => return Ok(authorization_error(&request, "invalid_scope", &e.to_string())),
+    };
+    let granted = scope.as_deref().map(scopes::parse).unwrap_or_default();
+    client.insert_consents(user_id, client_info.id, &granted).await;
+
+    Ok(issue_code(&*client, &client_info, &login_session, &request, scope).await)
+}
+
+pub async fn get_login(
+    request: AuthorizationParams,
+    next: Next,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    Ok(form_page(&server_config, |csrf| {
+        pages::login(&request, next.next.as_deref(), csrf, &server_config.upstream, None)
+    }))
+}
+
+// Where to go once the user is logged in: the authorization request, or the passkeys page when the user
+// came to manage their passkeys
+pub(crate) fn continue_uri(request: &AuthorizationParams, next: Option<&str>) -> String {
+    let query = serde_urlencoded::to_string(request).unwrap();
+    match next {
+        Some("passkeys") => format!("/oauth2/passkeys?{}", query),
+        _ => format!("/oauth2/authorize?{}", query),
+    }
+}
+
+// Creates a login session for the user and sets its cookie on a redirect to location
+pub(crate) async fn start_session(
+    client: &dyn Storage,
+    user_id: Uuid,
+    amr: &[String],
+    location: &str,
+    server_config: &ServerConfig,
+) -> warp::reply::Response {
+    let token = session::generate_secret(64);
+    let sid = session::generate_secret(32);
+    let lifetime = Duration::seconds(server_config.session_lifetime);
+    client.create_session(&session::hash_token(&token), &sid, user_id, amr, lifetime).await;
+
+    let mut response = found(location);
+    session::set_cookie(
+        &mut response,
+        session::cookie(server_config, SESSION_COOKIE, &token, lifetime.num_seconds()),
     );
-    Ok(warp::redirect(Uri::from_str(&url).unwrap()))
+    response
+}
+
+// Logs the user in and resumes the authorization request that sent them here
+pub async fn post_login(
+    params: LoginParams,
+    csrf: Option<String>,
+    client_ip: Option<IpAddr>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    check_csrf(csrf, &params.csrf_token)?;
+    let mut client = get_db_client(&database).await?;
+    let request = params.request;
+    let next = params.next.as_deref();
+    let username = params.username;
+    let lockout = &server_config.lockout;
+
+    let validation = if lockout::locked(&*client, lockout, &username, client_ip).await {
+        None
+    } else {
+        validate_password_credentials(&mut *client, username.clone(), params.password, &server_config).await
+    };
+    let user_id = match validation {
+        Some(user_id) => user_id,
+        None => {
+            let delay = lockout::failed(&*client, lockout, &username, client_ip).await;
+            drop(client);
+            tokio::time::sleep(delay).await;
+            return Ok(form_page(&server_config, |csrf| {
+                pages::login(&request, next, csrf, &server_config.upstream, Some(INVALID_CREDENTIALS))
+            }));
+        }
+    };
+    lockout::succeeded(&*client, &username).await;
+
+    // the login just happened, asking for it again would loop
+    let location = continue_uri(&without_prompt(&request, "login"), next);
+    Ok(start_session(&*client, user_id, &[mfa::PASSWORD.to_string()], &location, &server_config).await)
 }
 
 // Request an access token
 pub async fn get_access_token(
     params: Option<TokenParams>,
     client_authorization: String,
-    db_pool: deadpool_postgres::Pool,
+    client_ip: Option<IpAddr>,
+    database: Arc<dyn Database>,
     server_config: ServerConfig,
+    keys: Arc<SigningKeys>,
 ) -> std::result::Result<impl Reply, Rejection> {
-    let client: Client = db_pool.get().await.expect("Error connecting to database");
+    let mut client = get_db_client(&database).await?;
 
-    if client_authorization.is_empty() {
-        print!("Empty client auth?");
-        return Err(warp::reject::custom(AuthorizationError(
-            "Client credentials invalid".to_string(),
-        )));
-    }
+    // only an authenticated client learns which grants it may use
+    let client_id = validate_client(client_authorization, &*client)
+        .await
+        .ok_or_else(|| warp::reject::custom(InvalidClientError("Client credentials invalid".to_string())))?;
 
+    if let Some(obj) = params {
+        if let Some(info) = client.get_client_by_id(client_id).await {
+            if !info.grant_types.contains(&obj.grant_type) {
+                return Err(warp::reject::custom(UnauthorizedClientError(format!(
+                    "The client may not use the {} grant",
+                    obj.grant_type
+                ))));
+            }
+        }
 
-    match params {
-        Some(obj) => match obj.grant_type.as_str() {
+        match obj.grant_type.as_str() {
             "password" => {
-                if obj.username.is_some() && obj.password.is_some() {
-                    let client_db_id = validate_client(client_authorization, &client).await;
-                    let validation = db::validate_password_credentials(
-                        &client,
-                        obj.username.unwrap(),
-                        obj.password.unwrap(),
-                    )
-                    .await;
-
-                    if let (Some(client_id), Some(validated_user)) = (client_db_id, validation) {
-                        let token = generate_token();
-                        let res = db::insert_token(
-                            &client,
-                            token.clone(),
-                            obj.scope,
-                            validation,
+                if let (Some(username), Some(password)) = (obj.username, obj.password) {
+                    let lockout = &server_config.lockout;
+                    let validation = if lockout::locked(&*client, lockout, &username, client_ip).await {
+                        None
+                    } else {
+                        validate_password_credentials(&mut *client, username.clone(), password, &server_config)
+                            .await
+                    };
+
+                    if let Some(user_id) = validation {
+                        lockout::succeeded(&*client, &username).await;
+                        // the password grant has no way to ask for a second factor
+                        if let Some(info) = client.get_client_by_id(client_id).await {
+                            if mfa::required(&*client, &info, user_id).await {
+                                return Err(warp::reject::custom(InvalidGrantError(
+                                    "The user has to log in with a second factor, use the authorization code flow"
+                                        .to_string(),
+                                )));
+                            }
+                        }
+                        let policy = client.get_scope_policy(client_id, Some(user_id)).await;
+                        let scope = scopes::resolve(obj.scope.as_deref(), &policy).map_err(warp::reject::custom)?;
+                        let grant = TokenGrant {
+                            user_id: Some(user_id),
                             client_id,
-                            server_config.name,
-                            obj.device,
-                        )
-                        .await;
-                        return Ok(json(&res));
+                            scope,
+                            device: obj.device,
+                            auth_time: Some(Local::now()),
+                            amr: vec![mfa::PASSWORD.to_string()],
+                            nonce: None,
+                            sid: None,
+                        };
+                        return issue_token(&*client, grant, server_config, &keys).await;
                     } else {
-                        return Err(warp::reject::custom(AuthorizationError(
-                            "client or user not found".to_string(),
-                        )));
+                        let delay = lockout::failed(&*client, lockout, &username, client_ip).await;
+                        drop(client);
+                        tokio::time::sleep(delay).await;
+                        return Err(warp::reject::custom(InvalidGrantError(INVALID_CREDENTIALS.to_string())));
                     }
                 }
             }
             "client_credentials" => {
-                let client_db_id = validate_client(client_authorization, &client).await;
-                if let Some(client_id) = client_db_id {
-                    let token = generate_token();
-                    let res = db::insert_token(
-                        &client,
-                        token.clone(),
-                        obj.scope,
-                        None,
-                        client_id,
-                        server_config.name,
-                        obj.device,
-                    )
-                    .await;
-                    return Ok(json(&res));
-                } else {
-                    return Err(warp::reject::custom(AuthorizationError(
-                        "client id not found".to_string(),
-                    )));
-                }
+                let policy = client.get_scope_policy(client_id, None).await;
+                let scope = scopes::resolve(obj.scope.as_deref(), &policy).map_err(warp::reject::custom)?;
+                let grant = TokenGrant {
+                    user_id: None,
+                    client_id,
+                    scope,
+                    device: obj.device,
+                    auth_time: None,
+                    amr: vec![],
+                    nonce: None,
+                    sid: None,
+                };
+                return issue_token(&*client, grant, server_config, &keys).await;
             }
             "authorization_code" => { //TODO add device hier ook, als extra check?
-                println!("test");
-                println!("{}", client_authorization);
-                let client_db_id = validate_client(client_authorization, &client).await; //TODO support voor PCKE ipv client_secret hier
-                let code = obj.code.unwrap();
-                let pcke = obj.pcke.unwrap(); //TODO remove unwraps for safer code (unwrap will panic)
-                println!("Found client {}", client_db_id.unwrap());
-                let user_id = validate_code(&client, &code, &pcke).await;
-                println!("Found user {}", user_id.unwrap());
-                if let (Some(client_id), Some(user_uid)) = (client_db_id, user_id) {
-                    let token = generate_token();
-                    let res = db::insert_token(
-                        &client,
-                        token.clone(),
-                        obj.scope,
-                        user_id,
-                        client_id,
-                        server_config.name,
-                        obj.device,
-                    )
-                    .await;
-                    db::delete_code(&client, &code).await;
-                    return Ok(json(&res));
-                } else {
-                    return Err(warp::reject::custom(AuthorizationError(
-                        "client id or user id not found".to_string(),
-                    )));
-                }
+                //TODO support voor PCKE ipv client_secret hier
+                let code = obj.code.unwrap_or_default();
+                let pcke = obj.pcke.unwrap_or_default();
+                let redirect_uri = obj.redirect_uri.unwrap_or_default();
+                let authorization = match client.validate_code(&code, &pcke, client_id, &redirect_uri).await {
+                    Some(authorization) => authorization,
+                    None => {
+                        return Err(warp::reject::custom(InvalidGrantError(
+                            "Invalid authorization code".to_string(),
+                        )))
+                    }
+                };
+
+                // the code carries the scope the user approved, the client can only ask for less
+                let scope = scopes::downscope(obj.scope.as_deref(), authorization.scope.as_deref())
+                    .map_err(warp::reject::custom)?;
+                let grant = TokenGrant {
+                    user_id: Some(authorization.user_id),
+                    client_id,
+                    scope,
+                    device: obj.device,
+                    auth_time: authorization.auth_time,
+                    amr: authorization.amr,
+                    nonce: authorization.nonce,
+                    sid: authorization.sid,
+                };
+                return issue_token(&*client, grant, server_config, &keys).await;
+            }
+            "refresh_token" => {
+                let refresh_token = obj.refresh_token.unwrap_or_default();
+                let original = match client.find_refresh_token(&refresh_token, client_id).await {
+                    Some(grant) => grant,
+                    None => {
+                        return Err(warp::reject::custom(InvalidGrantError(
+                            "Unknown refresh token".to_string(),
+                        )))
+                    }
+                };
+
+                // the client may have lost scopes since the original grant, those are dropped silently
+                let policy = client.get_scope_policy(client_id, original.user_id).await;
+                let scope = scopes::downscope(obj.scope.as_deref(), original.scope.as_deref())
+                    .map_err(warp::reject::custom)?;
+                let grant = TokenGrant {
+                    scope: scopes::restrict(scope.as_deref(), &policy),
+                    ..original
+                };
+                return issue_token(&*client, grant, server_config, &keys).await;
             }
             _ => {
                 return Err(warp::reject::custom(AuthorizationError(
                     "Unsupported grant type".to_string(),
                 )));
             }
-        },
-        None => {}
+        }
     }
-    print!("end of the road..");
-
-    return Err(warp::reject::not_found());
+    Err(warp::reject::not_found())
 }
 
 pub async fn invalidate_token(
-    db_pool: deadpool_postgres::Pool,
+    _database: Arc<dyn Database>,
 ) -> std::result::Result<impl Reply, Rejection> {
     Ok("")
 }
 
-pub async fn get_health() -> std::result::Result<impl Reply, Rejection> {
+// RP-initiated logout. Ends the user's login session, tells the clients that used it and sends the
+// browser back to the client when it asked for that.
+pub async fn end_session(
+    params: EndSessionParams,
+    session: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+    keys: Arc<SigningKeys>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = get_db_client(&database).await?;
+
+    // an id token of this server, access and logout tokens carry another typ
+    let hint = match params.id_token_hint.as_deref() {
+        Some(token) => match keys.verify::<IdTokenClaims>(
+            token,
+            "JWT",
+            &server_config.name,
+            params.client_id.as_deref(),
+        ) {
+            Some(claims) if client.get_client_by_client_id(&claims.aud).await.is_some() => Some(claims),
+            _ => {
+                return Err(warp::reject::custom(InvalidRequestError(
+                    "Invalid id_token_hint".to_string(),
+                )))
+            }
+        },
+        None => None,
+    };
+
+    // the client is named by client_id or by the audience of the hint, both have to agree
+    let client_id = match (params.client_id, &hint) {
+        (Some(client_id), Some(claims)) if client_id != claims.aud => {
+            return Err(warp::reject::custom(InvalidRequestError(
+                "client_id does not match the id_token_hint".to_string(),
+            )))
+        }
+        (Some(client_id), _) => Some(client_id),
+        (None, Some(claims)) => Some(claims.aud.clone()),
+        (None, None) => None,
+    };
+
+    let redirect = match params.post_logout_redirect_uri {
+        Some(uri) => {
+            let client_info = match &client_id {
+                Some(client_id) => client.get_client_by_client_id(client_id).await,
+                None => None,
+            };
+            match client_info {
+                Some(info) if info.post_logout_redirect_uris.contains(&uri) => {
+                    let mut url = Url::parse(&uri).map_err(|_| {
+                        warp::reject::custom(InvalidRequestError("Invalid post_logout_redirect_uri".to_string()))
+                    })?;
+                    if let Some(state) = params.state.as_deref() {
+                        url.query_pairs_mut().append_pair("state", state);
+                    }
+                    Some(url.to_string())
+                }
+                _ => {
+                    return Err(warp::reject::custom(InvalidRequestError(
+                        "post_logout_redirect_uri is not registered for this client".to_string(),
+                    )))
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut frontchannel = vec![];
+    if let Some(login_session) = current_session(&*client, session).await {
+        // a hint for someone else does not end this user's session
+        let same_user = hint
+            .as_ref()
+            .map(|claims| claims.sub == login_session.user_id.to_string())
+            .unwrap_or(true);
+        if same_user {
+            let clients = client.get_session_clients(login_session.id).await;
+            client.delete_session(login_session.id).await;
+            logout::notify_backchannel(&clients, &login_session, &server_config.name, &keys);
+            frontchannel = logout::frontchannel_uris(&clients, &login_session, &server_config.name);
+        }
+    }
+
+    let mut response = match redirect {
+        Some(uri) if frontchannel.is_empty() => found(&uri),
+        redirect => warp::reply::html(pages::logged_out(&frontchannel, redirect.as_deref())).into_response(),
+    };
+    session::set_cookie(&mut response, session::cookie(&server_config, SESSION_COOKIE, "", 0));
+    Ok(response)
+}
+
+pub async fn get_jwks(keys: Arc<SigningKeys>) -> Response {
+    Ok(json(&keys.jwks()))
+}
+
+pub async fn get_health() -> Response {
     Ok(warp::reply::json(&"UP"))
 }
 
-pub async fn create_user(
-    db_pool: deadpool_postgres::Pool,
-) -> std::result::Result<impl Reply, Rejection> {
-    Ok("")
+pub(crate) fn valid_username(username: &str) -> bool {
+    (3..=50).contains(&username.chars().count())
+        && username
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '.' | '_' | '-' | '@'))
+}
+
+// Only catches typos, the address is proven by the verification mail
+pub(crate) fn valid_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            email.len() <= 100
+                && !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+// Checks the details of a new user and hashes the password, registration and the admin api share this
+pub(crate) async fn check_new_user(
+    username: &str,
+    email: &str,
+    password: String,
+    server_config: &ServerConfig,
+) -> Result<String, crate::errors::Error> {
+    if !valid_username(username) {
+        return Err(InvalidRequestError(
+            "Username must be 3 to 50 letters, digits or . _ - @".to_string(),
+        ));
+    }
+    if !valid_email(email) {
+        return Err(InvalidRequestError("Invalid email address".to_string()));
+    }
+    password::check_policy(&password, &[username, email], &server_config.password)
+        .map_err(InvalidRequestError)?;
+    Ok(hash_password(password, server_config).await)
+}
+
+pub(crate) async fn hash_password(password: String, server_config: &ServerConfig) -> String {
+    let config = server_config.password.clone();
+    tokio::task::spawn_blocking(move || password::hash(&password, &config))
+        .await
+        .expect("Password hashing panicked")
+}
+
+// Checks and stores a registration, the form and the json api share this. With email verification on
+// the new user gets a mail with a link to verify their address.
+async fn register(
+    client: &mut dyn Storage,
+    params: RegistrationParams,
+    server_config: &ServerConfig,
+    mailer: &Arc<dyn Mailer>,
+) -> Result<User, crate::errors::Error> {
+    let settings = &server_config.registration;
+    let username = params.username.trim();
+    let email = params.email.trim();
+
+    let invitation = match params.invitation.as_deref().map(str::trim) {
+        Some(code) if settings.invitation && !code.is_empty() => Some(session::hash_token(code)),
+        _ if settings.invitation => {
+            return Err(InvalidRequestError("An invitation code is required".to_string()))
+        }
+        _ => None,
+    };
+
+    let hash = check_new_user(username, email, params.password, server_config).await?;
+    let user = client.register_user(username, email, &hash, !settings.email_verification, invitation).await?;
+    if settings.email_verification {
+        account::send_verification(client, mailer, &user, server_config).await;
+    }
+    Ok(user)
+}
+
+pub async fn get_register(server_config: ServerConfig) -> std::result::Result<warp::reply::Response, Rejection> {
+    if !server_config.registration.enabled {
+        return Err(warp::reject::not_found());
+    }
+    let invitation = server_config.registration.invitation;
+    Ok(form_page(&server_config, |csrf| pages::register(csrf, invitation, None, "", "")))
+}
+
+// Registration from the form, mistakes are shown on the form again
+pub async fn post_register_form(
+    params: RegistrationForm,
+    csrf: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+    mailer: Arc<dyn Mailer>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    if !server_config.registration.enabled {
+        return Err(warp::reject::not_found());
+    }
+    check_csrf(csrf, &params.csrf_token)?;
+    let mut client = get_db_client(&database).await?;
+
+    let username = params.registration.username.clone();
+    let email = params.registration.email.clone();
+    match register(&mut *client, params.registration, &server_config, &mailer).await {
+        Ok(_) => Ok(warp::reply::html(pages::registered(server_config.registration.email_verification)).into_response()),
+        Err(InvalidRequestError(message)) | Err(ConflictError(message)) => {
+            let invitation = server_config.registration.invitation;
+            Ok(form_page(&server_config, |csrf| {
+                pages::register(csrf, invitation, Some(&message), &username, &email)
+            }))
+        }
+        Err(e) => Err(warp::reject::custom(e)),
+    }
+}
+
+pub async fn post_register(
+    params: RegistrationParams,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+    mailer: Arc<dyn Mailer>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    if !server_config.registration.enabled {
+        return Err(warp::reject::not_found());
+    }
+    let mut client = get_db_client(&database).await?;
+    let user = register(&mut *client, params, &server_config, &mailer)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(warp::reply::with_status(json(&user), StatusCode::CREATED).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::Memory;
+    use crate::models::{AuthorizationCode, AuthorizationParams, ClientUpdate};
+    use crate::storage::{ClientStore, CodeStore};
+    use crate::testing::{self, add_client, add_user, basic_auth, password_grant, token, CLIENT_ID, PASSWORD, USERNAME};
+    use chrono::Local;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn password_grant_issues_a_token_the_client_can_introspect() {
+        let mut memory = Memory::new();
+        let (_, secret) = add_client(&mut memory, CLIENT_ID, &["read"]).await;
+        add_user(&mut memory, USERNAME, PASSWORD).await;
+        let app = testing::app(&memory, testing::config());
+        let authorization = basic_auth(CLIENT_ID, &secret);
+
+        let (status, token) = password_grant(&app, &authorization, USERNAME, PASSWORD).await;
+        assert_eq!(status, 200);
+        assert_eq!(token["scope"], "read");
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/oauth2/introspect")
+            .header("authorization", &authorization)
+            .body(format!("token={}", token["access_token"].as_str().unwrap()))
+            .reply(&app)
+            .await;
+        assert_eq!(response.status(), 200);
+        let introspection: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(introspection["active"], true);
+        assert_eq!(introspection["username"], USERNAME);
+        assert_eq!(introspection["client_id"], CLIENT_ID);
+    }
+
+    #[tokio::test]
+    async fn password_grant_rejects_a_wrong_password() {
+        let mut memory = Memory::new();
+        let (_, secret) = add_client(&mut memory, CLIENT_ID, &["read"]).await;
+        add_user(&mut memory, USERNAME, PASSWORD).await;
+        let app = testing::app(&memory, testing::config());
+
+        let (status, error) = password_grant(&app, &basic_auth(CLIENT_ID, &secret), USERNAME, "wrong").await;
+        assert_eq!(status, 400);
+        assert_eq!(error["error"], "invalid_grant");
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_are_replaced_when_used() {
+        let mut memory = Memory::new();
+        let (_, secret) = add_client(&mut memory, CLIENT_ID, &["read"]).await;
+        add_user(&mut memory, USERNAME, PASSWORD).await;
+        let app = testing::app(&memory, testing::config());
+        let authorization = basic_auth(CLIENT_ID, &secret);
+        let refresh = |refresh_token: &serde_json::Value| {
+            format!("grant_type=refresh_token&refresh_token={}", refresh_token.as_str().unwrap())
+        };
+
+        let (_, first) = password_grant(&app, &authorization, USERNAME, PASSWORD).await;
+        let (status, second) = token(&app, &authorization, &refresh(&first["refresh_token"])).await;
+        assert_eq!(status, 200);
+        assert_ne!(second["refresh_token"], first["refresh_token"]);
+
+        let (status, error) = token(&app, &authorization, &refresh(&first["refresh_token"])).await;
+        assert_eq!((status, error["error"].as_str()), (400, Some("invalid_grant")));
+        assert_eq!(token(&app, &authorization, &refresh(&second["refresh_token"])).await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn an_authorization_code_is_redeemed_once_by_concurrent_requests() {
+        let mut memory = Memory::new();
+        let (client_id, secret) = add_client(&mut memory, CLIENT_ID, &["read"]).await;
+        let user = add_user(&mut memory, USERNAME, PASSWORD).await;
+        let request = AuthorizationParams {
+            client_id: CLIENT_ID.to_string(),
+            response_type: "code".to_string(),
+            redirect_uri: "http://localhost:3000/callback".to_string(),
+            scope: Some("read".to_string()),
+            state: None,
+            code_challenge: None,
+            prompt: None,
+            nonce: None,
+            max_age: None,
+        };
+        let authorization = AuthorizationCode {
+            user_id: user.id,
+            scope: Some("read".to_string()),
+            auth_time: Some(Local::now()),
+            amr: vec!["pwd".to_string()],
+            nonce: None,
+            sid: None,
+        };
+        memory.insert_code("code", client_id, &authorization, &request).await;
+        let app = testing::app(&memory, testing::config());
+        let authorization = basic_auth(CLIENT_ID, &secret);
+        let form = "grant_type=authorization_code&code=code&redirect_uri=http://localhost:3000/callback";
+
+        let (first, second) = tokio::join!(token(&app, &authorization, form), token(&app, &authorization, form));
+        let mut statuses = vec![first.0, second.0];
+        statuses.sort();
+        assert_eq!(statuses, vec![200, 400]);
+    }
+
+    #[tokio::test]
+    async fn scope_grants_hold_for_one_user_at_one_client() {
+        let mut memory = Memory::new();
+        let template = "projects:{action}:{project}";
+        let (top, top_secret) = add_client(&mut memory, CLIENT_ID, &[template]).await;
+        let (_, other_secret) = add_client(&mut memory, "other", &[template]).await;
+        let user = add_user(&mut memory, USERNAME, PASSWORD).await;
+        add_user(&mut memory, "other", PASSWORD).await;
+        memory.add_scope_grant("projects:read:1", Some(user.id), top);
+        memory.add_scope_grant("projects:read:2", None, top);
+        let app = testing::app(&memory, testing::config());
+
+        let request = |username: &str, scope: &str| {
+            format!("grant_type=password&username={}&password={}&scope={}", username, PASSWORD, scope)
+        };
+        let top = basic_auth(CLIENT_ID, &top_secret);
+        let other = basic_auth("other", &other_secret);
+        assert_eq!(token(&app, &top, &request(USERNAME, "projects:read:1")).await.0, 200);
+        // not for another user at the client, nor for the user at another client
+        let (status, error) = token(&app, &top, &request("other", "projects:read:1")).await;
+        assert_eq!((status, error["error"].as_str()), (400, Some("invalid_scope")));
+        assert_eq!(token(&app, &other, &request(USERNAME, "projects:read:1")).await.0, 400);
+        // what the client holds itself is for the client credentials grant, not for its users
+        assert_eq!(token(&app, &top, &request(USERNAME, "projects:read:2")).await.0, 400);
+        let (status, _) = token(&app, &top, "grant_type=client_credentials&scope=projects:read:2").await;
+        assert_eq!(status, 200);
+        assert_eq!(token(&app, &top, "grant_type=client_credentials&scope=projects:read:1").await.0, 400);
+    }
+
+    #[tokio::test]
+    async fn end_session_takes_only_id_tokens_of_this_server_as_hint() {
+        let mut memory = Memory::new();
+        add_client(&mut memory, CLIENT_ID, &[]).await;
+        let app = testing::app(&memory, testing::config());
+        let keys = testing::keys();
+        let end_session = |hint: String| {
+            warp::test::request()
+                .path(&format!("/oauth2/end_session?id_token_hint={}", hint))
+                .reply(&app)
+        };
+
+        let mut claims = serde_json::json!({
+            "iss": testing::config().server.name,
+            "aud": CLIENT_ID,
+            "sub": "someone",
+            "exp": 0,
+            "iat": 0,
+        });
+        assert_eq!(end_session(keys.sign(&claims)).await.status(), 200);
+        assert_eq!(end_session(keys.sign_typed("at+jwt", &claims)).await.status(), 400);
+        assert_eq!(end_session(keys.sign_typed("logout+jwt", &claims)).await.status(), 400);
+        claims["aud"] = "unknown".into();
+        assert_eq!(end_session(keys.sign(&claims)).await.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn malformed_client_authorization_is_an_invalid_client() {
+        let mut memory = Memory::new();
+        add_client(&mut memory, CLIENT_ID, &[]).await;
+        let app = testing::app(&memory, testing::config());
+
+        let without_secret = format!("Basic {}", base64::encode(CLIENT_ID));
+        let not_utf8 = format!("Basic {}", base64::encode([0xff, b':', 0xfe]));
+        let wrong_secret = basic_auth(CLIENT_ID, "wrong");
+        for authorization in ["", "Basic", "Basic !!!", "Bearer abc", &without_secret, &not_utf8, &wrong_secret] {
+            let (status, error) = token(&app, authorization, "grant_type=client_credentials").await;
+            assert_eq!((status, error["error"].as_str()), (401, Some("invalid_client")), "{}", authorization);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_an_authenticated_client_learns_which_grants_it_may_use() {
+        let mut memory = Memory::new();
+        let (id, secret) = add_client(&mut memory, CLIENT_ID, &[]).await;
+        let update: ClientUpdate = serde_json::from_value(json!({ "grant_types": ["authorization_code"] })).unwrap();
+        memory.update_client(id, &update).await.unwrap();
+        let app = testing::app(&memory, testing::config());
+
+        // a known client without its secret and an unknown one get the same answer
+        for authorization in [basic_auth(CLIENT_ID, "wrong"), basic_auth("unknown", "wrong")] {
+            let (status, error) = token(&app, &authorization, "grant_type=client_credentials").await;
+            assert_eq!((status, error["error"].as_str()), (401, Some("invalid_client")), "{}", authorization);
+        }
+        let (status, error) = token(&app, &basic_auth(CLIENT_ID, &secret), "grant_type=client_credentials").await;
+        assert_eq!((status, error["error"].as_str()), (400, Some("unauthorized_client")));
+    }
 }
@@ -0,0 +1,158 @@
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey};
+use rsa::pkcs8::{DecodePrivateKey, LineEnding};
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use std::fs;
+
+// The key ID tokens (and other jwts the server hands out) are signed with
+pub struct SigningKeys {
+    kid: String,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    n: String,
+    e: String,
+}
+
+#[derive(Serialize)]
+pub struct Jwk {
+    pub kty: &'static str,
+    #[serde(rename = "use")]
+    pub usage: &'static str,
+    pub alg: &'static str,
+    pub kid: String,
+    pub n: String,
+    pub e: String,
+}
+
+#[derive(Serialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+fn base64_url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+impl SigningKeys {
+    // Reads signing_key.pem (pkcs1 or pkcs8) from the cert dir. Without one a key is generated, which
+    // means tokens signed before a restart can't be verified anymore.
+    pub fn load(cert_dir: &str) -> SigningKeys {
+        let path = cert_dir.to_owned() + "/signing_key.pem";
+        let key = match fs::read_to_string(&path) {
+            Ok(pem) => RsaPrivateKey::from_pkcs8_pem(&pem)
+                .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+                .expect("Could not parse the signing key"),
+            Err(_) => {
+                println!("No signing key found at {}, generating one. Tokens won't survive a restart", path);
+                RsaPrivateKey::new(&mut rand::thread_rng(), 2048).expect("Could not generate a signing key")
+            }
+        };
+        SigningKeys::from_key(&key)
+    }
+
+    fn from_key(key: &RsaPrivateKey) -> SigningKeys {
+        let pem = key.to_pkcs1_pem(LineEnding::LF).unwrap();
+        let n = base64_url(&key.n().to_bytes_be());
+        let e = base64_url(&key.e().to_bytes_be());
+
+        let mut hasher = Sha256::new();
+        hasher.update(&n);
+        let kid = base64_url(&hasher.finalize()[..12]);
+
+        SigningKeys {
+            kid,
+            encoding_key: EncodingKey::from_rsa_pem(pem.as_bytes()).unwrap(),
+            decoding_key: DecodingKey::from_rsa_components(&n, &e).unwrap(),
+            n,
+            e,
+        }
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> String {
+        self.sign_typed("JWT", claims)
+    }
+
+    // With a typ header for tokens that mustn't pass for another kind, at+jwt for access tokens
+    pub fn sign_typed<T: Serialize>(&self, typ: &str, claims: &T) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.typ = Some(typ.to_string());
+        header.kid = Some(self.kid.clone());
+        jsonwebtoken::encode(&header, claims, &self.encoding_key).expect("Could not sign token")
+    }
+
+    // Checks the signature, the typ header, the issuer and, when it is known, the audience. Expired tokens are
+    // accepted, this is for hints like id_token_hint where an old token still names the user.
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        typ: &str,
+        issuer: &str,
+        audience: Option<&str>,
+    ) -> Option<T> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        if header.typ.as_deref() != Some(typ) {
+            return None;
+        }
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.validate_exp = false;
+        validation.required_spec_claims = HashSet::from(["iss".to_string(), "aud".to_string()]);
+        validation.set_issuer(&[issuer]);
+        match audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        jsonwebtoken::decode::<T>(token, &self.decoding_key, &validation)
+            .ok()
+            .map(|data| data.claims)
+    }
+
+    pub fn jwks(&self) -> Jwks {
+        Jwks {
+            keys: vec![Jwk {
+                kty: "RSA",
+                usage: "sig",
+                alg: "RS256",
+                kid: self.kid.clone(),
+                n: self.n.clone(),
+                e: self.e.clone(),
+            }],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use serde_json::{json, Value};
+
+    const ISSUER: &str = "http://localhost:8080";
+
+    fn claims(iss: &str, aud: &str) -> Value {
+        json!({ "iss": iss, "aud": aud, "sub": "someone", "exp": 0, "iat": 0 })
+    }
+
+    #[test]
+    fn verify_checks_the_type_issuer_and_audience() {
+        let keys = testing::keys();
+        let id_token = keys.sign(&claims(ISSUER, "top"));
+        assert!(keys.verify::<Value>(&id_token, "JWT", ISSUER, Some("top")).is_some());
+        // expired, but still good as a hint
+        assert!(keys.verify::<Value>(&id_token, "JWT", ISSUER, None).is_some());
+
+        assert!(keys.verify::<Value>(&id_token, "JWT", ISSUER, Some("other")).is_none());
+        assert!(keys.verify::<Value>(&id_token, "JWT", "http://elsewhere", Some("top")).is_none());
+        let foreign = keys.sign(&claims("http://elsewhere", "top"));
+        assert!(keys.verify::<Value>(&foreign, "JWT", ISSUER, Some("top")).is_none());
+        let anonymous = keys.sign(&json!({ "iss": ISSUER, "sub": "someone" }));
+        assert!(keys.verify::<Value>(&anonymous, "JWT", ISSUER, None).is_none());
+        for typ in ["at+jwt", "logout+jwt"] {
+            let token = keys.sign_typed(typ, &claims(ISSUER, "top"));
+            assert!(keys.verify::<Value>(&token, "JWT", ISSUER, Some("top")).is_none());
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use crate::attributes;
+use crate::federation;
+use crate::models::{LdapConfig, UserUpdate};
+use crate::storage::Storage;
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde_json::{Map, Value};
+use std::time::Duration;
+use uuid::Uuid;
+
+// Users of a directory (LDAP or Active Directory) log in with their directory password, the server binds
+// as them to check it. Each has a user here too, linked like the account of an upstream provider, so tokens,
+// consents and sessions work as for anyone else. The email, the attributes and the groups are copied from
+// the directory at every login. Users of the users table keep logging in with their own password.
+
+// The provider of the federated identities of directory users
+pub const PROVIDER: &str = "ldap";
+
+struct DirectoryUser {
+    dn: String,
+    email: Option<String>,
+    groups: Vec<String>,
+    attributes: Map<String, Value>,
+}
+
+// Attribute names aren't case sensitive, servers answer with their own spelling
+fn values<'a>(entry: &'a SearchEntry, name: &str) -> &'a [String] {
+    entry
+        .attrs
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_slice())
+        .unwrap_or_default()
+}
+
+// The cn of a group's dn, cn=Sales\, EMEA,ou=groups,dc=example,dc=com is "sales, emea". Values that
+// aren't a dn are taken as the name.
+fn group_name(dn: &str) -> Option<String> {
+    let mut rdn = String::new();
+    let mut escaped = false;
+    for c in dn.chars() {
+        match (escaped, c) {
+            (true, _) => {
+                rdn.push(c);
+                escaped = false;
+            }
+            (false, '\\') => escaped = true,
+            (false, ',') => break,
+            (false, _) => rdn.push(c),
+        }
+    }
+    match rdn.split_once('=') {
+        Some((kind, name)) if kind.trim().eq_ignore_ascii_case("cn") => Some(name.trim().to_lowercase()),
+        Some(_) => None,
+        None => Some(rdn.trim().to_lowercase()).filter(|n| !n.is_empty()),
+    }
+}
+
+async fn connect(config: &LdapConfig) -> Result<Ldap, String> {
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(Duration::from_secs(config.timeout))
+        .set_starttls(config.starttls);
+    let (conn, ldap) = LdapConnAsync::with_settings(settings, &config.url)
+        .await
+        .map_err(|e| format!("connecting to {} failed: {}", config.url, e))?;
+    ldap3::drive!(conn);
+    Ok(ldap)
+}
+
+// Looks the user up with the service account, exactly one entry has to match
+async fn find(ldap: &mut Ldap, config: &LdapConfig, username: &str) -> Result<Option<DirectoryUser>, String> {
+    let timeout = Duration::from_secs(config.timeout);
+    if let Some(bind_dn) = &config.bind_dn {
+        let password = config.bind_password.as_deref().unwrap_or("");
+        ldap.with_timeout(timeout)
+            .simple_bind(bind_dn, password)
+            .await
+            .and_then(|r| r.success())
+            .map_err(|e| format!("binding as {} failed: {}", bind_dn, e))?;
+    }
+
+    let filter = format!(
+        "(&{}({}={}))",
+        config.user_filter.as_deref().unwrap_or(""),
+        config.username_attribute,
+        ldap_escape(username)
+    );
+    let mut requested = vec![config.email_attribute.as_str(), config.group_attribute.as_str()];
+    requested.extend(config.attributes.values().map(String::as_str));
+    let (entries, _) = ldap
+        .with_timeout(timeout)
+        .search(&config.base_dn, Scope::Subtree, &filter, requested)
+        .await
+        .and_then(|r| r.success())
+        .map_err(|e| format!("searching {} failed: {}", filter, e))?;
+    if entries.len() != 1 {
+        return Ok(None);
+    }
+
+    let entry = SearchEntry::construct(entries.into_iter().next().unwrap());
+    let attributes = config
+        .attributes
+        .values()
+        .filter_map(|name| Some((name.clone(), values(&entry, name).first()?.clone())))
+        .map(|(name, value)| (name, Value::String(value)))
+        .collect();
+    Ok(Some(DirectoryUser {
+        email: values(&entry, &config.email_attribute).first().cloned(),
+        groups: values(&entry, &config.group_attribute).iter().filter_map(|g| group_name(g)).collect(),
+        attributes,
+        dn: entry.dn,
+    }))
+}
+
+// Checks the password by binding as the user. Without a password a bind is anonymous and works for
+// any dn, those are refused before they get to the server.
+async fn authenticate(config: &LdapConfig, username: &str, password: &str) -> Result<Option<DirectoryUser>, String> {
+    if password.is_empty() {
+        return Ok(None);
+    }
+    let mut ldap = connect(config).await?;
+    let user = match find(&mut ldap, config, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let bound = ldap
+        .with_timeout(Duration::from_secs(config.timeout))
+        .simple_bind(&user.dn, password)
+        .await
+        .map_err(|e| format!("binding as {} failed: {}", user.dn, e))?;
+    let _ = ldap.unbind().await;
+    Ok(if bound.rc == 0 { Some(user) } else { None })
+}
+
+// The values of the mapped attributes as the type of their definition, the directory only has text
+async fn typed_attributes(client: &dyn Storage, config: &LdapConfig, user: &DirectoryUser) -> Map<String, Value> {
+    let definitions = client.get_attribute_definitions().await;
+    config
+        .attributes
+        .iter()
+        .filter_map(|(name, source)| {
+            let definition = definitions.iter().find(|d| &d.name == name)?;
+            let text = user.attributes.get(source)?.as_str()?;
+            // booleans are TRUE and FALSE in a directory
+            let text = if definition.kind == "boolean" { text.to_lowercase() } else { text.to_string() };
+            Some((source.clone(), attributes::typed_value(definition, &text)))
+        })
+        .collect()
+}
+
+// The user here for the directory user, created at the first login
+async fn directory_user(client: &mut dyn Storage, username: &str, user: &DirectoryUser) -> Result<Uuid, String> {
+    let subject = username.to_lowercase();
+    if let Some(user_id) = client.find_federated_user(PROVIDER, &subject).await {
+        return Ok(user_id);
+    }
+    let email = user.email.as_deref().ok_or("the directory has no email address")?;
+    if client.get_user_by_email(email).await.is_some() {
+        return Err(format!("a user with email {} exists already", email));
+    }
+    let username = federation::new_username(client, PROVIDER, &[username, email]).await;
+    let created = client.register_directory_user(&username, email, &subject)
+        .await
+        .map_err(|e| e.to_string())?;
+    println!("Created user {} for directory user {}", created.username, user.dn);
+    Ok(created.id)
+}
+
+// The user for the username and password in the directory, with what the directory has on them
+pub async fn validate(client: &mut dyn Storage, config: &LdapConfig, username: &str, password: &str) -> Option<Uuid> {
+    // the caller holds a database connection meanwhile, a slow directory gets one timeout for all of it
+    let authentication = tokio::time::timeout(
+        Duration::from_secs(config.timeout),
+        authenticate(config, username, password),
+    );
+    let user = match authentication.await {
+        Ok(Ok(user)) => user?,
+        Ok(Err(e)) => {
+            println!("LDAP login of {} failed: {}", username, e);
+            return None;
+        }
+        Err(_) => {
+            println!("LDAP login of {} failed: {} did not answer in time", username, config.url);
+            return None;
+        }
+    };
+    let user_id = match directory_user(client, username, &user).await {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            println!("LDAP user {} can't log in: {}", user.dn, e);
+            return None;
+        }
+    };
+    if client.get_user(user_id).await.is_none_or(|u| u.disabled) {
+        return None;
+    }
+
+    if let Some(email) = &user.email {
+        let update = UserUpdate {
+            username: None,
+            email: Some(email.clone()),
+            email_verified: Some(true),
+            mfa_required: None,
+        };
+        if let Err(e) = client.update_user(user_id, &update).await {
+            println!("Updating the email of {} failed: {}", user.dn, e);
+        }
+    }
+    let source = typed_attributes(client, config, &user).await;
+    attributes::copy(client, user_id, &config.attributes, &source).await;
+    client.set_directory_groups(user_id, &user.groups).await;
+    Some(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use crate::storage::UserStore;
+    use crate::testing::{self, CLIENT_ID, PASSWORD, USERNAME};
+    use serde_json::json;
+    use std::net::SocketAddr;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    // a directory that takes connections and never answers
+    async fn silent_directory() -> LdapConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address: SocketAddr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+        serde_json::from_value(json!({
+            "url": format!("ldap://{}", address),
+            "bind_dn": "cn=reader,dc=example,dc=com",
+            "bind_password": "secret",
+            "base_dn": "dc=example,dc=com",
+            "timeout": 1,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_directory_that_does_not_answer_fails_the_login_in_time() {
+        let mut memory = Memory::new();
+        let config = silent_directory().await;
+        let started = Instant::now();
+        // the bind and the search together get the timeout once
+        assert!(validate(&mut memory, &config, "alice", "secret").await.is_none());
+        assert!(started.elapsed() < Duration::from_millis(1500));
+    }
+
+    #[tokio::test]
+    async fn password_logins_go_where_the_user_came_from() {
+        let mut memory = Memory::new();
+        let (_, secret) = testing::add_client(&mut memory, CLIENT_ID, &["openid"]).await;
+        testing::add_user(&mut memory, USERNAME, PASSWORD).await;
+        memory.register_directory_user("alice", "alice@example.com", "alice").await.unwrap();
+        let mut config = testing::config();
+        config.server.ldap = Some(silent_directory().await);
+        let app = testing::app(&memory, config);
+        let authorization = testing::basic_auth(CLIENT_ID, &secret);
+
+        // a local user never waits for the directory
+        let started = Instant::now();
+        let (status, _) = testing::password_grant(&app, &authorization, USERNAME, PASSWORD).await;
+        assert_eq!(status, 200);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // a directory user's password is only checked there, not against the row
+        let started = Instant::now();
+        let (status, body) = testing::password_grant(&app, &authorization, "alice", "secret").await;
+        assert_eq!(status, 400);
+        assert_eq!(body["error"], "invalid_grant");
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,99 @@
+use crate::models::LockoutConfig;
+use crate::storage::LockoutStore;
+use chrono::{Duration, Local};
+use std::net::IpAddr;
+
+// Brute-force protection for the password logins, the login page, the password grant and the code of the
+// second step. Failures are counted for the username, whether it exists or not, and for the client
+// address. Answers don't tell a locked username from a wrong password.
+
+pub const USER: &str = "user";
+pub const IP: &str = "ip";
+
+// Case and surrounding spaces don't give a username extra attempts
+pub fn user_subject(username: &str) -> String {
+    username.trim().to_lowercase().chars().take(100).collect()
+}
+
+fn subjects(config: &LockoutConfig, username: &str, ip: Option<IpAddr>) -> Vec<(&'static str, String, i32)> {
+    let mut subjects = vec![(USER, user_subject(username), config.user_threshold)];
+    if let Some(ip) = ip {
+        subjects.push((IP, ip.to_string(), config.ip_threshold));
+    }
+    subjects
+}
+
+pub async fn locked(client: &dyn LockoutStore, config: &LockoutConfig, username: &str, ip: Option<IpAddr>) -> bool {
+    for (kind, subject, threshold) in subjects(config, username, ip) {
+        if threshold > 0 && client.is_locked(kind, &subject).await {
+            return true;
+        }
+    }
+    false
+}
+
+// Counts the failure and locks what reached its threshold. Returns the delay for the username, the caller
+// gives back its database connection, waits and then answers.
+pub async fn failed(
+    client: &dyn LockoutStore,
+    config: &LockoutConfig,
+    username: &str,
+    ip: Option<IpAddr>,
+) -> std::time::Duration {
+    let window_start = Local::now() - Duration::seconds(config.window);
+    let mut user_failures = 0;
+    for (kind, subject, threshold) in subjects(config, username, ip) {
+        let failures = client.add_login_failure(kind, &subject, window_start).await;
+        if threshold > 0 && failures >= threshold {
+            if failures == threshold {
+                println!("Locked password logins for {} {} after {} failures", kind, subject, failures);
+            }
+            client.lock_login(kind, &subject, Local::now() + Duration::seconds(config.lock_duration)).await;
+        }
+        if kind == USER {
+            user_failures = failures;
+        }
+    }
+    // the address count isn't used here, many users can share an address
+    delay(config, user_failures)
+}
+
+fn delay(config: &LockoutConfig, failures: i32) -> std::time::Duration {
+    if failures <= config.delay_after {
+        return std::time::Duration::from_secs(0);
+    }
+    let doublings = (failures - config.delay_after - 1).min(16) as u32;
+    std::time::Duration::from_secs(2u64.pow(doublings).min(config.max_delay))
+}
+
+// A successful login forgets the failures of the username, those of the address stay
+pub async fn succeeded(client: &dyn LockoutStore, username: &str) {
+    client.clear_login_failures(USER, &user_subject(username)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn failures_are_answered_slower_without_waiting_here() {
+        let memory = Memory::new();
+        let config = LockoutConfig::default();
+        let started = Instant::now();
+        let mut delays = vec![];
+        for _ in 0..6 {
+            delays.push(failed(&memory, &config, "Test ", None).await.as_secs());
+        }
+        assert_eq!(delays, vec![0, 0, 0, 1, 2, 4]);
+        // the caller sleeps once it gave back its connection
+        assert!(started.elapsed().as_secs() < 1);
+
+        for _ in 0..10 {
+            failed(&memory, &config, "test", None).await;
+        }
+        assert!(locked(&memory, &config, "TEST", None).await);
+        assert_eq!(failed(&memory, &config, "test", None).await.as_secs(), config.max_delay);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::jwt::SigningKeys;
+use crate::models::{LoginSession, LogoutClient, LogoutTokenClaims};
+use crate::session;
+use chrono::Local;
+use serde_json::json;
+use std::time::Duration;
+use url::Url;
+
+// Telling clients a login session ended, see OpenID Connect Front-Channel Logout and Back-Channel Logout
+
+const BACKCHANNEL_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+// the typ of logout tokens, so they can't be taken for an id token
+const LOGOUT_TOKEN_TYPE: &str = "logout+jwt";
+
+fn logout_token(keys: &SigningKeys, issuer: &str, client_id: &str, login_session: &LoginSession) -> String {
+    keys.sign_typed(
+        LOGOUT_TOKEN_TYPE,
+        &LogoutTokenClaims {
+            iss: issuer.to_string(),
+            aud: client_id.to_string(),
+            iat: Local::now().timestamp(),
+            jti: session::generate_secret(32),
+            sub: login_session.user_id.to_string(),
+            sid: login_session.sid.clone(),
+            events: json!({ BACKCHANNEL_EVENT: {} }),
+        },
+    )
+}
+
+// Posts a logout token to every client with a back-channel logout uri. This runs in the background, a
+// client that is down should not keep the user from logging out.
+pub fn notify_backchannel(clients: &[LogoutClient], login_session: &LoginSession, issuer: &str, keys: &SigningKeys) {
+    for client in clients {
+        let uri = match &client.backchannel_logout_uri {
+            Some(uri) => uri.clone(),
+            None => continue,
+        };
+        let token = logout_token(keys, issuer, &client.client_id, login_session);
+
+        tokio::spawn(async move {
+            let response = reqwest::Client::new()
+                .post(&uri)
+                .form(&[("logout_token", token)])
+                .timeout(Duration::from_secs(5))
+                .send()
+                .await;
+            match response {
+                Ok(r) if r.status().is_success() => {}
+                Ok(r) => println!("Back-channel logout to {} returned {}", uri, r.status()),
+                Err(e) => println!("Back-channel logout to {} failed: {}", uri, e),
+            }
+        });
+    }
+}
+
+// The front-channel logout uris with iss and sid added, the logout page loads them in iframes
+pub fn frontchannel_uris(clients: &[LogoutClient], login_session: &LoginSession, issuer: &str) -> Vec<String> {
+    clients
+        .iter()
+        .filter_map(|c| c.frontchannel_logout_uri.as_deref())
+        .filter_map(|uri| Url::parse(uri).ok())
+        .map(|mut url| {
+            url.query_pairs_mut()
+                .append_pair("iss", issuer)
+                .append_pair("sid", &login_session.sid);
+            url.to_string()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::Value;
+    use uuid::Uuid;
+
+    #[test]
+    fn logout_tokens_do_not_pass_for_id_tokens() {
+        let keys = testing::keys();
+        let login_session = LoginSession {
+            id: 1,
+            sid: "sid".to_string(),
+            user_id: Uuid::new_v4(),
+            auth_time: Local::now(),
+            amr: vec![],
+        };
+        let token = logout_token(&keys, "http://localhost:8080", "top", &login_session);
+        let claims = keys.verify::<Value>(&token, LOGOUT_TOKEN_TYPE, "http://localhost:8080", Some("top"));
+        assert_eq!(claims.unwrap()["sid"], "sid");
+        assert!(keys.verify::<Value>(&token, "JWT", "http://localhost:8080", Some("top")).is_none());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::models::MailConfig;
+use crate::session;
+use chrono::Local;
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, Message};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SmtpTransport, Transport};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::{fs, io};
+use thiserror::Error;
+
+// Mails to users, sent through the transport in SERVER.MAIL.TRANSPORT. The stdout and file mailers
+// make it possible to follow the links without a mail server.
+
+#[derive(Error, Debug)]
+pub enum MailError {
+    #[error("invalid address: {0}")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("could not build the message: {0}")]
+    Message(#[from] lettre::error::Error),
+    #[error("smtp error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("could not write the mail: {0}")]
+    Io(#[from] io::Error),
+    #[error("unknown mail transport {0}")]
+    Transport(String),
+}
+
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<(), MailError>;
+}
+
+fn message(from: &Mailbox, mail: &Mail) -> Result<Message, MailError> {
+    Ok(Message::builder()
+        .from(from.clone())
+        .to(mail.to.parse()?)
+        .subject(mail.subject.as_str())
+        .header(ContentType::TEXT_PLAIN)
+        .body(mail.body.clone())?)
+}
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    pub fn new(config: &MailConfig) -> Result<Self, MailError> {
+        let mut builder = match config.smtp_security.as_str() {
+            "tls" => SmtpTransport::relay(&config.smtp_host)?,
+            "starttls" => SmtpTransport::starttls_relay(&config.smtp_host)?,
+            "none" => SmtpTransport::builder_dangerous(&config.smtp_host),
+            other => return Err(MailError::Transport(format!("smtp security {}", other))),
+        };
+        if let Some(port) = config.smtp_port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(SmtpMailer {
+            from: config.from.parse()?,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        self.transport.send(&message(&self.from, mail)?)?;
+        Ok(())
+    }
+}
+
+// Writes every mail as an .eml file to a directory
+pub struct FileMailer {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(config: &MailConfig) -> Result<Self, MailError> {
+        fs::create_dir_all(&config.dir)?;
+        Ok(FileMailer {
+            from: config.from.parse()?,
+            dir: PathBuf::from(&config.dir),
+        })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let name = format!(
+            "{}-{}.eml",
+            Local::now().format("%Y%m%d%H%M%S"),
+            session::generate_secret(8)
+        );
+        fs::write(self.dir.join(name), message(&self.from, mail)?.formatted())?;
+        Ok(())
+    }
+}
+
+// Prints mails instead of sending them, for development
+pub struct StdoutMailer;
+
+impl Mailer for StdoutMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        println!("To: {}\nSubject: {}\n\n{}\n", mail.to, mail.subject, mail.body);
+        Ok(())
+    }
+}
+
+pub fn mailer(config: &MailConfig) -> Result<Arc<dyn Mailer>, MailError> {
+    match config.transport.as_str() {
+        "smtp" => Ok(Arc::new(SmtpMailer::new(config)?)),
+        "file" => Ok(Arc::new(FileMailer::new(config)?)),
+        "stdout" => Ok(Arc::new(StdoutMailer)),
+        other => Err(MailError::Transport(other.to_string())),
+    }
+}
+
+// Sends on a blocking thread without waiting for it, a slow mail server doesn't hold up the response and
+// the response time doesn't tell whether a mail was sent
+pub fn send_in_background(mailer: Arc<dyn Mailer>, mail: Mail) {
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = mailer.send(&mail) {
+            println!("Sending mail to {} failed: {}", mail.to, e);
+        }
+    });
+}
+
+fn in_words(seconds: i64) -> String {
+    match seconds / 3600 {
+        1 => "1 hour".to_string(),
+        n if n > 1 => format!("{} hours", n),
+        _ => format!("{} minutes", (seconds / 60).max(1)),
+    }
+}
+
+pub fn verification_mail(to: &str, username: &str, link: &str, lifetime: i64) -> Mail {
+    Mail {
+        to: to.to_string(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Hello {},\n\nOpen the link below to verify your email address. The link can be used once and \
+             expires in {}.\n\n{}\n\nIf you didn't create an account you can ignore this mail.\n",
+            username,
+            in_words(lifetime),
+            link
+        ),
+    }
+}
+
+pub fn reset_mail(to: &str, username: &str, link: &str, lifetime: i64) -> Mail {
+    Mail {
+        to: to.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hello {},\n\nSomeone asked to reset the password of your account. Open the link below to choose \
+             a new password. The link can be used once and expires in {}.\n\n{}\n\nIf you didn't ask for \
+             this you can ignore this mail, your password stays the same.\n",
+            username,
+            in_words(lifetime),
+            link
+        ),
+    }
+}
@@ -1,29 +1,67 @@
+mod account;
+mod admin;
+mod attributes;
+mod cli;
 mod db;
 mod errors;
+mod federation;
 mod handlers;
+mod jwt;
+mod ldap;
+mod lockout;
+mod logout;
+mod mail;
+#[cfg(test)]
+mod memory;
+mod mfa;
+mod migrations;
 mod models;
+mod pages;
+mod passkeys;
+mod password;
+mod ratelimit;
 mod response;
+mod roles;
+mod scopes;
+mod session;
+mod sqlite;
+mod storage;
+mod tenants;
+#[cfg(test)]
+mod testing;
+mod totp;
+mod webauthn;
 
-use crate::db::*;
-use crate::models::{AuthorizationParams, Config, TokenParams};
-use deadpool_postgres::{Client, PoolError};
+use crate::models::{
+    AttributeDefinition, AuthorizationParams, ChallengeRequest, ClientUpdate, Config, ConsentParams, EmailForm,
+    EmailTokenQuery, EndSessionParams, FederationCallback, LoginParams, MfaParams, NewClient, NewUser, Next, PasskeyAssertion, PasskeyDeletion, PasskeyRegistration,
+    PasswordReset, PasswordResetForm, RegistrationForm, RegistrationParams, RoleDescription, RoleQuery,
+    SecretRequest, TokenParams, UserQuery, UserUpdate,
+};
+use crate::sqlite::Sqlite;
+use crate::storage::{Database, Postgres};
+use clap::Parser;
+use deadpool_postgres::PoolError;
 use dotenv::dotenv;
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::{fs, io};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
 use tokio_postgres::NoTls;
+use uuid::Uuid;
 use native_tls::{Certificate, TlsConnector};
 use postgres_native_tls::MakeTlsConnector;
-use warp::http::Response;
-use warp::{hyper::StatusCode, Filter, Rejection, Reply};
+use warp::filters::BoxedFilter;
+use warp::host::Authority;
+use warp::{Filter, Reply};
 use thiserror::Error;
 
 
 fn with_db(
-    db_pool: deadpool_postgres::Pool,
-) -> impl Filter<Extract = (deadpool_postgres::Pool,), Error = Infallible> + Clone {
-    warp::any().map(move || db_pool.clone())
+    database: Arc<dyn Database>,
+) -> impl Filter<Extract = (Arc<dyn Database>,), Error = Infallible> + Clone {
+    warp::any().map(move || database.clone())
 }
 
 fn with_config(
@@ -32,6 +70,81 @@ fn with_config(
     warp::any().map(move || config.server.clone())
 }
 
+fn with_mailer(
+    mailer: Arc<dyn mail::Mailer>,
+) -> impl Filter<Extract = (Arc<dyn mail::Mailer>,), Error = Infallible> + Clone {
+    warp::any().map(move || mailer.clone())
+}
+
+fn with_providers(
+    providers: Arc<federation::ProviderCache>,
+) -> impl Filter<Extract = (Arc<federation::ProviderCache>,), Error = Infallible> + Clone {
+    warp::any().map(move || providers.clone())
+}
+
+fn with_keys(
+    keys: Arc<jwt::SigningKeys>,
+) -> impl Filter<Extract = (Arc<jwt::SigningKeys>,), Error = Infallible> + Clone {
+    warp::any().map(move || keys.clone())
+}
+
+// The address of the client. Behind a proxy that is the last address in X-Forwarded-For, the ones before
+// it come from the client and can't be trusted.
+fn with_client_ip(
+    trust_forwarded_for: bool,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = warp::Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(move |remote: Option<SocketAddr>, forwarded: Option<String>| {
+            let forwarded = forwarded
+                .filter(|_| trust_forwarded_for)
+                .and_then(|f| f.rsplit(',').next().and_then(|ip| ip.trim().parse().ok()));
+            forwarded.or_else(|| remote.map(|r| r.ip()))
+        })
+}
+
+// Passes the requests sent to the host, whatever the port
+fn with_host(host: String) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::host::optional()
+        .and_then(move |authority: Option<Authority>| {
+            let matches = authority.is_some_and(|a| a.host().eq_ignore_ascii_case(&host));
+            async move {
+                if matches {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// The tenant's routes for the requests to its host, the other routes for the rest
+fn with_tenant(
+    host: String,
+    tenant: BoxedFilter<(warp::reply::Response,)>,
+    others: BoxedFilter<(warp::reply::Response,)>,
+) -> BoxedFilter<(warp::reply::Response,)> {
+    with_host(host).and(tenant).or(others).unify().boxed()
+}
+
+// Rejects the request with 429 when its client or address used up its requests for the route
+fn rate_limit(
+    limiter: Arc<ratelimit::RateLimiter>,
+    database: Arc<dyn Database>,
+    route: ratelimit::Route,
+    trust_forwarded_for: bool,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    with_client_ip(trust_forwarded_for)
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |ip: Option<IpAddr>, authorization: Option<String>| {
+            let limiter = limiter.clone();
+            let database = database.clone();
+            async move { limiter.check(&database, route, authorization.as_deref(), ip).await }
+        })
+        .untuple_one()
+}
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("could not read pem file for tls")]
@@ -50,64 +163,172 @@ fn setup_tls(cert_dir: String) -> Result<MakeTlsConnector, ConfigError> {
         .add_root_certificate(cert)
         .build()?;
     let connector = MakeTlsConnector::new(connector);
-    return Ok(connector);
+    Ok(connector)
+}
+
+// Diagnostics go to stderr so they don't mix with the output of the admin commands
+fn create_pool(config: &Config) -> deadpool_postgres::Pool {
+    match setup_tls(config.server.cert_dir.to_owned()) {
+        Ok(res) => {
+            eprintln!("Setup TLS");
+            config.pg.create_pool(res).unwrap()
+        },
+        Err(msg) =>  {
+            eprintln!("Error setting up TLS, continuing without. Message: {:?}", msg);
+            config.pg.create_pool(NoTls).unwrap()
+        },
+    }
+}
+
+// The database of the default tenant, an SQLite file when SQLITE.PATH is set
+fn database(config: &Config) -> Arc<dyn Database> {
+    match &config.sqlite {
+        Some(sqlite) => Arc::new(Sqlite::open(sqlite).expect("Could not open the SQLite database")),
+        None => Arc::new(Postgres::new(create_pool(config))),
+    }
+}
+
+// Brings an SQLite database up to date at startup, there is no separate bootstrap for it. A new database
+// gets the demo data of database_init.sql when bootstrap is set.
+async fn migrate_sqlite(database: &Sqlite, bootstrap: bool) {
+    let migrated = database.migrate().await.expect("Could not migrate the SQLite database");
+    for version in &migrated {
+        println!("Migrated the SQLite database to {}", version);
+    }
+    if bootstrap && migrated.contains(&"0001_init") {
+        let file = fs::read_to_string("database_init.sql").expect("Could not load bootstrap script. The bootstrap script should be in the root of the run context");
+        // the demo data starts on the line after the marker
+        let demo_data = file.split(tenants::DEMO_DATA).nth(1).and_then(|d| d.split_once('\n')).map(|(_, d)| d);
+        match database.execute_script(demo_data.unwrap_or_default()).await {
+            Ok(_) => println!("Demo data created"),
+            Err(msg) => println!("Error creating demo data, continuing startup. Message: {}", msg),
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
+    let cli = cli::Cli::parse();
     let config: Config = crate::models::Config::from_env().unwrap();
+    let tenants = tenants::load(&config).expect("Invalid tenant configuration");
 
-    let tls = setup_tls(config.server.cert_dir.to_owned());
-    let pool = match tls {
-        Ok(res) => {
-            println!("Setup TLS");
-            config.pg.create_pool(res).unwrap() 
-        },
-        Err(msg) =>  { 
-            println!("Error setting up TLS, continuing without. Message: {:?}", msg);
-            config.pg.create_pool(NoTls).unwrap() 
-        },
-    };
-
-    if config.bootstrap {
-        println!("Bootstrapping postgresql database...");
-        match pool.get().await {
-            Ok(client) => {
-                let file = fs::read_to_string("database_init.sql").expect("Could not load bootstrap script. The bootstrap script should be in the root of the run context");
-                db::create_tables(&client, file.as_str()).await;
-            }
-            Err(PoolError::Backend(e)) => {
-                println!("Backend Error {}", e);
+    match cli.command {
+        None | Some(cli::Command::Serve) => {}
+        Some(command) => {
+            // --tenant runs the command on the tenant's schema
+            let result = match cli.tenant.as_deref() {
+                Some(name) => match tenants.iter().find(|t| t.name == name) {
+                    Some(tenant) => cli::run(command, &tenant.config, &Postgres::new(create_pool(&tenant.config)), &[]).await,
+                    None => Err(errors::Error::NotFoundError(format!("Tenant {}", name))),
+                },
+                None => cli::run(command, &config, &*database(&config), &tenants).await,
+            };
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                std::process::exit(1);
             }
-            Err(_) => {
-                println!("Error - unknown");
-            }
-
+            return;
         }
     }
 
+    let database: Arc<dyn Database> = match &config.sqlite {
+        Some(sqlite) => {
+            let sqlite = Sqlite::open(sqlite).expect("Could not open the SQLite database");
+            migrate_sqlite(&sqlite, config.bootstrap).await;
+            Arc::new(sqlite)
+        }
+        None => {
+            let pool = create_pool(&config);
+            if config.bootstrap {
+                println!("Bootstrapping postgresql database...");
+                match pool.get().await {
+                    Ok(client) => {
+                        let file = fs::read_to_string("database_init.sql").expect("Could not load bootstrap script. The bootstrap script should be in the root of the run context");
+                        match db::create_tables(&client, file.as_str()).await {
+                            Ok(_) => println!("Database created"),
+                            Err(msg) => println!("Error creating database, continuing startup. Message: ${0}", msg),
+                        }
+                    }
+                    Err(PoolError::Backend(e)) => {
+                        println!("Backend Error {}", e);
+                    }
+                    Err(_) => {
+                        println!("Error - unknown");
+                    }
 
+                }
+            }
+            Arc::new(Postgres::new(pool))
+        }
+    };
+
+    let keys = Arc::new(jwt::SigningKeys::load(&config.server.cert_dir));
+    let mailer = mail::mailer(&config.server.mail).expect("Invalid mail configuration");
+    let mut app = routes(database, config.clone(), keys, mailer);
+
+    // a tenant gets the requests to its host, the rest goes to the default tenant
+    for tenant in tenants {
+        let pool = create_pool(&tenant.config);
+        if let Ok(client) = pool.get().await {
+            if !db::schema_exists(&client, &tenant.schema).await {
+                println!(
+                    "Tenant {} has no schema {} yet, create it with `tenants init {}`",
+                    tenant.name, tenant.schema, tenant.name
+                );
+            }
+        }
+        let keys = Arc::new(jwt::SigningKeys::load(&format!("{}/{}", tenant.config.server.cert_dir, tenant.name)));
+        let mailer = mail::mailer(&tenant.config.server.mail).expect("Invalid mail configuration");
+        println!("Serving tenant {} at {} as {}", tenant.name, tenant.host, tenant.config.server.name);
+        app = with_tenant(tenant.host, routes(Arc::new(Postgres::new(pool)), tenant.config, keys, mailer), app);
+    }
 
     println!(
         "Starting oauth server on http://{}:{}/",
         config.server.host, config.server.port
     );
 
+    // TODO regel een from_string voor het adres
+    let adrr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), config.server.port);
+
+    warp::serve(app).run(adrr).await
+}
+
+// All routes of a tenant, the default one included. Rejections are answered here, so a request that
+// reached a tenant's routes never continues to another tenant's.
+fn routes(
+    database: Arc<dyn Database>,
+    config: Config,
+    keys: Arc<jwt::SigningKeys>,
+    mailer: Arc<dyn mail::Mailer>,
+) -> BoxedFilter<(warp::reply::Response,)> {
     let auth = warp::header::<String>("Authorization")
-        .or(warp::any().map(|| String::new()))
+        .or(warp::any().map(String::new))
         .unify();
 
     let introspect_body = warp::body::form()
         .map(|form: HashMap<String, String>| form.get("token").unwrap().to_string());
 
-    let token_body = warp::body::form().map(|form: TokenParams| Some(form));
+    let token_body = warp::body::form().map(Some::<TokenParams>);
+
+    let authorization_params = warp::query::<AuthorizationParams>();
+
+    let consent_body = warp::body::form::<ConsentParams>();
+
+    let login_body = warp::body::form::<LoginParams>();
+
+    let session = warp::cookie::optional(session::SESSION_COOKIE);
+
+    let csrf = warp::cookie::optional(session::CSRF_COOKIE);
+
+    let client_ip = with_client_ip(config.server.trust_forwarded_for);
 
-    let authorization_params = warp::query().map(|params: AuthorizationParams| {
-        println!("Mappiong params");
-        params
-    });
+    let limiter = Arc::new(ratelimit::RateLimiter::new(config.server.rate_limit.clone()));
+    let limit = |route| rate_limit(limiter.clone(), database.clone(), route, config.server.trust_forwarded_for);
+
+    let providers = Arc::new(federation::ProviderCache::new());
 
     let oauth_route = warp::post().and(warp::path("oauth2"));
     let oauth_get_route = warp::get().and(warp::path("oauth2"));
@@ -115,33 +336,757 @@ async fn main() {
     let introspect_route = oauth_route
         .and(warp::path("introspect"))
         .and(warp::path::end())
+        .and(limit(ratelimit::Route::Introspect))
         .and(auth)
         .and(introspect_body)
-        .and(with_db(pool.clone()))
+        .and(with_db(database.clone()))
         .and_then(handlers::introspect_token);
 
+    let userinfo_route = warp::get()
+        .or(warp::post())
+        .unify()
+        .and(warp::path("oauth2"))
+        .and(warp::path("userinfo"))
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and_then(handlers::get_userinfo);
+
     let logout_route = oauth_route
         .and(warp::path("logout"))
         .and(warp::path::end())
-        .and(with_db(pool.clone()))
+        .and(with_db(database.clone()))
         .and_then(handlers::invalidate_token);
 
     let authorize_route = oauth_get_route
         .and(warp::path("authorize"))
         .and(warp::path::end())
+        .and(limit(ratelimit::Route::Authorize))
         .and(authorization_params)
-        .and(with_db(pool.clone()))
+        .and(session)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
         .and_then(handlers::get_authorization);
 
+    let login_page_route = oauth_get_route
+        .and(warp::path("login"))
+        .and(warp::path::end())
+        .and(authorization_params)
+        .and(warp::query::<Next>())
+        .and(with_config(config.clone()))
+        .and_then(handlers::get_login);
+
+    let login_route = oauth_route
+        .and(warp::path("login"))
+        .and(warp::path::end())
+        .and(login_body)
+        .and(csrf)
+        .and(client_ip.clone())
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(handlers::post_login);
+
+    let federated_login_route = oauth_get_route
+        .and(warp::path("federated"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(authorization_params)
+        .and(warp::query::<Next>())
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and(with_providers(providers.clone()))
+        .and_then(federation::get_federated_login);
+
+    let federated_callback_route = oauth_get_route
+        .and(warp::path("federated"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("callback"))
+        .and(warp::path::end())
+        .and(warp::query::<FederationCallback>())
+        .and(warp::cookie::optional(federation::STATE_COOKIE))
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and(with_providers(providers.clone()))
+        .and_then(federation::get_federated_callback);
+
+    let mfa_page_route = oauth_get_route
+        .and(warp::path("mfa"))
+        .and(warp::path::end())
+        .and(authorization_params)
+        .and(warp::query::<Next>())
+        .and(session)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(mfa::get_mfa);
+
+    let mfa_route = oauth_route
+        .and(warp::path("mfa"))
+        .and(warp::path::end())
+        .and(warp::body::form::<MfaParams>())
+        .and(session)
+        .and(csrf)
+        .and(client_ip.clone())
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(mfa::post_mfa);
+
+    let challenge_route = oauth_route
+        .and(warp::path("passkey"))
+        .and(warp::path("challenge"))
+        .and(warp::path::end())
+        .and(warp::body::json::<ChallengeRequest>())
+        .and(session)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(passkeys::post_challenge);
+
+    let passkey_login_route = oauth_route
+        .and(warp::path("passkey"))
+        .and(warp::path("login"))
+        .and(warp::path::end())
+        .and(warp::body::form::<PasskeyAssertion>())
+        .and(csrf)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(passkeys::post_passkey_login);
+
+    let mfa_passkey_route = oauth_route
+        .and(warp::path("mfa"))
+        .and(warp::path("passkey"))
+        .and(warp::path::end())
+        .and(warp::body::form::<PasskeyAssertion>())
+        .and(session)
+        .and(csrf)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(passkeys::post_mfa_passkey);
+
+    let passkeys_page_route = oauth_get_route
+        .and(warp::path("passkeys"))
+        .and(warp::path::end())
+        .and(authorization_params)
+        .and(session)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(passkeys::get_passkeys);
+
+    let add_passkey_route = oauth_route
+        .and(warp::path("passkeys"))
+        .and(warp::path::end())
+        .and(warp::body::form::<PasskeyRegistration>())
+        .and(session)
+        .and(csrf)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(passkeys::post_passkey);
+
+    let delete_passkey_route = oauth_route
+        .and(warp::path("passkeys"))
+        .and(warp::path("delete"))
+        .and(warp::path::end())
+        .and(warp::body::form::<PasskeyDeletion>())
+        .and(session)
+        .and(csrf)
+        .and(with_db(database.clone()))
+        .and_then(passkeys::post_delete_passkey);
+
+    let consent_route = oauth_route
+        .and(warp::path("consent"))
+        .and(warp::path::end())
+        .and(consent_body)
+        .and(session)
+        .and(csrf)
+        .and(with_db(database.clone()))
+        .and_then(handlers::post_consent);
+
     let token_route = oauth_route
         .and(warp::path("token"))
         .and(warp::path::end())
+        .and(limit(ratelimit::Route::Token))
         .and(token_body)
         .and(auth)
-        .and(with_db(pool.clone()))
+        .and(client_ip.clone())
+        .and(with_db(database.clone()))
         .and(with_config(config.clone()))
+        .and(with_keys(keys.clone()))
         .and_then(handlers::get_access_token);
 
+    // RP-initiated logout, clients may send the browser here with a GET or a form post
+    let end_session_route = warp::get()
+        .and(warp::query::<EndSessionParams>())
+        .or(warp::post().and(warp::body::form::<EndSessionParams>()))
+        .unify();
+    let end_session_route = warp::path("oauth2")
+        .and(warp::path("end_session"))
+        .and(warp::path::end())
+        .and(end_session_route)
+        .and(session)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and(with_keys(keys.clone()))
+        .and_then(handlers::end_session);
+
+    let jwks_route = oauth_get_route
+        .and(warp::path("jwks"))
+        .and(warp::path::end())
+        .and(with_keys(keys.clone()))
+        .and_then(handlers::get_jwks);
+
+    let register_page_route = warp::get()
+        .and(warp::path("register"))
+        .and(warp::path::end())
+        .and(with_config(config.clone()))
+        .and_then(handlers::get_register);
+
+    // the same path takes json for the api and a form post from the page
+    let register_route = warp::post()
+        .and(warp::path("register"))
+        .and(warp::path::end())
+        .and(warp::body::json::<RegistrationParams>())
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and(with_mailer(mailer.clone()))
+        .and_then(handlers::post_register);
+
+    let register_form_route = warp::post()
+        .and(warp::path("register"))
+        .and(warp::path::end())
+        .and(warp::body::form::<RegistrationForm>())
+        .and(csrf)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and(with_mailer(mailer.clone()))
+        .and_then(handlers::post_register_form);
+
+    let verify_email_route = warp::get()
+        .and(warp::path("verify_email"))
+        .and(warp::path::end())
+        .and(warp::query::<EmailTokenQuery>())
+        .and(with_db(database.clone()))
+        .and_then(account::verify_email);
+
+    let resend_page_route = warp::get()
+        .and(warp::path!("verify_email" / "resend"))
+        .and(with_config(config.clone()))
+        .and_then(account::get_resend_verification);
+
+    let resend_route = warp::post()
+        .and(warp::path!("verify_email" / "resend"))
+        .and(warp::body::form::<EmailForm>())
+        .and(csrf)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and(with_mailer(mailer.clone()))
+        .and_then(account::post_resend_verification);
+
+    let forgot_page_route = warp::get()
+        .and(warp::path("forgot_password"))
+        .and(warp::path::end())
+        .and(with_config(config.clone()))
+        .and_then(account::get_forgot_password);
+
+    let forgot_route = warp::post()
+        .and(warp::path("forgot_password"))
+        .and(warp::path::end())
+        .and(warp::body::form::<EmailForm>())
+        .and(csrf)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and(with_mailer(mailer.clone()))
+        .and_then(account::post_forgot_password);
+
+    let reset_page_route = warp::get()
+        .and(warp::path("reset_password"))
+        .and(warp::path::end())
+        .and(warp::query::<EmailTokenQuery>())
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(account::get_reset_password);
+
+    let reset_route = warp::post()
+        .and(warp::path("reset_password"))
+        .and(warp::path::end())
+        .and(warp::body::form::<PasswordResetForm>())
+        .and(csrf)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(account::post_reset_password);
+
+    let admin_users = warp::path("admin").and(warp::path("users"));
+    let user_id = warp::path::param::<Uuid>();
+
+    let list_users_route = warp::get()
+        .and(admin_users)
+        .and(warp::path::end())
+        .and(warp::query::<UserQuery>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::list_users);
+
+    let get_user_route = warp::get()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::get_user);
+
+    let create_user_route = warp::post()
+        .and(admin_users)
+        .and(warp::path::end())
+        .and(warp::body::json::<NewUser>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::create_user);
+
+    let update_user_route = warp::patch()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path::end())
+        .and(warp::body::json::<UserUpdate>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::update_user);
+
+    let disable_user_route = warp::post()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path("disable").map(|| true).or(warp::path("enable").map(|| false)).unify())
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::set_user_disabled);
+
+    let delete_user_route = warp::delete()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::delete_user);
+
+    let reset_password_route = warp::post()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path("reset_password"))
+        .and(warp::path::end())
+        .and(warp::body::json::<PasswordReset>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::reset_password);
+
+    let reset_mfa_route = warp::delete()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path("mfa"))
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::reset_mfa);
+
+    let list_passkeys_route = warp::get()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path("passkeys"))
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::list_passkeys);
+
+    let delete_passkey_admin_route = warp::delete()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path("passkeys"))
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::delete_passkey);
+
+    let unlock_user_route = warp::post()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path("unlock"))
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::unlock_user);
+
+    let unlock_ip_route = warp::delete()
+        .and(warp::path("admin"))
+        .and(warp::path("lockouts"))
+        .and(warp::path::param::<IpAddr>())
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::unlock_ip);
+
+    let user_attributes_route = warp::get()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path("attributes"))
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::get_user_attributes);
+
+    let update_user_attributes_route = warp::patch()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path("attributes"))
+        .and(warp::path::end())
+        .and(warp::body::json::<serde_json::Map<String, serde_json::Value>>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::update_user_attributes);
+
+    let admin_attributes = warp::path("admin").and(warp::path("attributes"));
+
+    let list_attributes_route = warp::get()
+        .and(admin_attributes)
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::list_attributes);
+
+    let put_attribute_route = warp::put()
+        .and(admin_attributes)
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::body::json::<AttributeDefinition>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::put_attribute);
+
+    let delete_attribute_route = warp::delete()
+        .and(admin_attributes)
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::delete_attribute);
+
+    let admin_clients = warp::path("admin").and(warp::path("clients"));
+    let client_id = warp::path::param::<String>();
+
+    let list_clients_route = warp::get()
+        .and(admin_clients)
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::list_clients);
+
+    let get_client_route = warp::get()
+        .and(admin_clients)
+        .and(client_id)
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::get_client);
+
+    let create_client_route = warp::post()
+        .and(admin_clients)
+        .and(warp::path::end())
+        .and(warp::body::json::<NewClient>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::create_client);
+
+    let update_client_route = warp::patch()
+        .and(admin_clients)
+        .and(client_id)
+        .and(warp::path::end())
+        .and(warp::body::json::<ClientUpdate>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::update_client);
+
+    let disable_client_route = warp::post()
+        .and(admin_clients)
+        .and(client_id)
+        .and(warp::path("disable").map(|| true).or(warp::path("enable").map(|| false)).unify())
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::set_client_disabled);
+
+    let list_secrets_route = warp::get()
+        .and(admin_clients)
+        .and(client_id)
+        .and(warp::path("secrets"))
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::list_client_secrets);
+
+    let add_secret_route = warp::post()
+        .and(admin_clients)
+        .and(client_id)
+        .and(warp::path("secrets"))
+        .and(warp::path::end())
+        .and(warp::body::json::<SecretRequest>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::add_client_secret);
+
+    let expire_secret_route = warp::delete()
+        .and(admin_clients)
+        .and(client_id)
+        .and(warp::path("secrets"))
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::expire_client_secret);
+
+    let list_tokens_route = warp::get()
+        .and(admin_clients)
+        .and(client_id)
+        .and(warp::path("tokens"))
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::list_client_tokens);
+
+    // global roles at /admin/roles, those of a client at /admin/clients/{client_id}/roles
+    let admin_roles = warp::path("admin")
+        .and(warp::path("roles"))
+        .map(|| None)
+        .or(warp::path("admin")
+            .and(warp::path("clients"))
+            .and(warp::path::param::<String>())
+            .and(warp::path("roles"))
+            .map(Some))
+        .unify();
+
+    let list_roles_route = warp::get()
+        .and(admin_roles)
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::list_roles);
+
+    let put_role_route = warp::put()
+        .and(admin_roles)
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::body::json::<RoleDescription>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::put_role);
+
+    let delete_role_route = warp::delete()
+        .and(admin_roles)
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::delete_role);
+
+    // PUT assigns, DELETE takes away
+    let assign = warp::put().map(|| true).or(warp::delete().map(|| false)).unify();
+
+    let user_roles_route = warp::get()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path("roles"))
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::get_user_roles);
+
+    let user_role_route = admin_users
+        .and(user_id)
+        .and(warp::path("roles"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(assign)
+        .and(warp::query::<RoleQuery>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::set_user_role);
+
+    let admin_groups = warp::path("admin").and(warp::path("groups"));
+    let group = warp::path::param::<String>();
+
+    let list_groups_route = warp::get()
+        .and(admin_groups)
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::list_groups);
+
+    let get_group_route = warp::get()
+        .and(admin_groups)
+        .and(group)
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::get_group);
+
+    let put_group_route = warp::put()
+        .and(admin_groups)
+        .and(group)
+        .and(warp::path::end())
+        .and(warp::body::json::<RoleDescription>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::put_group);
+
+    let delete_group_route = warp::delete()
+        .and(admin_groups)
+        .and(group)
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::delete_group);
+
+    let group_member_route = admin_groups
+        .and(group)
+        .and(warp::path("members"))
+        .and(user_id)
+        .and(warp::path::end())
+        .and(assign)
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::set_group_member);
+
+    let group_role_route = admin_groups
+        .and(group)
+        .and(warp::path("roles"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(assign)
+        .and(warp::query::<RoleQuery>())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::set_group_role);
+
+    let list_identities_route = warp::get()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path("identities"))
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::list_identities);
+
+    let delete_identity_route = warp::delete()
+        .and(admin_users)
+        .and(user_id)
+        .and(warp::path("identities"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(auth)
+        .and(with_db(database.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin::delete_identity);
+
+    let admin_user_routes = list_users_route
+        .or(get_user_route)
+        .or(create_user_route)
+        .or(update_user_route)
+        .or(disable_user_route)
+        .or(delete_user_route)
+        .or(reset_password_route)
+        .or(reset_mfa_route)
+        .or(list_passkeys_route)
+        .or(delete_passkey_admin_route)
+        .or(list_identities_route)
+        .or(delete_identity_route)
+        .or(unlock_user_route)
+        .or(unlock_ip_route)
+        .or(user_attributes_route)
+        .or(update_user_attributes_route)
+        .boxed();
+
+    let admin_client_routes = list_clients_route
+        .or(get_client_route)
+        .or(create_client_route)
+        .or(update_client_route)
+        .or(disable_client_route)
+        .or(list_secrets_route)
+        .or(add_secret_route)
+        .or(expire_secret_route)
+        .or(list_tokens_route)
+        .or(list_attributes_route)
+        .or(put_attribute_route)
+        .or(delete_attribute_route)
+        .boxed();
+
+    let admin_role_routes = list_roles_route
+        .or(put_role_route)
+        .or(delete_role_route)
+        .or(user_roles_route)
+        .or(user_role_route)
+        .or(list_groups_route)
+        .or(get_group_route)
+        .or(put_group_route)
+        .or(delete_group_route)
+        .or(group_member_route)
+        .or(group_role_route)
+        .boxed();
+
+    let account_routes = register_page_route
+        .or(register_route)
+        .or(register_form_route)
+        .or(verify_email_route)
+        .or(resend_page_route)
+        .or(resend_route)
+        .or(forgot_page_route)
+        .or(forgot_route)
+        .or(reset_page_route)
+        .or(reset_route)
+        .boxed();
+
+    let mfa_routes = mfa_page_route
+        .or(mfa_route)
+        .or(mfa_passkey_route)
+        .or(challenge_route)
+        .or(passkey_login_route)
+        .or(passkeys_page_route)
+        .or(add_passkey_route)
+        .or(delete_passkey_route)
+        .boxed();
+
     let health_route = warp::get()
         .and(warp::path("q"))
         .and(warp::path("health"))
@@ -149,15 +1094,25 @@ async fn main() {
         .and_then(handlers::get_health)
         .recover(errors::handle_get_notallowed);
 
+    // groups of routes are boxed, a single chain of this many filters overflows the stack in debug builds
     let routes = authorize_route
         .or(health_route)
         .or(introspect_route)
+        .or(userinfo_route)
         .or(token_route)
         .or(logout_route)
+        .or(consent_route)
+        .or(login_page_route)
+        .or(login_route)
+        .or(federated_login_route.or(federated_callback_route).boxed())
+        .or(mfa_routes)
+        .or(end_session_route)
+        .or(jwks_route)
+        .or(account_routes)
+        .or(admin_user_routes)
+        .or(admin_client_routes)
+        .or(admin_role_routes)
         .recover(errors::handle_rejection);
 
-    // TODO regel een from_string voor het adres
-    let adrr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), config.server.port);
-
-    warp::serve(routes).run(adrr).await
+    routes.map(Reply::into_response).boxed()
 }
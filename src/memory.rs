@@ -0,0 +1,1862 @@
+use crate::db::DEFAULT_TOKEN_LIFETIME;
+use crate::errors::Error::{self, ConflictError, InvalidRequestError, InvalidScopeError};
+use crate::federation;
+use crate::ldap;
+use crate::models::{
+    AccessToken, AttributeDefinition, AuthorizationCode, AuthorizationParams, ChallengePurpose, ClientDetails,
+    ClientInfo, ClientScope, ClientSecret, ClientUpdate, EmailTokenPurpose, FederatedIdentity, FederationState,
+    Group, GroupMember, Introspection, LoginSession, LogoutClient, NewClient, Passkey, PasskeyCredential, Role,
+    Scope, ScopePolicy, ScopeRule, TokenGrant, TokenInfo, TotpCredential, User, UserSource, UserUpdate,
+};
+use crate::session;
+use crate::storage::{
+    AttributeStore, ClientStore, CodeStore, Database, FederationStore, LockoutStore, MfaStore, RoleStore, ScopeStore,
+    SessionStore, Storage, TokenStore, UserStore,
+};
+use crate::webauthn::NewCredential;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Local};
+use std::sync::{Arc, Mutex, MutexGuard};
+use uuid::Uuid;
+
+// Storage in memory for the tests, the tables of database_init.sql as vectors. It does what the queries in
+// db.rs do, so a handler under test sees what it would see with Postgres. Deleting a user, client, role or
+// group removes what references it like the on delete cascade constraints do. Methods that take &mut self
+// change a copy of the tables that only replaces them when everything succeeded, like a transaction.
+
+#[derive(Clone, Default)]
+pub struct Memory {
+    tables: Arc<Mutex<Tables>>,
+}
+
+#[derive(Clone, Default)]
+struct Tables {
+    next_id: i32,
+    users: Vec<UserRow>,
+    invitations: Vec<InvitationRow>,
+    email_tokens: Vec<EmailTokenRow>,
+    clients: Vec<ClientRow>,
+    client_secrets: Vec<SecretRow>,
+    scopes: Vec<ScopeRow>,
+    client_scopes: Vec<ClientScopeRow>,
+    scope_rules: Vec<ScopeRule>,
+    scope_grants: Vec<GrantRow>,
+    consents: Vec<(Uuid, Uuid, String)>,
+    access_tokens: Vec<TokenRow>,
+    codes: Vec<CodeRow>,
+    sessions: Vec<SessionRow>,
+    session_clients: Vec<(i32, Uuid)>,
+    totp: Vec<TotpRow>,
+    recovery_codes: Vec<RecoveryRow>,
+    challenges: Vec<ChallengeRow>,
+    passkeys: Vec<PasskeyRow>,
+    login_failures: Vec<FailureRow>,
+    attributes: Vec<AttributeDefinition>,
+    user_data: Vec<(Uuid, String, String)>,
+    roles: Vec<RoleRow>,
+    groups: Vec<GroupRow>,
+    group_members: Vec<(i32, Uuid)>,
+    group_roles: Vec<(i32, i32)>,
+    user_roles: Vec<(Uuid, i32)>,
+    federation_states: Vec<FederationStateRow>,
+    identities: Vec<IdentityRow>,
+}
+
+#[derive(Clone)]
+struct UserRow {
+    id: Uuid,
+    username: String,
+    email: String,
+    password: String,
+    email_verified: bool,
+    disabled: bool,
+    created: DateTime<Local>,
+    mfa_required: bool,
+    source: UserSource,
+}
+
+#[derive(Clone)]
+struct InvitationRow {
+    code_hash: String,
+    email: Option<String>,
+    expires: Option<DateTime<Local>>,
+    used_by: Option<Uuid>,
+    used: bool,
+}
+
+#[derive(Clone)]
+struct EmailTokenRow {
+    token_hash: String,
+    user_id: Uuid,
+    purpose: &'static str,
+    email: String,
+    expires: DateTime<Local>,
+    used: bool,
+}
+
+#[derive(Clone)]
+struct ClientRow {
+    id: Uuid,
+    client_id: String,
+    display_name: Option<String>,
+    redirect_uris: Vec<String>,
+    post_logout_redirect_uris: Vec<String>,
+    frontchannel_logout_uri: Option<String>,
+    backchannel_logout_uri: Option<String>,
+    grant_types: Vec<String>,
+    access_token_lifetime: Option<i32>,
+    refresh_token_lifetime: Option<i32>,
+    first_party: bool,
+    disabled: bool,
+    created: DateTime<Local>,
+    mfa_required: bool,
+    rate_limit: Option<i32>,
+    jwt_access_tokens: bool,
+}
+
+#[derive(Clone)]
+struct SecretRow {
+    id: i32,
+    client_id: Uuid,
+    secret_hash: String,
+    prefix: String,
+    created: DateTime<Local>,
+    expires: Option<DateTime<Local>>,
+}
+
+#[derive(Clone)]
+struct ScopeRow {
+    id: i32,
+    name: String,
+    description: Option<String>,
+    requires_consent: bool,
+}
+
+#[derive(Clone)]
+struct ClientScopeRow {
+    client_id: Uuid,
+    scope_id: i32,
+    is_default: bool,
+}
+
+#[derive(Clone)]
+struct GrantRow {
+    scope: String,
+    user_id: Option<Uuid>,
+    client_id: Option<Uuid>,
+}
+
+#[derive(Clone)]
+struct TokenRow {
+    id: i32,
+    access_token: String,
+    refresh_token_hash: Option<String>,
+    expires: DateTime<Local>,
+    refresh_expires: Option<DateTime<Local>>,
+    created: DateTime<Local>,
+    scope: Option<String>,
+    user_id: Option<Uuid>,
+    client_id: Uuid,
+    device: String,
+    issuer: String,
+    auth_time: Option<DateTime<Local>>,
+    amr: Option<String>,
+    sid: Option<String>,
+}
+
+#[derive(Clone)]
+struct CodeRow {
+    client_id: Uuid,
+    user_id: Uuid,
+    code: String,
+    pcke_hash: Option<String>,
+    scope: Option<String>,
+    redirect_uri: Option<String>,
+    nonce: Option<String>,
+    auth_time: Option<DateTime<Local>>,
+    amr: String,
+    sid: Option<String>,
+    expires: DateTime<Local>,
+}
+
+#[derive(Clone)]
+struct SessionRow {
+    id: i32,
+    session_token: String,
+    sid: String,
+    user_id: Uuid,
+    amr: String,
+    created: DateTime<Local>,
+    expires: DateTime<Local>,
+}
+
+#[derive(Clone)]
+struct TotpRow {
+    user_id: Uuid,
+    secret: String,
+    confirmed: Option<DateTime<Local>>,
+    last_used_step: Option<i64>,
+}
+
+#[derive(Clone)]
+struct RecoveryRow {
+    user_id: Uuid,
+    code_hash: String,
+    used: bool,
+}
+
+#[derive(Clone)]
+struct ChallengeRow {
+    challenge: String,
+    purpose: &'static str,
+    user_id: Option<Uuid>,
+    expires: DateTime<Local>,
+}
+
+#[derive(Clone)]
+struct PasskeyRow {
+    id: i32,
+    user_id: Uuid,
+    credential_id: String,
+    public_key: Vec<u8>,
+    sign_count: i64,
+    name: String,
+    created: DateTime<Local>,
+    last_used: Option<DateTime<Local>>,
+}
+
+#[derive(Clone)]
+struct FailureRow {
+    kind: String,
+    subject: String,
+    failures: i32,
+    last_failure: DateTime<Local>,
+    locked_until: Option<DateTime<Local>>,
+}
+
+#[derive(Clone)]
+struct RoleRow {
+    id: i32,
+    name: String,
+    description: Option<String>,
+    client_id: Option<Uuid>,
+}
+
+#[derive(Clone)]
+struct GroupRow {
+    id: i32,
+    name: String,
+    description: Option<String>,
+}
+
+#[derive(Clone)]
+struct FederationStateRow {
+    state: String,
+    provider: String,
+    nonce: String,
+    code_verifier: String,
+    request: String,
+    next: Option<String>,
+    expires: DateTime<Local>,
+}
+
+#[derive(Clone)]
+struct IdentityRow {
+    provider: String,
+    subject: String,
+    user_id: Uuid,
+    email: Option<String>,
+    created: DateTime<Local>,
+    last_login: Option<DateTime<Local>>,
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split_whitespace().map(String::from).collect()
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Memory::default()
+    }
+
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        self.tables.lock().unwrap()
+    }
+
+    // Runs f on a copy of the tables, the copy replaces them when f succeeds
+    fn transaction<T>(&self, f: impl FnOnce(&mut Tables) -> Result<T, Error>) -> Result<T, Error> {
+        let mut tables = self.tables();
+        let mut copy = tables.clone();
+        let result = f(&mut copy)?;
+        *tables = copy;
+        Ok(result)
+    }
+
+    // The scopes and their grants have no store methods, the bootstrap script adds them
+    pub fn add_scope(&self, name: &str, requires_consent: bool) {
+        let mut tables = self.tables();
+        if tables.scopes.iter().any(|s| s.name == name) {
+            return;
+        }
+        let id = tables.id();
+        tables.scopes.push(ScopeRow {
+            id,
+            name: name.to_string(),
+            description: Some(format!("The {} scope", name)),
+            requires_consent,
+        });
+    }
+
+    pub fn add_scope_grant(&self, scope: &str, user_id: Option<Uuid>, client_db_id: Uuid) {
+        self.tables().scope_grants.push(GrantRow {
+            scope: scope.to_string(),
+            user_id,
+            client_id: Some(client_db_id),
+        });
+    }
+}
+
+impl Tables {
+    fn id(&mut self) -> i32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn user(&self, user_id: Uuid) -> Option<&UserRow> {
+        self.users.iter().find(|u| u.id == user_id)
+    }
+
+    fn client(&self, client_db_id: Uuid) -> Option<&ClientRow> {
+        self.clients.iter().find(|c| c.id == client_db_id)
+    }
+
+    fn client_scopes(&self, client_db_id: Uuid) -> Vec<ClientScope> {
+        let mut scopes: Vec<ClientScope> = self
+            .client_scopes
+            .iter()
+            .filter(|cs| cs.client_id == client_db_id)
+            .filter_map(|cs| {
+                self.scopes.iter().find(|s| s.id == cs.scope_id).map(|s| ClientScope {
+                    name: s.name.clone(),
+                    is_default: cs.is_default,
+                })
+            })
+            .collect();
+        scopes.sort_by(|a, b| a.name.cmp(&b.name));
+        scopes
+    }
+
+    fn set_client_scopes(&mut self, client_db_id: Uuid, scopes: &[String], defaults: &[String]) -> Result<(), Error> {
+        if let Some(scope) = defaults.iter().find(|d| !scopes.contains(d)) {
+            return Err(InvalidScopeError(format!("Default scope {} is not one of the client's scopes", scope)));
+        }
+        self.client_scopes.retain(|cs| cs.client_id != client_db_id);
+        let rows: Vec<ClientScopeRow> = self
+            .scopes
+            .iter()
+            .filter(|s| scopes.contains(&s.name))
+            .map(|s| ClientScopeRow {
+                client_id: client_db_id,
+                scope_id: s.id,
+                is_default: defaults.contains(&s.name),
+            })
+            .collect();
+        if rows.len() != scopes.len() {
+            return Err(InvalidScopeError("Unknown scope".to_string()));
+        }
+        self.client_scopes.extend(rows);
+        Ok(())
+    }
+
+    fn client_details(&self, row: &ClientRow) -> ClientDetails {
+        ClientDetails {
+            client_id: row.client_id.clone(),
+            display_name: row.display_name.clone(),
+            redirect_uris: row.redirect_uris.clone(),
+            post_logout_redirect_uris: row.post_logout_redirect_uris.clone(),
+            frontchannel_logout_uri: row.frontchannel_logout_uri.clone(),
+            backchannel_logout_uri: row.backchannel_logout_uri.clone(),
+            grant_types: row.grant_types.clone(),
+            scopes: self.client_scopes(row.id),
+            access_token_lifetime: row.access_token_lifetime,
+            refresh_token_lifetime: row.refresh_token_lifetime,
+            first_party: row.first_party,
+            mfa_required: row.mfa_required,
+            rate_limit: row.rate_limit,
+            jwt_access_tokens: row.jwt_access_tokens,
+            disabled: row.disabled,
+            created: row.created.timestamp(),
+            client_secret: None,
+        }
+    }
+
+    fn role(&self, row: &RoleRow) -> Role {
+        Role {
+            name: row.name.clone(),
+            client_id: row.client_id.and_then(|id| self.client(id)).map(|c| c.client_id.clone()),
+            description: row.description.clone(),
+        }
+    }
+
+    // Sorted like "order by c.client_id nulls first, r.name"
+    fn roles(&self, ids: impl Fn(i32) -> bool) -> Vec<Role> {
+        let mut roles: Vec<Role> = self.roles.iter().filter(|r| ids(r.id)).map(|r| self.role(r)).collect();
+        roles.sort_by(|a, b| (&a.client_id, &a.name).cmp(&(&b.client_id, &b.name)));
+        roles
+    }
+
+    fn group(&self, row: &GroupRow, members: bool) -> Group {
+        let mut group_members: Vec<GroupMember> = self
+            .group_members
+            .iter()
+            .filter(|(g, _)| *g == row.id)
+            .filter_map(|(_, u)| self.user(*u))
+            .map(|u| GroupMember {
+                user_id: u.id,
+                username: u.username.clone(),
+            })
+            .collect();
+        group_members.sort_by(|a, b| a.username.cmp(&b.username));
+        Group {
+            name: row.name.clone(),
+            description: row.description.clone(),
+            roles: self.roles(|id| self.group_roles.contains(&(row.id, id))),
+            members: Some(group_members).filter(|_| members),
+        }
+    }
+
+    fn remove_role(&mut self, role_id: i32) {
+        self.roles.retain(|r| r.id != role_id);
+        self.group_roles.retain(|(_, r)| *r != role_id);
+        self.user_roles.retain(|(_, r)| *r != role_id);
+    }
+
+    fn remove_sessions(&mut self, keep: impl Fn(&SessionRow) -> bool) {
+        let removed: Vec<i32> = self.sessions.iter().filter(|s| !keep(s)).map(|s| s.id).collect();
+        self.sessions.retain(keep);
+        self.session_clients.retain(|(s, _)| !removed.contains(s));
+    }
+
+    fn remove_user(&mut self, user_id: Uuid) {
+        self.users.retain(|u| u.id != user_id);
+        for invitation in self.invitations.iter_mut().filter(|i| i.used_by == Some(user_id)) {
+            invitation.used_by = None;
+        }
+        self.email_tokens.retain(|t| t.user_id != user_id);
+        self.scope_grants.retain(|g| g.user_id != Some(user_id));
+        self.consents.retain(|(u, _, _)| *u != user_id);
+        self.access_tokens.retain(|t| t.user_id != Some(user_id));
+        self.codes.retain(|c| c.user_id != user_id);
+        self.remove_sessions(|s| s.user_id != user_id);
+        self.totp.retain(|t| t.user_id != user_id);
+        self.recovery_codes.retain(|r| r.user_id != user_id);
+        self.challenges.retain(|c| c.user_id != Some(user_id));
+        self.passkeys.retain(|p| p.user_id != user_id);
+        self.user_data.retain(|(u, _, _)| *u != user_id);
+        self.group_members.retain(|(_, u)| *u != user_id);
+        self.user_roles.retain(|(u, _)| *u != user_id);
+        self.identities.retain(|i| i.user_id != user_id);
+    }
+
+    fn remove_client(&mut self, client_db_id: Uuid) {
+        self.clients.retain(|c| c.id != client_db_id);
+        self.client_secrets.retain(|s| s.client_id != client_db_id);
+        self.client_scopes.retain(|cs| cs.client_id != client_db_id);
+        self.scope_grants.retain(|g| g.client_id != Some(client_db_id));
+        self.consents.retain(|(_, c, _)| *c != client_db_id);
+        self.access_tokens.retain(|t| t.client_id != client_db_id);
+        self.codes.retain(|c| c.client_id != client_db_id);
+        self.session_clients.retain(|(_, c)| *c != client_db_id);
+        let roles: Vec<i32> = self.roles.iter().filter(|r| r.client_id == Some(client_db_id)).map(|r| r.id).collect();
+        for role_id in roles {
+            self.remove_role(role_id);
+        }
+    }
+}
+
+fn user(row: &UserRow) -> User {
+    User {
+        id: row.id,
+        username: row.username.clone(),
+        email: row.email.clone(),
+        email_verified: row.email_verified,
+        mfa_required: row.mfa_required,
+        disabled: row.disabled,
+        created: row.created.timestamp(),
+    }
+}
+
+fn client_info(row: &ClientRow) -> ClientInfo {
+    ClientInfo {
+        id: row.id,
+        client_id: row.client_id.clone(),
+        display_name: row.display_name.clone(),
+        redirect_uris: row.redirect_uris.clone(),
+        post_logout_redirect_uris: row.post_logout_redirect_uris.clone(),
+        first_party: row.first_party,
+        grant_types: row.grant_types.clone(),
+        access_token_lifetime: row.access_token_lifetime,
+        refresh_token_lifetime: row.refresh_token_lifetime,
+        mfa_required: row.mfa_required,
+        jwt_access_tokens: row.jwt_access_tokens,
+    }
+}
+
+fn client_secret(row: &SecretRow) -> ClientSecret {
+    ClientSecret {
+        id: row.id,
+        prefix: row.prefix.clone(),
+        created: row.created.timestamp(),
+        expires: row.expires.map(|t| t.timestamp()),
+    }
+}
+
+fn contains_ignoring_case(value: &str, part: Option<&str>) -> bool {
+    part.is_none_or(|p| value.to_lowercase().contains(&p.to_lowercase()))
+}
+
+#[async_trait]
+impl Database for Memory {
+    async fn connect(&self) -> Result<Box<dyn Storage>, Error> {
+        Ok(Box::new(self.clone()))
+    }
+
+    async fn migrate(&self) -> Result<Vec<&'static str>, Error> {
+        Ok(Vec::new())
+    }
+}
+
+#[async_trait]
+impl ClientStore for Memory {
+    async fn validate_client_credentials(&self, client_id: String, secret: String) -> Option<Uuid> {
+        let tables = self.tables();
+        let secret_hash = session::hash_token(&secret);
+        let now = Local::now();
+        let client = tables.clients.iter().find(|c| c.client_id == client_id && !c.disabled)?;
+        tables
+            .client_secrets
+            .iter()
+            .any(|s| s.client_id == client.id && s.secret_hash == secret_hash && s.expires.is_none_or(|e| e > now))
+            .then_some(client.id)
+    }
+
+    async fn add_client_secret(
+        &self,
+        client_db_id: Uuid,
+        expire_time: Option<DateTime<Local>>,
+    ) -> (ClientSecret, String) {
+        let secret = session::generate_secret(48);
+        let mut tables = self.tables();
+        let row = SecretRow {
+            id: tables.id(),
+            client_id: client_db_id,
+            secret_hash: session::hash_token(&secret),
+            prefix: secret[..6].to_string(),
+            created: Local::now(),
+            expires: expire_time,
+        };
+        let client_secret = client_secret(&row);
+        tables.client_secrets.push(row);
+        (client_secret, secret)
+    }
+
+    async fn get_client_secrets(&self, client_db_id: Uuid) -> Vec<ClientSecret> {
+        let tables = self.tables();
+        tables
+            .client_secrets
+            .iter()
+            .filter(|s| s.client_id == client_db_id)
+            .map(client_secret)
+            .collect()
+    }
+
+    async fn expire_client_secret(&self, client_db_id: Uuid, secret_id: i32, expire_time: DateTime<Local>) -> bool {
+        let mut tables = self.tables();
+        match tables.client_secrets.iter_mut().find(|s| s.client_id == client_db_id && s.id == secret_id) {
+            Some(secret) => {
+                secret.expires = Some(expire_time);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn expire_other_client_secrets(&self, client_db_id: Uuid, keep_id: i32, expire_time: DateTime<Local>) {
+        let mut tables = self.tables();
+        for secret in tables.client_secrets.iter_mut() {
+            let expires_later = secret.expires.is_none_or(|e| e > expire_time);
+            if secret.client_id == client_db_id && secret.id != keep_id && expires_later {
+                secret.expires = Some(expire_time);
+            }
+        }
+    }
+
+    async fn get_client_details(&self, client_id: &str) -> Option<(Uuid, ClientDetails)> {
+        let tables = self.tables();
+        let row = tables.clients.iter().find(|c| c.client_id == client_id)?;
+        Some((row.id, tables.client_details(row)))
+    }
+
+    async fn get_clients(&self) -> Vec<ClientDetails> {
+        let tables = self.tables();
+        let mut clients: Vec<ClientDetails> = tables.clients.iter().map(|c| tables.client_details(c)).collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        clients
+    }
+
+    async fn get_client_by_client_id(&self, client_id: &str) -> Option<ClientInfo> {
+        let tables = self.tables();
+        tables.clients.iter().find(|c| c.client_id == client_id && !c.disabled).map(client_info)
+    }
+
+    async fn get_client_by_id(&self, client_db_id: Uuid) -> Option<ClientInfo> {
+        let tables = self.tables();
+        tables.client(client_db_id).filter(|c| !c.disabled).map(client_info)
+    }
+
+    async fn create_client(&mut self, client_id: &str, params: &NewClient) -> Result<Uuid, Error> {
+        self.transaction(|tables| {
+            if tables.clients.iter().any(|c| c.client_id == client_id) {
+                return Err(ConflictError(format!("Client {} already exists", client_id)));
+            }
+            let id = Uuid::new_v4();
+            tables.clients.push(ClientRow {
+                id,
+                client_id: client_id.to_string(),
+                display_name: params.display_name.clone(),
+                redirect_uris: params.redirect_uris.clone(),
+                post_logout_redirect_uris: params.post_logout_redirect_uris.clone(),
+                frontchannel_logout_uri: params.frontchannel_logout_uri.clone(),
+                backchannel_logout_uri: params.backchannel_logout_uri.clone(),
+                grant_types: params
+                    .grant_types
+                    .clone()
+                    .unwrap_or_else(|| split_list("authorization_code refresh_token")),
+                access_token_lifetime: params.access_token_lifetime,
+                refresh_token_lifetime: params.refresh_token_lifetime,
+                first_party: params.first_party,
+                disabled: false,
+                created: Local::now(),
+                mfa_required: params.mfa_required,
+                rate_limit: params.rate_limit,
+                jwt_access_tokens: params.jwt_access_tokens,
+            });
+            tables.set_client_scopes(id, &params.scopes, &params.default_scopes)?;
+            Ok(id)
+        })
+    }
+
+    async fn update_client(&mut self, client_db_id: Uuid, update: &ClientUpdate) -> Result<(), Error> {
+        self.transaction(|tables| {
+            if let Some(client) = tables.clients.iter_mut().find(|c| c.id == client_db_id) {
+                macro_rules! set {
+                    ($field:ident) => {
+                        if let Some(value) = &update.$field {
+                            client.$field = value.clone().into();
+                        }
+                    };
+                }
+                set!(display_name);
+                set!(redirect_uris);
+                set!(post_logout_redirect_uris);
+                set!(frontchannel_logout_uri);
+                set!(backchannel_logout_uri);
+                set!(grant_types);
+                set!(access_token_lifetime);
+                set!(refresh_token_lifetime);
+                set!(first_party);
+                set!(mfa_required);
+                set!(rate_limit);
+                set!(jwt_access_tokens);
+            }
+            if update.scopes.is_some() || update.default_scopes.is_some() {
+                let current = tables.client_scopes(client_db_id);
+                let scopes = update
+                    .scopes
+                    .clone()
+                    .unwrap_or_else(|| current.iter().map(|s| s.name.clone()).collect());
+                let defaults = update
+                    .default_scopes
+                    .clone()
+                    .unwrap_or_else(|| current.iter().filter(|s| s.is_default).map(|s| s.name.clone()).collect());
+                tables.set_client_scopes(client_db_id, &scopes, &defaults)?;
+            }
+            Ok(())
+        })
+    }
+
+    async fn set_client_disabled(&self, client_db_id: Uuid, disabled: bool) {
+        let mut tables = self.tables();
+        if let Some(client) = tables.clients.iter_mut().find(|c| c.id == client_db_id) {
+            client.disabled = disabled;
+        }
+    }
+
+    async fn revoke_client(&self, client_db_id: Uuid) {
+        let mut tables = self.tables();
+        tables.codes.retain(|c| c.client_id != client_db_id);
+        tables.access_tokens.retain(|t| t.client_id != client_db_id);
+    }
+
+    async fn delete_client(&self, client_db_id: Uuid) -> bool {
+        let mut tables = self.tables();
+        let exists = tables.client(client_db_id).is_some();
+        tables.remove_client(client_db_id);
+        exists
+    }
+
+    async fn get_client_tokens(&self, client_db_id: Uuid) -> Vec<TokenInfo> {
+        let tables = self.tables();
+        let now = Local::now();
+        let mut tokens: Vec<&TokenRow> = tables
+            .access_tokens
+            .iter()
+            .filter(|t| t.client_id == client_db_id)
+            .filter(|t| {
+                t.expires > now || (t.refresh_token_hash.is_some() && t.refresh_expires.is_none_or(|e| e > now))
+            })
+            .collect();
+        tokens.sort_by_key(|t| std::cmp::Reverse(t.created));
+        tokens
+            .into_iter()
+            .map(|t| TokenInfo {
+                id: t.id,
+                user_id: t.user_id,
+                username: t.user_id.and_then(|id| tables.user(id)).map(|u| u.username.clone()),
+                scope: t.scope.clone(),
+                device: t.device.clone(),
+                created: t.created.timestamp(),
+                expires: t.expires.timestamp(),
+                refresh_token: t.refresh_token_hash.is_some(),
+            })
+            .collect()
+    }
+
+    async fn get_client_rate_limit(&self, client_id: &str) -> Option<i32> {
+        let tables = self.tables();
+        tables.clients.iter().find(|c| c.client_id == client_id).and_then(|c| c.rate_limit)
+    }
+}
+
+#[async_trait]
+impl UserStore for Memory {
+    async fn get_password_hash(&self, username: &str) -> Option<(Uuid, String)> {
+        let tables = self.tables();
+        tables
+            .users
+            .iter()
+            .find(|u| u.username == username && u.email_verified && !u.disabled)
+            .map(|u| (u.id, u.password.clone()))
+    }
+
+    async fn get_user_source(&self, username: &str) -> Option<UserSource> {
+        self.tables().users.iter().find(|u| u.username == username).map(|u| u.source)
+    }
+
+    async fn register_user(
+        &mut self,
+        username: &str,
+        email: &str,
+        password_hash: &str,
+        email_verified: bool,
+        invitation: Option<String>,
+    ) -> Result<User, Error> {
+        self.transaction(|tables| {
+            if tables.users.iter().any(|u| u.username == username) {
+                return Err(ConflictError("Username is already taken".to_string()));
+            }
+            if tables.users.iter().any(|u| u.email == email) {
+                return Err(ConflictError("Email address is already registered".to_string()));
+            }
+            let row = UserRow {
+                id: Uuid::new_v4(),
+                username: username.to_string(),
+                email: email.to_string(),
+                password: password_hash.to_string(),
+                email_verified,
+                disabled: false,
+                created: Local::now(),
+                mfa_required: false,
+                source: UserSource::Local,
+            };
+            let created = user(&row);
+            tables.users.push(row);
+
+            if let Some(code_hash) = invitation {
+                let now = Local::now();
+                let invitation = tables.invitations.iter_mut().find(|i| {
+                    i.code_hash == code_hash
+                        && !i.used
+                        && i.expires.is_none_or(|e| e > now)
+                        && i.email.as_ref().is_none_or(|e| e.eq_ignore_ascii_case(email))
+                });
+                match invitation {
+                    Some(invitation) => {
+                        invitation.used = true;
+                        invitation.used_by = Some(created.id);
+                    }
+                    None => return Err(InvalidRequestError("Invalid invitation code".to_string())),
+                }
+            }
+            Ok(created)
+        })
+    }
+
+    async fn register_directory_user(&mut self, username: &str, email: &str, subject: &str) -> Result<User, Error> {
+        self.transaction(|tables| {
+            if tables.users.iter().any(|u| u.username == username) {
+                return Err(ConflictError("Username is already taken".to_string()));
+            }
+            if tables.users.iter().any(|u| u.email == email) {
+                return Err(ConflictError("Email address is already registered".to_string()));
+            }
+            let row = UserRow {
+                id: Uuid::new_v4(),
+                username: username.to_string(),
+                email: email.to_string(),
+                password: federation::NO_PASSWORD.to_string(),
+                email_verified: true,
+                disabled: false,
+                created: Local::now(),
+                mfa_required: false,
+                source: UserSource::Directory,
+            };
+            let created = user(&row);
+            tables.users.push(row);
+            tables.identities.push(IdentityRow {
+                provider: ldap::PROVIDER.to_string(),
+                subject: subject.to_string(),
+                user_id: created.id,
+                email: Some(email.to_string()),
+                created: Local::now(),
+                last_login: Some(Local::now()),
+            });
+            Ok(created)
+        })
+    }
+
+    async fn get_users(
+        &self,
+        username: Option<&str>,
+        email: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> (Vec<User>, i64) {
+        let tables = self.tables();
+        let mut users: Vec<&UserRow> = tables
+            .users
+            .iter()
+            .filter(|u| contains_ignoring_case(&u.username, username) && contains_ignoring_case(&u.email, email))
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        let total = users.len() as i64;
+        let page = users.into_iter().skip(offset as usize).take(limit as usize).map(user).collect();
+        (page, total)
+    }
+
+    async fn get_user(&self, user_id: Uuid) -> Option<User> {
+        self.tables().user(user_id).map(user)
+    }
+
+    async fn get_user_by_username(&self, username: &str) -> Option<User> {
+        self.tables().users.iter().find(|u| u.username == username).map(user)
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Option<User> {
+        self.tables().users.iter().find(|u| u.email.eq_ignore_ascii_case(email)).map(user)
+    }
+
+    async fn update_user(&self, user_id: Uuid, update: &UserUpdate) -> Result<Option<User>, Error> {
+        let mut tables = self.tables();
+        let others = |f: &dyn Fn(&UserRow) -> bool| tables.users.iter().any(|u| u.id != user_id && f(u));
+        if let Some(username) = &update.username {
+            if others(&|u| &u.username == username) {
+                return Err(ConflictError("Username is already taken".to_string()));
+            }
+        }
+        if let Some(email) = &update.email {
+            if others(&|u| &u.email == email) {
+                return Err(ConflictError("Email address is already registered".to_string()));
+            }
+        }
+        let row = match tables.users.iter_mut().find(|u| u.id == user_id) {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        if let Some(username) = &update.username {
+            row.username = username.clone();
+        }
+        if let Some(email) = &update.email {
+            row.email = email.clone();
+        }
+        if let Some(email_verified) = update.email_verified {
+            row.email_verified = email_verified;
+        }
+        if let Some(mfa_required) = update.mfa_required {
+            row.mfa_required = mfa_required;
+        }
+        Ok(Some(user(row)))
+    }
+
+    async fn set_user_disabled(&self, user_id: Uuid, disabled: bool) -> Option<User> {
+        let mut tables = self.tables();
+        let row = tables.users.iter_mut().find(|u| u.id == user_id)?;
+        row.disabled = disabled;
+        Some(user(row))
+    }
+
+    async fn set_password(&self, user_id: Uuid, password_hash: &str) -> bool {
+        let mut tables = self.tables();
+        match tables.users.iter_mut().find(|u| u.id == user_id) {
+            Some(row) => {
+                row.password = password_hash.to_string();
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn delete_user(&self, user_id: Uuid) -> bool {
+        let mut tables = self.tables();
+        let exists = tables.user(user_id).is_some();
+        tables.remove_user(user_id);
+        exists
+    }
+
+    async fn revoke_user(&self, user_id: Uuid) {
+        let mut tables = self.tables();
+        tables.remove_sessions(|s| s.user_id != user_id);
+        tables.codes.retain(|c| c.user_id != user_id);
+        tables.access_tokens.retain(|t| t.user_id != Some(user_id));
+    }
+
+    async fn create_email_token(
+        &self,
+        user_id: Uuid,
+        purpose: EmailTokenPurpose,
+        email: &str,
+        lifetime: Duration,
+    ) -> String {
+        let token = session::generate_secret(48);
+        let mut tables = self.tables();
+        tables
+            .email_tokens
+            .retain(|t| !(t.user_id == user_id && t.purpose == purpose.as_str() && !t.used));
+        tables.email_tokens.push(EmailTokenRow {
+            token_hash: session::hash_token(&token),
+            user_id,
+            purpose: purpose.as_str(),
+            email: email.to_string(),
+            expires: Local::now() + lifetime,
+            used: false,
+        });
+        token
+    }
+
+    async fn find_email_token(&self, token: &str, purpose: EmailTokenPurpose) -> Option<(Uuid, String)> {
+        let token_hash = session::hash_token(token);
+        let now = Local::now();
+        let tables = self.tables();
+        tables
+            .email_tokens
+            .iter()
+            .find(|t| t.token_hash == token_hash && t.purpose == purpose.as_str() && !t.used && t.expires > now)
+            .map(|t| (t.user_id, t.email.clone()))
+    }
+
+    async fn use_email_token(&self, token: &str, purpose: EmailTokenPurpose) -> Option<(Uuid, String)> {
+        let token_hash = session::hash_token(token);
+        let now = Local::now();
+        let mut tables = self.tables();
+        let row = tables
+            .email_tokens
+            .iter_mut()
+            .find(|t| t.token_hash == token_hash && t.purpose == purpose.as_str() && !t.used && t.expires > now)?;
+        row.used = true;
+        Some((row.user_id, row.email.clone()))
+    }
+
+    async fn set_email_verified(&self, user_id: Uuid, email: &str) -> bool {
+        let mut tables = self.tables();
+        match tables.users.iter_mut().find(|u| u.id == user_id && u.email == email) {
+            Some(row) => {
+                row.email_verified = true;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[async_trait]
+impl TokenStore for Memory {
+    async fn validate_access_token(&self, access_token: String, client_db_id: Uuid) -> Option<Introspection> {
+        let tables = self.tables();
+        let token = tables
+            .access_tokens
+            .iter()
+            .find(|t| t.access_token == access_token && t.client_id == client_db_id)?;
+        let client = tables.client(client_db_id)?;
+        let user = token.user_id.and_then(|id| tables.user(id));
+        Some(Introspection {
+            active: token.expires >= Local::now(),
+            client_id: client.client_id.clone(),
+            username: user.map(|u| u.username.clone()),
+            user_id: user.map(|u| u.id),
+            scope: token.scope.clone(),
+            effective_scope: None,
+            token_type: "bearer".to_string(),
+            issuer: token.issuer.clone(),
+            exp: token.expires.timestamp(),
+            iat: token.created.timestamp(),
+            auth_time: token.auth_time.map(|t| t.timestamp()),
+            amr: token.amr.as_deref().map(split_list).unwrap_or_default(),
+            roles: Vec::new(),
+            groups: Vec::new(),
+            claims: serde_json::Map::new(),
+        })
+    }
+
+    async fn insert_token(
+        &self,
+        generated_token: String,
+        refresh_token: Option<String>,
+        grant: TokenGrant,
+        issuer: String,
+        client_info: &ClientInfo,
+    ) -> AccessToken {
+        let token_duration = Duration::seconds(
+            client_info
+                .access_token_lifetime
+                .map(i64::from)
+                .unwrap_or(DEFAULT_TOKEN_LIFETIME),
+        );
+        let now = Local::now();
+        let refresh_expires = match (&refresh_token, client_info.refresh_token_lifetime) {
+            (Some(_), Some(lifetime)) => Some(now + Duration::seconds(lifetime.into())),
+            _ => None,
+        };
+        let device = grant.device.clone().unwrap_or_else(|| "unknown".to_string());
+
+        let mut tables = self.tables();
+        // unique_uid_cid, tokens without a user don't conflict like nulls in a unique constraint
+        let position = tables.access_tokens.iter().position(|t| {
+            grant.user_id.is_some()
+                && t.user_id == grant.user_id
+                && t.client_id == grant.client_id
+                && t.device == device
+        });
+        let id = match position {
+            Some(i) => tables.access_tokens.remove(i).id,
+            None => tables.id(),
+        };
+        tables.access_tokens.push(TokenRow {
+            id,
+            access_token: generated_token.clone(),
+            refresh_token_hash: refresh_token.as_deref().map(session::hash_token),
+            expires: now + token_duration,
+            refresh_expires,
+            created: now,
+            scope: grant.scope.clone(),
+            user_id: grant.user_id,
+            client_id: grant.client_id,
+            device,
+            issuer,
+            auth_time: grant.auth_time,
+            amr: Some(grant.amr.join(" ")).filter(|a| !a.is_empty()),
+            sid: grant.sid.clone(),
+        });
+
+        AccessToken {
+            access_token: generated_token,
+            token_type: "bearer".to_string(),
+            expires_in: token_duration.num_seconds(),
+            scope: grant.scope,
+            refresh_token,
+            id_token: None,
+        }
+    }
+
+    async fn find_refresh_token(&self, refresh_token: &str, client_db_id: Uuid) -> Option<TokenGrant> {
+        let refresh_token_hash = session::hash_token(refresh_token);
+        let now = Local::now();
+        let tables = self.tables();
+        tables
+            .access_tokens
+            .iter()
+            .find(|t| {
+                t.refresh_token_hash.as_deref() == Some(refresh_token_hash.as_str())
+                    && t.client_id == client_db_id
+                    && t.refresh_expires.is_none_or(|e| e > now)
+            })
+            .map(|t| TokenGrant {
+                user_id: t.user_id,
+                client_id: client_db_id,
+                scope: t.scope.clone(),
+                device: Some(t.device.clone()),
+                auth_time: t.auth_time,
+                amr: t.amr.as_deref().map(split_list).unwrap_or_default(),
+                nonce: None,
+                sid: t.sid.clone(),
+            })
+    }
+
+    async fn revoke_tokens(&self, user_id: Option<Uuid>, client_db_id: Option<Uuid>, device: Option<&str>) -> u64 {
+        let mut tables = self.tables();
+        let before = tables.access_tokens.len();
+        tables.access_tokens.retain(|t| {
+            !(user_id.is_none_or(|u| t.user_id == Some(u))
+                && client_db_id.is_none_or(|c| t.client_id == c)
+                && device.is_none_or(|d| t.device == d))
+        });
+        (before - tables.access_tokens.len()) as u64
+    }
+
+    async fn get_active_token_scope(&self, access_token: &str) -> Option<String> {
+        let now = Local::now();
+        let tables = self.tables();
+        tables
+            .access_tokens
+            .iter()
+            .find(|t| t.access_token == access_token && t.expires > now)
+            .and_then(|t| t.scope.clone())
+    }
+
+    async fn get_active_token_user(&self, access_token: &str) -> Option<(Option<Uuid>, Option<String>)> {
+        let now = Local::now();
+        let tables = self.tables();
+        tables
+            .access_tokens
+            .iter()
+            .find(|t| t.access_token == access_token && t.expires > now)
+            .map(|t| (t.user_id, t.scope.clone()))
+    }
+}
+
+#[async_trait]
+impl CodeStore for Memory {
+    async fn insert_code(
+        &self,
+        code: &str,
+        client_db_id: Uuid,
+        authorization: &AuthorizationCode,
+        request: &AuthorizationParams,
+    ) {
+        self.tables().codes.push(CodeRow {
+            client_id: client_db_id,
+            user_id: authorization.user_id,
+            code: code.to_string(),
+            pcke_hash: request.code_challenge.clone(),
+            scope: authorization.scope.clone(),
+            redirect_uri: Some(request.redirect_uri.clone()),
+            nonce: authorization.nonce.clone(),
+            auth_time: authorization.auth_time,
+            amr: authorization.amr.join(" "),
+            sid: authorization.sid.clone(),
+            expires: Local::now() + Duration::minutes(10),
+        });
+    }
+
+    async fn validate_code(
+        &self,
+        code: &str,
+        pcke: &str,
+        client_db_id: Uuid,
+        redirect_uri: &str,
+    ) -> Option<AuthorizationCode> {
+        let pcke_hash = session::hash_token(pcke);
+        let now = Local::now();
+        let mut tables = self.tables();
+        let matches = |c: &CodeRow| {
+            c.code == code
+                && c.client_id == client_db_id
+                && c.pcke_hash.as_ref().is_none_or(|h| *h == pcke_hash)
+                && c.redirect_uri.as_deref().is_none_or(|r| r == redirect_uri)
+                && c.expires > now
+        };
+        let (codes, rest): (Vec<CodeRow>, Vec<CodeRow>) = tables.codes.drain(..).partition(matches);
+        tables.codes = rest;
+        match codes.as_slice() {
+            [c] => Some(AuthorizationCode {
+                user_id: c.user_id,
+                scope: c.scope.clone(),
+                auth_time: c.auth_time,
+                amr: split_list(&c.amr),
+                nonce: c.nonce.clone(),
+                sid: c.sid.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+}
+
+#[async_trait]
+impl SessionStore for Memory {
+    async fn create_session(&self, session_hash: &str, sid: &str, user_id: Uuid, amr: &[String], lifetime: Duration) {
+        let mut tables = self.tables();
+        let id = tables.id();
+        tables.sessions.push(SessionRow {
+            id,
+            session_token: session_hash.to_string(),
+            sid: sid.to_string(),
+            user_id,
+            amr: amr.join(" "),
+            created: Local::now(),
+            expires: Local::now() + lifetime,
+        });
+    }
+
+    async fn find_session(&self, session_hash: &str) -> Option<LoginSession> {
+        let now = Local::now();
+        let tables = self.tables();
+        tables
+            .sessions
+            .iter()
+            .find(|s| s.session_token == session_hash && s.expires > now)
+            .map(|s| LoginSession {
+                id: s.id,
+                sid: s.sid.clone(),
+                user_id: s.user_id,
+                auth_time: s.created,
+                amr: split_list(&s.amr),
+            })
+    }
+
+    async fn set_session_amr(&self, session_id: i32, amr: &[String]) {
+        let mut tables = self.tables();
+        if let Some(session) = tables.sessions.iter_mut().find(|s| s.id == session_id) {
+            session.amr = amr.join(" ");
+        }
+    }
+
+    async fn delete_session(&self, session_id: i32) {
+        self.tables().remove_sessions(|s| s.id != session_id);
+    }
+
+    async fn add_session_client(&self, session_id: i32, client_db_id: Uuid) {
+        let mut tables = self.tables();
+        if !tables.session_clients.contains(&(session_id, client_db_id)) {
+            tables.session_clients.push((session_id, client_db_id));
+        }
+    }
+
+    async fn get_session_clients(&self, session_id: i32) -> Vec<LogoutClient> {
+        let tables = self.tables();
+        tables
+            .session_clients
+            .iter()
+            .filter(|(s, _)| *s == session_id)
+            .filter_map(|(_, c)| tables.client(*c))
+            .map(|c| LogoutClient {
+                client_id: c.client_id.clone(),
+                frontchannel_logout_uri: c.frontchannel_logout_uri.clone(),
+                backchannel_logout_uri: c.backchannel_logout_uri.clone(),
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl ScopeStore for Memory {
+    async fn get_scopes(&self) -> Vec<Scope> {
+        let tables = self.tables();
+        tables
+            .scopes
+            .iter()
+            .map(|s| Scope {
+                name: s.name.clone(),
+                description: s.description.clone(),
+                requires_consent: s.requires_consent,
+            })
+            .collect()
+    }
+
+    async fn get_scope_rules(&self) -> Vec<ScopeRule> {
+        self.tables().scope_rules.clone()
+    }
+
+    async fn get_scope_policy(&self, client_db_id: Uuid, user_id: Option<Uuid>) -> ScopePolicy {
+        let tables = self.tables();
+        ScopePolicy {
+            allowed: tables.client_scopes(client_db_id),
+            grants: tables
+                .scope_grants
+                .iter()
+                .filter(|g| g.client_id == Some(client_db_id) && g.user_id == user_id)
+                .map(|g| g.scope.clone())
+                .collect(),
+            rules: tables.scope_rules.clone(),
+        }
+    }
+
+    async fn get_consented_scopes(&self, user_id: Uuid, client_db_id: Uuid) -> Vec<String> {
+        let tables = self.tables();
+        tables
+            .consents
+            .iter()
+            .filter(|(u, c, _)| *u == user_id && *c == client_db_id)
+            .map(|(_, _, s)| s.clone())
+            .collect()
+    }
+
+    async fn insert_consents(&self, user_id: Uuid, client_db_id: Uuid, scopes: &[String]) {
+        let mut tables = self.tables();
+        for scope in scopes {
+            let consent = (user_id, client_db_id, scope.clone());
+            if !tables.consents.contains(&consent) {
+                tables.consents.push(consent);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MfaStore for Memory {
+    async fn get_totp(&self, user_id: Uuid) -> Option<TotpCredential> {
+        let tables = self.tables();
+        tables.totp.iter().find(|t| t.user_id == user_id).map(|t| TotpCredential {
+            secret: t.secret.clone(),
+            confirmed: t.confirmed.is_some(),
+        })
+    }
+
+    async fn set_pending_totp(&self, user_id: Uuid, secret: &str) {
+        let mut tables = self.tables();
+        match tables.totp.iter_mut().find(|t| t.user_id == user_id) {
+            Some(totp) if totp.confirmed.is_none() => totp.secret = secret.to_string(),
+            Some(_) => {}
+            None => tables.totp.push(TotpRow {
+                user_id,
+                secret: secret.to_string(),
+                confirmed: None,
+                last_used_step: None,
+            }),
+        }
+    }
+
+    async fn confirm_totp(&self, user_id: Uuid, step: i64) {
+        let mut tables = self.tables();
+        if let Some(totp) = tables.totp.iter_mut().find(|t| t.user_id == user_id) {
+            totp.confirmed = Some(Local::now());
+            totp.last_used_step = Some(step);
+        }
+    }
+
+    async fn use_totp_step(&self, user_id: Uuid, step: i64) -> bool {
+        let mut tables = self.tables();
+        match tables.totp.iter_mut().find(|t| {
+            t.user_id == user_id && t.confirmed.is_some() && t.last_used_step.is_none_or(|last| last < step)
+        }) {
+            Some(totp) => {
+                totp.last_used_step = Some(step);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn set_recovery_codes(&self, user_id: Uuid, code_hashes: &[String]) {
+        let mut tables = self.tables();
+        tables.recovery_codes.retain(|r| r.user_id != user_id);
+        for code_hash in code_hashes {
+            tables.recovery_codes.push(RecoveryRow {
+                user_id,
+                code_hash: code_hash.clone(),
+                used: false,
+            });
+        }
+    }
+
+    async fn use_recovery_code(&self, user_id: Uuid, code_hash: &str) -> bool {
+        let mut tables = self.tables();
+        match tables
+            .recovery_codes
+            .iter_mut()
+            .find(|r| r.user_id == user_id && r.code_hash == code_hash && !r.used)
+        {
+            Some(code) => {
+                code.used = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn delete_mfa(&self, user_id: Uuid) -> bool {
+        let mut tables = self.tables();
+        tables.recovery_codes.retain(|r| r.user_id != user_id);
+        let before = tables.totp.len();
+        tables.totp.retain(|t| t.user_id != user_id);
+        tables.totp.len() != before
+    }
+
+    async fn create_challenge(
+        &self,
+        challenge: &str,
+        purpose: ChallengePurpose,
+        user_id: Option<Uuid>,
+        lifetime: Duration,
+    ) {
+        let now = Local::now();
+        let mut tables = self.tables();
+        tables.challenges.retain(|c| c.expires >= now);
+        tables.challenges.push(ChallengeRow {
+            challenge: session::hash_token(challenge),
+            purpose: purpose.as_str(),
+            user_id,
+            expires: now + lifetime,
+        });
+    }
+
+    async fn use_challenge(&self, challenge: &str, purpose: ChallengePurpose) -> Option<Option<Uuid>> {
+        let challenge = session::hash_token(challenge);
+        let now = Local::now();
+        let mut tables = self.tables();
+        let i = tables
+            .challenges
+            .iter()
+            .position(|c| c.challenge == challenge && c.purpose == purpose.as_str() && c.expires > now)?;
+        Some(tables.challenges.remove(i).user_id)
+    }
+
+    async fn add_passkey(&self, user_id: Uuid, credential: &NewCredential, name: &str) -> bool {
+        let mut tables = self.tables();
+        if tables.passkeys.iter().any(|p| p.credential_id == credential.credential_id) {
+            return false;
+        }
+        let id = tables.id();
+        tables.passkeys.push(PasskeyRow {
+            id,
+            user_id,
+            credential_id: credential.credential_id.clone(),
+            public_key: credential.public_key.clone(),
+            sign_count: credential.sign_count,
+            name: name.to_string(),
+            created: Local::now(),
+            last_used: None,
+        });
+        true
+    }
+
+    async fn get_passkeys(&self, user_id: Uuid) -> Vec<Passkey> {
+        let tables = self.tables();
+        tables
+            .passkeys
+            .iter()
+            .filter(|p| p.user_id == user_id)
+            .map(|p| Passkey {
+                id: p.id,
+                name: p.name.clone(),
+                created: p.created.timestamp(),
+                last_used: p.last_used.map(|t| t.timestamp()),
+            })
+            .collect()
+    }
+
+    async fn get_passkey_ids(&self, user_id: Uuid) -> Vec<String> {
+        let tables = self.tables();
+        tables
+            .passkeys
+            .iter()
+            .filter(|p| p.user_id == user_id)
+            .map(|p| p.credential_id.clone())
+            .collect()
+    }
+
+    async fn find_passkey(&self, credential_id: &str) -> Option<PasskeyCredential> {
+        let tables = self.tables();
+        let passkey = tables.passkeys.iter().find(|p| p.credential_id == credential_id)?;
+        tables
+            .user(passkey.user_id)
+            .filter(|u| u.email_verified && !u.disabled)
+            .map(|_| PasskeyCredential {
+                id: passkey.id,
+                user_id: passkey.user_id,
+                public_key: passkey.public_key.clone(),
+                sign_count: passkey.sign_count,
+            })
+    }
+
+    async fn use_passkey(&self, passkey_id: i32, sign_count: i64) {
+        let mut tables = self.tables();
+        if let Some(passkey) = tables.passkeys.iter_mut().find(|p| p.id == passkey_id) {
+            passkey.sign_count = sign_count;
+            passkey.last_used = Some(Local::now());
+        }
+    }
+
+    async fn delete_passkey(&self, user_id: Uuid, passkey_id: i32) -> bool {
+        let mut tables = self.tables();
+        let before = tables.passkeys.len();
+        tables.passkeys.retain(|p| !(p.id == passkey_id && p.user_id == user_id));
+        tables.passkeys.len() != before
+    }
+}
+
+#[async_trait]
+impl LockoutStore for Memory {
+    async fn is_locked(&self, kind: &str, subject: &str) -> bool {
+        let now = Local::now();
+        let tables = self.tables();
+        tables
+            .login_failures
+            .iter()
+            .any(|f| f.kind == kind && f.subject == subject && f.locked_until.is_some_and(|l| l > now))
+    }
+
+    async fn add_login_failure(&self, kind: &str, subject: &str, window_start: DateTime<Local>) -> i32 {
+        let now = Local::now();
+        let mut tables = self.tables();
+        match tables.login_failures.iter_mut().find(|f| f.kind == kind && f.subject == subject) {
+            Some(failure) => {
+                failure.failures = if failure.last_failure < window_start { 1 } else { failure.failures + 1 };
+                failure.last_failure = now;
+                failure.failures
+            }
+            None => {
+                tables.login_failures.push(FailureRow {
+                    kind: kind.to_string(),
+                    subject: subject.to_string(),
+                    failures: 1,
+                    last_failure: now,
+                    locked_until: None,
+                });
+                1
+            }
+        }
+    }
+
+    async fn lock_login(&self, kind: &str, subject: &str, locked_until: DateTime<Local>) {
+        let mut tables = self.tables();
+        if let Some(failure) = tables.login_failures.iter_mut().find(|f| f.kind == kind && f.subject == subject) {
+            failure.locked_until = Some(locked_until);
+        }
+    }
+
+    async fn clear_login_failures(&self, kind: &str, subject: &str) -> bool {
+        let mut tables = self.tables();
+        let before = tables.login_failures.len();
+        tables.login_failures.retain(|f| !(f.kind == kind && f.subject == subject));
+        tables.login_failures.len() != before
+    }
+}
+
+#[async_trait]
+impl AttributeStore for Memory {
+    async fn get_attribute_definitions(&self) -> Vec<AttributeDefinition> {
+        let mut definitions = self.tables().attributes.clone();
+        definitions.sort_by(|a, b| a.name.cmp(&b.name));
+        definitions
+    }
+
+    async fn put_attribute_definition(&self, definition: &AttributeDefinition) {
+        let mut tables = self.tables();
+        tables.attributes.retain(|a| a.name != definition.name);
+        tables.attributes.push(definition.clone());
+    }
+
+    async fn delete_attribute_definition(&mut self, name: &str) -> Result<bool, Error> {
+        self.transaction(|tables| {
+            tables.user_data.retain(|(_, key, _)| key != name);
+            let before = tables.attributes.len();
+            tables.attributes.retain(|a| a.name != name);
+            Ok(tables.attributes.len() != before)
+        })
+    }
+
+    async fn get_attribute_values(&self, name: &str) -> Vec<String> {
+        let tables = self.tables();
+        tables
+            .user_data
+            .iter()
+            .filter(|(_, key, _)| key == name)
+            .map(|(_, _, value)| value.clone())
+            .collect()
+    }
+
+    async fn get_user_attributes(&self, user_id: Uuid) -> Vec<(String, String)> {
+        let tables = self.tables();
+        let mut attributes: Vec<(String, String)> = tables
+            .user_data
+            .iter()
+            .filter(|(u, _, _)| *u == user_id)
+            .map(|(_, key, value)| (key.clone(), value.clone()))
+            .collect();
+        attributes.sort();
+        attributes
+    }
+
+    async fn set_user_attributes(&mut self, user_id: Uuid, changes: &[(String, Option<String>)]) -> Result<(), Error> {
+        self.transaction(|tables| {
+            for (name, value) in changes {
+                tables.user_data.retain(|(u, key, _)| !(*u == user_id && key == name));
+                if let Some(value) = value {
+                    tables.user_data.push((user_id, name.clone(), value.clone()));
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[async_trait]
+impl RoleStore for Memory {
+    async fn get_roles(&self, client_db_id: Option<Uuid>) -> Vec<Role> {
+        let tables = self.tables();
+        let mut roles: Vec<Role> = tables
+            .roles
+            .iter()
+            .filter(|r| r.client_id == client_db_id)
+            .map(|r| tables.role(r))
+            .collect();
+        roles.sort_by(|a, b| a.name.cmp(&b.name));
+        roles
+    }
+
+    async fn find_role(&self, client_db_id: Option<Uuid>, name: &str) -> Option<i32> {
+        let tables = self.tables();
+        tables.roles.iter().find(|r| r.client_id == client_db_id && r.name == name).map(|r| r.id)
+    }
+
+    async fn put_role(&self, client_db_id: Option<Uuid>, name: &str, description: &Option<String>) {
+        let mut tables = self.tables();
+        match tables.roles.iter_mut().find(|r| r.client_id == client_db_id && r.name == name) {
+            Some(role) => role.description = description.clone(),
+            None => {
+                let id = tables.id();
+                tables.roles.push(RoleRow {
+                    id,
+                    name: name.to_string(),
+                    description: description.clone(),
+                    client_id: client_db_id,
+                });
+            }
+        }
+    }
+
+    async fn delete_role(&self, client_db_id: Option<Uuid>, name: &str) -> bool {
+        let mut tables = self.tables();
+        match tables.roles.iter().find(|r| r.client_id == client_db_id && r.name == name).map(|r| r.id) {
+            Some(role_id) => {
+                tables.remove_role(role_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn get_groups(&self) -> Vec<Group> {
+        let tables = self.tables();
+        let mut groups: Vec<Group> = tables.groups.iter().map(|g| tables.group(g, false)).collect();
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+        groups
+    }
+
+    async fn get_group(&self, name: &str) -> Option<(i32, Group)> {
+        let tables = self.tables();
+        let row = tables.groups.iter().find(|g| g.name == name)?;
+        Some((row.id, tables.group(row, true)))
+    }
+
+    async fn put_group(&self, name: &str, description: &Option<String>) {
+        let mut tables = self.tables();
+        match tables.groups.iter_mut().find(|g| g.name == name) {
+            Some(group) => group.description = description.clone(),
+            None => {
+                let id = tables.id();
+                tables.groups.push(GroupRow {
+                    id,
+                    name: name.to_string(),
+                    description: description.clone(),
+                });
+            }
+        }
+    }
+
+    async fn delete_group(&self, name: &str) -> bool {
+        let mut tables = self.tables();
+        let group_id = match tables.groups.iter().find(|g| g.name == name) {
+            Some(group) => group.id,
+            None => return false,
+        };
+        tables.groups.retain(|g| g.id != group_id);
+        tables.group_members.retain(|(g, _)| *g != group_id);
+        tables.group_roles.retain(|(g, _)| *g != group_id);
+        true
+    }
+
+    async fn add_group_member(&self, group_id: i32, user_id: Uuid) {
+        let mut tables = self.tables();
+        if !tables.group_members.contains(&(group_id, user_id)) {
+            tables.group_members.push((group_id, user_id));
+        }
+    }
+
+    async fn remove_group_member(&self, group_id: i32, user_id: Uuid) -> bool {
+        let mut tables = self.tables();
+        let before = tables.group_members.len();
+        tables.group_members.retain(|m| *m != (group_id, user_id));
+        tables.group_members.len() != before
+    }
+
+    async fn add_group_role(&self, group_id: i32, role_id: i32) {
+        let mut tables = self.tables();
+        if !tables.group_roles.contains(&(group_id, role_id)) {
+            tables.group_roles.push((group_id, role_id));
+        }
+    }
+
+    async fn remove_group_role(&self, group_id: i32, role_id: i32) -> bool {
+        let mut tables = self.tables();
+        let before = tables.group_roles.len();
+        tables.group_roles.retain(|r| *r != (group_id, role_id));
+        tables.group_roles.len() != before
+    }
+
+    async fn add_user_role(&self, user_id: Uuid, role_id: i32) {
+        let mut tables = self.tables();
+        if !tables.user_roles.contains(&(user_id, role_id)) {
+            tables.user_roles.push((user_id, role_id));
+        }
+    }
+
+    async fn remove_user_role(&self, user_id: Uuid, role_id: i32) -> bool {
+        let mut tables = self.tables();
+        let before = tables.user_roles.len();
+        tables.user_roles.retain(|r| *r != (user_id, role_id));
+        tables.user_roles.len() != before
+    }
+
+    async fn get_user_roles(&self, user_id: Uuid) -> Vec<Role> {
+        let tables = self.tables();
+        tables.roles(|id| tables.user_roles.contains(&(user_id, id)))
+    }
+
+    async fn get_user_groups(&self, user_id: Uuid) -> Vec<String> {
+        let tables = self.tables();
+        let mut groups: Vec<String> = tables
+            .groups
+            .iter()
+            .filter(|g| tables.group_members.contains(&(g.id, user_id)))
+            .map(|g| g.name.clone())
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    async fn set_directory_groups(&mut self, user_id: Uuid, names: &[String]) {
+        let mut tables = self.tables();
+        let groups: Vec<i32> = tables
+            .groups
+            .iter()
+            .filter(|g| names.contains(&g.name.to_lowercase()))
+            .map(|g| g.id)
+            .collect();
+        tables.group_members.retain(|(g, u)| *u != user_id || groups.contains(g));
+        for group_id in groups {
+            if !tables.group_members.contains(&(group_id, user_id)) {
+                tables.group_members.push((group_id, user_id));
+            }
+        }
+    }
+
+    async fn get_effective_roles(&self, user_id: Uuid) -> Vec<Role> {
+        let tables = self.tables();
+        let groups: Vec<i32> = tables
+            .group_members
+            .iter()
+            .filter(|(_, u)| *u == user_id)
+            .map(|(g, _)| *g)
+            .collect();
+        tables.roles(|id| {
+            tables.user_roles.contains(&(user_id, id))
+                || tables.group_roles.iter().any(|(g, r)| *r == id && groups.contains(g))
+        })
+    }
+}
+
+#[async_trait]
+impl FederationStore for Memory {
+    async fn create_federation_state(&self, state: &str, params: &FederationState, lifetime: Duration) {
+        let now = Local::now();
+        let mut tables = self.tables();
+        tables.federation_states.retain(|s| s.expires >= now);
+        tables.federation_states.push(FederationStateRow {
+            state: session::hash_token(state),
+            provider: params.provider.clone(),
+            nonce: params.nonce.clone(),
+            code_verifier: params.code_verifier.clone(),
+            request: params.request.clone(),
+            next: params.next.clone(),
+            expires: now + lifetime,
+        });
+    }
+
+    async fn use_federation_state(&self, state: &str) -> Option<FederationState> {
+        let state = session::hash_token(state);
+        let now = Local::now();
+        let mut tables = self.tables();
+        let i = tables
+            .federation_states
+            .iter()
+            .position(|s| s.state == state && s.expires > now)?;
+        let row = tables.federation_states.remove(i);
+        Some(FederationState {
+            provider: row.provider,
+            nonce: row.nonce,
+            code_verifier: row.code_verifier,
+            request: row.request,
+            next: row.next,
+        })
+    }
+
+    async fn find_federated_user(&self, provider: &str, subject: &str) -> Option<Uuid> {
+        let mut tables = self.tables();
+        let identity = tables
+            .identities
+            .iter_mut()
+            .find(|i| i.provider == provider && i.subject == subject)?;
+        identity.last_login = Some(Local::now());
+        Some(identity.user_id)
+    }
+
+    async fn link_federated_identity(&self, provider: &str, subject: &str, user_id: Uuid, email: Option<&str>) {
+        let mut tables = self.tables();
+        if !tables.identities.iter().any(|i| i.provider == provider && i.subject == subject) {
+            tables.identities.push(IdentityRow {
+                provider: provider.to_string(),
+                subject: subject.to_string(),
+                user_id,
+                email: email.map(String::from),
+                created: Local::now(),
+                last_login: Some(Local::now()),
+            });
+        }
+    }
+
+    async fn get_federated_identities(&self, user_id: Uuid) -> Vec<FederatedIdentity> {
+        let tables = self.tables();
+        let mut identities: Vec<FederatedIdentity> = tables
+            .identities
+            .iter()
+            .filter(|i| i.user_id == user_id)
+            .map(|i| FederatedIdentity {
+                provider: i.provider.clone(),
+                subject: i.subject.clone(),
+                email: i.email.clone(),
+                created: i.created.timestamp(),
+                last_login: i.last_login.map(|t| t.timestamp()),
+            })
+            .collect();
+        identities.sort_by(|a, b| (&a.provider, &a.subject).cmp(&(&b.provider, &b.subject)));
+        identities
+    }
+
+    async fn delete_federated_identities(&self, user_id: Uuid, provider: &str) -> bool {
+        let mut tables = self.tables();
+        let before = tables.identities.len();
+        tables.identities.retain(|i| !(i.user_id == user_id && i.provider == provider));
+        tables.identities.len() != before
+    }
+}
@@ -0,0 +1,270 @@
+use crate::handlers::{check_csrf, continue_uri, current_session, form_page, found, get_db_client, redirect_to_login};
+use crate::lockout;
+use crate::models::{AuthorizationParams, ClientInfo, LoginSession, MfaParams, Next, ServerConfig};
+use crate::pages;
+use crate::session;
+use crate::storage::{Database, Storage};
+use crate::totp;
+use chrono::Local;
+use rand::seq::SliceRandom;
+use std::net::IpAddr;
+use std::sync::Arc;
+use url::form_urlencoded;
+use uuid::Uuid;
+use warp::{Rejection, Reply};
+
+// The second login step. After the password the user gives a code from their TOTP authenticator, one of
+// their recovery codes or a passkey, and the login session is marked with amr "otp" or "hwk". A user that
+// has to use MFA but has no second factor yet sets one up here first.
+
+const RECOVERY_CODES: usize = 10;
+// no characters that are easily mistaken for each other
+const RECOVERY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+// RFC 8176 authentication method references
+pub const PASSWORD: &str = "pwd";
+pub const OTP: &str = "otp";
+pub const HARDWARE_KEY: &str = "hwk";
+pub const USER_PRESENCE: &str = "user";
+
+// Whether the user has to give a second factor: the client or the user requires it, or the user has an
+// authenticator and so expects to be asked for it
+pub(crate) async fn required(client: &dyn Storage, client_info: &ClientInfo, user_id: Uuid) -> bool {
+    if client_info.mfa_required {
+        return true;
+    }
+    if client.get_user(user_id).await.is_some_and(|u| u.mfa_required) {
+        return true;
+    }
+    client.get_totp(user_id).await.is_some_and(|t| t.confirmed)
+}
+
+// A passkey counts as a factor on its own and as the second one
+pub(crate) fn verified(login_session: &LoginSession) -> bool {
+    login_session.amr.iter().any(|m| m == OTP || m == HARDWARE_KEY)
+}
+
+// The session's methods and the one just used, how the session started still counts
+pub(crate) fn with_method(login_session: &LoginSession, method: &str) -> Vec<String> {
+    let mut amr = login_session.amr.clone();
+    if !amr.iter().any(|m| m == method) {
+        amr.push(method.to_string());
+    }
+    amr
+}
+
+pub(crate) async fn needs_second_factor(client: &dyn Storage, client_info: &ClientInfo, login_session: &LoginSession) -> bool {
+    !verified(login_session) && required(client, client_info, login_session.user_id).await
+}
+
+// Whether the user set up a second factor, they then have to use it before changing their passkeys
+pub(crate) async fn has_second_factor(client: &dyn Storage, user_id: Uuid) -> bool {
+    client.get_totp(user_id).await.is_some_and(|t| t.confirmed)
+        || !client.get_passkey_ids(user_id).await.is_empty()
+}
+
+pub(crate) fn redirect_to_mfa(request: &AuthorizationParams, next: Option<&str>) -> warp::reply::Response {
+    let mut query = serde_urlencoded::to_string(request).unwrap();
+    if let Some(next) = next {
+        query.push_str("&next=");
+        query.extend(form_urlencoded::byte_serialize(next.as_bytes()));
+    }
+    found(&format!("/oauth2/mfa?{}", query))
+}
+
+// Codes are shown as xxxxx-xxxxx, the dash, spaces and case don't matter when typing one in
+fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let chars: String = (0..10)
+        .map(|_| *RECOVERY_ALPHABET.choose(&mut rng).unwrap() as char)
+        .collect();
+    format!("{}-{}", &chars[..5], &chars[5..])
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    session::hash_token(&normalized)
+}
+
+// An authenticator that isn't confirmed yet keeps its secret, so reloading the page shows the QR code
+// that may already have been scanned
+fn enroll_page(
+    server_config: &ServerConfig,
+    request: &AuthorizationParams,
+    next: Option<&str>,
+    username: &str,
+    secret: &str,
+    error: Option<&str>,
+) -> warp::reply::Response {
+    let uri = totp::provisioning_uri(secret, &server_config.name, username);
+    let qr = totp::qr_svg(&uri);
+    form_page(server_config, |csrf| {
+        pages::mfa_enroll(request, next, csrf, &qr, &uri, secret, error)
+    })
+}
+
+async fn verify_page(
+    client: &dyn Storage,
+    server_config: &ServerConfig,
+    request: &AuthorizationParams,
+    next: Option<&str>,
+    user_id: Uuid,
+    error: Option<&str>,
+) -> warp::reply::Response {
+    let totp = client.get_totp(user_id).await.is_some_and(|t| t.confirmed);
+    let passkeys = !client.get_passkey_ids(user_id).await.is_empty();
+    form_page(server_config, |csrf| pages::mfa(request, next, csrf, totp, passkeys, error))
+}
+
+pub async fn get_mfa(
+    request: AuthorizationParams,
+    next: Next,
+    session: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = get_db_client(&database).await?;
+    let login_session = match current_session(&*client, session).await {
+        Some(login_session) => login_session,
+        None => return Ok(redirect_to_login(&request)),
+    };
+    let user_id = login_session.user_id;
+    let next = next.next.as_deref();
+
+    if has_second_factor(&*client, user_id).await {
+        return Ok(verify_page(&*client, &server_config, &request, next, user_id, None).await);
+    }
+    let secret = match client.get_totp(user_id).await {
+        Some(totp) => totp.secret,
+        None => {
+            let secret = totp::generate_secret();
+            client.set_pending_totp(user_id, &secret).await;
+            secret
+        }
+    };
+    let username = client.get_user(user_id).await.map(|u| u.username).unwrap_or_default();
+    Ok(enroll_page(&server_config, &request, next, &username, &secret, None))
+}
+
+// Checks the code and marks the session. Confirming a new authenticator also hands out the recovery
+// codes, they are only shown this once.
+pub async fn post_mfa(
+    params: MfaParams,
+    session: Option<String>,
+    csrf: Option<String>,
+    client_ip: Option<IpAddr>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    check_csrf(csrf, &params.csrf_token)?;
+    let client = get_db_client(&database).await?;
+    let request = params.request;
+    let next = params.next.as_deref();
+    let login_session = match current_session(&*client, session).await {
+        Some(login_session) => login_session,
+        None => return Ok(redirect_to_login(&request)),
+    };
+    let user_id = login_session.user_id;
+    let amr = with_method(&login_session, OTP);
+
+    let totp = match client.get_totp(user_id).await {
+        Some(totp) => totp,
+        None => return Ok(redirect_to_mfa(&request, next)),
+    };
+
+    if !totp.confirmed {
+        return match totp::verify(&totp.secret, &params.code, Local::now()) {
+            Some(step) => {
+                client.confirm_totp(user_id, step).await;
+                let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| generate_recovery_code()).collect();
+                let hashes: Vec<String> = codes.iter().map(|c| hash_recovery_code(c)).collect();
+                client.set_recovery_codes(user_id, &hashes).await;
+                client.set_session_amr(login_session.id, &amr).await;
+                Ok(warp::reply::html(pages::recovery_codes(&codes, &continue_uri(&request, next))).into_response())
+            }
+            None => {
+                let username = client.get_user(user_id).await.map(|u| u.username).unwrap_or_default();
+                Ok(enroll_page(
+                    &server_config,
+                    &request,
+                    next,
+                    &username,
+                    &totp.secret,
+                    Some("Invalid code, check the time on your device and try again"),
+                ))
+            }
+        };
+    }
+
+    // six digits are guessed quicker than a password, failures count toward the user's lockout
+    let username = client.get_user(user_id).await.map(|u| u.username).unwrap_or_default();
+    let lockout = &server_config.lockout;
+    if lockout::locked(&*client, lockout, &username, client_ip).await {
+        let error = Some("Too many failed attempts, try again later");
+        return Ok(verify_page(&*client, &server_config, &request, next, user_id, error).await);
+    }
+    let accepted = match totp::verify(&totp.secret, &params.code, Local::now()) {
+        Some(step) => client.use_totp_step(user_id, step).await,
+        None => client.use_recovery_code(user_id, &hash_recovery_code(&params.code)).await,
+    };
+    if !accepted {
+        let delay = lockout::failed(&*client, lockout, &username, client_ip).await;
+        let page = verify_page(&*client, &server_config, &request, next, user_id, Some("Invalid code")).await;
+        drop(client);
+        tokio::time::sleep(delay).await;
+        return Ok(page);
+    }
+    lockout::succeeded(&*client, &username).await;
+
+    client.set_session_amr(login_session.id, &amr).await;
+    Ok(found(&continue_uri(&request, next)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::FEDERATED;
+    use crate::memory::Memory;
+    use crate::session::{CSRF_COOKIE, SESSION_COOKIE};
+    use crate::storage::{MfaStore, SessionStore};
+    use crate::testing::{self, add_client, add_user, CLIENT_ID, PASSWORD as USER_PASSWORD, USERNAME};
+
+    #[tokio::test]
+    async fn a_second_factor_adds_to_how_the_session_started() {
+        let mut memory = Memory::new();
+        add_client(&mut memory, CLIENT_ID, &[]).await;
+        let user = add_user(&mut memory, USERNAME, USER_PASSWORD).await;
+        memory.set_pending_totp(user.id, &totp::generate_secret()).await;
+        memory.confirm_totp(user.id, 0).await;
+        memory.set_recovery_codes(user.id, &[hash_recovery_code("abcd-efgh")]).await;
+        let session_hash = session::hash_token("session");
+        memory
+            .create_session(&session_hash, "sid", user.id, &[FEDERATED.to_string()], chrono::Duration::hours(1))
+            .await;
+        let app = testing::app(&memory, testing::config());
+
+        let form = form_urlencoded::Serializer::new(String::new())
+            .append_pair("client_id", CLIENT_ID)
+            .append_pair("response_type", "code")
+            .append_pair("redirect_uri", "http://localhost:3000/callback")
+            .append_pair("code", "abcd-efgh")
+            .append_pair("csrf_token", "csrf")
+            .finish();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/oauth2/mfa")
+            .header("cookie", format!("{}=session; {}=csrf", SESSION_COOKIE, CSRF_COOKIE))
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(form)
+            .reply(&app)
+            .await;
+        assert_eq!(response.status(), 302);
+
+        let login_session = memory.find_session(&session_hash).await.unwrap();
+        assert_eq!(login_session.amr, vec![FEDERATED, OTP]);
+    }
+}
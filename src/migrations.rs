@@ -0,0 +1,127 @@
+use crate::db;
+use crate::errors::Error;
+use deadpool_postgres::Client;
+
+// Schema changes for databases created by an earlier version, in the order they are applied. A change
+// to the schema goes in database_init.sql (used when bootstrapping) and in a new file in migrations/,
+// database_init.sql marks all of these as applied. SQLite databases have their own, see sqlite.rs.
+const MIGRATIONS: &[(&str, &str)] = &[
+    ("0000_baseline", include_str!("../migrations/0000_baseline.sql")),
+    (
+        "0001_client_cascades",
+        include_str!("../migrations/0001_client_cascades.sql"),
+    ),
+    (
+        "0002_email_tokens",
+        include_str!("../migrations/0002_email_tokens.sql"),
+    ),
+    ("0003_mfa", include_str!("../migrations/0003_mfa.sql")),
+    ("0004_passkeys", include_str!("../migrations/0004_passkeys.sql")),
+    (
+        "0005_login_failures",
+        include_str!("../migrations/0005_login_failures.sql"),
+    ),
+    (
+        "0006_client_rate_limit",
+        include_str!("../migrations/0006_client_rate_limit.sql"),
+    ),
+    (
+        "0007_user_attributes",
+        include_str!("../migrations/0007_user_attributes.sql"),
+    ),
+    ("0008_roles", include_str!("../migrations/0008_roles.sql")),
+    (
+        "0009_federation",
+        include_str!("../migrations/0009_federation.sql"),
+    ),
+    (
+        "0010_refresh_token_hash",
+        include_str!("../migrations/0010_refresh_token_hash.sql"),
+    ),
+    ("0011_user_source", include_str!("../migrations/0011_user_source.sql")),
+    (
+        "0012_client_secret_hashes",
+        include_str!("../migrations/0012_client_secret_hashes.sql"),
+    ),
+];
+
+// Applies the migrations the database doesn't have yet, returns their versions
+pub async fn migrate(client: &mut Client) -> Result<Vec<&'static str>, Error> {
+    let applied = db::applied_migrations(client).await?;
+    let mut migrated = Vec::new();
+    for (version, script) in MIGRATIONS {
+        if applied.iter().any(|a| a == version) {
+            continue;
+        }
+        db::apply_migration(client, version, script).await?;
+        migrated.push(*version);
+    }
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use tokio_postgres::NoTls;
+
+    // An empty database on the server of PG.HOST, PG.USER and PG.PASSWORD, the local one without them
+    async fn scratch_database(name: &str) -> Client {
+        let mut config = deadpool_postgres::Config::new();
+        config.host = Some(env::var("PG.HOST").unwrap_or_else(|_| "127.0.0.1".to_string()));
+        config.user = Some(env::var("PG.USER").unwrap_or_else(|_| "postgres".to_string()));
+        config.password = Some(env::var("PG.PASSWORD").unwrap_or_else(|_| "postgres".to_string()));
+        config.dbname = Some("postgres".to_string());
+        let server = config.create_pool(NoTls).unwrap().get().await.unwrap();
+        server.batch_execute(&format!("drop database if exists {}", name)).await.unwrap();
+        server.batch_execute(&format!("create database {}", name)).await.unwrap();
+
+        config.dbname = Some(name.to_string());
+        config.create_pool(NoTls).unwrap().get().await.unwrap()
+    }
+
+    async fn columns(client: &Client) -> Vec<String> {
+        let rows = client
+            .query(
+                "select table_name, column_name, data_type, character_maximum_length, is_nullable
+                 from information_schema.columns where table_schema = 'public' order by table_name, column_name",
+                &[],
+            )
+            .await
+            .unwrap();
+        rows.iter()
+            .map(|row| {
+                let length: Option<i32> = row.get(3);
+                format!("{}.{} {} {:?} {}", row.get::<_, &str>(0), row.get::<_, &str>(1), row.get::<_, &str>(2),
+                    length, row.get::<_, &str>(4))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore] // needs a Postgres server, run with cargo test -- --ignored
+    async fn the_first_schema_is_migrated_to_the_current_one() {
+        let mut migrated = scratch_database("migrations_from_baseline").await;
+        migrated.batch_execute(include_str!("../migrations/baseline_init.sql")).await.unwrap();
+        let versions: Vec<&str> = MIGRATIONS.iter().map(|(version, _)| *version).collect();
+        assert_eq!(migrate(&mut migrated).await.unwrap(), versions);
+        assert!(migrate(&mut migrated).await.unwrap().is_empty());
+
+        // a bootstrapped database has nothing to migrate
+        let mut bootstrapped = scratch_database("migrations_bootstrapped").await;
+        bootstrapped.batch_execute(include_str!("../database_init.sql")).await.unwrap();
+        assert!(migrate(&mut bootstrapped).await.unwrap().is_empty());
+
+        assert_eq!(columns(&migrated).await, columns(&bootstrapped).await);
+
+        // the client logs in with the secret it had
+        let client_id = db::validate_client_credentials(&migrated, "top".to_string(), "top_321".to_string()).await;
+        assert!(client_id.is_some());
+        let secrets = db::get_client_secrets(&migrated, client_id.unwrap()).await;
+        assert_eq!(secrets.iter().map(|s| s.prefix.as_str()).collect::<Vec<_>>(), vec!["top_32"]);
+
+        // the password that was stored as is is marked to be hashed at the next login
+        let (_, password) = db::get_password_hash(&migrated, "test").await.unwrap();
+        assert_eq!(password, "plain$test");
+    }
+}
@@ -1,5 +1,8 @@
+use chrono::{DateTime, Local};
 use config::ConfigError;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
 use tokio_pg_mapper_derive::PostgresMapper;
 use uuid::Uuid;
 
@@ -18,23 +21,442 @@ pub struct TokenParams {
     pub refresh_token: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct AuthorizationParams {
     pub client_id: String,
     pub response_type: String,
     pub redirect_uri: String,
-    pub scope: String,
+    pub scope: Option<String>,
     pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub prompt: Option<String>,
+    pub nonce: Option<String>,
+    pub max_age: Option<String>, // seconds, kept as a string so the request survives being flattened into a form
 }
 
-#[derive(Serialize, Deserialize, PostgresMapper)]
-#[pg_mapper(table = "users")]
-pub struct User {
+// Posted by the consent page, carries the original authorization request along
+#[derive(Deserialize)]
+pub struct ConsentParams {
+    #[serde(flatten)]
+    pub request: AuthorizationParams,
+    pub decision: String,
+    pub csrf_token: String,
+}
+
+// Posted by the second login step, code is a TOTP code or a recovery code
+#[derive(Deserialize)]
+pub struct MfaParams {
+    #[serde(flatten)]
+    pub request: AuthorizationParams,
+    pub code: String,
+    pub csrf_token: String,
+    pub next: Option<String>,
+}
+
+// Posted by the login page, the authorization request is resumed after logging in. next=passkeys goes
+// to the passkeys page first.
+#[derive(Deserialize)]
+pub struct LoginParams {
+    #[serde(flatten)]
+    pub request: AuthorizationParams,
+    pub username: String,
+    pub password: String,
+    pub csrf_token: String,
+    pub next: Option<String>,
+}
+
+// Where the login pages go once the user is logged in, see LoginParams
+#[derive(Deserialize)]
+pub struct Next {
+    pub next: Option<String>,
+}
+
+// The result of navigator.credentials.get(), posted to log in with a passkey or as the second factor.
+// The binary fields are base64url.
+#[derive(Deserialize)]
+pub struct PasskeyAssertion {
+    #[serde(flatten)]
+    pub request: AuthorizationParams,
+    pub csrf_token: String,
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub next: Option<String>,
+}
+
+// The result of navigator.credentials.create()
+#[derive(Deserialize)]
+pub struct PasskeyRegistration {
+    #[serde(flatten)]
+    pub request: AuthorizationParams,
+    pub csrf_token: String,
+    pub name: String,
+    pub client_data_json: String,
+    pub attestation_object: String,
+    pub next: Option<String>,
+}
+
+// id is a string, numbers don't survive serde's flatten in a form
+#[derive(Deserialize)]
+pub struct PasskeyDeletion {
+    #[serde(flatten)]
+    pub request: AuthorizationParams,
+    pub csrf_token: String,
+    pub id: String,
+}
+
+// What a WebAuthn challenge is for, stored in webauthn_challenges.purpose
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengePurpose {
+    Login,
+    SecondFactor,
+    Register,
+}
+
+impl ChallengePurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChallengePurpose::Login => "login",
+            ChallengePurpose::SecondFactor => "second_factor",
+            ChallengePurpose::Register => "register",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChallengeRequest {
+    pub purpose: ChallengePurpose,
+}
+
+pub struct ClientInfo {
+    pub id: Uuid,
+    pub client_id: String,
+    pub display_name: Option<String>,
+    pub redirect_uris: Vec<String>,
+    pub post_logout_redirect_uris: Vec<String>,
+    pub first_party: bool,
+    pub grant_types: Vec<String>,
+    pub access_token_lifetime: Option<i32>,  // seconds
+    pub refresh_token_lifetime: Option<i32>, // seconds
+    pub mfa_required: bool,
+    pub jwt_access_tokens: bool,
+}
+
+// A client as the admin api shows it
+#[derive(Serialize)]
+pub struct ClientDetails {
+    pub client_id: String,
+    pub display_name: Option<String>,
+    pub redirect_uris: Vec<String>,
+    pub post_logout_redirect_uris: Vec<String>,
+    pub frontchannel_logout_uri: Option<String>,
+    pub backchannel_logout_uri: Option<String>,
+    pub grant_types: Vec<String>,
+    pub scopes: Vec<ClientScope>,
+    pub access_token_lifetime: Option<i32>,
+    pub refresh_token_lifetime: Option<i32>,
+    pub first_party: bool,
+    pub mfa_required: bool,
+    pub rate_limit: Option<i32>,
+    pub jwt_access_tokens: bool,
+    pub disabled: bool,
+    pub created: i64,
+    // only in the response to creating the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+// Body of POST /admin/clients, the client_id and secret are generated
+#[derive(Deserialize, Clone)]
+pub struct NewClient {
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub post_logout_redirect_uris: Vec<String>,
+    pub frontchannel_logout_uri: Option<String>,
+    pub backchannel_logout_uri: Option<String>,
+    pub grant_types: Option<Vec<String>>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub default_scopes: Vec<String>,
+    pub access_token_lifetime: Option<i32>,
+    pub refresh_token_lifetime: Option<i32>,
+    #[serde(default)]
+    pub first_party: bool,
+    #[serde(default)]
+    pub mfa_required: bool,
+    pub rate_limit: Option<i32>,
+    #[serde(default)]
+    pub jwt_access_tokens: bool,
+}
+
+// Body of PATCH /admin/clients/{client_id}, only the given fields change. Scopes are replaced as a whole.
+#[derive(Deserialize, Clone)]
+pub struct ClientUpdate {
+    pub display_name: Option<String>,
+    pub redirect_uris: Option<Vec<String>>,
+    pub post_logout_redirect_uris: Option<Vec<String>>,
+    pub frontchannel_logout_uri: Option<String>,
+    pub backchannel_logout_uri: Option<String>,
+    pub grant_types: Option<Vec<String>>,
+    pub scopes: Option<Vec<String>>,
+    pub default_scopes: Option<Vec<String>>,
+    pub access_token_lifetime: Option<i32>,
+    pub refresh_token_lifetime: Option<i32>,
+    pub first_party: Option<bool>,
+    pub mfa_required: Option<bool>,
+    pub rate_limit: Option<i32>,
+    pub jwt_access_tokens: Option<bool>,
+}
+
+// Body of POST /admin/clients/{client_id}/secrets. previous_expires_in phases out the secrets the client
+// already has, in seconds from now.
+#[derive(Deserialize)]
+pub struct SecretRequest {
+    pub expires_in: Option<i64>,
+    pub previous_expires_in: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct NewClientSecret {
+    #[serde(flatten)]
+    pub metadata: ClientSecret,
+    pub client_secret: String,
+}
+
+// An active token of a client, without the token itself
+#[derive(Serialize)]
+pub struct TokenInfo {
     pub id: i32,
+    pub user_id: Option<Uuid>,
     pub username: Option<String>,
+    pub scope: Option<String>,
+    pub device: String,
+    pub created: i64,
+    pub expires: i64,
+    pub refresh_token: bool,
+}
+
+// RP-initiated logout, see OpenID Connect RP-Initiated Logout section 2
+#[derive(Deserialize)]
+pub struct EndSessionParams {
+    pub id_token_hint: Option<String>,
+    pub post_logout_redirect_uri: Option<String>,
+    pub client_id: Option<String>,
+    pub state: Option<String>,
+}
+
+// A client a login session was used for and how to tell it the session ended
+pub struct LogoutClient {
+    pub client_id: String,
+    pub frontchannel_logout_uri: Option<String>,
+    pub backchannel_logout_uri: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct AuthorizationCode {
+    pub user_id: Uuid,
+    pub scope: Option<String>,
+    pub auth_time: Option<DateTime<Local>>,
+    pub amr: Vec<String>,
+    pub nonce: Option<String>,
+    pub sid: Option<String>,
+}
+
+// auth_time is when the user actually entered their credentials, sessions are reused after that
+pub struct LoginSession {
+    pub id: i32,
+    pub sid: String, // identifies the session towards clients, unlike the session token it is not a secret
+    pub user_id: Uuid,
+    pub auth_time: DateTime<Local>,
+    // how the user logged in, RFC 8176 values: pwd, otp
+    pub amr: Vec<String>,
+}
+
+// Where a user's password is checked, stored in users.source
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UserSource {
+    Local,
+    Directory,
+}
+
+impl UserSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserSource::Local => "local",
+            UserSource::Directory => "directory",
+        }
+    }
+
+    pub fn parse(source: &str) -> Self {
+        match source {
+            "directory" => UserSource::Directory,
+            _ => UserSource::Local,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct User {
+    pub id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub mfa_required: bool,
+    pub disabled: bool,
+    pub created: i64,
+}
+
+// An account at an upstream provider the user logs in with
+#[derive(Serialize)]
+pub struct FederatedIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created: i64,
+    pub last_login: Option<i64>,
+}
+
+// Where an upstream provider sends the user back to, with a code or an error
+#[derive(Deserialize)]
+pub struct FederationCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+// A login at an upstream provider that is under way, request is the query of the authorization request
+#[derive(Clone)]
+pub struct FederationState {
+    pub provider: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub request: String,
+    pub next: Option<String>,
+}
+
+// Filters and paging for GET /admin/users, username and email match on a part of the value
+#[derive(Deserialize)]
+pub struct UserQuery {
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UserList {
+    pub users: Vec<User>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+// Body of POST /admin/users, users created by an admin are verified unless said otherwise
+#[derive(Deserialize)]
+pub struct NewUser {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+    pub email_verified: Option<bool>,
+}
+
+// Body of PATCH /admin/users/{id}, only the given fields change
+#[derive(Deserialize, Clone)]
+pub struct UserUpdate {
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub mfa_required: Option<bool>,
+}
+
+// Body of POST /admin/users/{id}/reset_password, without a password one is generated and returned
+#[derive(Deserialize, Serialize)]
+pub struct PasswordReset {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub password: Option<String>,
 }
 
+// Sent to /register as json, the form adds a csrf token
+#[derive(Deserialize)]
+pub struct RegistrationParams {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+    pub invitation: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RegistrationForm {
+    #[serde(flatten)]
+    pub registration: RegistrationParams,
+    pub csrf_token: String,
+}
+
+// A user's TOTP authenticator, it only counts once the user confirmed it with a first code
+pub struct TotpCredential {
+    pub secret: String,
+    pub confirmed: bool,
+}
+
+// A passkey as listed to its user and the admin api
+#[derive(Serialize)]
+pub struct Passkey {
+    pub id: i32,
+    pub name: String,
+    pub created: i64,
+    pub last_used: Option<i64>,
+}
+
+// A passkey with what it takes to check a login
+pub struct PasskeyCredential {
+    pub id: i32,
+    pub user_id: Uuid,
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+}
+
+// What a token sent by mail can be used for, stored in email_tokens.purpose
+#[derive(Clone, Copy)]
+pub enum EmailTokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl EmailTokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmailTokenPurpose::VerifyEmail => "verify_email",
+            EmailTokenPurpose::ResetPassword => "reset_password",
+        }
+    }
+}
+
+// The token from a link in a mail
+#[derive(Deserialize)]
+pub struct EmailTokenQuery {
+    pub token: String,
+}
+
+// The forms asking for a verification or password reset mail
+#[derive(Deserialize)]
+pub struct EmailForm {
+    pub email: String,
+    pub csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordResetForm {
+    pub token: String,
+    pub password: String,
+    pub csrf_token: String,
+}
+
 #[derive(Serialize, Deserialize, PostgresMapper)]
 #[pg_mapper(table = "access_tokens")]
 pub struct AccessToken {
@@ -42,6 +464,94 @@ pub struct AccessToken {
     pub token_type: String,
     pub scope: Option<String>,
     pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+// See OpenID Connect core section 2
+#[derive(Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub amr: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    // the user's attributes the granted scopes release
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+// See OpenID Connect Back-Channel Logout section 2.4
+#[derive(Serialize)]
+pub struct LogoutTokenClaims {
+    pub iss: String,
+    pub aud: String,
+    pub iat: i64,
+    pub jti: String,
+    pub sub: String,
+    pub sid: String,
+    pub events: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "scopes")]
+pub struct Scope {
+    pub name: String,
+    pub description: Option<String>,
+    pub requires_consent: bool,
+}
+
+// What is shown of a client secret after it was created, the secret itself is not stored
+#[derive(Serialize, Clone)]
+pub struct ClientSecret {
+    pub id: i32,
+    pub prefix: String,
+    pub created: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<i64>,
+}
+
+// A scope a client is allowed to request, default scopes are granted when the request has none
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClientScope {
+    pub name: String,
+    pub is_default: bool,
+}
+
+// Rule saying that holding a scope implies another, parameters carry over: projects:write:{project} implies projects:read:{project}
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScopeRule {
+    pub scope: String,
+    pub implies: String,
+}
+
+// Everything needed to decide which scopes a client, possibly on behalf of a user, can get
+pub struct ScopePolicy {
+    pub allowed: Vec<ClientScope>,
+    pub grants: Vec<String>,
+    pub rules: Vec<ScopeRule>,
+}
+
+// What an access token is issued for, also what a refresh token resolves back to
+pub struct TokenGrant {
+    pub user_id: Option<Uuid>,
+    pub client_id: Uuid,
+    pub scope: Option<String>,
+    pub device: Option<String>,
+    pub auth_time: Option<DateTime<Local>>,
+    pub amr: Vec<String>,
+    pub nonce: Option<String>,
+    pub sid: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -51,10 +561,113 @@ pub struct Introspection {
     pub username: Option<String>,
     pub user_id: Option<Uuid>,
     pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_scope: Option<String>,
     pub token_type: String,
     pub issuer: String,
     pub exp: i64,
     pub iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub amr: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub roles: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub groups: Vec<String>,
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+// RFC 9068, an access token of a client with jwt_access_tokens. The audience is the client, there are no
+// resource indicators. sub is the client for client_credentials tokens.
+#[derive(Serialize)]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub client_id: String,
+    pub exp: i64,
+    pub iat: i64,
+    pub jti: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub amr: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+// See OpenID Connect core section 5.3.2, sub and the claims the token's scopes release
+#[derive(Serialize)]
+pub struct UserInfo {
+    pub sub: String,
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+// A custom attribute users can have, see attributes.rs. The name comes from the path when it is defined.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AttributeDefinition {
+    #[serde(default)]
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub description: Option<String>,
+    pub max_length: Option<i32>,
+    pub pattern: Option<String>,
+    pub scope: Option<String>,
+}
+
+// A global role, or one of the client with client_id. See roles.rs
+#[derive(Serialize, Clone)]
+pub struct Role {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    pub description: Option<String>,
+}
+
+// Body of PUT for a role or a group
+#[derive(Deserialize)]
+pub struct RoleDescription {
+    pub description: Option<String>,
+}
+
+// Names a client role where a global one is the default, ?client_id=
+#[derive(Deserialize)]
+pub struct RoleQuery {
+    pub client_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Group {
+    pub name: String,
+    pub description: Option<String>,
+    pub roles: Vec<Role>,
+    // only when a single group is shown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub members: Option<Vec<GroupMember>>,
+}
+
+#[derive(Serialize)]
+pub struct GroupMember {
+    pub user_id: Uuid,
+    pub username: String,
+}
+
+// The roles assigned to the user, their groups and all roles they have through either
+#[derive(Serialize)]
+pub struct UserRoles {
+    pub roles: Vec<Role>,
+    pub groups: Vec<String>,
+    pub effective: Vec<Role>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -63,13 +676,326 @@ pub struct ServerConfig {
     pub port: u16,
     pub name: String,
     pub cert_dir: String,
+    #[serde(default = "default_secure_cookies")]
+    pub secure_cookies: bool,
+    #[serde(default = "default_session_lifetime")]
+    pub session_lifetime: i64, // seconds
+    #[serde(default)]
+    pub password: PasswordConfig,
+    #[serde(default)]
+    pub registration: RegistrationConfig,
+    // the scope a token needs for the /admin api
+    #[serde(default = "default_admin_scope")]
+    pub admin_scope: String,
+    // where users reach the server, links in mails point here. Defaults to http://{host}:{port}
+    pub public_url: Option<String>,
+    #[serde(default)]
+    pub mail: MailConfig,
+    #[serde(default)]
+    pub webauthn: WebauthnConfig,
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    // take the client address from X-Forwarded-For, only when a proxy in front of the server sets it
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    // OpenID Connect providers users can log in with, by name
+    #[serde(default)]
+    pub upstream: HashMap<String, UpstreamConfig>,
+    // a directory users log in with next to the users table
+    pub ldap: Option<LdapConfig>,
+}
+
+impl ServerConfig {
+    pub fn public_url(&self) -> String {
+        match &self.public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("http://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+// How mails are sent: "stdout" prints them, "file" writes them to dir and "smtp" uses the smtp settings
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MailConfig {
+    pub transport: String,
+    pub from: String,
+    pub dir: String,
+    pub smtp_host: String,
+    pub smtp_port: Option<u16>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    // "starttls", "tls" or "none"
+    pub smtp_security: String,
+    // seconds the links in verification and password reset mails can be used
+    pub verification_lifetime: i64,
+    pub reset_lifetime: i64,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            transport: "stdout".to_string(),
+            from: "noreply@localhost".to_string(),
+            dir: "mail".to_string(),
+            smtp_host: "localhost".to_string(),
+            smtp_port: None,
+            smtp_username: None,
+            smtp_password: None,
+            smtp_security: "starttls".to_string(),
+            verification_lifetime: 60 * 60 * 24,
+            reset_lifetime: 60 * 60,
+        }
+    }
+}
+
+fn default_admin_scope() -> String {
+    "admin".to_string()
+}
+
+// An upstream OpenID Connect provider, set with SERVER.UPSTREAM.{NAME}.*. The login page links to it and
+// its users get an account here the first time they log in, see federation.rs.
+#[derive(Deserialize, Debug, Clone)]
+pub struct UpstreamConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    // on the login page, the name by default
+    pub display_name: Option<String>,
+    #[serde(default = "default_upstream_scope")]
+    pub scope: String,
+    // the endpoints are discovered at {issuer}/.well-known/openid-configuration unless they are set
+    pub authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+    pub jwks_uri: Option<String>,
+    // the claim a new user's username comes from, the email address when it's missing or taken
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    // create users that don't have an account yet
+    #[serde(default = "default_true")]
+    pub provision: bool,
+    // link the account with the same verified email address the first time
+    #[serde(default = "default_true")]
+    pub link_by_email: bool,
+    // treat email addresses as verified when the provider doesn't send email_verified
+    #[serde(default)]
+    pub trust_email: bool,
+    // user attribute name to the claim it is copied from at every login
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+fn default_upstream_scope() -> String {
+    "openid email profile".to_string()
+}
+
+fn default_username_claim() -> String {
+    "preferred_username".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// An LDAP directory or Active Directory, set with SERVER.LDAP.*. Users that came from the directory or
+// aren't in the users table yet log in with their directory password, see ldap.rs.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LdapConfig {
+    // ldap://host:389 or ldaps://host:636
+    pub url: String,
+    // upgrade an ldap:// connection with StartTLS
+    #[serde(default)]
+    pub starttls: bool,
+    // the account users are looked up with, an anonymous search without it
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    // where the users are, like ou=people,dc=example,dc=com
+    pub base_dn: String,
+    // sAMAccountName for Active Directory
+    #[serde(default = "default_ldap_username_attribute")]
+    pub username_attribute: String,
+    // what else users have to match, like (objectClass=person)
+    pub user_filter: Option<String>,
+    #[serde(default = "default_ldap_email_attribute")]
+    pub email_attribute: String,
+    // the dns of the user's groups, the cn of each is the group's name
+    #[serde(default = "default_ldap_group_attribute")]
+    pub group_attribute: String,
+    // user attribute name to the directory attribute it is copied from at every login
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    #[serde(default = "default_ldap_timeout")]
+    pub timeout: u64, // seconds
+}
+
+fn default_ldap_username_attribute() -> String {
+    "uid".to_string()
+}
+
+fn default_ldap_email_attribute() -> String {
+    "mail".to_string()
+}
+
+fn default_ldap_group_attribute() -> String {
+    "memberOf".to_string()
+}
+
+fn default_ldap_timeout() -> u64 {
+    5
+}
+
+// An SQLite database file instead of Postgres, set with SQLITE.PATH. See sqlite.rs.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SqliteConfig {
+    // created with the tables when it doesn't exist
+    pub path: String,
+    // how long a query waits for a write of another process, like the command line
+    #[serde(default = "default_sqlite_busy_timeout")]
+    pub busy_timeout: u64, // milliseconds
+}
+
+fn default_sqlite_busy_timeout() -> u64 {
+    5000
+}
+
+// Passkeys are bound to the rp id, a domain. Defaults to the host of the public url, set it to a parent
+// domain to share passkeys with other sites under it.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct WebauthnConfig {
+    pub rp_id: Option<String>,
+}
+
+// Slowing down password guessing. Failed logins are counted per username and per client address, after
+// delay_after failures of a username every further one is answered later, doubling up to max_delay.
+// Reaching a threshold locks password logins for the username or address for lock_duration, a
+// threshold of 0 turns that lock off.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LockoutConfig {
+    pub user_threshold: i32,
+    pub ip_threshold: i32,
+    pub lock_duration: i64, // seconds
+    // failures are forgotten this long after the last one
+    pub window: i64, // seconds
+    pub delay_after: i32,
+    pub max_delay: u64, // seconds
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            user_threshold: 10,
+            ip_threshold: 100,
+            lock_duration: 15 * 60,
+            window: 15 * 60,
+            delay_after: 3,
+            max_delay: 8,
+        }
+    }
+}
+
+// Token bucket rate limits in requests per minute, for each client and each client address on a route.
+// A bucket holds a minute's worth of requests, 0 turns a limit off. A client's rate_limit replaces the
+// client limit of every route. Client limits need the client's credentials, so authorize only has the
+// address limit.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub token: RouteLimits,
+    pub introspect: RouteLimits,
+    pub authorize: RouteLimits,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            token: RouteLimits::default(),
+            // resource servers introspect every request they get
+            introspect: RouteLimits { client: 3000, ip: 3000 },
+            authorize: RouteLimits { client: 0, ip: 60 },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RouteLimits {
+    pub client: u32,
+    pub ip: u32,
+}
+
+impl Default for RouteLimits {
+    fn default() -> Self {
+        RouteLimits { client: 600, ip: 300 }
+    }
+}
+
+// Self-service registration at /register, off unless enabled
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RegistrationConfig {
+    pub enabled: bool,
+    // new users can't log in until their email address is verified
+    pub email_verification: bool,
+    // registering needs an invitation code from the invitations table
+    pub invitation: bool,
+}
+
+// Argon2id cost parameters for new password hashes, the defaults follow the OWASP recommendation
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PasswordConfig {
+    pub memory_cost: u32, // KiB
+    pub time_cost: u32,
+    pub parallelism: u32,
+    // policy for new passwords, in characters
+    pub min_length: usize,
+    pub max_length: usize,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        PasswordConfig {
+            memory_cost: 19 * 1024,
+            time_cost: 2,
+            parallelism: 1,
+            min_length: 8,
+            max_length: 128,
+        }
+    }
+}
+
+fn default_secure_cookies() -> bool {
+    true
+}
+
+fn default_session_lifetime() -> i64 {
+    60 * 60 * 8
+}
+
+// A tenant is served for requests to its host, see tenants.rs. TENANTS.{NAME}.HOST sets it and
+// TENANTS.{NAME}.SERVER.* change the server settings for the tenant.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TenantConfig {
+    pub host: String,
+    // the database schema with the tenant's tables, tenant_{name} by default
+    pub schema: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub server: ServerConfig,
     pub bootstrap: bool,
+    #[serde(default)]
     pub pg: deadpool_postgres::Config,
+    pub sqlite: Option<SqliteConfig>,
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantConfig>,
 }
 
 impl Config {
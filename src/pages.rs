@@ -0,0 +1,534 @@
+use crate::models::{AuthorizationParams, Passkey, UpstreamConfig};
+use chrono::{Local, TimeZone};
+use std::collections::HashMap;
+
+// Pages the server renders itself. Kept as plain format strings, there are only a handful of them.
+
+pub fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#x27;")
+}
+
+fn layout(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>{title}</title>
+  <style>
+    body {{ font-family: sans-serif; background: #f4f4f4; }}
+    main {{ max-width: 420px; margin: 60px auto; background: #fff; padding: 24px 32px; border-radius: 6px; }}
+    li {{ margin: 6px 0; }}
+    input[type=text], input[type=password], input[type=email] {{ width: 100%; padding: 8px; margin: 4px 0 12px; box-sizing: border-box; }}
+    button {{ padding: 8px 16px; margin-right: 8px; }}
+    .error {{ color: #b00020; }}
+  </style>
+</head>
+<body>
+<main>
+{body}
+</main>
+</body>
+</html>"#,
+        title = escape(title),
+        body = body
+    )
+}
+
+fn optional(value: &Option<String>) -> String {
+    escape(value.as_deref().unwrap_or(""))
+}
+
+// Carries the pending authorization request through a form post
+pub fn authorization_fields(request: &AuthorizationParams) -> String {
+    let mut fields = format!(
+        r#"<input type="hidden" name="client_id" value="{}">
+<input type="hidden" name="response_type" value="{}">
+<input type="hidden" name="redirect_uri" value="{}">
+"#,
+        escape(&request.client_id),
+        escape(&request.response_type),
+        escape(&request.redirect_uri)
+    );
+    for (name, value) in [
+        ("scope", &request.scope),
+        ("state", &request.state),
+        ("code_challenge", &request.code_challenge),
+        ("prompt", &request.prompt),
+        ("nonce", &request.nonce),
+        ("max_age", &request.max_age),
+    ] {
+        if value.is_some() {
+            fields.push_str(&format!(
+                "<input type=\"hidden\" name=\"{}\" value=\"{}\">\n",
+                name,
+                optional(value)
+            ));
+        }
+    }
+    fields
+}
+
+// Lists what the client asks for, scopes are (scope, description) pairs
+pub fn consent(
+    client_name: &str,
+    scopes: &[(String, String)],
+    request: &AuthorizationParams,
+    csrf_token: &str,
+) -> String {
+    let items: String = scopes
+        .iter()
+        .map(|(scope, description)| {
+            format!(
+                "<li><strong>{}</strong><br><small>{}</small></li>\n",
+                escape(description),
+                escape(scope)
+            )
+        })
+        .collect();
+
+    let body = format!(
+        r#"<h2>{client} wants to access your account</h2>
+<p>This will allow {client} to:</p>
+<ul>
+{items}</ul>
+<form method="post" action="/oauth2/consent">
+<input type="hidden" name="csrf_token" value="{csrf}">
+{fields}<button type="submit" name="decision" value="allow">Allow</button>
+<button type="submit" name="decision" value="deny">Deny</button>
+</form>"#,
+        client = escape(client_name),
+        items = items,
+        csrf = escape(csrf_token),
+        fields = authorization_fields(request)
+    );
+    layout("Authorize", &body)
+}
+
+// Runs the WebAuthn ceremonies in the browser. The options come from /oauth2/passkey/challenge, the
+// result is put in the hidden fields of the form, which is then posted.
+const PASSKEY_SCRIPT: &str = r#"<script>
+function b64url(buffer) {
+  return btoa(String.fromCharCode.apply(null, new Uint8Array(buffer)))
+    .replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+}
+function unb64url(value) {
+  return Uint8Array.from(atob(value.replace(/-/g, '+').replace(/_/g, '/')), function (c) { return c.charCodeAt(0); });
+}
+async function passkeyOptions(purpose) {
+  const response = await fetch('/oauth2/passkey/challenge', {
+    method: 'POST', headers: {'Content-Type': 'application/json'}, body: JSON.stringify({purpose: purpose})
+  });
+  if (!response.ok) throw new Error('no challenge');
+  const options = await response.json();
+  options.challenge = unb64url(options.challenge);
+  if (options.user) options.user.id = unb64url(options.user.id);
+  (options.allowCredentials || []).concat(options.excludeCredentials || []).forEach(function (c) { c.id = unb64url(c.id); });
+  return options;
+}
+function passkeyFailed() {
+  document.getElementById('passkey-error').textContent = 'The passkey could not be used.';
+}
+async function passkeyGet(form, purpose) {
+  try {
+    const credential = await navigator.credentials.get({publicKey: await passkeyOptions(purpose)});
+    form.elements.credential_id.value = b64url(credential.rawId);
+    form.elements.client_data_json.value = b64url(credential.response.clientDataJSON);
+    form.elements.authenticator_data.value = b64url(credential.response.authenticatorData);
+    form.elements.signature.value = b64url(credential.response.signature);
+    form.submit();
+  } catch (e) { passkeyFailed(); }
+}
+async function passkeyCreate(form) {
+  try {
+    const credential = await navigator.credentials.create({publicKey: await passkeyOptions('register')});
+    form.elements.client_data_json.value = b64url(credential.response.clientDataJSON);
+    form.elements.attestation_object.value = b64url(credential.response.attestationObject);
+    form.submit();
+  } catch (e) { passkeyFailed(); }
+}
+</script>"#;
+
+fn error_line(error: Option<&str>) -> String {
+    match error {
+        Some(e) => format!("<p class=\"error\">{}</p>\n", escape(e)),
+        None => String::new(),
+    }
+}
+
+fn next_field(next: Option<&str>) -> String {
+    match next {
+        Some(next) => format!("<input type=\"hidden\" name=\"next\" value=\"{}\">\n", escape(next)),
+        None => String::new(),
+    }
+}
+
+// A button that signs a challenge with a passkey and posts the result to action
+fn passkey_form(action: &str, purpose: &str, label: &str, request: &AuthorizationParams, next: Option<&str>, csrf_token: &str) -> String {
+    format!(
+        r#"<form method="post" action="{action}">
+<input type="hidden" name="csrf_token" value="{csrf}">
+{fields}{next}<input type="hidden" name="credential_id">
+<input type="hidden" name="client_data_json">
+<input type="hidden" name="authenticator_data">
+<input type="hidden" name="signature">
+<button type="button" onclick="passkeyGet(this.form, '{purpose}')">{label}</button>
+</form>
+<p class="error" id="passkey-error"></p>
+"#,
+        action = escape(action),
+        csrf = escape(csrf_token),
+        fields = authorization_fields(request),
+        next = next_field(next),
+        purpose = escape(purpose),
+        label = escape(label)
+    )
+}
+
+// A form that registers a new passkey, next is where to go afterwards
+fn register_passkey_form(request: &AuthorizationParams, next: Option<&str>, csrf_token: &str) -> String {
+    format!(
+        r#"<form method="post" action="/oauth2/passkeys">
+<input type="hidden" name="csrf_token" value="{csrf}">
+{fields}{next}<input type="hidden" name="client_data_json">
+<input type="hidden" name="attestation_object">
+<label for="name">Name</label>
+<input type="text" id="name" name="name" placeholder="My laptop" maxlength="100">
+<button type="button" onclick="passkeyCreate(this.form)">Add a passkey</button>
+</form>
+<p class="error" id="passkey-error"></p>
+"#,
+        csrf = escape(csrf_token),
+        fields = authorization_fields(request),
+        next = next_field(next)
+    )
+}
+
+// Links to the upstream providers, they carry the authorization request along
+fn upstream_links(request: &AuthorizationParams, next: Option<&str>, upstream: &HashMap<String, UpstreamConfig>) -> String {
+    let mut query = serde_urlencoded::to_string(request).unwrap();
+    if let Some(next) = next {
+        query.push('&');
+        query.push_str(&serde_urlencoded::to_string([("next", next)]).unwrap());
+    }
+    let mut providers: Vec<(&String, &UpstreamConfig)> = upstream.iter().collect();
+    providers.sort_by_key(|(name, _)| *name);
+    providers
+        .iter()
+        .map(|(name, config)| {
+            format!(
+                "<p><a href=\"/oauth2/federated/{}?{}\">Log in with {}</a></p>\n",
+                escape(name),
+                escape(&query),
+                escape(config.display_name.as_deref().unwrap_or(name))
+            )
+        })
+        .collect()
+}
+
+pub fn login(
+    request: &AuthorizationParams,
+    next: Option<&str>,
+    csrf_token: &str,
+    upstream: &HashMap<String, UpstreamConfig>,
+    error: Option<&str>,
+) -> String {
+    let query = serde_urlencoded::to_string(request).unwrap();
+    let body = format!(
+        r#"<h2>Log in</h2>
+{error}<form method="post" action="/oauth2/login">
+<input type="hidden" name="csrf_token" value="{csrf}">
+{fields}{next}<label for="username">Username</label>
+<input type="text" id="username" name="username" autocomplete="username webauthn" autofocus required>
+<label for="password">Password</label>
+<input type="password" id="password" name="password" autocomplete="current-password" required>
+<button type="submit">Log in</button>
+</form>
+{passkey}{upstream}<p><a href="/forgot_password">Forgot your password?</a> · <a href="/oauth2/passkeys?{query}">Manage passkeys</a></p>
+{script}"#,
+        error = error_line(error),
+        csrf = escape(csrf_token),
+        fields = authorization_fields(request),
+        next = next_field(next),
+        passkey = passkey_form("/oauth2/passkey/login", "login", "Log in with a passkey", request, next, csrf_token),
+        upstream = upstream_links(request, next, upstream),
+        query = escape(&query),
+        script = PASSKEY_SCRIPT
+    );
+    layout("Log in", &body)
+}
+
+fn code_field(error: Option<&str>) -> String {
+    format!(
+        r#"{error}<label for="code">Code</label>
+<input type="text" id="code" name="code" inputmode="numeric" autocomplete="one-time-code" autofocus required>
+"#,
+        error = error_line(error)
+    )
+}
+
+// The second login step, a code from the authenticator, a recovery code or a passkey, depending on what
+// the user set up
+pub fn mfa(
+    request: &AuthorizationParams,
+    next: Option<&str>,
+    csrf_token: &str,
+    totp: bool,
+    passkeys: bool,
+    error: Option<&str>,
+) -> String {
+    let code = if totp {
+        format!(
+            r#"<p>Enter the code from your authenticator app. If you lost it, you can use one of your recovery codes.</p>
+<form method="post" action="/oauth2/mfa">
+<input type="hidden" name="csrf_token" value="{csrf}">
+{fields}{next}{code}<button type="submit">Verify</button>
+</form>
+"#,
+            csrf = escape(csrf_token),
+            fields = authorization_fields(request),
+            next = next_field(next),
+            code = code_field(error)
+        )
+    } else {
+        error_line(error)
+    };
+    let passkey = if passkeys {
+        passkey_form("/oauth2/mfa/passkey", "second_factor", "Use a passkey", request, next, csrf_token)
+    } else {
+        String::new()
+    };
+    let body = format!(
+        "<h2>Two-step verification</h2>\n{}{}{}",
+        code, passkey, PASSKEY_SCRIPT
+    );
+    layout("Two-step verification", &body)
+}
+
+// Setting up a second factor: an authenticator app, qr is an svg the server generated itself, or a passkey
+pub fn mfa_enroll(
+    request: &AuthorizationParams,
+    next: Option<&str>,
+    csrf_token: &str,
+    qr: &str,
+    uri: &str,
+    secret: &str,
+    error: Option<&str>,
+) -> String {
+    let body = format!(
+        r#"<h2>Set up two-step verification</h2>
+<p>Scan this QR code with your authenticator app, or <a href="{uri}">open it on this device</a>.</p>
+{qr}
+<p>Can't scan it? Enter this key: <code>{secret}</code></p>
+<p>Then enter the code the app shows to finish.</p>
+<form method="post" action="/oauth2/mfa">
+<input type="hidden" name="csrf_token" value="{csrf}">
+{fields}{next}{code}<button type="submit">Verify</button>
+</form>
+<h3>Or use a passkey</h3>
+{passkey}{script}"#,
+        uri = escape(uri),
+        qr = qr,
+        secret = escape(secret),
+        csrf = escape(csrf_token),
+        fields = authorization_fields(request),
+        next = next_field(next),
+        code = code_field(error),
+        passkey = register_passkey_form(request, next, csrf_token),
+        script = PASSKEY_SCRIPT
+    );
+    layout("Set up two-step verification", &body)
+}
+
+// The user's passkeys with a button to remove each and a form to add one
+pub fn passkeys(request: &AuthorizationParams, csrf_token: &str, passkeys: &[Passkey], error: Option<&str>) -> String {
+    let date = |timestamp: i64| Local.timestamp_opt(timestamp, 0).unwrap().format("%Y-%m-%d").to_string();
+    let items: String = passkeys
+        .iter()
+        .map(|passkey| {
+            format!(
+                r#"<li><form method="post" action="/oauth2/passkeys/delete">
+<input type="hidden" name="csrf_token" value="{csrf}">
+{fields}<input type="hidden" name="id" value="{id}">
+<strong>{name}</strong><br><small>Added {created}, last used {last_used}</small>
+<button type="submit">Remove</button>
+</form></li>
+"#,
+                csrf = escape(csrf_token),
+                fields = authorization_fields(request),
+                id = passkey.id,
+                name = escape(&passkey.name),
+                created = date(passkey.created),
+                last_used = passkey.last_used.map(date).unwrap_or_else(|| "never".to_string())
+            )
+        })
+        .collect();
+    let list = if items.is_empty() {
+        "<p>You don't have any passkeys yet.</p>\n".to_string()
+    } else {
+        format!("<ul>\n{}</ul>\n", items)
+    };
+
+    let body = format!(
+        r#"<h2>Passkeys</h2>
+<p>With a passkey you log in with your device's screen lock instead of your password.</p>
+{error}{list}{register}<p><a href="/oauth2/authorize?{query}">Continue</a></p>
+{script}"#,
+        error = error_line(error),
+        list = list,
+        register = register_passkey_form(request, Some("passkeys"), csrf_token),
+        query = escape(&serde_urlencoded::to_string(request).unwrap()),
+        script = PASSKEY_SCRIPT
+    );
+    layout("Passkeys", &body)
+}
+
+pub fn recovery_codes(codes: &[String], continue_uri: &str) -> String {
+    let items: String = codes
+        .iter()
+        .map(|code| format!("<li><code>{}</code></li>\n", escape(code)))
+        .collect();
+
+    let body = format!(
+        r#"<h2>Save your recovery codes</h2>
+<p>Each of these codes lets you log in once without your authenticator app. Keep them somewhere safe, they won't be shown again.</p>
+<ul>
+{items}</ul>
+<p><a href="{href}">Continue</a></p>"#,
+        items = items,
+        href = escape(continue_uri)
+    );
+    layout("Recovery codes", &body)
+}
+
+// The registration form, values are filled in again when the form comes back with an error
+pub fn register(
+    csrf_token: &str,
+    invitation: bool,
+    error: Option<&str>,
+    username: &str,
+    email: &str,
+) -> String {
+    let error = match error {
+        Some(e) => format!("<p class=\"error\">{}</p>\n", escape(e)),
+        None => String::new(),
+    };
+    let invitation = if invitation {
+        r#"<label for="invitation">Invitation code</label>
+<input type="text" id="invitation" name="invitation" autocomplete="off" required>
+"#
+    } else {
+        ""
+    };
+
+    let body = format!(
+        r#"<h2>Create an account</h2>
+{error}<form method="post" action="/register">
+<input type="hidden" name="csrf_token" value="{csrf}">
+<label for="username">Username</label>
+<input type="text" id="username" name="username" value="{username}" autocomplete="username" autofocus required>
+<label for="email">Email</label>
+<input type="email" id="email" name="email" value="{email}" autocomplete="email" required>
+<label for="password">Password</label>
+<input type="password" id="password" name="password" autocomplete="new-password" required>
+{invitation}<button type="submit">Register</button>
+</form>"#,
+        error = error,
+        csrf = escape(csrf_token),
+        username = escape(username),
+        email = escape(email),
+        invitation = invitation
+    );
+    layout("Register", &body)
+}
+
+pub fn registered(email_verification: bool) -> String {
+    let message = if email_verification {
+        "Your account has been created. Verify your email address before logging in."
+    } else {
+        "Your account has been created, you can log in now."
+    };
+    layout(
+        "Registered",
+        &format!("<h2>Welcome</h2>\n<p>{}</p>", message),
+    )
+}
+
+// A page that only tells the user something
+pub fn notice(title: &str, message: &str) -> String {
+    layout(
+        title,
+        &format!("<h2>{}</h2>\n<p>{}</p>", escape(title), escape(message)),
+    )
+}
+
+// Asks for an email address to send a mail to, used for forgotten passwords and new verification mails
+pub fn email_form(action: &str, title: &str, intro: &str, csrf_token: &str) -> String {
+    let body = format!(
+        r#"<h2>{title}</h2>
+<p>{intro}</p>
+<form method="post" action="{action}">
+<input type="hidden" name="csrf_token" value="{csrf}">
+<label for="email">Email</label>
+<input type="email" id="email" name="email" autocomplete="email" autofocus required>
+<button type="submit">Send</button>
+</form>"#,
+        title = escape(title),
+        intro = escape(intro),
+        action = escape(action),
+        csrf = escape(csrf_token)
+    );
+    layout(title, &body)
+}
+
+pub fn reset_password(token: &str, csrf_token: &str, error: Option<&str>) -> String {
+    let error = match error {
+        Some(e) => format!("<p class=\"error\">{}</p>\n", escape(e)),
+        None => String::new(),
+    };
+
+    let body = format!(
+        r#"<h2>Choose a new password</h2>
+{error}<form method="post" action="/reset_password">
+<input type="hidden" name="csrf_token" value="{csrf}">
+<input type="hidden" name="token" value="{token}">
+<label for="password">New password</label>
+<input type="password" id="password" name="password" autocomplete="new-password" autofocus required>
+<button type="submit">Change password</button>
+</form>"#,
+        error = error,
+        csrf = escape(csrf_token),
+        token = escape(token)
+    );
+    layout("Reset password", &body)
+}
+
+// Loads the clients' front-channel logout uris in hidden iframes and continues to the client once they are done
+pub fn logged_out(frontchannel_uris: &[String], redirect: Option<&str>) -> String {
+    let iframes: String = frontchannel_uris
+        .iter()
+        .map(|uri| format!("<iframe src=\"{}\" style=\"display:none\"></iframe>\n", escape(uri)))
+        .collect();
+
+    let redirect = match redirect {
+        Some(uri) => format!(
+            r#"<p><a href="{href}">Continue</a></p>
+<script>window.onload = function () {{ window.location.href = {js}; }};</script>"#,
+            href = escape(uri),
+            js = serde_json::to_string(uri).unwrap().replace("</", "<\\/")
+        ),
+        None => String::new(),
+    };
+
+    let body = format!(
+        "<h2>You have been logged out</h2>\n{}{}",
+        iframes, redirect
+    );
+    layout("Logged out", &body)
+}
@@ -0,0 +1,362 @@
+use crate::errors::Error::AuthorizationError;
+use crate::handlers::{
+    check_csrf, continue_uri, current_session, form_page, found, get_db_client, redirect_to_login, start_session,
+    without_prompt,
+};
+use crate::mfa::{self, HARDWARE_KEY, USER_PRESENCE};
+use crate::models::{
+    AuthorizationParams, ChallengePurpose, ChallengeRequest, LoginSession, PasskeyAssertion, PasskeyDeletion,
+    PasskeyRegistration, ServerConfig,
+};
+use crate::pages;
+use crate::storage::{Database, Storage};
+use crate::webauthn::{self, RelyingParty, WebauthnError};
+use chrono::Duration;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use uuid::Uuid;
+use warp::{reply::json, Rejection, Reply};
+
+// Passkeys: logging in without a password, using a passkey as the second factor and managing them. The
+// browser asks for a challenge, runs the WebAuthn ceremony and posts the result with a form.
+
+const CHALLENGE_LIFETIME: i64 = 5 * 60;
+const TIMEOUT_MS: i64 = 2 * 60 * 1000;
+
+// A user that set up a second factor has to use it in this session before adding or removing passkeys,
+// so a stolen password is not enough to add one
+async fn may_change_passkeys(client: &dyn Storage, login_session: &LoginSession) -> bool {
+    mfa::verified(login_session) || !mfa::has_second_factor(client, login_session.user_id).await
+}
+
+fn credential_descriptors(ids: &[String]) -> Vec<Value> {
+    ids.iter().map(|id| json!({ "type": "public-key", "id": id })).collect()
+}
+
+// The options for navigator.credentials, binary values are base64url and decoded by the page
+pub async fn post_challenge(
+    params: ChallengeRequest,
+    session: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = get_db_client(&database).await?;
+    let rp = RelyingParty::new(&server_config);
+    let challenge = webauthn::generate_challenge();
+
+    if params.purpose == ChallengePurpose::Login {
+        client.create_challenge(&challenge, params.purpose, None, Duration::seconds(CHALLENGE_LIFETIME)).await;
+        // the browser offers the passkeys it has for this site, the user doesn't have to be known yet
+        return Ok(json(&json!({
+            "challenge": challenge,
+            "rpId": rp.id,
+            "timeout": TIMEOUT_MS,
+            "userVerification": "required",
+            "allowCredentials": [],
+        }))
+        .into_response());
+    }
+
+    let login_session = current_session(&*client, session)
+        .await
+        .ok_or_else(|| warp::reject::custom(AuthorizationError("Not logged in".to_string())))?;
+    let user_id = login_session.user_id;
+    let passkeys = client.get_passkey_ids(user_id).await;
+
+    if params.purpose == ChallengePurpose::Register && !may_change_passkeys(&*client, &login_session).await {
+        return Err(warp::reject::custom(AuthorizationError(
+            "Verify with your second factor first".to_string(),
+        )));
+    }
+    client.create_challenge(&challenge, params.purpose, Some(user_id), Duration::seconds(CHALLENGE_LIFETIME)).await;
+
+    let options = match params.purpose {
+        ChallengePurpose::Register => {
+            let username = client.get_user(user_id).await.map(|u| u.username).unwrap_or_default();
+            json!({
+                "challenge": challenge,
+                "rp": { "id": rp.id, "name": rp.name },
+                "user": { "id": webauthn::encode(user_id.as_bytes()), "name": username, "displayName": username },
+                "pubKeyCredParams": [{ "type": "public-key", "alg": -7 }, { "type": "public-key", "alg": -257 }],
+                "timeout": TIMEOUT_MS,
+                "attestation": "none",
+                "authenticatorSelection": { "residentKey": "required", "userVerification": "required" },
+                "excludeCredentials": credential_descriptors(&passkeys),
+            })
+        }
+        _ => json!({
+            "challenge": challenge,
+            "rpId": rp.id,
+            "timeout": TIMEOUT_MS,
+            "userVerification": "preferred",
+            "allowCredentials": credential_descriptors(&passkeys),
+        }),
+    };
+    Ok(json(&options).into_response())
+}
+
+// Checks a signed challenge and returns the user of the passkey. For a second factor the challenge was
+// made for the logged in user and the passkey has to be theirs.
+async fn check_assertion(
+    client: &dyn Storage,
+    server_config: &ServerConfig,
+    params: &PasskeyAssertion,
+    purpose: ChallengePurpose,
+) -> Result<Uuid, WebauthnError> {
+    let client_data = webauthn::decode(&params.client_data_json)?;
+    let authenticator_data = webauthn::decode(&params.authenticator_data)?;
+    let signature = webauthn::decode(&params.signature)?;
+
+    let challenge = webauthn::client_challenge(&client_data)?;
+    let challenge_user = client.use_challenge(&challenge, purpose)
+        .await
+        .ok_or(WebauthnError::Invalid("unknown challenge"))?;
+    let passkey = client.find_passkey(&params.credential_id)
+        .await
+        .ok_or(WebauthnError::Invalid("unknown passkey"))?;
+    if challenge_user.is_some_and(|u| u != passkey.user_id) {
+        return Err(WebauthnError::Invalid("passkey of another user"));
+    }
+
+    let sign_count = webauthn::verify_assertion(
+        &RelyingParty::new(server_config),
+        &client_data,
+        &authenticator_data,
+        &signature,
+        &passkey.public_key,
+        passkey.sign_count,
+        purpose == ChallengePurpose::Login,
+    )
+    .map_err(|e| {
+        if let WebauthnError::CloneDetected = e {
+            eprintln!("Passkey {} of user {} may have been cloned", passkey.id, passkey.user_id);
+        }
+        e
+    })?;
+    client.use_passkey(passkey.id, sign_count).await;
+    Ok(passkey.user_id)
+}
+
+// Logging in with a passkey instead of a password. The passkey proves possession and the user was
+// verified by the authenticator, so it counts as multi-factor.
+pub async fn post_passkey_login(
+    params: PasskeyAssertion,
+    csrf: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    check_csrf(csrf, &params.csrf_token)?;
+    let client = get_db_client(&database).await?;
+    let next = params.next.as_deref();
+
+    match check_assertion(&*client, &server_config, &params, ChallengePurpose::Login).await {
+        Ok(user_id) => {
+            let amr = [HARDWARE_KEY.to_string(), USER_PRESENCE.to_string()];
+            let location = continue_uri(&without_prompt(&params.request, "login"), next);
+            Ok(start_session(&*client, user_id, &amr, &location, &server_config).await)
+        }
+        Err(_) => Ok(form_page(&server_config, |csrf| {
+            pages::login(
+                &params.request,
+                next,
+                csrf,
+                &server_config.upstream,
+                Some("The passkey could not be used to log in"),
+            )
+        })),
+    }
+}
+
+pub async fn post_mfa_passkey(
+    params: PasskeyAssertion,
+    session: Option<String>,
+    csrf: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    check_csrf(csrf, &params.csrf_token)?;
+    let client = get_db_client(&database).await?;
+    let next = params.next.as_deref();
+    let login_session = match current_session(&*client, session).await {
+        Some(login_session) => login_session,
+        None => return Ok(redirect_to_login(&params.request)),
+    };
+
+    match check_assertion(&*client, &server_config, &params, ChallengePurpose::SecondFactor).await {
+        Ok(user_id) if user_id == login_session.user_id => {
+            client.set_session_amr(login_session.id, &mfa::with_method(&login_session, HARDWARE_KEY)).await;
+            Ok(found(&continue_uri(&params.request, next)))
+        }
+        _ => Ok(mfa::redirect_to_mfa(&params.request, next)),
+    }
+}
+
+async fn passkeys_page(
+    client: &dyn Storage,
+    server_config: &ServerConfig,
+    request: &AuthorizationParams,
+    user_id: Uuid,
+    error: Option<&str>,
+) -> warp::reply::Response {
+    let passkeys = client.get_passkeys(user_id).await;
+    form_page(server_config, |csrf| pages::passkeys(request, csrf, &passkeys, error))
+}
+
+// Lists the user's passkeys with a form to add one. The user logs in first when there is no session.
+pub async fn get_passkeys(
+    request: AuthorizationParams,
+    session: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let client = get_db_client(&database).await?;
+    let login_session = match current_session(&*client, session).await {
+        Some(login_session) => login_session,
+        None => {
+            return Ok(form_page(&server_config, |csrf| {
+                pages::login(&request, Some("passkeys"), csrf, &server_config.upstream, None)
+            }))
+        }
+    };
+    if !may_change_passkeys(&*client, &login_session).await {
+        return Ok(mfa::redirect_to_mfa(&request, Some("passkeys")));
+    }
+    Ok(passkeys_page(&*client, &server_config, &request, login_session.user_id, None).await)
+}
+
+// Stores a new passkey. Without a second factor yet, this is how a user sets one up, and the session
+// counts as verified with it.
+pub async fn post_passkey(
+    params: PasskeyRegistration,
+    session: Option<String>,
+    csrf: Option<String>,
+    database: Arc<dyn Database>,
+    server_config: ServerConfig,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    check_csrf(csrf, &params.csrf_token)?;
+    let client = get_db_client(&database).await?;
+    let request = &params.request;
+    let next = params.next.as_deref();
+    let login_session = match current_session(&*client, session).await {
+        Some(login_session) => login_session,
+        None => return Ok(redirect_to_login(request)),
+    };
+    if !may_change_passkeys(&*client, &login_session).await {
+        return Ok(mfa::redirect_to_mfa(request, next));
+    }
+    let user_id = login_session.user_id;
+
+    let registration = webauthn::decode(&params.client_data_json).and_then(|client_data| {
+        let attestation = webauthn::decode(&params.attestation_object)?;
+        let challenge = webauthn::client_challenge(&client_data)?;
+        Ok((client_data, attestation, challenge))
+    });
+    let credential = match registration {
+        Ok((client_data, attestation, challenge))
+            if client.use_challenge(&challenge, ChallengePurpose::Register).await == Some(Some(user_id)) =>
+        {
+            webauthn::verify_registration(&RelyingParty::new(&server_config), &client_data, &attestation)
+        }
+        Ok(_) => Err(WebauthnError::Invalid("unknown challenge")),
+        Err(e) => Err(e),
+    };
+
+    let name: String = match params.name.trim() {
+        "" => "Passkey".to_string(),
+        name => name.chars().take(100).collect(),
+    };
+    let error = match credential {
+        Ok(credential) if client.add_passkey(user_id, &credential, &name).await => None,
+        Ok(_) => Some("This passkey is already registered"),
+        Err(_) => Some("The passkey could not be registered"),
+    };
+    if let Some(error) = error {
+        return Ok(passkeys_page(&*client, &server_config, request, user_id, Some(error)).await);
+    }
+
+    if !mfa::verified(&login_session) {
+        client.set_session_amr(login_session.id, &mfa::with_method(&login_session, HARDWARE_KEY)).await;
+    }
+    Ok(found(&continue_uri(request, next)))
+}
+
+pub async fn post_delete_passkey(
+    params: PasskeyDeletion,
+    session: Option<String>,
+    csrf: Option<String>,
+    database: Arc<dyn Database>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    check_csrf(csrf, &params.csrf_token)?;
+    let client = get_db_client(&database).await?;
+    let login_session = match current_session(&*client, session).await {
+        Some(login_session) => login_session,
+        None => return Ok(redirect_to_login(&params.request)),
+    };
+    if !may_change_passkeys(&*client, &login_session).await {
+        return Ok(mfa::redirect_to_mfa(&params.request, Some("passkeys")));
+    }
+    if let Ok(id) = params.id.parse() {
+        client.delete_passkey(login_session.user_id, id).await;
+    }
+    Ok(found(&continue_uri(&params.request, Some("passkeys"))))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::Memory;
+    use crate::models::ChallengePurpose;
+    use crate::session::CSRF_COOKIE;
+    use crate::storage::{MfaStore, SessionStore};
+    use crate::testing::{self, add_client, add_user, Authenticator, CLIENT_ID, PASSWORD, USERNAME};
+    use crate::webauthn::{self, RelyingParty};
+    use chrono::Duration;
+    use url::form_urlencoded;
+
+    #[tokio::test]
+    async fn passkey_login_takes_each_challenge_once() {
+        let mut memory = Memory::new();
+        add_client(&mut memory, CLIENT_ID, &[]).await;
+        let user = add_user(&mut memory, USERNAME, PASSWORD).await;
+        let config = testing::config();
+        let rp = RelyingParty::new(&config.server);
+        let mut authenticator = Authenticator::new(&rp);
+        let (client_data, attestation_object) = authenticator.register("registration");
+        let credential = webauthn::verify_registration(&rp, &client_data, &attestation_object).unwrap();
+        assert!(memory.add_passkey(user.id, &credential, "Passkey").await);
+        memory.create_challenge("issued", ChallengePurpose::Login, None, Duration::minutes(5)).await;
+        let app = testing::app(&memory, config);
+
+        let mut login = |challenge: &str, sign_count: u32| {
+            authenticator.sign_count = sign_count;
+            let (client_data, authenticator_data, signature) = authenticator.assert(challenge);
+            let form = form_urlencoded::Serializer::new(String::new())
+                .append_pair("client_id", CLIENT_ID)
+                .append_pair("response_type", "code")
+                .append_pair("redirect_uri", "http://localhost:3000/callback")
+                .append_pair("csrf_token", "csrf")
+                .append_pair("credential_id", &authenticator.encoded_id())
+                .append_pair("client_data_json", &webauthn::encode(&client_data))
+                .append_pair("authenticator_data", &webauthn::encode(&authenticator_data))
+                .append_pair("signature", &webauthn::encode(&signature))
+                .finish();
+            warp::test::request()
+                .method("POST")
+                .path("/oauth2/passkey/login")
+                .header("cookie", format!("{}=csrf", CSRF_COOKIE))
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(form)
+                .reply(&app)
+        };
+
+        // a challenge the server never handed out
+        assert_eq!(login("made up", 2).await.status(), 200);
+        let response = login("issued", 2).await;
+        assert_eq!(response.status(), 302);
+        let cookie = response.headers()["set-cookie"].to_str().unwrap();
+        let token = cookie.trim_start_matches("session=").split(';').next().unwrap();
+        let login_session = memory.find_session(&crate::session::hash_token(token)).await.unwrap();
+        assert_eq!(login_session.user_id, user.id);
+        // nor one that was used already
+        assert_eq!(login("issued", 3).await.status(), 200);
+    }
+}
@@ -0,0 +1,285 @@
+use crate::models::PasswordConfig;
+use crate::session;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use sha2::Sha256;
+use std::convert::TryFrom;
+use std::sync::OnceLock;
+
+// New passwords are stored as Argon2id PHC strings. Hashes imported from other applications (bcrypt,
+// PBKDF2, scrypt) are still accepted and replaced by an Argon2id hash the next time the user logs in, as are
+// the plain passwords of the first release.
+
+// A format a stored password hash can be in
+trait PasswordScheme: Sync {
+    // Whether the stored hash is in this format
+    fn recognizes(&self, hash: &str) -> bool;
+    fn verify(&self, password: &str, hash: &str) -> bool;
+    // Whether a valid hash should be replaced by one made with the current settings
+    fn outdated(&self, _hash: &str, _config: &PasswordConfig) -> bool {
+        true
+    }
+}
+
+struct Argon2Scheme;
+struct BcryptScheme;
+struct Pbkdf2Scheme;
+struct DjangoPbkdf2Scheme;
+struct ScryptScheme;
+struct PlainScheme;
+
+const SCHEMES: &[&dyn PasswordScheme] = &[
+    &Argon2Scheme,
+    &BcryptScheme,
+    &Pbkdf2Scheme,
+    &DjangoPbkdf2Scheme,
+    &ScryptScheme,
+    &PlainScheme,
+];
+
+// The parsed hash carries its own parameters, PasswordVerifier compares the outputs in constant time
+fn verify_phc(verifier: &dyn PasswordVerifier, password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => verifier.verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+impl PasswordScheme for Argon2Scheme {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$argon2")
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        verify_phc(&Argon2::default(), password, hash)
+    }
+
+    fn outdated(&self, hash: &str, config: &PasswordConfig) -> bool {
+        match PasswordHash::new(hash) {
+            Ok(parsed) => {
+                let params = Params::try_from(&parsed);
+                parsed.algorithm.as_str() != "argon2id"
+                    || params.map_or(true, |p| {
+                        p.m_cost() < config.memory_cost
+                            || p.t_cost() < config.time_cost
+                            || p.p_cost() < config.parallelism
+                    })
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+// $2a$, $2b$ and $2y$ modular crypt strings, as written by PHP's password_hash
+impl PasswordScheme for BcryptScheme {
+    fn recognizes(&self, hash: &str) -> bool {
+        ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+// $pbkdf2-sha256$ and $pbkdf2-sha512$ PHC strings
+impl PasswordScheme for Pbkdf2Scheme {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$pbkdf2")
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        verify_phc(&pbkdf2::Pbkdf2, password, hash)
+    }
+}
+
+// Django's pbkdf2_sha256$<iterations>$<salt>$<base64 hash>
+impl PasswordScheme for DjangoPbkdf2Scheme {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("pbkdf2_sha256$")
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        let parts: Vec<&str> = hash.split('$').collect();
+        let (iterations, salt, expected) = match parts[..] {
+            [_, iterations, salt, expected] => match iterations.parse::<u32>() {
+                Ok(iterations) => (iterations, salt, expected),
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+
+        let mut output = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), iterations, &mut output);
+        session::constant_time_eq(&base64::encode(output), expected)
+    }
+}
+
+// $scrypt$ PHC strings
+impl PasswordScheme for ScryptScheme {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$scrypt$")
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        verify_phc(&scrypt::Scrypt, password, hash)
+    }
+}
+
+// plain$ and the password, how migrating marks the passwords of the first release that weren't hashed
+impl PasswordScheme for PlainScheme {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("plain$")
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        session::constant_time_eq(password, &hash["plain$".len()..])
+    }
+}
+
+pub enum Verification {
+    Invalid,
+    Valid,
+    // the password is right, the hash should be replaced by the one given
+    Rehash(String),
+}
+
+fn argon2(config: &PasswordConfig) -> Argon2<'static> {
+    let params = Params::new(config.memory_cost, config.time_cost, config.parallelism, None)
+        .expect("Invalid password hashing parameters");
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+pub fn hash(password: &str, config: &PasswordConfig) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2(config)
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Could not hash password")
+        .to_string()
+}
+
+pub fn verify(password: &str, hash: &str, config: &PasswordConfig) -> Verification {
+    match SCHEMES.iter().find(|scheme| scheme.recognizes(hash)) {
+        Some(scheme) if scheme.verify(password, hash) => {
+            if scheme.outdated(hash, config) {
+                Verification::Rehash(self::hash(password, config))
+            } else {
+                Verification::Valid
+            }
+        }
+        _ => Verification::Invalid,
+    }
+}
+
+// The policy for new passwords, others is what the password may not be equal to (username, email)
+pub fn check_policy(password: &str, others: &[&str], config: &PasswordConfig) -> Result<(), String> {
+    let length = password.chars().count();
+    if length < config.min_length {
+        return Err(format!("Password must be at least {} characters", config.min_length));
+    }
+    if length > config.max_length {
+        return Err(format!("Password must be at most {} characters", config.max_length));
+    }
+    if others.iter().any(|other| other.eq_ignore_ascii_case(password)) {
+        return Err("Password can't be the same as your username or email address".to_string());
+    }
+    Ok(())
+}
+
+// Used when the username is unknown, so logging in as someone who doesn't exist takes as long as a
+// wrong password and the timing doesn't tell which usernames are valid
+pub fn verify_dummy(password: &str, config: &PasswordConfig) {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    let dummy = DUMMY.get_or_init(|| hash(&session::generate_secret(32), config));
+    Argon2Scheme.verify(password, dummy);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn config() -> PasswordConfig {
+        testing::config().server.password
+    }
+
+    // the known answers were made for this password by libxcrypt's crypt(3) (bcrypt), by `openssl kdf` of
+    // OpenSSL 3 (PBKDF2 and scrypt, written out as PHC strings) and by Python's hashlib.pbkdf2_hmac (Django)
+    const IMPORTED: &str = "correct horse battery staple";
+    const WRONG: &str = "correct horse battery stapler";
+    const BCRYPT: &str = "$2b$04$y70yruuiKqGQ4v5kt0mdCeXpZK4Z4RNQvtuVkx9MzZDhKVenaXtCW";
+    const BCRYPT_PHP: &str = "$2y$04$T4c0r2SuxT1u3aTbXCiRZeSRRY6WqHShPR4SuzVbt2GYQxYEw6NSe";
+    const PBKDF2_SHA256: &str =
+        "$pbkdf2-sha256$i=1000,l=32$ZgPR7hZtNRk8wrq/Hb7dTA$Vgpi1he1VwpF1RdlwOgrACV4xCNLtCCUIKhLWfqaYU8";
+    const PBKDF2_SHA512: &str = concat!(
+        "$pbkdf2-sha512$i=1000,l=64$ZgPR7hZtNRk8wrq/Hb7dTA$",
+        "IgQz7SKHiMRC1w1s6ag+I0bblsNKA+qKdjWS7p/un+qKLPicoj7tq7E1C6nbe2XE8D0psN5n91C754QAQ219fA",
+    );
+    const DJANGO: &str = "pbkdf2_sha256$1000$QmT4Hs1TTNn0ZvQp1VJ8Xr$VIWKfXPnPV6cph81Ahg8fvctcJegqEkHrb3E3JJkzPI=";
+    const SCRYPT: &str = "$scrypt$ln=10,r=8,p=1$ZgPR7hZtNRk8wrq/Hb7dTA$y4GJErs30PA+x7ZmyF31tD83w3N74bc6HFmPHtUcCks";
+
+    fn check(scheme: &dyn PasswordScheme, hash: &str) {
+        assert!(scheme.recognizes(hash), "{}", hash);
+        assert!(scheme.verify(IMPORTED, hash), "{}", hash);
+        assert!(!scheme.verify(WRONG, hash), "{}", hash);
+    }
+
+    #[test]
+    fn bcrypt_hashes_are_verified() {
+        check(&BcryptScheme, BCRYPT);
+        check(&BcryptScheme, BCRYPT_PHP);
+        // from the test vectors of Openwall's crypt_blowfish
+        assert!(BcryptScheme.verify("U*U", "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"));
+    }
+
+    #[test]
+    fn pbkdf2_hashes_are_verified() {
+        check(&Pbkdf2Scheme, PBKDF2_SHA256);
+        check(&Pbkdf2Scheme, PBKDF2_SHA512);
+    }
+
+    #[test]
+    fn django_pbkdf2_hashes_are_verified() {
+        check(&DjangoPbkdf2Scheme, DJANGO);
+        assert!(!DjangoPbkdf2Scheme.verify(IMPORTED, &DJANGO.replace("$1000$", "$many$")));
+    }
+
+    #[test]
+    fn scrypt_hashes_are_verified() {
+        check(&ScryptScheme, SCRYPT);
+    }
+
+    #[test]
+    fn imported_hashes_are_replaced_by_argon2id_once_verified() {
+        let config = config();
+        for imported in [BCRYPT, PBKDF2_SHA256, DJANGO, SCRYPT] {
+            let hash = match verify(IMPORTED, imported, &config) {
+                Verification::Rehash(hash) => hash,
+                _ => panic!("{} should be replaced", imported),
+            };
+            assert!(hash.starts_with("$argon2id$"));
+            assert!(matches!(verify(IMPORTED, &hash, &config), Verification::Valid));
+            assert!(matches!(verify(WRONG, imported, &config), Verification::Invalid));
+        }
+
+        // so is an Argon2id hash made with less memory than configured
+        let hash = super::hash(IMPORTED, &config);
+        let stronger = PasswordConfig { memory_cost: config.memory_cost * 2, ..config.clone() };
+        assert!(matches!(verify(IMPORTED, &hash, &stronger), Verification::Rehash(_)));
+    }
+
+    #[test]
+    fn plain_passwords_of_the_first_release_are_taken_once() {
+        let config = config();
+        let hash = match verify("test", "plain$test", &config) {
+            Verification::Rehash(hash) => hash,
+            _ => panic!("A plain password should be replaced by a hash"),
+        };
+        assert!(matches!(verify("test", &hash, &config), Verification::Valid));
+        assert!(matches!(verify("tes", "plain$test", &config), Verification::Invalid));
+        // only marked values are compared as they are
+        assert!(matches!(verify("test", "test", &config), Verification::Invalid));
+    }
+}
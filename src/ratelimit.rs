@@ -0,0 +1,234 @@
+use crate::errors::Error::TooManyRequestsError;
+use crate::models::{RateLimitConfig, RouteLimits};
+use crate::session;
+use crate::storage::Database;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::Rejection;
+
+// Token bucket rate limits for the busy endpoints, checked before a request gets a database connection.
+// The buckets are kept in memory, so every server process counts on its own. A request counts for its
+// client only when it carries the client's credentials, anyone can name a client and use up its requests.
+// Whether credentials are valid and the client's own limit are read from the database and kept for a
+// minute, wrong ones too.
+
+const CREDENTIALS_LIFETIME: Duration = Duration::from_secs(60);
+// past this many buckets the full ones are dropped, a full bucket is the same as a missing one
+const MAX_BUCKETS: usize = 100_000;
+
+#[derive(Clone, Copy)]
+pub enum Route {
+    Token,
+    Introspect,
+    Authorize,
+}
+
+impl Route {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Route::Token => "token",
+            Route::Introspect => "introspect",
+            Route::Authorize => "authorize",
+        }
+    }
+
+    fn limits<'a>(&self, config: &'a RateLimitConfig) -> &'a RouteLimits {
+        match self {
+            Route::Token => &config.token,
+            Route::Introspect => &config.introspect,
+            Route::Authorize => &config.authorize,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+// What the limiter knows of a pair of client credentials
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Credentials {
+    Invalid,
+    // with the client's own limit
+    Valid(Option<i32>),
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    // by the hash of client id and secret
+    credentials: Mutex<HashMap<String, (Credentials, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            credentials: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes a request from the bucket, when it's empty returns the seconds until there is one again
+    fn take(&self, key: String, per_minute: u32) -> Result<(), u64> {
+        if per_minute == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                bucket.tokens < bucket.capacity
+            });
+        }
+        let capacity = f64::from(per_minute);
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            capacity,
+            per_second: capacity / 60.0,
+            updated: now,
+        });
+        // a changed limit applies to the bucket right away
+        bucket.capacity = capacity;
+        bucket.per_second = capacity / 60.0;
+        bucket.refill(now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / bucket.per_second).ceil() as u64)
+        }
+    }
+
+    async fn credentials(&self, database: &Arc<dyn Database>, client_id: &str, secret: &str) -> Credentials {
+        let key = session::hash_token(&format!("{}:{}", client_id, secret));
+        if let Some((credentials, read)) = self.credentials.lock().unwrap().get(&key) {
+            if read.elapsed() < CREDENTIALS_LIFETIME {
+                return *credentials;
+            }
+        }
+        // without a connection only the address counts, the handler reports the database error
+        let client = match database.connect().await {
+            Ok(client) => client,
+            Err(_) => return Credentials::Invalid,
+        };
+        let credentials = match client.validate_client_credentials(client_id.to_string(), secret.to_string()).await {
+            Some(_) => Credentials::Valid(client.get_client_rate_limit(client_id).await),
+            None => Credentials::Invalid,
+        };
+
+        let mut cache = self.credentials.lock().unwrap();
+        if cache.len() >= MAX_BUCKETS {
+            cache.retain(|_, (_, read)| read.elapsed() < CREDENTIALS_LIFETIME);
+        }
+        cache.insert(key, (credentials, Instant::now()));
+        credentials
+    }
+
+    pub async fn check(
+        &self,
+        database: &Arc<dyn Database>,
+        route: Route,
+        authorization: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> Result<(), Rejection> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let limits = route.limits(&self.config);
+
+        // the address first, so wrong credentials can't reach the database faster than it allows
+        let mut result = match ip {
+            Some(ip) => self.take(format!("{}:ip:{}", route.as_str(), ip), limits.ip),
+            None => Ok(()),
+        };
+        if let (Ok(()), Some((client_id, secret))) = (&result, authorization.and_then(client_credentials)) {
+            if let Credentials::Valid(limit) = self.credentials(database, &client_id, &secret).await {
+                let per_minute = limit.map_or(limits.client, |limit| limit.max(0) as u32);
+                result = self.take(format!("{}:client:{}", route.as_str(), client_id), per_minute);
+            }
+        }
+        result.map_err(|seconds| warp::reject::custom(TooManyRequestsError(seconds)))
+    }
+}
+
+// The client id and secret of basic authentication
+fn client_credentials(authorization: &str) -> Option<(String, String)> {
+    let decoded = authorization
+        .strip_prefix("Basic ")
+        .and_then(|credentials| base64::decode(credentials.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())?;
+    let (client_id, secret) = decoded.split_once(':')?;
+    Some((client_id.to_string(), secret.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use crate::testing::{add_client, basic_auth, CLIENT_ID};
+
+    fn limiter(token: RouteLimits) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            enabled: true,
+            token,
+            ..RateLimitConfig::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn only_authenticated_requests_count_for_their_client() {
+        let mut memory = Memory::new();
+        let (_, secret) = add_client(&mut memory, CLIENT_ID, &[]).await;
+        let database: Arc<dyn Database> = Arc::new(memory);
+        let limiter = limiter(RouteLimits { client: 2, ip: 0 });
+        let check = |authorization: String| {
+            let (limiter, database) = (&limiter, &database);
+            async move { limiter.check(database, Route::Token, Some(&authorization), None).await.is_ok() }
+        };
+
+        // someone naming the client doesn't use up its requests, nor does someone naming no client at all
+        for _ in 0..5 {
+            assert!(check(basic_auth(CLIENT_ID, "wrong")).await);
+            assert!(check(basic_auth("unknown", "wrong")).await);
+        }
+        assert!(check(basic_auth(CLIENT_ID, &secret)).await);
+        assert!(check(basic_auth(CLIENT_ID, &secret)).await);
+        assert!(!check(basic_auth(CLIENT_ID, &secret)).await);
+
+        // wrong credentials were looked up once
+        let cache = limiter.credentials.lock().unwrap();
+        assert_eq!(cache.len(), 3);
+        let key = session::hash_token("unknown:wrong");
+        assert_eq!(cache[&key].0, Credentials::Invalid);
+    }
+
+    #[tokio::test]
+    async fn wrong_credentials_are_limited_by_address() {
+        let mut memory = Memory::new();
+        add_client(&mut memory, CLIENT_ID, &[]).await;
+        let database: Arc<dyn Database> = Arc::new(memory);
+        let limiter = limiter(RouteLimits { client: 0, ip: 2 });
+        let ip = Some(IpAddr::from([192, 0, 2, 1]));
+        let authorization = basic_auth(CLIENT_ID, "wrong");
+        for expected in [true, true, false] {
+            let result = limiter.check(&database, Route::Token, Some(&authorization), ip).await;
+            assert_eq!(result.is_ok(), expected);
+        }
+    }
+}
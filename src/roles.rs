@@ -0,0 +1,41 @@
+use crate::errors::Error::{self, InvalidRequestError};
+use crate::storage::RoleStore;
+use uuid::Uuid;
+
+// Roles and groups for coarse authorization by the resource servers. A role is global or belongs to a
+// client, a user has the roles assigned to them and those of their groups. Tokens carry the user's global
+// roles and the roles of the token's client, by name, and the names of the user's groups.
+
+// Roles and groups are named like admin, billing:read or team-a
+pub fn check_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c));
+    if !valid {
+        return Err(InvalidRequestError(
+            "Names have letters, digits and _ - . : up to 64 characters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn check_description(description: &Option<String>) -> Result<(), Error> {
+    if description.as_ref().is_some_and(|d| d.chars().count() > 512) {
+        return Err(InvalidRequestError("The description can be at most 512 characters".to_string()));
+    }
+    Ok(())
+}
+
+// The roles and groups for a token of the user for the client
+pub async fn claims(client: &dyn RoleStore, user_id: Uuid, client_id: &str) -> (Vec<String>, Vec<String>) {
+    let mut roles: Vec<String> = client.get_effective_roles(user_id)
+        .await
+        .into_iter()
+        .filter(|r| r.client_id.as_deref().is_none_or(|c| c == client_id))
+        .map(|r| r.name)
+        .collect();
+    // a client role can have the name of a global one
+    roles.sort();
+    roles.dedup();
+    (roles, client.get_user_groups(user_id).await)
+}
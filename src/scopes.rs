@@ -0,0 +1,250 @@
+use crate::errors::Error::{self, InvalidScopeError};
+use crate::models::{Scope, ScopePolicy, ScopeRule};
+use std::collections::HashMap;
+
+// Scope segments are separated by a colon, like projects:read:1234
+const SEPARATOR: char = ':';
+const WILDCARD: &str = "*";
+
+// Scopes are a space delimited list, see RFC-6749 section 3.3
+pub fn parse(scope: &str) -> Vec<String> {
+    let mut scopes: Vec<String> = Vec::new();
+    for s in scope.split_whitespace() {
+        if !scopes.iter().any(|x| x == s) {
+            scopes.push(s.to_string());
+        }
+    }
+    scopes
+}
+
+pub fn join(scopes: &[String]) -> Option<String> {
+    if scopes.is_empty() {
+        None
+    } else {
+        Some(scopes.join(" "))
+    }
+}
+
+fn segments(scope: &str) -> Vec<&str> {
+    scope.split(SEPARATOR).collect()
+}
+
+fn parameter(segment: &str) -> Option<&str> {
+    segment.strip_prefix('{').and_then(|s| s.strip_suffix('}'))
+}
+
+// A template declares parameters, projects:read:{project}
+pub fn is_template(scope: &str) -> bool {
+    segments(scope).iter().any(|s| parameter(s).is_some())
+}
+
+// Matches a requested scope against a declared template and returns the parameter values.
+// A trailing wildcard in the request stands for all remaining segments, so projects:* matches
+// projects:{action}:{project}.
+pub fn match_template(template: &str, scope: &str) -> Option<HashMap<String, String>> {
+    let template = segments(template);
+    let scope = segments(scope);
+    let mut params = HashMap::new();
+
+    for (i, t) in template.iter().enumerate() {
+        let s = scope.get(i)?;
+        if *s == WILDCARD && i == scope.len() - 1 {
+            return Some(params);
+        }
+        match parameter(t) {
+            Some(name) => {
+                params.insert(name.to_string(), s.to_string());
+            }
+            None if t != s => return None,
+            None => {}
+        }
+    }
+
+    if template.len() == scope.len() {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+// Fills in the parameters of a template, parameters without a value are left as is
+pub fn substitute(template: &str, params: &HashMap<String, String>) -> String {
+    segments(template)
+        .iter()
+        .map(|s| match parameter(s).and_then(|name| params.get(name)) {
+            Some(value) => value.as_str(),
+            None => s,
+        })
+        .collect::<Vec<&str>>()
+        .join(&SEPARATOR.to_string())
+}
+
+// Whether a granted scope covers a requested one. A wildcard segment covers any single value,
+// a trailing wildcard covers everything below it.
+pub fn covers(grant: &str, scope: &str) -> bool {
+    let grant = segments(grant);
+    let scope = segments(scope);
+
+    for (i, g) in grant.iter().enumerate() {
+        let s = match scope.get(i) {
+            Some(s) => s,
+            None => return false,
+        };
+        if *g == WILDCARD && i == grant.len() - 1 {
+            return true;
+        }
+        if *g != WILDCARD && g != s {
+            return false;
+        }
+    }
+
+    grant.len() == scope.len()
+}
+
+// Applies the implication rules until nothing new comes out, projects:* implies projects:read:*
+pub fn expand(scopes: &[String], rules: &[ScopeRule]) -> Vec<String> {
+    let mut expanded: Vec<String> = scopes.to_vec();
+    let mut i = 0;
+    while i < expanded.len() {
+        for rule in rules {
+            if let Some(params) = match_rule(&rule.scope, &expanded[i]) {
+                let implied = substitute(&rule.implies, &params);
+                if !expanded.contains(&implied) {
+                    expanded.push(implied);
+                }
+            }
+        }
+        i += 1;
+    }
+    expanded
+}
+
+// Rules bind parameters but, unlike requests, treat wildcards literally so that a concrete
+// scope never implies a wildcard one
+fn match_rule(rule: &str, scope: &str) -> Option<HashMap<String, String>> {
+    let rule = segments(rule);
+    let scope = segments(scope);
+    if rule.len() != scope.len() {
+        return None;
+    }
+
+    let mut params = HashMap::new();
+    for (r, s) in rule.iter().zip(scope.iter()) {
+        match parameter(r) {
+            Some(name) => {
+                params.insert(name.to_string(), s.to_string());
+            }
+            None if r != s => return None,
+            None => {}
+        }
+    }
+    Some(params)
+}
+
+fn is_allowed(scope: &str, policy: &ScopePolicy) -> Result<(), Error> {
+    if policy
+        .allowed
+        .iter()
+        .any(|a| !is_template(&a.name) && a.name == scope)
+    {
+        return Ok(());
+    }
+
+    if !policy
+        .allowed
+        .iter()
+        .any(|a| is_template(&a.name) && match_template(&a.name, scope).is_some())
+    {
+        return Err(InvalidScopeError(format!(
+            "Scope {} is not allowed for this client",
+            scope
+        )));
+    }
+
+    // parameterized scopes also need a grant for the user or client that covers them
+    let grants = expand(&policy.grants, &policy.rules);
+    if grants.iter().any(|g| covers(g, scope)) {
+        Ok(())
+    } else {
+        Err(InvalidScopeError(format!("Scope {} has not been granted", scope)))
+    }
+}
+
+// Works out the scope granted to a client. Without a requested scope the client gets its default
+// scopes, otherwise every requested scope has to be one the client is allowed to request.
+pub fn resolve(requested: Option<&str>, policy: &ScopePolicy) -> Result<Option<String>, Error> {
+    let requested = match requested.map(parse) {
+        Some(scopes) if !scopes.is_empty() => scopes,
+        _ => {
+            let defaults: Vec<String> = policy
+                .allowed
+                .iter()
+                .filter(|s| s.is_default && !is_template(&s.name))
+                .map(|s| s.name.clone())
+                .collect();
+            return Ok(join(&defaults));
+        }
+    };
+
+    for scope in requested.iter() {
+        is_allowed(scope, policy)?;
+    }
+
+    Ok(join(&requested))
+}
+
+// A refresh can only narrow the original grant, never widen it
+pub fn downscope(requested: Option<&str>, original: Option<&str>) -> Result<Option<String>, Error> {
+    let original = original.map(parse).unwrap_or_default();
+    let requested = match requested.map(parse) {
+        Some(scopes) if !scopes.is_empty() => scopes,
+        _ => return Ok(join(&original)),
+    };
+
+    if let Some(scope) = requested
+        .iter()
+        .find(|r| !original.iter().any(|o| covers(o, r)))
+    {
+        return Err(InvalidScopeError(format!(
+            "Scope {} exceeds the scope originally granted",
+            scope
+        )));
+    }
+
+    Ok(join(&requested))
+}
+
+// Drops the scopes a client is no longer allowed to hold
+pub fn restrict(scope: Option<&str>, policy: &ScopePolicy) -> Option<String> {
+    let scopes: Vec<String> = scope
+        .map(parse)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|s| is_allowed(s, policy).is_ok())
+        .collect();
+    join(&scopes)
+}
+
+// Finds the registry entry for a scope and fills the parameters into its description.
+// Returns the description and whether the scope needs the user's consent, unknown scopes always do.
+pub fn describe(scope: &str, registry: &[Scope]) -> (String, bool) {
+    if let Some(entry) = registry.iter().find(|s| s.name == scope) {
+        let description = entry.description.clone().unwrap_or_else(|| scope.to_string());
+        return (description, entry.requires_consent);
+    }
+
+    for entry in registry.iter().filter(|s| is_template(&s.name)) {
+        if let Some(params) = match_template(&entry.name, scope) {
+            let mut description = entry.description.clone().unwrap_or_else(|| scope.to_string());
+            for segment in segments(&entry.name) {
+                if let Some(name) = parameter(segment) {
+                    let value = params.get(name).map(|v| v.as_str()).unwrap_or("all");
+                    description = description.replace(segment, value);
+                }
+            }
+            return (description, entry.requires_consent);
+        }
+    }
+
+    (scope.to_string(), true)
+}
@@ -0,0 +1,55 @@
+use crate::models::ServerConfig;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use warp::http::header::{HeaderValue, SET_COOKIE};
+
+// Cookies used by the pages the server renders itself
+pub const SESSION_COOKIE: &str = "session";
+pub const CSRF_COOKIE: &str = "csrf";
+
+pub fn generate_secret(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+// Session tokens are only stored hashed, a leaked login_sessions table can't be used to log in
+pub fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(token);
+    format!("{:x}", hasher.finalize())
+}
+
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// The csrf token is kept in a cookie and repeated in the form (double submit), a cross site post
+// can't read the cookie to fill in the form
+pub fn csrf_valid(cookie: Option<&str>, form: &str) -> bool {
+    match cookie {
+        Some(cookie) => !form.is_empty() && constant_time_eq(cookie, form),
+        None => false,
+    }
+}
+
+pub fn cookie(server_config: &ServerConfig, name: &str, value: &str, max_age: i64) -> HeaderValue {
+    let mut cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+        name, value, max_age
+    );
+    if server_config.secure_cookies {
+        cookie.push_str("; Secure");
+    }
+    HeaderValue::from_str(&cookie).unwrap()
+}
+
+pub fn set_cookie(response: &mut warp::reply::Response, cookie: HeaderValue) {
+    response.headers_mut().append(SET_COOKIE, cookie);
+}
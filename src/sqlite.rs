@@ -0,0 +1,2509 @@
+use crate::db::{split_list, DEFAULT_TOKEN_LIFETIME};
+use crate::errors::Error::{self, ConflictError, InvalidRequestError, InvalidScopeError, SqliteError};
+use crate::federation;
+use crate::ldap;
+use crate::models::{
+    AccessToken, AttributeDefinition, AuthorizationCode, AuthorizationParams, ChallengePurpose, ClientDetails,
+    ClientInfo, ClientScope, ClientSecret, ClientUpdate, EmailTokenPurpose, FederatedIdentity, FederationState,
+    Group, GroupMember, Introspection, LoginSession, LogoutClient, NewClient, Passkey, PasskeyCredential, Role,
+    Scope, ScopePolicy, ScopeRule, SqliteConfig, TokenGrant, TokenInfo, TotpCredential, User, UserSource, UserUpdate,
+};
+use crate::session;
+use crate::storage::{
+    AttributeStore, ClientStore, CodeStore, Database, FederationStore, LockoutStore, MfaStore, RoleStore, ScopeStore,
+    SessionStore, Storage, TokenStore, UserStore,
+};
+use crate::webauthn::NewCredential;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone, Utc};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::Type;
+use rusqlite::{ffi, params, Connection, OptionalExtension, Params, Row};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+// An SQLite database in a single file, for deployments where running Postgres for the server is too much.
+// The tables are those of database_init.sql (see migrations/sqlite/) and the queries those of db.rs in
+// SQLite's dialect. The server has one connection and its queries take turns. rusqlite blocks, so they run
+// on tokio's blocking threads and not on the workers that serve requests. The database is in WAL mode, so the
+// command line can read it while the server writes.
+
+const MIGRATIONS: &[(&str, &str)] = &[
+    ("0001_init", include_str!("../migrations/sqlite/0001_init.sql")),
+    ("0002_refresh_token_hash", include_str!("../migrations/sqlite/0002_refresh_token_hash.sql")),
+    ("0003_user_source", include_str!("../migrations/sqlite/0003_user_source.sql")),
+];
+
+// How times are stored, strftime('%Y-%m-%d %H:%M:%f', 'now') in UTC
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+#[derive(Clone)]
+pub struct Sqlite {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Sqlite {
+    // Opens the database, a missing file is created. Queries use now() for the current time and migrations
+    // sha256() to hash what was stored as is, they are defined here because SQLite doesn't have them.
+    pub fn open(config: &SqliteConfig) -> Result<Self, Error> {
+        let connection = Connection::open(&config.path)?;
+        let journal_mode: String = connection.pragma_update_and_check(None, "journal_mode", "wal", |row| row.get(0))?;
+        if journal_mode != "wal" {
+            eprintln!("SQLite database {} uses journal mode {} instead of wal", config.path, journal_mode);
+        }
+        // cascades are off unless they are turned on for each connection
+        connection.pragma_update(None, "foreign_keys", "on")?;
+        connection.busy_timeout(std::time::Duration::from_millis(config.busy_timeout))?;
+        connection.create_scalar_function("now", 0, FunctionFlags::SQLITE_UTF8, |_| Ok(sql_time(Local::now())))?;
+        connection.create_scalar_function("sha256", 1, FunctionFlags::SQLITE_UTF8, |context| {
+            Ok(session::hash_token(&context.get::<String>(0)?))
+        })?;
+        Ok(Sqlite {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    // Runs f with the connection on a blocking thread, once the queries before it are done
+    async fn run<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> T + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || f(&mut connection.lock().expect("SQLite connection poisoned")))
+            .await
+            .expect("SQLite query panicked")
+    }
+
+    // Runs the statements of a script in one transaction, for the demo data of database_init.sql
+    pub async fn execute_script(&self, script: &str) -> Result<(), Error> {
+        let script = script.to_owned();
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(&script)?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn sql_time(time: DateTime<Local>) -> String {
+    time.with_timezone(&Utc).format(TIME_FORMAT).to_string()
+}
+
+fn conversion_error(index: usize, e: impl std::error::Error + Send + Sync + 'static) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e))
+}
+
+fn time(row: &Row, index: usize) -> rusqlite::Result<DateTime<Local>> {
+    let text: String = row.get(index)?;
+    NaiveDateTime::parse_from_str(&text, "%Y-%m-%d %H:%M:%S%.f")
+        .map(|t| Utc.from_utc_datetime(&t).with_timezone(&Local))
+        .map_err(|e| conversion_error(index, e))
+}
+
+fn optional_time(row: &Row, index: usize) -> rusqlite::Result<Option<DateTime<Local>>> {
+    match row.get::<_, Option<String>>(index)? {
+        Some(_) => time(row, index).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn id(row: &Row, index: usize) -> rusqlite::Result<Uuid> {
+    let text: String = row.get(index)?;
+    Uuid::parse_str(&text).map_err(|e| conversion_error(index, e))
+}
+
+fn optional_id(row: &Row, index: usize) -> rusqlite::Result<Option<Uuid>> {
+    match row.get::<_, Option<String>>(index)? {
+        Some(_) => id(row, index).map(Some),
+        None => Ok(None),
+    }
+}
+
+// SQLite has no arrays, lists are passed as json and read with json_each
+fn json_list(values: &[String]) -> String {
+    serde_json::to_string(values).unwrap()
+}
+
+fn query<T, P, F>(connection: &Connection, sql: &str, params: P, map: F) -> rusqlite::Result<Vec<T>>
+where
+    P: Params,
+    F: FnMut(&Row) -> rusqlite::Result<T>,
+{
+    let mut statement = connection.prepare_cached(sql)?;
+    let rows = statement.query_map(params, map)?.collect();
+    rows
+}
+
+// Turns a violated unique constraint on users into a message that says which value is taken
+fn unique_violation(e: rusqlite::Error) -> Error {
+    let column = match &e {
+        rusqlite::Error::SqliteFailure(failure, Some(message))
+            if failure.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE =>
+        {
+            message.rsplit(' ').next().unwrap_or_default().to_string()
+        }
+        _ => return SqliteError(e),
+    };
+    match column.as_str() {
+        "users.username" => ConflictError("Username is already taken".to_string()),
+        "users.email" => ConflictError("Email address is already registered".to_string()),
+        _ => SqliteError(e),
+    }
+}
+
+const CLIENT_COLUMNS: &str = "id, client_id, display_name, redirect_uris, post_logout_redirect_uris,
+    frontchannel_logout_uri, backchannel_logout_uri, grant_types, access_token_lifetime, refresh_token_lifetime,
+    first_party, disabled, creation_time, mfa_required, rate_limit, jwt_access_tokens";
+
+fn client_details(connection: &Connection, row: &Row) -> rusqlite::Result<(Uuid, ClientDetails)> {
+    let client_db_id = id(row, 0)?;
+    let details = ClientDetails {
+        client_id: row.get(1)?,
+        display_name: row.get(2)?,
+        redirect_uris: split_list(row.get(3)?),
+        post_logout_redirect_uris: split_list(row.get(4)?),
+        frontchannel_logout_uri: row.get(5)?,
+        backchannel_logout_uri: row.get(6)?,
+        grant_types: split_list(row.get(7)?),
+        scopes: client_scopes(connection, client_db_id)?,
+        access_token_lifetime: row.get(8)?,
+        refresh_token_lifetime: row.get(9)?,
+        first_party: row.get(10)?,
+        mfa_required: row.get(13)?,
+        rate_limit: row.get(14)?,
+        jwt_access_tokens: row.get(15)?,
+        disabled: row.get(11)?,
+        created: time(row, 12)?.timestamp(),
+        client_secret: None,
+    };
+    Ok((client_db_id, details))
+}
+
+const CLIENT_INFO_COLUMNS: &str = "id, client_id, display_name, redirect_uris, first_party, post_logout_redirect_uris,
+    grant_types, access_token_lifetime, refresh_token_lifetime, mfa_required, jwt_access_tokens";
+
+fn client_info(row: &Row) -> rusqlite::Result<ClientInfo> {
+    Ok(ClientInfo {
+        id: id(row, 0)?,
+        client_id: row.get(1)?,
+        display_name: row.get(2)?,
+        redirect_uris: split_list(row.get(3)?),
+        post_logout_redirect_uris: split_list(row.get(5)?),
+        first_party: row.get(4)?,
+        grant_types: split_list(row.get(6)?),
+        access_token_lifetime: row.get(7)?,
+        refresh_token_lifetime: row.get(8)?,
+        mfa_required: row.get(9)?,
+        jwt_access_tokens: row.get(10)?,
+    })
+}
+
+fn client_secret(row: &Row) -> rusqlite::Result<ClientSecret> {
+    Ok(ClientSecret {
+        id: row.get(0)?,
+        prefix: row.get(1)?,
+        created: time(row, 2)?.timestamp(),
+        expires: optional_time(row, 3)?.map(|t| t.timestamp()),
+    })
+}
+
+fn client_scopes(connection: &Connection, client_db_id: Uuid) -> rusqlite::Result<Vec<ClientScope>> {
+    query(
+        connection,
+        "select s.name, cs.is_default from client_scopes as cs join scopes as s on cs.scope_id = s.id
+         where cs.client_id = ?1 order by s.name",
+        [client_db_id.to_string()],
+        |row| {
+            Ok(ClientScope {
+                name: row.get(0)?,
+                is_default: row.get(1)?,
+            })
+        },
+    )
+}
+
+// Replaces the scopes of the client, default scopes have to be among the scopes
+fn set_client_scopes(
+    connection: &Connection,
+    client_db_id: Uuid,
+    scopes: &[String],
+    defaults: &[String],
+) -> Result<(), Error> {
+    if let Some(scope) = defaults.iter().find(|d| !scopes.contains(d)) {
+        return Err(InvalidScopeError(format!("Default scope {} is not one of the client's scopes", scope)));
+    }
+
+    connection.execute("delete from client_scopes where client_id = ?1", [client_db_id.to_string()])?;
+    let inserted = connection.execute(
+        "insert into client_scopes (client_id, scope_id, is_default)
+         select ?1, id, name in (select value from json_each(?3)) from scopes
+         where name in (select value from json_each(?2))",
+        params![client_db_id.to_string(), json_list(scopes), json_list(defaults)],
+    )?;
+
+    if inserted != scopes.len() {
+        return Err(InvalidScopeError("Unknown scope".to_string()));
+    }
+    Ok(())
+}
+
+const USER_COLUMNS: &str = "id, username, email, email_verified, disabled, creation_time, mfa_required";
+
+fn user(row: &Row) -> rusqlite::Result<User> {
+    Ok(User {
+        id: id(row, 0)?,
+        username: row.get(1)?,
+        email: row.get(2)?,
+        email_verified: row.get(3)?,
+        mfa_required: row.get(6)?,
+        disabled: row.get(4)?,
+        created: time(row, 5)?.timestamp(),
+    })
+}
+
+fn role(row: &Row) -> rusqlite::Result<Role> {
+    Ok(Role {
+        name: row.get(0)?,
+        client_id: row.get(1)?,
+        description: row.get(2)?,
+    })
+}
+
+fn group_roles(connection: &Connection, group_id: i32) -> rusqlite::Result<Vec<Role>> {
+    query(
+        connection,
+        "select r.name, c.client_id, r.description from group_roles as gr join roles as r on gr.role_id = r.id
+         left join clients as c on r.client_id = c.id where gr.group_id = ?1 order by c.client_id nulls first, r.name",
+        [group_id],
+        role,
+    )
+}
+
+fn group_members(connection: &Connection, group_id: i32) -> rusqlite::Result<Vec<GroupMember>> {
+    query(
+        connection,
+        "select u.id, u.username from group_members as gm join users as u on gm.user_id = u.id
+         where gm.group_id = ?1 order by u.username",
+        [group_id],
+        |row| {
+            Ok(GroupMember {
+                user_id: id(row, 0)?,
+                username: row.get(1)?,
+            })
+        },
+    )
+}
+
+#[async_trait]
+impl Database for Sqlite {
+    async fn connect(&self) -> Result<Box<dyn Storage>, Error> {
+        Ok(Box::new(self.clone()))
+    }
+
+    async fn migrate(&self) -> Result<Vec<&'static str>, Error> {
+        self.run(move |connection| {
+            connection.execute_batch(
+                "create table if not exists schema_migrations (
+                   version varchar(255) primary key,
+                   applied_time timestamp not null default (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+                 )",
+            )?;
+            let applied: Vec<String> =
+                query(connection, "select version from schema_migrations", [], |row| row.get(0))?;
+
+            let mut migrated = Vec::new();
+            for (version, script) in MIGRATIONS {
+                if applied.iter().any(|a| a == version) {
+                    continue;
+                }
+                let transaction = connection.transaction()?;
+                transaction.execute_batch(script)?;
+                transaction.execute("insert into schema_migrations (version) values (?1)", [version])?;
+                transaction.commit()?;
+                migrated.push(*version);
+            }
+            Ok(migrated)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ClientStore for Sqlite {
+    async fn validate_client_credentials(&self, client_id: String, secret: String) -> Option<Uuid> {
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select c.id from clients as c join client_secrets as s on s.client_id = c.id
+                     where c.client_id = ?1 and not c.disabled and s.secret_hash = ?2
+                     and (s.expire_time is null or s.expire_time > now())",
+                    params![client_id, session::hash_token(&secret)],
+                    |row| id(row, 0),
+                )
+                .optional()
+                .expect("Error executing query on clients table")
+        })
+        .await
+    }
+
+    async fn add_client_secret(
+        &self,
+        client_db_id: Uuid,
+        expire_time: Option<DateTime<Local>>,
+    ) -> (ClientSecret, String) {
+        let secret = session::generate_secret(48);
+        self.run(move |connection| {
+            let client_secret = connection
+                .query_row(
+                    "insert into client_secrets (client_id, secret_hash, prefix, expire_time) values (?1, ?2, ?3, ?4)
+                     returning id, prefix, creation_time, expire_time",
+                    params![
+                        client_db_id.to_string(),
+                        session::hash_token(&secret),
+                        &secret[..6],
+                        expire_time.map(sql_time)
+                    ],
+                    client_secret,
+                )
+                .expect("Error inserting client secret");
+            (client_secret, secret)
+        })
+        .await
+    }
+
+    async fn get_client_secrets(&self, client_db_id: Uuid) -> Vec<ClientSecret> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select id, prefix, creation_time, expire_time from client_secrets
+                 where client_id = ?1 order by creation_time",
+                [client_db_id.to_string()],
+                client_secret,
+            )
+            .expect("Error executing query on client_secrets table")
+        })
+        .await
+    }
+
+    async fn expire_client_secret(&self, client_db_id: Uuid, secret_id: i32, expire_time: DateTime<Local>) -> bool {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "update client_secrets set expire_time = ?3 where client_id = ?1 and id = ?2",
+                    params![client_db_id.to_string(), secret_id, sql_time(expire_time)],
+                )
+                .expect("Error updating client_secrets table")
+                == 1
+        })
+        .await
+    }
+
+    async fn expire_other_client_secrets(&self, client_db_id: Uuid, keep_id: i32, expire_time: DateTime<Local>) {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "update client_secrets set expire_time = ?3
+                     where client_id = ?1 and id != ?2 and (expire_time is null or expire_time > ?3)",
+                    params![client_db_id.to_string(), keep_id, sql_time(expire_time)],
+                )
+                .expect("Error updating client_secrets table");
+        })
+        .await
+    }
+
+    async fn get_client_details(&self, client_id: &str) -> Option<(Uuid, ClientDetails)> {
+        let client_id = client_id.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    &format!("select {} from clients where client_id = ?1", CLIENT_COLUMNS),
+                    [client_id],
+                    |row| client_details(connection, row),
+                )
+                .optional()
+                .expect("Error executing query on clients table")
+        })
+        .await
+    }
+
+    async fn get_clients(&self) -> Vec<ClientDetails> {
+        self.run(move |connection| {
+            query(
+                connection,
+                &format!("select {} from clients order by client_id", CLIENT_COLUMNS),
+                [],
+                |row| client_details(connection, row).map(|(_, details)| details),
+            )
+            .expect("Error executing query on clients table")
+        })
+        .await
+    }
+
+    async fn get_client_by_client_id(&self, client_id: &str) -> Option<ClientInfo> {
+        let client_id = client_id.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    &format!("select {} from clients where client_id = ?1 and not disabled", CLIENT_INFO_COLUMNS),
+                    [client_id],
+                    client_info,
+                )
+                .optional()
+                .expect("Error executing query on clients table")
+        })
+        .await
+    }
+
+    async fn get_client_by_id(&self, client_db_id: Uuid) -> Option<ClientInfo> {
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    &format!("select {} from clients where id = ?1 and not disabled", CLIENT_INFO_COLUMNS),
+                    [client_db_id.to_string()],
+                    client_info,
+                )
+                .optional()
+                .expect("Error executing query on clients table")
+        })
+        .await
+    }
+
+    async fn create_client(&mut self, client_id: &str, params: &NewClient) -> Result<Uuid, Error> {
+        let client_id = client_id.to_owned();
+        let params = params.to_owned();
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            let grant_types = params
+                .grant_types
+                .as_ref()
+                .map(|g| g.join(" "))
+                .unwrap_or_else(|| "authorization_code refresh_token".to_string());
+
+            let client_db_id = transaction.query_row(
+                "insert into clients (client_id, display_name, redirect_uris, post_logout_redirect_uris, frontchannel_logout_uri,
+                 backchannel_logout_uri, grant_types, access_token_lifetime, refresh_token_lifetime, first_party, mfa_required,
+                 rate_limit, jwt_access_tokens) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13) returning id",
+                params![
+                    client_id,
+                    params.display_name,
+                    params.redirect_uris.join(" "),
+                    params.post_logout_redirect_uris.join(" "),
+                    params.frontchannel_logout_uri,
+                    params.backchannel_logout_uri,
+                    grant_types,
+                    params.access_token_lifetime,
+                    params.refresh_token_lifetime,
+                    params.first_party,
+                    params.mfa_required,
+                    params.rate_limit,
+                    params.jwt_access_tokens,
+                ],
+                |row| id(row, 0),
+            )?;
+
+            set_client_scopes(&transaction, client_db_id, &params.scopes, &params.default_scopes)?;
+            transaction.commit()?;
+            Ok(client_db_id)
+        })
+        .await
+    }
+
+    async fn update_client(&mut self, client_db_id: Uuid, update: &ClientUpdate) -> Result<(), Error> {
+        let update = update.to_owned();
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+
+            transaction.execute(
+                "update clients set display_name = coalesce(?2, display_name), redirect_uris = coalesce(?3, redirect_uris),
+                 post_logout_redirect_uris = coalesce(?4, post_logout_redirect_uris),
+                 frontchannel_logout_uri = coalesce(?5, frontchannel_logout_uri),
+                 backchannel_logout_uri = coalesce(?6, backchannel_logout_uri), grant_types = coalesce(?7, grant_types),
+                 access_token_lifetime = coalesce(?8, access_token_lifetime),
+                 refresh_token_lifetime = coalesce(?9, refresh_token_lifetime), first_party = coalesce(?10, first_party),
+                 mfa_required = coalesce(?11, mfa_required), rate_limit = coalesce(?12, rate_limit),
+                 jwt_access_tokens = coalesce(?13, jwt_access_tokens)
+                 where id = ?1",
+                params![
+                    client_db_id.to_string(),
+                    update.display_name,
+                    update.redirect_uris.as_ref().map(|u| u.join(" ")),
+                    update.post_logout_redirect_uris.as_ref().map(|u| u.join(" ")),
+                    update.frontchannel_logout_uri,
+                    update.backchannel_logout_uri,
+                    update.grant_types.as_ref().map(|g| g.join(" ")),
+                    update.access_token_lifetime,
+                    update.refresh_token_lifetime,
+                    update.first_party,
+                    update.mfa_required,
+                    update.rate_limit,
+                    update.jwt_access_tokens,
+                ],
+            )?;
+
+            if update.scopes.is_some() || update.default_scopes.is_some() {
+                // a missing list keeps what the client has
+                let current = client_scopes(&transaction, client_db_id)?;
+                let scopes = update
+                    .scopes
+                    .clone()
+                    .unwrap_or_else(|| current.iter().map(|s| s.name.clone()).collect());
+                let defaults = update.default_scopes.clone().unwrap_or_else(|| {
+                    current
+                        .iter()
+                        .filter(|s| s.is_default)
+                        .map(|s| s.name.clone())
+                        .collect()
+                });
+                set_client_scopes(&transaction, client_db_id, &scopes, &defaults)?;
+            }
+
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_client_disabled(&self, client_db_id: Uuid, disabled: bool) {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "update clients set disabled = ?2 where id = ?1",
+                    params![client_db_id.to_string(), disabled],
+                )
+                .expect("Error updating clients table");
+        })
+        .await
+    }
+
+    async fn revoke_client(&self, client_db_id: Uuid) {
+        self.run(move |connection| {
+            for table in ["authorization_codes", "access_tokens"] {
+                connection
+                    .execute(&format!("delete from {} where client_id = ?1", table), [client_db_id.to_string()])
+                    .expect("Error revoking client");
+            }
+        })
+        .await
+    }
+
+    async fn delete_client(&self, client_db_id: Uuid) -> bool {
+        self.run(move |connection| {
+            connection
+                .execute("delete from clients where id = ?1", [client_db_id.to_string()])
+                .expect("Error deleting from clients table")
+                == 1
+        })
+        .await
+    }
+
+    async fn get_client_tokens(&self, client_db_id: Uuid) -> Vec<TokenInfo> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select a.id, a.user_id, u.username, a.scope, a.device, a.creation_time, a.expire_time,
+                 a.refresh_token_hash is not null
+                 from access_tokens as a left join users as u on a.user_id = u.id
+                 where a.client_id = ?1 and (a.expire_time > now()
+                   or (a.refresh_token_hash is not null and (a.refresh_expire_time is null or a.refresh_expire_time > now())))
+                 order by a.creation_time desc",
+                [client_db_id.to_string()],
+                |row| {
+                    Ok(TokenInfo {
+                        id: row.get(0)?,
+                        user_id: optional_id(row, 1)?,
+                        username: row.get(2)?,
+                        scope: row.get(3)?,
+                        device: row.get(4)?,
+                        created: time(row, 5)?.timestamp(),
+                        expires: time(row, 6)?.timestamp(),
+                        refresh_token: row.get(7)?,
+                    })
+                },
+            )
+            .expect("Error executing query on access_tokens table")
+        })
+        .await
+    }
+
+    async fn get_client_rate_limit(&self, client_id: &str) -> Option<i32> {
+        let client_id = client_id.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row("select rate_limit from clients where client_id = ?1", [client_id], |row| row.get(0))
+                .optional()
+                .expect("Error executing query on clients table")
+                .flatten()
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl UserStore for Sqlite {
+    async fn get_password_hash(&self, username: &str) -> Option<(Uuid, String)> {
+        let username = username.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select id, password from users where username = ?1 and email_verified and not disabled",
+                    [username],
+                    |row| Ok((id(row, 0)?, row.get(1)?)),
+                )
+                .optional()
+                .expect("Error executing query on users table")
+        })
+        .await
+    }
+
+    async fn get_user_source(&self, username: &str) -> Option<UserSource> {
+        let username = username.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row("select source from users where username = ?1", [username], |row| {
+                    Ok(UserSource::parse(&row.get::<_, String>(0)?))
+                })
+                .optional()
+                .expect("Error executing query on users table")
+        })
+        .await
+    }
+
+    async fn register_user(
+        &mut self,
+        username: &str,
+        email: &str,
+        password_hash: &str,
+        email_verified: bool,
+        invitation: Option<String>,
+    ) -> Result<User, Error> {
+        let username = username.to_owned();
+        let email = email.to_owned();
+        let password_hash = password_hash.to_owned();
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+
+            let user = transaction
+                .query_row(
+                    &format!(
+                        "insert into users (username, email, password, email_verified) values (?1, ?2, ?3, ?4) returning {}",
+                        USER_COLUMNS
+                    ),
+                    params![username, email, password_hash, email_verified],
+                    user,
+                )
+                .map_err(unique_violation)?;
+
+            if let Some(code_hash) = invitation {
+                let claimed = transaction.execute(
+                    "update invitations set used_time = now(), used_by = ?1
+                     where code_hash = ?2 and used_time is null and (expire_time is null or expire_time > now())
+                     and (email is null or lower(email) = lower(?3))",
+                    params![user.id.to_string(), code_hash, email],
+                )?;
+                if claimed != 1 {
+                    return Err(InvalidRequestError("Invalid invitation code".to_string()));
+                }
+            }
+
+            transaction.commit()?;
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn register_directory_user(&mut self, username: &str, email: &str, subject: &str) -> Result<User, Error> {
+        let username = username.to_owned();
+        let email = email.to_owned();
+        let subject = subject.to_owned();
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            let user = transaction
+                .query_row(
+                    &format!(
+                        "insert into users (username, email, password, email_verified, source)
+                         values (?1, ?2, ?3, true, ?4) returning {}",
+                        USER_COLUMNS
+                    ),
+                    params![username, email, federation::NO_PASSWORD, UserSource::Directory.as_str()],
+                    user,
+                )
+                .map_err(unique_violation)?;
+            transaction.execute(
+                "insert into federated_identities (provider, subject, user_id, email, last_login_time)
+                 values (?1, ?2, ?3, ?4, now())",
+                params![ldap::PROVIDER, subject, user.id.to_string(), email],
+            )?;
+            transaction.commit()?;
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn get_users(
+        &self,
+        username: Option<&str>,
+        email: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> (Vec<User>, i64) {
+        // like ignores case for ascii, as ilike does
+        let filter = "where (?1 is null or username like '%' || ?1 || '%')
+                      and (?2 is null or email like '%' || ?2 || '%')";
+        let (username, email) = (username.map(String::from), email.map(String::from));
+        self.run(move |connection| {
+            let users = query(
+                connection,
+                &format!("select {} from users {} order by username limit ?3 offset ?4", USER_COLUMNS, filter),
+                params![username, email, limit, offset],
+                user,
+            )
+            .expect("Error executing query on users table");
+
+            let total = connection
+                .query_row(&format!("select count(*) from users {}", filter), params![username, email], |row| {
+                    row.get(0)
+                })
+                .expect("Error executing query on users table");
+
+            (users, total)
+        })
+        .await
+    }
+
+    async fn get_user(&self, user_id: Uuid) -> Option<User> {
+        self.run(move |connection| {
+            connection
+                .query_row(&format!("select {} from users where id = ?1", USER_COLUMNS), [user_id.to_string()], user)
+                .optional()
+                .expect("Error executing query on users table")
+        })
+        .await
+    }
+
+    async fn get_user_by_username(&self, username: &str) -> Option<User> {
+        let username = username.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(&format!("select {} from users where username = ?1", USER_COLUMNS), [username], user)
+                .optional()
+                .expect("Error executing query on users table")
+        })
+        .await
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Option<User> {
+        let email = email.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    &format!("select {} from users where lower(email) = lower(?1)", USER_COLUMNS),
+                    [email],
+                    user,
+                )
+                .optional()
+                .expect("Error executing query on users table")
+        })
+        .await
+    }
+
+    async fn update_user(&self, user_id: Uuid, update: &UserUpdate) -> Result<Option<User>, Error> {
+        let update = update.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    &format!(
+                        "update users set username = coalesce(?2, username), email = coalesce(?3, email),
+                         email_verified = coalesce(?4, email_verified), mfa_required = coalesce(?5, mfa_required)
+                         where id = ?1 returning {}",
+                        USER_COLUMNS
+                    ),
+                    params![
+                        user_id.to_string(),
+                        update.username,
+                        update.email,
+                        update.email_verified,
+                        update.mfa_required
+                    ],
+                    user,
+                )
+                .optional()
+                .map_err(unique_violation)
+        })
+        .await
+    }
+
+    async fn set_user_disabled(&self, user_id: Uuid, disabled: bool) -> Option<User> {
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    &format!("update users set disabled = ?2 where id = ?1 returning {}", USER_COLUMNS),
+                    params![user_id.to_string(), disabled],
+                    user,
+                )
+                .optional()
+                .expect("Error updating users table")
+        })
+        .await
+    }
+
+    async fn set_password(&self, user_id: Uuid, password_hash: &str) -> bool {
+        let password_hash = password_hash.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "update users set password = ?2 where id = ?1",
+                    params![user_id.to_string(), password_hash],
+                )
+                .expect("Error updating users table")
+                == 1
+        })
+        .await
+    }
+
+    async fn delete_user(&self, user_id: Uuid) -> bool {
+        self.run(move |connection| {
+            connection
+                .execute("delete from users where id = ?1", [user_id.to_string()])
+                .expect("Error deleting from users table")
+                == 1
+        })
+        .await
+    }
+
+    async fn revoke_user(&self, user_id: Uuid) {
+        self.run(move |connection| {
+            for table in ["login_sessions", "authorization_codes", "access_tokens"] {
+                connection
+                    .execute(&format!("delete from {} where user_id = ?1", table), [user_id.to_string()])
+                    .expect("Error revoking user");
+            }
+        })
+        .await
+    }
+
+    async fn create_email_token(
+        &self,
+        user_id: Uuid,
+        purpose: EmailTokenPurpose,
+        email: &str,
+        lifetime: Duration,
+    ) -> String {
+        let email = email.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "delete from email_tokens where user_id = ?1 and purpose = ?2 and used_time is null",
+                    params![user_id.to_string(), purpose.as_str()],
+                )
+                .expect("Error deleting from email_tokens table");
+
+            let token = session::generate_secret(48);
+            connection
+                .execute(
+                    "insert into email_tokens (token_hash, user_id, purpose, email, expire_time) values (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        session::hash_token(&token),
+                        user_id.to_string(),
+                        purpose.as_str(),
+                        email,
+                        sql_time(Local::now() + lifetime)
+                    ],
+                )
+                .expect("Error inserting into email_tokens table");
+            token
+        })
+        .await
+    }
+
+    async fn find_email_token(&self, token: &str, purpose: EmailTokenPurpose) -> Option<(Uuid, String)> {
+        let token = token.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select user_id, email from email_tokens
+                     where token_hash = ?1 and purpose = ?2 and used_time is null and expire_time > now()",
+                    params![session::hash_token(&token), purpose.as_str()],
+                    |row| Ok((id(row, 0)?, row.get(1)?)),
+                )
+                .optional()
+                .expect("Error executing query on email_tokens table")
+        })
+        .await
+    }
+
+    async fn use_email_token(&self, token: &str, purpose: EmailTokenPurpose) -> Option<(Uuid, String)> {
+        let token = token.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "update email_tokens set used_time = now()
+                     where token_hash = ?1 and purpose = ?2 and used_time is null and expire_time > now()
+                     returning user_id, email",
+                    params![session::hash_token(&token), purpose.as_str()],
+                    |row| Ok((id(row, 0)?, row.get(1)?)),
+                )
+                .optional()
+                .expect("Error updating email_tokens table")
+        })
+        .await
+    }
+
+    async fn set_email_verified(&self, user_id: Uuid, email: &str) -> bool {
+        let email = email.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "update users set email_verified = true where id = ?1 and email = ?2",
+                    params![user_id.to_string(), email],
+                )
+                .expect("Error updating users table")
+                == 1
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl TokenStore for Sqlite {
+    async fn validate_access_token(&self, access_token: String, client_db_id: Uuid) -> Option<Introspection> {
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select a.scope, a.expire_time, a.creation_time, c.username, c.id, b.client_id, b.display_name,
+                     a.token_type, a.issuer, a.auth_time, a.amr
+                     from access_tokens as a join clients as b on a.client_id = b.id left join users as c on a.user_id = c.id
+                     where a.access_token = ?1 and b.id = ?2",
+                    params![access_token, client_db_id.to_string()],
+                    |row| {
+                        let expire_time = time(row, 1)?;
+                        Ok(Introspection {
+                            active: expire_time >= Local::now(),
+                            client_id: row.get(5)?,
+                            username: row.get(3)?,
+                            user_id: optional_id(row, 4)?,
+                            scope: row.get(0)?,
+                            effective_scope: None,
+                            token_type: row.get(7)?,
+                            issuer: row.get(8)?,
+                            exp: expire_time.timestamp(),
+                            iat: time(row, 2)?.timestamp(),
+                            auth_time: optional_time(row, 9)?.map(|t| t.timestamp()),
+                            amr: row.get::<_, Option<String>>(10)?.map(split_list).unwrap_or_default(),
+                            roles: Vec::new(),
+                            groups: Vec::new(),
+                            claims: serde_json::Map::new(),
+                        })
+                    },
+                )
+                .optional()
+                .expect("Error executing query on access token/clients table")
+        })
+        .await
+    }
+
+    async fn insert_token(
+        &self,
+        generated_token: String,
+        refresh_token: Option<String>,
+        grant: TokenGrant,
+        issuer: String,
+        client_info: &ClientInfo,
+    ) -> AccessToken {
+        let token_duration = Duration::seconds(
+            client_info
+                .access_token_lifetime
+                .map(i64::from)
+                .unwrap_or(DEFAULT_TOKEN_LIFETIME),
+        );
+        let refresh_expire_time = match (&refresh_token, client_info.refresh_token_lifetime) {
+            (Some(_), Some(lifetime)) => Some(Local::now() + Duration::seconds(lifetime.into())),
+            _ => None,
+        };
+        let amr = Some(grant.amr.join(" ")).filter(|a| !a.is_empty());
+        let refresh_token_hash = refresh_token.as_deref().map(session::hash_token);
+        let device = grant.device.clone().unwrap_or_else(|| "unknown".to_string());
+
+        // a new token for the same user, client and device replaces the old one, see unique_uid_cid
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into access_tokens (access_token, expire_time, user_id, client_id, scope, creation_time, token_type,
+                     issuer, device, refresh_token_hash, auth_time, sid, refresh_expire_time, amr)
+                     values (?1, ?2, ?3, ?4, ?5, now(), 'bearer', ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+                     on conflict (user_id, client_id, device) do
+                     update set access_token = ?1, expire_time = ?2, creation_time = now(), scope = ?5, issuer = ?6, device = ?7,
+                     refresh_token_hash = ?8, auth_time = ?9, sid = ?10, refresh_expire_time = ?11, amr = ?12",
+                    params![
+                        generated_token,
+                        sql_time(Local::now() + token_duration),
+                        grant.user_id.map(|id| id.to_string()),
+                        grant.client_id.to_string(),
+                        grant.scope,
+                        issuer,
+                        device,
+                        refresh_token_hash,
+                        grant.auth_time.map(sql_time),
+                        grant.sid,
+                        refresh_expire_time.map(sql_time),
+                        amr
+                    ],
+                )
+                .expect("Error creating access token");
+
+            AccessToken {
+                access_token: generated_token,
+                token_type: "bearer".to_string(),
+                expires_in: token_duration.num_seconds(),
+                scope: grant.scope,
+                refresh_token,
+                id_token: None,
+            }
+        })
+        .await
+    }
+
+    async fn find_refresh_token(&self, refresh_token: &str, client_db_id: Uuid) -> Option<TokenGrant> {
+        let refresh_token_hash = session::hash_token(refresh_token);
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select user_id, scope, device, auth_time, sid, amr from access_tokens
+                     where refresh_token_hash = ?1 and client_id = ?2
+                     and (refresh_expire_time is null or refresh_expire_time > now())",
+                    params![refresh_token_hash, client_db_id.to_string()],
+                    |row| {
+                        Ok(TokenGrant {
+                            user_id: optional_id(row, 0)?,
+                            client_id: client_db_id,
+                            scope: row.get(1)?,
+                            device: row.get(2)?,
+                            auth_time: optional_time(row, 3)?,
+                            amr: row.get::<_, Option<String>>(5)?.map(split_list).unwrap_or_default(),
+                            nonce: None,
+                            sid: row.get(4)?,
+                        })
+                    },
+                )
+                .optional()
+                .expect("Error executing query on access_tokens table")
+        })
+        .await
+    }
+
+    async fn revoke_tokens(&self, user_id: Option<Uuid>, client_db_id: Option<Uuid>, device: Option<&str>) -> u64 {
+        let device = device.map(String::from);
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "delete from access_tokens where (?1 is null or user_id = ?1)
+                     and (?2 is null or client_id = ?2) and (?3 is null or device = ?3)",
+                    params![user_id.map(|id| id.to_string()), client_db_id.map(|id| id.to_string()), device],
+                )
+                .expect("Error revoking tokens") as u64
+        })
+        .await
+    }
+
+    async fn get_active_token_scope(&self, access_token: &str) -> Option<String> {
+        let access_token = access_token.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select scope from access_tokens where access_token = ?1 and expire_time > now()",
+                    [access_token],
+                    |row| row.get(0),
+                )
+                .optional()
+                .expect("Error executing query on access_tokens table")
+                .flatten()
+        })
+        .await
+    }
+
+    async fn get_active_token_user(&self, access_token: &str) -> Option<(Option<Uuid>, Option<String>)> {
+        let access_token = access_token.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select user_id, scope from access_tokens where access_token = ?1 and expire_time > now()",
+                    [access_token],
+                    |row| Ok((optional_id(row, 0)?, row.get(1)?)),
+                )
+                .optional()
+                .expect("Error executing query on access_tokens table")
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl CodeStore for Sqlite {
+    async fn insert_code(
+        &self,
+        code: &str,
+        client_db_id: Uuid,
+        authorization: &AuthorizationCode,
+        request: &AuthorizationParams,
+    ) {
+        let code = code.to_owned();
+        let authorization = authorization.to_owned();
+        let request = request.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into authorization_codes (client_id, user_id, code, device, pcke_hash, scope, redirect_uri, nonce,
+                     auth_time, sid, creation_time, expire_time, amr)
+                     values (?1, ?2, ?3, 'unknown', ?4, ?5, ?6, ?7, ?8, ?9, now(), ?10, ?11)",
+                    params![
+                        client_db_id.to_string(),
+                        authorization.user_id.to_string(),
+                        code,
+                        request.code_challenge,
+                        authorization.scope,
+                        request.redirect_uri,
+                        authorization.nonce,
+                        authorization.auth_time.map(sql_time),
+                        authorization.sid,
+                        sql_time(Local::now() + Duration::minutes(10)),
+                        authorization.amr.join(" ")
+                    ],
+                )
+                .expect("Error inserting into authorization_codes table");
+        })
+        .await
+    }
+
+    async fn validate_code(
+        &self,
+        code: &str,
+        pcke: &str,
+        client_db_id: Uuid,
+        redirect_uri: &str,
+    ) -> Option<AuthorizationCode> {
+        let code = code.to_owned();
+        let pcke = pcke.to_owned();
+        let redirect_uri = redirect_uri.to_owned();
+        self.run(move |connection| {
+            let mut codes = query(
+                connection,
+                "delete from authorization_codes where code = ?1 and client_id = ?2
+                 and (pcke_hash is null or pcke_hash = ?3) and (redirect_uri is null or redirect_uri = ?4)
+                 and expire_time > now()
+                 returning user_id, scope, auth_time, nonce, sid, amr",
+                params![code, client_db_id.to_string(), session::hash_token(&pcke), redirect_uri],
+                |row| {
+                    Ok(AuthorizationCode {
+                        user_id: id(row, 0)?,
+                        scope: row.get(1)?,
+                        auth_time: optional_time(row, 2)?,
+                        amr: row.get::<_, Option<String>>(5)?.map(split_list).unwrap_or_default(),
+                        nonce: row.get(3)?,
+                        sid: row.get(4)?,
+                    })
+                },
+            )
+            .expect("Error executing query on authorization_codes table");
+
+            if codes.len() == 1 {
+                codes.pop()
+            } else {
+                None
+            }
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl SessionStore for Sqlite {
+    async fn create_session(&self, session_hash: &str, sid: &str, user_id: Uuid, amr: &[String], lifetime: Duration) {
+        let session_hash = session_hash.to_owned();
+        let sid = sid.to_owned();
+        let amr = amr.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into login_sessions (session_token, sid, user_id, amr, creation_time, expire_time)
+                     values (?1, ?2, ?3, ?4, now(), ?5)",
+                    params![session_hash, sid, user_id.to_string(), amr.join(" "), sql_time(Local::now() + lifetime)],
+                )
+                .expect("Error inserting into login_sessions table");
+        })
+        .await
+    }
+
+    async fn find_session(&self, session_hash: &str) -> Option<LoginSession> {
+        let session_hash = session_hash.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select id, sid, user_id, creation_time, amr from login_sessions
+                     where session_token = ?1 and expire_time > now()",
+                    [session_hash],
+                    |row| {
+                        Ok(LoginSession {
+                            id: row.get(0)?,
+                            sid: row.get(1)?,
+                            user_id: id(row, 2)?,
+                            auth_time: time(row, 3)?,
+                            amr: split_list(row.get(4)?),
+                        })
+                    },
+                )
+                .optional()
+                .expect("Error executing query on login_sessions table")
+        })
+        .await
+    }
+
+    async fn set_session_amr(&self, session_id: i32, amr: &[String]) {
+        let amr = amr.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute("update login_sessions set amr = ?2 where id = ?1", params![session_id, amr.join(" ")])
+                .expect("Error updating login_sessions table");
+        })
+        .await
+    }
+
+    async fn delete_session(&self, session_id: i32) {
+        self.run(move |connection| {
+            connection
+                .execute("delete from login_sessions where id = ?1", [session_id])
+                .expect("Error deleting from login_sessions table");
+        })
+        .await
+    }
+
+    async fn add_session_client(&self, session_id: i32, client_db_id: Uuid) {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into session_clients (session_id, client_id) values (?1, ?2) on conflict do nothing",
+                    params![session_id, client_db_id.to_string()],
+                )
+                .expect("Error inserting into session_clients table");
+        })
+        .await
+    }
+
+    async fn get_session_clients(&self, session_id: i32) -> Vec<LogoutClient> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select c.client_id, c.frontchannel_logout_uri, c.backchannel_logout_uri from session_clients as s
+                 join clients as c on s.client_id = c.id where s.session_id = ?1",
+                [session_id],
+                |row| {
+                    Ok(LogoutClient {
+                        client_id: row.get(0)?,
+                        frontchannel_logout_uri: row.get(1)?,
+                        backchannel_logout_uri: row.get(2)?,
+                    })
+                },
+            )
+            .expect("Error executing query on session_clients table")
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ScopeStore for Sqlite {
+    async fn get_scopes(&self) -> Vec<Scope> {
+        self.run(move |connection| {
+            query(connection, "select name, description, requires_consent from scopes", [], |row| {
+                Ok(Scope {
+                    name: row.get(0)?,
+                    description: row.get(1)?,
+                    requires_consent: row.get(2)?,
+                })
+            })
+            .expect("Error executing query on scopes table")
+        })
+        .await
+    }
+
+    async fn get_scope_rules(&self) -> Vec<ScopeRule> {
+        self.run(move |connection| {
+            query(connection, "select scope, implies from scope_implications", [], |row| {
+                Ok(ScopeRule {
+                    scope: row.get(0)?,
+                    implies: row.get(1)?,
+                })
+            })
+            .expect("Error executing query on scope_implications table")
+        })
+        .await
+    }
+
+    async fn get_scope_policy(&self, client_db_id: Uuid, user_id: Option<Uuid>) -> ScopePolicy {
+        self.run(move |connection| {
+            // grants for parameterized scopes, those of the user at this client or, without a user, of the client
+            let grants = query(
+                connection,
+                "select scope from scope_grants where client_id = ?1 and user_id is ?2",
+                params![client_db_id.to_string(), user_id.map(|id| id.to_string())],
+                |row| row.get(0),
+            )
+            .expect("Error executing query on scope_grants table");
+            let rules = query(connection, "select scope, implies from scope_implications", [], |row| {
+                Ok(ScopeRule {
+                    scope: row.get(0)?,
+                    implies: row.get(1)?,
+                })
+            })
+            .expect("Error executing query on scope_implications table");
+
+            ScopePolicy {
+                allowed: client_scopes(connection, client_db_id).expect("Error executing query on client_scopes table"),
+                grants,
+                rules,
+            }
+        })
+        .await
+    }
+
+    async fn get_consented_scopes(&self, user_id: Uuid, client_db_id: Uuid) -> Vec<String> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select scope from consents where user_id = ?1 and client_id = ?2",
+                [user_id.to_string(), client_db_id.to_string()],
+                |row| row.get(0),
+            )
+            .expect("Error executing query on consents table")
+        })
+        .await
+    }
+
+    async fn insert_consents(&self, user_id: Uuid, client_db_id: Uuid, scopes: &[String]) {
+        let scopes = scopes.to_owned();
+        self.run(move |connection| {
+            for scope in scopes {
+                connection
+                    .execute(
+                        "insert into consents (user_id, client_id, scope, creation_time) values (?1, ?2, ?3, now())
+                         on conflict (user_id, client_id, scope) do nothing",
+                        params![user_id.to_string(), client_db_id.to_string(), scope],
+                    )
+                    .expect("Error inserting into consents table");
+            }
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl MfaStore for Sqlite {
+    async fn get_totp(&self, user_id: Uuid) -> Option<TotpCredential> {
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select secret, confirmed_time is not null from totp_credentials where user_id = ?1",
+                    [user_id.to_string()],
+                    |row| {
+                        Ok(TotpCredential {
+                            secret: row.get(0)?,
+                            confirmed: row.get(1)?,
+                        })
+                    },
+                )
+                .optional()
+                .expect("Error executing query on totp_credentials table")
+        })
+        .await
+    }
+
+    async fn set_pending_totp(&self, user_id: Uuid, secret: &str) {
+        let secret = secret.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into totp_credentials (user_id, secret) values (?1, ?2)
+                     on conflict (user_id) do update set secret = ?2, creation_time = now()
+                     where totp_credentials.confirmed_time is null",
+                    params![user_id.to_string(), secret],
+                )
+                .expect("Error inserting into totp_credentials table");
+        })
+        .await
+    }
+
+    async fn confirm_totp(&self, user_id: Uuid, step: i64) {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "update totp_credentials set confirmed_time = now(), last_used_step = ?2 where user_id = ?1",
+                    params![user_id.to_string(), step],
+                )
+                .expect("Error updating totp_credentials table");
+        })
+        .await
+    }
+
+    async fn use_totp_step(&self, user_id: Uuid, step: i64) -> bool {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "update totp_credentials set last_used_step = ?2 where user_id = ?1 and confirmed_time is not null
+                     and (last_used_step is null or last_used_step < ?2)",
+                    params![user_id.to_string(), step],
+                )
+                .expect("Error updating totp_credentials table")
+                == 1
+        })
+        .await
+    }
+
+    async fn set_recovery_codes(&self, user_id: Uuid, code_hashes: &[String]) {
+        let code_hashes = code_hashes.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute("delete from recovery_codes where user_id = ?1", [user_id.to_string()])
+                .expect("Error deleting from recovery_codes table");
+            for code_hash in code_hashes {
+                connection
+                    .execute(
+                        "insert into recovery_codes (user_id, code_hash) values (?1, ?2)",
+                        params![user_id.to_string(), code_hash],
+                    )
+                    .expect("Error inserting into recovery_codes table");
+            }
+        })
+        .await
+    }
+
+    async fn use_recovery_code(&self, user_id: Uuid, code_hash: &str) -> bool {
+        let code_hash = code_hash.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "update recovery_codes set used_time = now() where user_id = ?1 and code_hash = ?2 and used_time is null",
+                    params![user_id.to_string(), code_hash],
+                )
+                .expect("Error updating recovery_codes table")
+                == 1
+        })
+        .await
+    }
+
+    async fn delete_mfa(&self, user_id: Uuid) -> bool {
+        self.run(move |connection| {
+            connection
+                .execute("delete from recovery_codes where user_id = ?1", [user_id.to_string()])
+                .expect("Error deleting from recovery_codes table");
+            connection
+                .execute("delete from totp_credentials where user_id = ?1", [user_id.to_string()])
+                .expect("Error deleting from totp_credentials table")
+                == 1
+        })
+        .await
+    }
+
+    async fn create_challenge(
+        &self,
+        challenge: &str,
+        purpose: ChallengePurpose,
+        user_id: Option<Uuid>,
+        lifetime: Duration,
+    ) {
+        let challenge = challenge.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute("delete from webauthn_challenges where expire_time < now()", [])
+                .expect("Error deleting from webauthn_challenges table");
+            connection
+                .execute(
+                    "insert into webauthn_challenges (challenge, purpose, user_id, expire_time) values (?1, ?2, ?3, ?4)",
+                    params![
+                        session::hash_token(&challenge),
+                        purpose.as_str(),
+                        user_id.map(|id| id.to_string()),
+                        sql_time(Local::now() + lifetime)
+                    ],
+                )
+                .expect("Error inserting into webauthn_challenges table");
+        })
+        .await
+    }
+
+    async fn use_challenge(&self, challenge: &str, purpose: ChallengePurpose) -> Option<Option<Uuid>> {
+        let challenge = challenge.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "delete from webauthn_challenges where challenge = ?1 and purpose = ?2 and expire_time > now()
+                     returning user_id",
+                    params![session::hash_token(&challenge), purpose.as_str()],
+                    |row| optional_id(row, 0),
+                )
+                .optional()
+                .expect("Error deleting from webauthn_challenges table")
+        })
+        .await
+    }
+
+    async fn add_passkey(&self, user_id: Uuid, credential: &NewCredential, name: &str) -> bool {
+        let credential = credential.to_owned();
+        let name = name.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into webauthn_credentials (user_id, credential_id, public_key, sign_count, name)
+                     values (?1, ?2, ?3, ?4, ?5) on conflict (credential_id) do nothing",
+                    params![
+                        user_id.to_string(),
+                        credential.credential_id,
+                        credential.public_key,
+                        credential.sign_count,
+                        name
+                    ],
+                )
+                .expect("Error inserting into webauthn_credentials table")
+                == 1
+        })
+        .await
+    }
+
+    async fn get_passkeys(&self, user_id: Uuid) -> Vec<Passkey> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select id, name, creation_time, last_used_time from webauthn_credentials where user_id = ?1 order by id",
+                [user_id.to_string()],
+                |row| {
+                    Ok(Passkey {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        created: time(row, 2)?.timestamp(),
+                        last_used: optional_time(row, 3)?.map(|t| t.timestamp()),
+                    })
+                },
+            )
+            .expect("Error executing query on webauthn_credentials table")
+        })
+        .await
+    }
+
+    async fn get_passkey_ids(&self, user_id: Uuid) -> Vec<String> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select credential_id from webauthn_credentials where user_id = ?1",
+                [user_id.to_string()],
+                |row| row.get(0),
+            )
+            .expect("Error executing query on webauthn_credentials table")
+        })
+        .await
+    }
+
+    async fn find_passkey(&self, credential_id: &str) -> Option<PasskeyCredential> {
+        let credential_id = credential_id.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select w.id, w.user_id, w.public_key, w.sign_count from webauthn_credentials as w
+                     join users as u on w.user_id = u.id where w.credential_id = ?1 and u.email_verified and not u.disabled",
+                    [credential_id],
+                    |row| {
+                        Ok(PasskeyCredential {
+                            id: row.get(0)?,
+                            user_id: id(row, 1)?,
+                            public_key: row.get(2)?,
+                            sign_count: row.get(3)?,
+                        })
+                    },
+                )
+                .optional()
+                .expect("Error executing query on webauthn_credentials table")
+        })
+        .await
+    }
+
+    async fn use_passkey(&self, passkey_id: i32, sign_count: i64) {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "update webauthn_credentials set sign_count = ?2, last_used_time = now() where id = ?1",
+                    params![passkey_id, sign_count],
+                )
+                .expect("Error updating webauthn_credentials table");
+        })
+        .await
+    }
+
+    async fn delete_passkey(&self, user_id: Uuid, passkey_id: i32) -> bool {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "delete from webauthn_credentials where id = ?1 and user_id = ?2",
+                    params![passkey_id, user_id.to_string()],
+                )
+                .expect("Error deleting from webauthn_credentials table")
+                == 1
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl LockoutStore for Sqlite {
+    async fn is_locked(&self, kind: &str, subject: &str) -> bool {
+        let kind = kind.to_owned();
+        let subject = subject.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select 1 from login_failures where kind = ?1 and subject = ?2 and locked_until > now()",
+                    [kind, subject],
+                    |_| Ok(()),
+                )
+                .optional()
+                .expect("Error selecting from login_failures table")
+                .is_some()
+        })
+        .await
+    }
+
+    async fn add_login_failure(&self, kind: &str, subject: &str, window_start: DateTime<Local>) -> i32 {
+        let kind = kind.to_owned();
+        let subject = subject.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "insert into login_failures as f (kind, subject, failures, last_failure) values (?1, ?2, 1, now())
+                     on conflict (kind, subject) do update set
+                       failures = case when f.last_failure < ?3 then 1 else f.failures + 1 end,
+                       last_failure = now()
+                     returning failures",
+                    params![kind, subject, sql_time(window_start)],
+                    |row| row.get(0),
+                )
+                .expect("Error inserting into login_failures table")
+        })
+        .await
+    }
+
+    async fn lock_login(&self, kind: &str, subject: &str, locked_until: DateTime<Local>) {
+        let kind = kind.to_owned();
+        let subject = subject.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "update login_failures set locked_until = ?3 where kind = ?1 and subject = ?2",
+                    params![kind, subject, sql_time(locked_until)],
+                )
+                .expect("Error updating login_failures table");
+        })
+        .await
+    }
+
+    async fn clear_login_failures(&self, kind: &str, subject: &str) -> bool {
+        let kind = kind.to_owned();
+        let subject = subject.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute("delete from login_failures where kind = ?1 and subject = ?2", [kind, subject])
+                .expect("Error deleting from login_failures table")
+                == 1
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl AttributeStore for Sqlite {
+    async fn get_attribute_definitions(&self) -> Vec<AttributeDefinition> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select name, type, description, max_length, pattern, scope from attributes order by name",
+                [],
+                |row| {
+                    Ok(AttributeDefinition {
+                        name: row.get(0)?,
+                        kind: row.get(1)?,
+                        description: row.get(2)?,
+                        max_length: row.get(3)?,
+                        pattern: row.get(4)?,
+                        scope: row.get(5)?,
+                    })
+                },
+            )
+            .expect("Error executing query on attributes table")
+        })
+        .await
+    }
+
+    async fn put_attribute_definition(&self, definition: &AttributeDefinition) {
+        let definition = definition.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into attributes (name, type, description, max_length, pattern, scope)
+                     values (?1, ?2, ?3, ?4, ?5, ?6)
+                     on conflict (name) do update set type = ?2, description = ?3, max_length = ?4, pattern = ?5, scope = ?6",
+                    params![
+                        definition.name,
+                        definition.kind,
+                        definition.description,
+                        definition.max_length,
+                        definition.pattern,
+                        definition.scope
+                    ],
+                )
+                .expect("Error inserting into attributes table");
+        })
+        .await
+    }
+
+    async fn delete_attribute_definition(&mut self, name: &str) -> Result<bool, Error> {
+        let name = name.to_owned();
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute("delete from user_data where key = ?1", [&name])?;
+            let deleted = transaction.execute("delete from attributes where name = ?1", [&name])?;
+            transaction.commit()?;
+            Ok(deleted == 1)
+        })
+        .await
+    }
+
+    async fn get_attribute_values(&self, name: &str) -> Vec<String> {
+        let name = name.to_owned();
+        self.run(move |connection| {
+            query(connection, "select value from user_data where key = ?1", [name], |row| row.get(0))
+                .expect("Error executing query on user_data table")
+        })
+        .await
+    }
+
+    async fn get_user_attributes(&self, user_id: Uuid) -> Vec<(String, String)> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select key, value from user_data where user_id = ?1 order by key",
+                [user_id.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .expect("Error executing query on user_data table")
+        })
+        .await
+    }
+
+    async fn set_user_attributes(&mut self, user_id: Uuid, changes: &[(String, Option<String>)]) -> Result<(), Error> {
+        let changes = changes.to_owned();
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            for (name, value) in changes {
+                match value {
+                    Some(value) => transaction.execute(
+                        "insert into user_data (user_id, key, value) values (?1, ?2, ?3)
+                         on conflict (user_id, key) do update set value = ?3",
+                        params![user_id.to_string(), name, value],
+                    )?,
+                    None => transaction.execute(
+                        "delete from user_data where user_id = ?1 and key = ?2",
+                        params![user_id.to_string(), name],
+                    )?,
+                };
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl RoleStore for Sqlite {
+    async fn get_roles(&self, client_db_id: Option<Uuid>) -> Vec<Role> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select r.name, c.client_id, r.description from roles as r left join clients as c on r.client_id = c.id
+                 where r.client_id is ?1 order by r.name",
+                [client_db_id.map(|id| id.to_string())],
+                role,
+            )
+            .expect("Error executing query on roles table")
+        })
+        .await
+    }
+
+    async fn find_role(&self, client_db_id: Option<Uuid>, name: &str) -> Option<i32> {
+        let name = name.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "select id from roles where client_id is ?1 and name = ?2",
+                    params![client_db_id.map(|id| id.to_string()), name],
+                    |row| row.get(0),
+                )
+                .optional()
+                .expect("Error executing query on roles table")
+        })
+        .await
+    }
+
+    async fn put_role(&self, client_db_id: Option<Uuid>, name: &str, description: &Option<String>) {
+        // each kind of role has its own unique index
+        let conflict = match client_db_id {
+            Some(_) => "(client_id, name) where client_id is not null",
+            None => "(name) where client_id is null",
+        };
+        let name = name.to_owned();
+        let description = description.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    &format!(
+                        "insert into roles (name, description, client_id) values (?1, ?2, ?3)
+                         on conflict {} do update set description = ?2",
+                        conflict
+                    ),
+                    params![name, description, client_db_id.map(|id| id.to_string())],
+                )
+                .expect("Error updating roles table");
+        })
+        .await
+    }
+
+    async fn delete_role(&self, client_db_id: Option<Uuid>, name: &str) -> bool {
+        let name = name.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "delete from roles where client_id is ?1 and name = ?2",
+                    params![client_db_id.map(|id| id.to_string()), name],
+                )
+                .expect("Error deleting from roles table")
+                == 1
+        })
+        .await
+    }
+
+    async fn get_groups(&self) -> Vec<Group> {
+        self.run(move |connection| {
+            query(connection, "select id, name, description from groups order by name", [], |row| {
+                Ok(Group {
+                    name: row.get(1)?,
+                    description: row.get(2)?,
+                    roles: group_roles(connection, row.get(0)?)?,
+                    members: None,
+                })
+            })
+            .expect("Error executing query on groups table")
+        })
+        .await
+    }
+
+    async fn get_group(&self, name: &str) -> Option<(i32, Group)> {
+        let name = name.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row("select id, name, description from groups where name = ?1", [name], |row| {
+                    let group_id: i32 = row.get(0)?;
+                    Ok((
+                        group_id,
+                        Group {
+                            name: row.get(1)?,
+                            description: row.get(2)?,
+                            roles: group_roles(connection, group_id)?,
+                            members: Some(group_members(connection, group_id)?),
+                        },
+                    ))
+                })
+                .optional()
+                .expect("Error executing query on groups table")
+        })
+        .await
+    }
+
+    async fn put_group(&self, name: &str, description: &Option<String>) {
+        let name = name.to_owned();
+        let description = description.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into groups (name, description) values (?1, ?2) on conflict (name) do update set description = ?2",
+                    params![name, description],
+                )
+                .expect("Error updating groups table");
+        })
+        .await
+    }
+
+    async fn delete_group(&self, name: &str) -> bool {
+        let name = name.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute("delete from groups where name = ?1", [name])
+                .expect("Error deleting from groups table")
+                == 1
+        })
+        .await
+    }
+
+    async fn add_group_member(&self, group_id: i32, user_id: Uuid) {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into group_members (group_id, user_id) values (?1, ?2) on conflict do nothing",
+                    params![group_id, user_id.to_string()],
+                )
+                .expect("Error updating group_members table");
+        })
+        .await
+    }
+
+    async fn remove_group_member(&self, group_id: i32, user_id: Uuid) -> bool {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "delete from group_members where group_id = ?1 and user_id = ?2",
+                    params![group_id, user_id.to_string()],
+                )
+                .expect("Error deleting from group_members table")
+                == 1
+        })
+        .await
+    }
+
+    async fn add_group_role(&self, group_id: i32, role_id: i32) {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into group_roles (group_id, role_id) values (?1, ?2) on conflict do nothing",
+                    [group_id, role_id],
+                )
+                .expect("Error updating group_roles table");
+        })
+        .await
+    }
+
+    async fn remove_group_role(&self, group_id: i32, role_id: i32) -> bool {
+        self.run(move |connection| {
+            connection
+                .execute("delete from group_roles where group_id = ?1 and role_id = ?2", [group_id, role_id])
+                .expect("Error deleting from group_roles table")
+                == 1
+        })
+        .await
+    }
+
+    async fn add_user_role(&self, user_id: Uuid, role_id: i32) {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into user_roles (user_id, role_id) values (?1, ?2) on conflict do nothing",
+                    params![user_id.to_string(), role_id],
+                )
+                .expect("Error updating user_roles table");
+        })
+        .await
+    }
+
+    async fn remove_user_role(&self, user_id: Uuid, role_id: i32) -> bool {
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "delete from user_roles where user_id = ?1 and role_id = ?2",
+                    params![user_id.to_string(), role_id],
+                )
+                .expect("Error deleting from user_roles table")
+                == 1
+        })
+        .await
+    }
+
+    async fn get_user_roles(&self, user_id: Uuid) -> Vec<Role> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select r.name, c.client_id, r.description from user_roles as ur join roles as r on ur.role_id = r.id
+                 left join clients as c on r.client_id = c.id where ur.user_id = ?1 order by c.client_id nulls first, r.name",
+                [user_id.to_string()],
+                role,
+            )
+            .expect("Error executing query on user_roles table")
+        })
+        .await
+    }
+
+    async fn get_user_groups(&self, user_id: Uuid) -> Vec<String> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select g.name from group_members as gm join groups as g on gm.group_id = g.id
+                 where gm.user_id = ?1 order by g.name",
+                [user_id.to_string()],
+                |row| row.get(0),
+            )
+            .expect("Error executing query on group_members table")
+        })
+        .await
+    }
+
+    async fn set_directory_groups(&mut self, user_id: Uuid, names: &[String]) {
+        let names = names.to_owned();
+        self.run(move |connection| {
+            let transaction = connection.transaction().expect("Error starting a transaction");
+            transaction
+                .execute(
+                    "delete from group_members where user_id = ?1
+                     and group_id not in (select id from groups where lower(name) in (select value from json_each(?2)))",
+                    params![user_id.to_string(), json_list(&names)],
+                )
+                .expect("Error deleting from group_members table");
+            transaction
+                .execute(
+                    "insert into group_members (group_id, user_id)
+                     select id, ?1 from groups where lower(name) in (select value from json_each(?2))
+                     on conflict do nothing",
+                    params![user_id.to_string(), json_list(&names)],
+                )
+                .expect("Error inserting into group_members table");
+            transaction.commit().expect("Error updating group_members table");
+        })
+        .await
+    }
+
+    async fn get_effective_roles(&self, user_id: Uuid) -> Vec<Role> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select distinct r.name, c.client_id, r.description from roles as r left join clients as c on r.client_id = c.id
+                 where r.id in (select role_id from user_roles where user_id = ?1
+                                union select gr.role_id from group_roles as gr
+                                join group_members as gm on gr.group_id = gm.group_id where gm.user_id = ?1)
+                 order by c.client_id nulls first, r.name",
+                [user_id.to_string()],
+                role,
+            )
+            .expect("Error executing query on roles table")
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl FederationStore for Sqlite {
+    async fn create_federation_state(&self, state: &str, params: &FederationState, lifetime: Duration) {
+        let state = state.to_owned();
+        let params = params.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute("delete from federation_states where expire_time < now()", [])
+                .expect("Error deleting from federation_states table");
+            connection
+                .execute(
+                    "insert into federation_states (state, provider, nonce, code_verifier, request, next, expire_time)
+                     values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        session::hash_token(&state),
+                        params.provider,
+                        params.nonce,
+                        params.code_verifier,
+                        params.request,
+                        params.next,
+                        sql_time(Local::now() + lifetime)
+                    ],
+                )
+                .expect("Error inserting into federation_states table");
+        })
+        .await
+    }
+
+    async fn use_federation_state(&self, state: &str) -> Option<FederationState> {
+        let state = state.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "delete from federation_states where state = ?1 and expire_time > now()
+                     returning provider, nonce, code_verifier, request, next",
+                    [session::hash_token(&state)],
+                    |row| {
+                        Ok(FederationState {
+                            provider: row.get(0)?,
+                            nonce: row.get(1)?,
+                            code_verifier: row.get(2)?,
+                            request: row.get(3)?,
+                            next: row.get(4)?,
+                        })
+                    },
+                )
+                .optional()
+                .expect("Error deleting from federation_states table")
+        })
+        .await
+    }
+
+    async fn find_federated_user(&self, provider: &str, subject: &str) -> Option<Uuid> {
+        let provider = provider.to_owned();
+        let subject = subject.to_owned();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "update federated_identities set last_login_time = now() where provider = ?1 and subject = ?2
+                     returning user_id",
+                    [provider, subject],
+                    |row| id(row, 0),
+                )
+                .optional()
+                .expect("Error updating federated_identities table")
+        })
+        .await
+    }
+
+    async fn link_federated_identity(&self, provider: &str, subject: &str, user_id: Uuid, email: Option<&str>) {
+        let provider = provider.to_owned();
+        let subject = subject.to_owned();
+        let email = email.map(String::from);
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "insert into federated_identities (provider, subject, user_id, email, last_login_time)
+                     values (?1, ?2, ?3, ?4, now()) on conflict (provider, subject) do nothing",
+                    params![provider, subject, user_id.to_string(), email],
+                )
+                .expect("Error inserting into federated_identities table");
+        })
+        .await
+    }
+
+    async fn get_federated_identities(&self, user_id: Uuid) -> Vec<FederatedIdentity> {
+        self.run(move |connection| {
+            query(
+                connection,
+                "select provider, subject, email, creation_time, last_login_time from federated_identities
+                 where user_id = ?1 order by provider, subject",
+                [user_id.to_string()],
+                |row| {
+                    Ok(FederatedIdentity {
+                        provider: row.get(0)?,
+                        subject: row.get(1)?,
+                        email: row.get(2)?,
+                        created: time(row, 3)?.timestamp(),
+                        last_login: optional_time(row, 4)?.map(|t| t.timestamp()),
+                    })
+                },
+            )
+            .expect("Error executing query on federated_identities table")
+        })
+        .await
+    }
+
+    async fn delete_federated_identities(&self, user_id: Uuid, provider: &str) -> bool {
+        let provider = provider.to_owned();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "delete from federated_identities where user_id = ?1 and provider = ?2",
+                    params![user_id.to_string(), provider],
+                )
+                .expect("Error deleting from federated_identities table")
+                > 0
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::AuthorizationParams;
+
+    // A database in memory with the tables of migrations/sqlite
+    async fn database() -> Sqlite {
+        let config = SqliteConfig {
+            path: ":memory:".to_string(),
+            busy_timeout: 1000,
+        };
+        let database = Sqlite::open(&config).unwrap();
+        assert_eq!(database.migrate().await.unwrap(), vec!["0001_init", "0002_refresh_token_hash", "0003_user_source"]);
+        database
+            .execute_script("insert into scopes (name, requires_consent) values ('read', false), ('write', false)")
+            .await
+            .unwrap();
+        database
+    }
+
+    async fn client(database: &mut Sqlite, client_id: &str) -> ClientInfo {
+        let params: NewClient = serde_json::from_value(serde_json::json!({
+            "redirect_uris": ["http://localhost:3000/callback"],
+            "scopes": ["read", "write"],
+            "default_scopes": ["read"],
+        }))
+        .unwrap();
+        let id = database.create_client(client_id, &params).await.unwrap();
+        database.get_client_by_id(id).await.unwrap()
+    }
+
+    fn grant(user_id: Uuid, client_db_id: Uuid) -> TokenGrant {
+        TokenGrant {
+            user_id: Some(user_id),
+            client_id: client_db_id,
+            scope: Some("read".to_string()),
+            device: None,
+            auth_time: Some(Local::now()),
+            amr: vec!["pwd".to_string()],
+            nonce: None,
+            sid: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn migrations_run_once() {
+        let database = database().await;
+        assert!(database.migrate().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn clients_and_their_secrets() {
+        let mut database = database().await;
+        let client = client(&mut database, "top").await;
+        let (_, details) = database.get_client_details("top").await.unwrap();
+        assert_eq!(details.grant_types, vec!["authorization_code", "refresh_token"]);
+        assert_eq!(details.scopes.iter().filter(|s| s.is_default).count(), 1);
+
+        let (metadata, secret) = database.add_client_secret(client.id, None).await;
+        assert!(secret.starts_with(&metadata.prefix));
+        let valid = database.validate_client_credentials("top".to_string(), secret.clone()).await;
+        assert_eq!(valid, Some(client.id));
+        assert!(database.validate_client_credentials("top".to_string(), "wrong".to_string()).await.is_none());
+
+        database.expire_client_secret(client.id, metadata.id, Local::now()).await;
+        assert!(database.validate_client_credentials("top".to_string(), secret).await.is_none());
+
+        let params: NewClient = serde_json::from_value(serde_json::json!({ "scopes": ["unknown"] })).unwrap();
+        assert!(matches!(database.create_client("other", &params).await, Err(InvalidScopeError(_))));
+        assert!(database.get_client_details("other").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn users_are_unique_and_need_a_verified_email_to_log_in() {
+        let mut database = database().await;
+        let user = database.register_user("test", "test@example.com", "hash", false, None).await.unwrap();
+        assert!(database.get_password_hash("test").await.is_none());
+        assert!(database.set_email_verified(user.id, "test@example.com").await);
+        assert_eq!(database.get_password_hash("test").await, Some((user.id, "hash".to_string())));
+
+        let taken = database.register_user("test", "other@example.com", "hash", true, None).await;
+        assert!(matches!(taken, Err(ConflictError(m)) if m == "Username is already taken"));
+        let taken = database.register_user("other", "test@example.com", "hash", true, None).await;
+        assert!(matches!(taken, Err(ConflictError(m)) if m == "Email address is already registered"));
+        let invitation = Some("unknown".to_string());
+        let invited = database.register_user("third", "third@example.com", "hash", true, invitation).await;
+        assert!(matches!(invited, Err(InvalidRequestError(_))));
+        assert!(database.get_user_by_username("third").await.is_none());
+
+        let (users, total) = database.get_users(Some("TES"), None, 10, 0).await;
+        assert_eq!((users.len(), total), (1, 1));
+        assert_eq!(users[0].id, user.id);
+    }
+
+    #[tokio::test]
+    async fn directory_users_are_told_apart_from_local_ones() {
+        let mut database = database().await;
+        database.register_user("test", "test@example.com", "hash", true, None).await.unwrap();
+        let user = database.register_directory_user("alice", "alice@example.com", "alice").await.unwrap();
+        assert_eq!(database.get_user_source("test").await, Some(UserSource::Local));
+        assert_eq!(database.get_user_source("alice").await, Some(UserSource::Directory));
+        assert_eq!(database.get_user_source("nobody").await, None);
+        assert_eq!(database.find_federated_user(ldap::PROVIDER, "alice").await, Some(user.id));
+
+        // neither the user nor the link is made when the address is taken
+        let taken = database.register_directory_user("bob", "test@example.com", "bob").await;
+        assert!(matches!(taken, Err(ConflictError(m)) if m == "Email address is already registered"));
+        assert!(database.find_federated_user(ldap::PROVIDER, "bob").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_new_token_replaces_the_one_for_the_same_device() {
+        let mut database = database().await;
+        let client = client(&mut database, "top").await;
+        let user = database.register_user("test", "test@example.com", "hash", true, None).await.unwrap();
+
+        let refresh_token = Some("r1".to_string());
+        database
+            .insert_token("a1".to_string(), refresh_token, grant(user.id, client.id), "iss".to_string(), &client)
+            .await;
+        let refresh_token = Some("r2".to_string());
+        database
+            .insert_token("a2".to_string(), refresh_token, grant(user.id, client.id), "iss".to_string(), &client)
+            .await;
+        assert_eq!(database.get_client_tokens(client.id).await.len(), 1);
+        assert!(database.find_refresh_token("r1", client.id).await.is_none());
+        let refreshed = database.find_refresh_token("r2", client.id).await.unwrap();
+        assert_eq!(refreshed.amr, vec!["pwd"]);
+
+        let introspection = database.validate_access_token("a2".to_string(), client.id).await.unwrap();
+        assert!(introspection.active);
+        assert_eq!(introspection.username.as_deref(), Some("test"));
+
+        // the tokens go with the user
+        assert!(database.delete_user(user.id).await);
+        assert!(database.get_client_tokens(client.id).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn codes_need_the_verifier_and_redirect_uri() {
+        let mut database = database().await;
+        let client = client(&mut database, "top").await;
+        let user = database.register_user("test", "test@example.com", "hash", true, None).await.unwrap();
+        let request = AuthorizationParams {
+            client_id: "top".to_string(),
+            response_type: "code".to_string(),
+            redirect_uri: "http://localhost:3000/callback".to_string(),
+            scope: Some("read".to_string()),
+            state: None,
+            code_challenge: Some(session::hash_token("verifier")),
+            prompt: None,
+            nonce: None,
+            max_age: None,
+        };
+        let authorization = AuthorizationCode {
+            user_id: user.id,
+            scope: Some("read".to_string()),
+            auth_time: Some(Local::now()),
+            amr: vec!["pwd".to_string(), "otp".to_string()],
+            nonce: None,
+            sid: None,
+        };
+        database.insert_code("code", client.id, &authorization, &request).await;
+
+        assert!(database.validate_code("code", "wrong", client.id, &request.redirect_uri).await.is_none());
+        assert!(database.validate_code("code", "verifier", client.id, "http://evil").await.is_none());
+        let code = database.validate_code("code", "verifier", client.id, &request.redirect_uri).await.unwrap();
+        assert_eq!(code.amr, vec!["pwd", "otp"]);
+        assert!(database.validate_code("code", "verifier", client.id, &request.redirect_uri).await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queries_from_many_tasks_take_turns() {
+        let database = database().await;
+        let tasks: Vec<_> = (0..20)
+            .map(|i| {
+                let mut database = database.clone();
+                tokio::spawn(async move {
+                    let email = format!("user{}@example.com", i);
+                    database.register_user(&format!("user{}", i), &email, "hash", true, None).await.unwrap()
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(database.get_users(None, None, 100, 0).await.1, 20);
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_are_stored_as_a_hash() {
+        let mut database = database().await;
+        let client = client(&mut database, "top").await;
+        let user = database.register_user("test", "test@example.com", "hash", true, None).await.unwrap();
+        let refresh_token = Some("r1".to_string());
+        database
+            .insert_token("a1".to_string(), refresh_token, grant(user.id, client.id), "iss".to_string(), &client)
+            .await;
+
+        let stored: String = database
+            .run(|connection| connection.query_row("select refresh_token_hash from access_tokens", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(stored, session::hash_token("r1"));
+        assert!(database.find_refresh_token(&stored, client.id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn scope_grants_hold_for_one_user_at_one_client() {
+        let mut database = database().await;
+        let (top, other) = (client(&mut database, "top").await.id, client(&mut database, "other").await.id);
+        let first = database.register_user("first", "first@example.com", "hash", true, None).await.unwrap().id;
+        let second = database.register_user("second", "second@example.com", "hash", true, None).await.unwrap().id;
+        database
+            .execute_script(&format!(
+                "insert into scope_grants (scope, user_id, client_id) values ('projects:read:1', '{}', '{}'),
+                 ('projects:read:2', '{}', '{}'), ('projects:read:3', null, '{}')",
+                first, top, second, other, top
+            ))
+            .await
+            .unwrap();
+
+        let grants = |client_db_id, user_id| {
+            let database = &database;
+            async move { database.get_scope_policy(client_db_id, user_id).await.grants }
+        };
+        assert_eq!(grants(top, Some(first)).await, vec!["projects:read:1"]);
+        assert!(grants(top, Some(second)).await.is_empty());
+        assert!(grants(other, Some(first)).await.is_empty());
+        assert_eq!(grants(other, Some(second)).await, vec!["projects:read:2"]);
+        assert_eq!(grants(top, None).await, vec!["projects:read:3"]);
+        assert!(grants(other, None).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_issued_before_they_were_hashed_still_work() {
+        let mut database = Sqlite::open(&SqliteConfig {
+            path: ":memory:".to_string(),
+            busy_timeout: 1000,
+        })
+        .unwrap();
+        let (version, script) = MIGRATIONS[0];
+        database.execute_script(script).await.unwrap();
+        database
+            .execute_script(&format!(
+                "create table schema_migrations (version varchar(255) primary key, applied_time timestamp);
+                 insert into schema_migrations (version) values ('{}');
+                 insert into scopes (name, requires_consent) values ('read', false), ('write', false);",
+                version
+            ))
+            .await
+            .unwrap();
+        let client = client(&mut database, "top").await;
+        database
+            .execute_script(&format!(
+                "insert into access_tokens (access_token, refresh_token, expire_time, creation_time, token_type,
+                 client_id, device, issuer) values ('a1', 'r1', now(), now(), 'bearer', '{}', 'unknown', 'iss')",
+                client.id
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(database.migrate().await.unwrap(), vec!["0002_refresh_token_hash", "0003_user_source"]);
+        assert!(database.find_refresh_token("r1", client.id).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn users_linked_to_the_directory_before_it_was_stored_come_from_it() {
+        let mut database = Sqlite::open(&SqliteConfig {
+            path: ":memory:".to_string(),
+            busy_timeout: 1000,
+        })
+        .unwrap();
+        for (_, script) in &MIGRATIONS[..2] {
+            database.execute_script(script).await.unwrap();
+        }
+        database
+            .execute_script(
+                "create table schema_migrations (version varchar(255) primary key, applied_time timestamp);
+                 insert into schema_migrations (version) values ('0001_init'), ('0002_refresh_token_hash');",
+            )
+            .await
+            .unwrap();
+        let local = database.register_user("test", "test@example.com", "hash", true, None).await.unwrap();
+        let linked = database.register_user("alice", "alice@example.com", "!", true, None).await.unwrap();
+        database.link_federated_identity(ldap::PROVIDER, "alice", linked.id, None).await;
+        database.link_federated_identity("idp", "u-1", local.id, None).await;
+
+        assert_eq!(database.migrate().await.unwrap(), vec!["0003_user_source"]);
+        assert_eq!(database.get_user_source("test").await, Some(UserSource::Local));
+        assert_eq!(database.get_user_source("alice").await, Some(UserSource::Directory));
+    }
+}
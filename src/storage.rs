@@ -0,0 +1,823 @@
+use crate::db;
+use crate::errors::Error::{self, DBPoolError};
+use crate::migrations;
+use crate::models::{
+    AccessToken, AttributeDefinition, AuthorizationCode, AuthorizationParams, ChallengePurpose, ClientDetails,
+    ClientInfo, ClientSecret, ClientUpdate, EmailTokenPurpose, FederatedIdentity, FederationState, Group,
+    Introspection, LoginSession, LogoutClient, NewClient, Passkey, PasskeyCredential, Role, Scope, ScopePolicy,
+    ScopeRule, TokenGrant, TokenInfo, TotpCredential, User, UserSource, UserUpdate,
+};
+use crate::webauthn::NewCredential;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Local};
+use uuid::Uuid;
+
+// Where the server keeps its data. Handlers ask the Database for a connection and use the stores on it,
+// they don't know which database is behind it. A connection is a Storage, the sum of a store per area
+// (clients, users, tokens, ...), and helpers take the store they need. Postgres is the implementation
+// here, its queries are in db.rs, sqlite.rs has another and memory.rs the one the tests use.
+
+#[async_trait]
+pub trait Database: Send + Sync {
+    async fn connect(&self) -> Result<Box<dyn Storage>, Error>;
+
+    // Applies the schema changes the database doesn't have yet, returns their versions
+    async fn migrate(&self) -> Result<Vec<&'static str>, Error>;
+}
+
+// One connection to the database, it has all of the stores. Methods that take &mut self use a transaction.
+pub trait Storage:
+    ClientStore
+    + UserStore
+    + TokenStore
+    + CodeStore
+    + SessionStore
+    + ScopeStore
+    + MfaStore
+    + LockoutStore
+    + AttributeStore
+    + RoleStore
+    + FederationStore
+{
+}
+
+impl<T> Storage for T
+where
+    T: ?Sized
+        + ClientStore
+        + UserStore
+        + TokenStore
+        + CodeStore
+        + SessionStore
+        + ScopeStore
+        + MfaStore
+        + LockoutStore
+        + AttributeStore
+        + RoleStore
+        + FederationStore
+{
+}
+
+// The clients, their secrets and settings
+#[async_trait]
+pub trait ClientStore: Send + Sync {
+    async fn validate_client_credentials(&self, client_id: String, secret: String) -> Option<Uuid>;
+    // Stores a new secret for the client. The secret is returned here and never again, only its hash is kept.
+    async fn add_client_secret(
+        &self,
+        client_db_id: Uuid,
+        expire_time: Option<DateTime<Local>>,
+    ) -> (ClientSecret, String);
+    async fn get_client_secrets(&self, client_db_id: Uuid) -> Vec<ClientSecret>;
+    // Sets when a secret stops working, used to phase out the old secret after a new one was added
+    async fn expire_client_secret(&self, client_db_id: Uuid, secret_id: i32, expire_time: DateTime<Local>) -> bool;
+    // Phases out the client's other secrets, secrets that already expire sooner are left alone
+    async fn expire_other_client_secrets(&self, client_db_id: Uuid, keep_id: i32, expire_time: DateTime<Local>);
+    // Disabled clients included, unlike get_client_by_client_id
+    async fn get_client_details(&self, client_id: &str) -> Option<(Uuid, ClientDetails)>;
+    async fn get_clients(&self) -> Vec<ClientDetails>;
+    async fn get_client_by_client_id(&self, client_id: &str) -> Option<ClientInfo>;
+    async fn get_client_by_id(&self, client_db_id: Uuid) -> Option<ClientInfo>;
+    async fn create_client(&mut self, client_id: &str, params: &NewClient) -> Result<Uuid, Error>;
+    async fn update_client(&mut self, client_db_id: Uuid, update: &ClientUpdate) -> Result<(), Error>;
+    async fn set_client_disabled(&self, client_db_id: Uuid, disabled: bool);
+    // Removes the client's tokens and pending codes
+    async fn revoke_client(&self, client_db_id: Uuid);
+    // Deletes the client with its secrets, scopes, grants, consents and tokens
+    async fn delete_client(&self, client_db_id: Uuid) -> bool;
+    // Tokens of the client that can still be used, either the access token or its refresh token
+    async fn get_client_tokens(&self, client_db_id: Uuid) -> Vec<TokenInfo>;
+    // The client's own limit in requests per minute, None for unknown clients and clients without one
+    async fn get_client_rate_limit(&self, client_id: &str) -> Option<i32>;
+}
+
+// Users of the users table and the tokens in mails to them
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    // The password hash of a user that can log in with a password, not disabled and with a verified email
+    async fn get_password_hash(&self, username: &str) -> Option<(Uuid, String)>;
+    // Whether the user with that username has a password here or in the directory
+    async fn get_user_source(&self, username: &str) -> Option<UserSource>;
+    // Creates the user, uniqueness of the username and email is left to the constraints on the users table.
+    // The invitation (a hash of the code) is claimed in the same transaction, a failed registration leaves it unused.
+    async fn register_user(
+        &mut self,
+        username: &str,
+        email: &str,
+        password_hash: &str,
+        email_verified: bool,
+        invitation: Option<String>,
+    ) -> Result<User, Error>;
+    // Creates the user for a directory user at their first login, linked to the directory by the subject
+    async fn register_directory_user(&mut self, username: &str, email: &str, subject: &str) -> Result<User, Error>;
+    // A page of users and the total number of matches, the filters match on a part of the username or email
+    async fn get_users(&self, username: Option<&str>, email: Option<&str>, limit: i64, offset: i64) -> (Vec<User>, i64);
+    async fn get_user(&self, user_id: Uuid) -> Option<User>;
+    async fn get_user_by_username(&self, username: &str) -> Option<User>;
+    async fn get_user_by_email(&self, email: &str) -> Option<User>;
+    async fn update_user(&self, user_id: Uuid, update: &UserUpdate) -> Result<Option<User>, Error>;
+    async fn set_user_disabled(&self, user_id: Uuid, disabled: bool) -> Option<User>;
+    async fn set_password(&self, user_id: Uuid, password_hash: &str) -> bool;
+    // Everything the user holds (data, tokens, codes, sessions, consents, grants) goes with it
+    async fn delete_user(&self, user_id: Uuid) -> bool;
+    // Logs the user out everywhere: login sessions, pending codes and access and refresh tokens
+    async fn revoke_user(&self, user_id: Uuid);
+    // A token for a link in a mail to the given address. Earlier unused tokens of the user for the same
+    // purpose stop working, only the link in the latest mail does.
+    async fn create_email_token(
+        &self,
+        user_id: Uuid,
+        purpose: EmailTokenPurpose,
+        email: &str,
+        lifetime: Duration,
+    ) -> String;
+    // The user and address of a token that can still be used, without using it
+    async fn find_email_token(&self, token: &str, purpose: EmailTokenPurpose) -> Option<(Uuid, String)>;
+    // Marks the token used and returns its user and address, a token only works once
+    async fn use_email_token(&self, token: &str, purpose: EmailTokenPurpose) -> Option<(Uuid, String)>;
+    // Only verifies the address the mail went to, a user that changed their email since has to verify again
+    async fn set_email_verified(&self, user_id: Uuid, email: &str) -> bool;
+}
+
+// Access and refresh tokens
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    async fn validate_access_token(&self, access_token: String, client_db_id: Uuid) -> Option<Introspection>;
+    async fn insert_token(
+        &self,
+        generated_token: String,
+        refresh_token: Option<String>,
+        grant: TokenGrant,
+        issuer: String,
+        client_info: &ClientInfo,
+    ) -> AccessToken;
+    // Looks up the grant behind a refresh token, refresh tokens are bound to the client they were issued to
+    async fn find_refresh_token(&self, refresh_token: &str, client_db_id: Uuid) -> Option<TokenGrant>;
+    // Deletes the access and refresh tokens matching all of the given filters, returns how many were deleted
+    async fn revoke_tokens(&self, user_id: Option<Uuid>, client_db_id: Option<Uuid>, device: Option<&str>) -> u64;
+    // The scope of an access token that is still valid, tokens of any client are accepted
+    async fn get_active_token_scope(&self, access_token: &str) -> Option<String>;
+    // The user and scope of an access token that hasn't expired, for userinfo
+    async fn get_active_token_user(&self, access_token: &str) -> Option<(Option<Uuid>, Option<String>)>;
+}
+
+// Codes of the authorization code flow
+#[async_trait]
+pub trait CodeStore: Send + Sync {
+    async fn insert_code(
+        &self,
+        code: &str,
+        client_db_id: Uuid,
+        authorization: &AuthorizationCode,
+        request: &AuthorizationParams,
+    );
+    // Codes are single use and bound to the client and redirect uri they were issued for, a code that checks
+    // out is taken at once so two requests can't both redeem it.
+    // The pcke hash is optional, confidential clients can do without.
+    async fn validate_code(
+        &self,
+        code: &str,
+        pcke: &str,
+        client_db_id: Uuid,
+        redirect_uri: &str,
+    ) -> Option<AuthorizationCode>;
+}
+
+// Login sessions and the clients they were used for, for logout
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn create_session(&self, session_hash: &str, sid: &str, user_id: Uuid, amr: &[String], lifetime: Duration);
+    // Returns the user behind a login session, expired sessions are ignored
+    async fn find_session(&self, session_hash: &str) -> Option<LoginSession>;
+    // Records the methods the user logged in with, after the second factor
+    async fn set_session_amr(&self, session_id: i32, amr: &[String]);
+    async fn delete_session(&self, session_id: i32);
+    async fn add_session_client(&self, session_id: i32, client_db_id: Uuid);
+    async fn get_session_clients(&self, session_id: i32) -> Vec<LogoutClient>;
+}
+
+// The scopes, their rules and grants and the scopes users consented to
+#[async_trait]
+pub trait ScopeStore: Send + Sync {
+    async fn get_scopes(&self) -> Vec<Scope>;
+    async fn get_scope_rules(&self) -> Vec<ScopeRule>;
+    async fn get_scope_policy(&self, client_db_id: Uuid, user_id: Option<Uuid>) -> ScopePolicy;
+    async fn get_consented_scopes(&self, user_id: Uuid, client_db_id: Uuid) -> Vec<String>;
+    async fn insert_consents(&self, user_id: Uuid, client_db_id: Uuid, scopes: &[String]);
+}
+
+// Authenticators, recovery codes and passkeys
+#[async_trait]
+pub trait MfaStore: Send + Sync {
+    async fn get_totp(&self, user_id: Uuid) -> Option<TotpCredential>;
+    // Starts enrolling an authenticator, a confirmed one is never replaced this way
+    async fn set_pending_totp(&self, user_id: Uuid, secret: &str);
+    async fn confirm_totp(&self, user_id: Uuid, step: i64);
+    // Accepts the time step of a valid code once, a code can't be used again by someone looking over a shoulder
+    async fn use_totp_step(&self, user_id: Uuid, step: i64) -> bool;
+    // Replaces the user's recovery codes, only hashes are stored
+    async fn set_recovery_codes(&self, user_id: Uuid, code_hashes: &[String]);
+    async fn use_recovery_code(&self, user_id: Uuid, code_hash: &str) -> bool;
+    // For a user that lost their authenticator, the next login enrolls a new one if MFA is required
+    async fn delete_mfa(&self, user_id: Uuid) -> bool;
+    // Stores the challenge of a WebAuthn ceremony, expired ones are cleaned up on the way
+    async fn create_challenge(
+        &self,
+        challenge: &str,
+        purpose: ChallengePurpose,
+        user_id: Option<Uuid>,
+        lifetime: Duration,
+    );
+    // Removes the challenge and returns the user it was made for, a challenge only works once
+    async fn use_challenge(&self, challenge: &str, purpose: ChallengePurpose) -> Option<Option<Uuid>>;
+    // False when the credential is already registered
+    async fn add_passkey(&self, user_id: Uuid, credential: &NewCredential, name: &str) -> bool;
+    async fn get_passkeys(&self, user_id: Uuid) -> Vec<Passkey>;
+    // The base64url ids of the user's passkeys, for the browser to pick from
+    async fn get_passkey_ids(&self, user_id: Uuid) -> Vec<String>;
+    // Passkeys of disabled and unverified users are not found, like their passwords
+    async fn find_passkey(&self, credential_id: &str) -> Option<PasskeyCredential>;
+    async fn use_passkey(&self, passkey_id: i32, sign_count: i64);
+    async fn delete_passkey(&self, user_id: Uuid, passkey_id: i32) -> bool;
+}
+
+// Failed logins per username and address
+#[async_trait]
+pub trait LockoutStore: Send + Sync {
+    async fn is_locked(&self, kind: &str, subject: &str) -> bool;
+    // Counts a failed login and returns the failures so far, when the last one was before window_start the
+    // count starts over
+    async fn add_login_failure(&self, kind: &str, subject: &str, window_start: DateTime<Local>) -> i32;
+    async fn lock_login(&self, kind: &str, subject: &str, locked_until: DateTime<Local>);
+    // Forgets the failures and lifts a lock, false when there were none
+    async fn clear_login_failures(&self, kind: &str, subject: &str) -> bool;
+}
+
+// Attribute definitions and the values users have
+#[async_trait]
+pub trait AttributeStore: Send + Sync {
+    async fn get_attribute_definitions(&self) -> Vec<AttributeDefinition>;
+    // Adds the attribute or replaces its definition
+    async fn put_attribute_definition(&self, definition: &AttributeDefinition);
+    // Removes the attribute with the values users have for it
+    async fn delete_attribute_definition(&mut self, name: &str) -> Result<bool, Error>;
+    // Every user's value of the attribute, to check them against a new definition
+    async fn get_attribute_values(&self, name: &str) -> Vec<String>;
+    async fn get_user_attributes(&self, user_id: Uuid) -> Vec<(String, String)>;
+    // Sets the given attributes of the user at once, None removes one
+    async fn set_user_attributes(&mut self, user_id: Uuid, changes: &[(String, Option<String>)]) -> Result<(), Error>;
+}
+
+// Roles, groups and who has them
+#[async_trait]
+pub trait RoleStore: Send + Sync {
+    // The global roles without a client, otherwise the client's own
+    async fn get_roles(&self, client_db_id: Option<Uuid>) -> Vec<Role>;
+    async fn find_role(&self, client_db_id: Option<Uuid>, name: &str) -> Option<i32>;
+    // Adds the role or changes its description
+    async fn put_role(&self, client_db_id: Option<Uuid>, name: &str, description: &Option<String>);
+    // The assignments of the role go with it
+    async fn delete_role(&self, client_db_id: Option<Uuid>, name: &str) -> bool;
+    async fn get_groups(&self) -> Vec<Group>;
+    // The group with its roles and members
+    async fn get_group(&self, name: &str) -> Option<(i32, Group)>;
+    async fn put_group(&self, name: &str, description: &Option<String>);
+    async fn delete_group(&self, name: &str) -> bool;
+    async fn add_group_member(&self, group_id: i32, user_id: Uuid);
+    async fn remove_group_member(&self, group_id: i32, user_id: Uuid) -> bool;
+    async fn add_group_role(&self, group_id: i32, role_id: i32);
+    async fn remove_group_role(&self, group_id: i32, role_id: i32) -> bool;
+    async fn add_user_role(&self, user_id: Uuid, role_id: i32);
+    async fn remove_user_role(&self, user_id: Uuid, role_id: i32) -> bool;
+    // The roles assigned to the user directly
+    async fn get_user_roles(&self, user_id: Uuid) -> Vec<Role>;
+    async fn get_user_groups(&self, user_id: Uuid) -> Vec<String>;
+    // Makes the user a member of the local groups with the names (lowercase) and of no others, groups of
+    // the directory that don't exist here are left out
+    async fn set_directory_groups(&mut self, user_id: Uuid, names: &[String]);
+    // The roles assigned to the user and those of their groups
+    async fn get_effective_roles(&self, user_id: Uuid) -> Vec<Role>;
+}
+
+// Accounts at upstream providers and directories linked to users
+#[async_trait]
+pub trait FederationStore: Send + Sync {
+    // Stores a login at an upstream provider until the user comes back, expired ones are cleaned up on the way
+    async fn create_federation_state(&self, state: &str, params: &FederationState, lifetime: Duration);
+    // Removes the login of the state and returns it, a state only works once
+    async fn use_federation_state(&self, state: &str) -> Option<FederationState>;
+    // The user the account at the provider is linked to, the login is recorded on the way
+    async fn find_federated_user(&self, provider: &str, subject: &str) -> Option<Uuid>;
+    async fn link_federated_identity(&self, provider: &str, subject: &str, user_id: Uuid, email: Option<&str>);
+    async fn get_federated_identities(&self, user_id: Uuid) -> Vec<FederatedIdentity>;
+    // Unlinks the user's accounts at the provider, false when there were none
+    async fn delete_federated_identities(&self, user_id: Uuid, provider: &str) -> bool;
+}
+
+pub struct Postgres {
+    pool: deadpool_postgres::Pool,
+}
+
+impl Postgres {
+    pub fn new(pool: deadpool_postgres::Pool) -> Self {
+        Postgres { pool }
+    }
+}
+
+#[async_trait]
+impl Database for Postgres {
+    async fn connect(&self) -> Result<Box<dyn Storage>, Error> {
+        let client = self.pool.get().await.map_err(DBPoolError)?;
+        Ok(Box::new(client))
+    }
+
+    async fn migrate(&self) -> Result<Vec<&'static str>, Error> {
+        let mut client = self.pool.get().await.map_err(DBPoolError)?;
+        migrations::migrate(&mut client).await
+    }
+}
+
+#[async_trait]
+impl ClientStore for deadpool_postgres::Client {
+    async fn validate_client_credentials(&self, client_id: String, secret: String) -> Option<Uuid> {
+        db::validate_client_credentials(self, client_id, secret).await
+    }
+
+    async fn add_client_secret(
+        &self,
+        client_db_id: Uuid,
+        expire_time: Option<DateTime<Local>>,
+    ) -> (ClientSecret, String) {
+        db::add_client_secret(self, client_db_id, expire_time).await
+    }
+
+    async fn get_client_secrets(&self, client_db_id: Uuid) -> Vec<ClientSecret> {
+        db::get_client_secrets(self, client_db_id).await
+    }
+
+    async fn expire_client_secret(&self, client_db_id: Uuid, secret_id: i32, expire_time: DateTime<Local>) -> bool {
+        db::expire_client_secret(self, client_db_id, secret_id, expire_time).await
+    }
+
+    async fn expire_other_client_secrets(&self, client_db_id: Uuid, keep_id: i32, expire_time: DateTime<Local>) {
+        db::expire_other_client_secrets(self, client_db_id, keep_id, expire_time).await
+    }
+
+    async fn get_client_details(&self, client_id: &str) -> Option<(Uuid, ClientDetails)> {
+        db::get_client_details(self, client_id).await
+    }
+
+    async fn get_clients(&self) -> Vec<ClientDetails> {
+        db::get_clients(self).await
+    }
+
+    async fn get_client_by_client_id(&self, client_id: &str) -> Option<ClientInfo> {
+        db::get_client_by_client_id(self, client_id).await
+    }
+
+    async fn get_client_by_id(&self, client_db_id: Uuid) -> Option<ClientInfo> {
+        db::get_client_by_id(self, client_db_id).await
+    }
+
+    async fn create_client(&mut self, client_id: &str, params: &NewClient) -> Result<Uuid, Error> {
+        db::create_client(self, client_id, params).await
+    }
+
+    async fn update_client(&mut self, client_db_id: Uuid, update: &ClientUpdate) -> Result<(), Error> {
+        db::update_client(self, client_db_id, update).await
+    }
+
+    async fn set_client_disabled(&self, client_db_id: Uuid, disabled: bool) {
+        db::set_client_disabled(self, client_db_id, disabled).await
+    }
+
+    async fn revoke_client(&self, client_db_id: Uuid) {
+        db::revoke_client(self, client_db_id).await
+    }
+
+    async fn delete_client(&self, client_db_id: Uuid) -> bool {
+        db::delete_client(self, client_db_id).await
+    }
+
+    async fn get_client_tokens(&self, client_db_id: Uuid) -> Vec<TokenInfo> {
+        db::get_client_tokens(self, client_db_id).await
+    }
+
+    async fn get_client_rate_limit(&self, client_id: &str) -> Option<i32> {
+        db::get_client_rate_limit(self, client_id).await
+    }
+}
+
+#[async_trait]
+impl UserStore for deadpool_postgres::Client {
+    async fn get_password_hash(&self, username: &str) -> Option<(Uuid, String)> {
+        db::get_password_hash(self, username).await
+    }
+
+    async fn get_user_source(&self, username: &str) -> Option<UserSource> {
+        db::get_user_source(self, username).await
+    }
+
+    async fn register_user(
+        &mut self,
+        username: &str,
+        email: &str,
+        password_hash: &str,
+        email_verified: bool,
+        invitation: Option<String>,
+    ) -> Result<User, Error> {
+        db::register_user(self, username, email, password_hash, email_verified, invitation).await
+    }
+
+    async fn register_directory_user(&mut self, username: &str, email: &str, subject: &str) -> Result<User, Error> {
+        db::register_directory_user(self, username, email, subject).await
+    }
+
+    async fn get_users(
+        &self,
+        username: Option<&str>,
+        email: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> (Vec<User>, i64) {
+        db::get_users(self, username, email, limit, offset).await
+    }
+
+    async fn get_user(&self, user_id: Uuid) -> Option<User> {
+        db::get_user(self, user_id).await
+    }
+
+    async fn get_user_by_username(&self, username: &str) -> Option<User> {
+        db::get_user_by_username(self, username).await
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Option<User> {
+        db::get_user_by_email(self, email).await
+    }
+
+    async fn update_user(&self, user_id: Uuid, update: &UserUpdate) -> Result<Option<User>, Error> {
+        db::update_user(self, user_id, update).await
+    }
+
+    async fn set_user_disabled(&self, user_id: Uuid, disabled: bool) -> Option<User> {
+        db::set_user_disabled(self, user_id, disabled).await
+    }
+
+    async fn set_password(&self, user_id: Uuid, password_hash: &str) -> bool {
+        db::set_password(self, user_id, password_hash).await
+    }
+
+    async fn delete_user(&self, user_id: Uuid) -> bool {
+        db::delete_user(self, user_id).await
+    }
+
+    async fn revoke_user(&self, user_id: Uuid) {
+        db::revoke_user(self, user_id).await
+    }
+
+    async fn create_email_token(
+        &self,
+        user_id: Uuid,
+        purpose: EmailTokenPurpose,
+        email: &str,
+        lifetime: Duration,
+    ) -> String {
+        db::create_email_token(self, user_id, purpose, email, lifetime).await
+    }
+
+    async fn find_email_token(&self, token: &str, purpose: EmailTokenPurpose) -> Option<(Uuid, String)> {
+        db::find_email_token(self, token, purpose).await
+    }
+
+    async fn use_email_token(&self, token: &str, purpose: EmailTokenPurpose) -> Option<(Uuid, String)> {
+        db::use_email_token(self, token, purpose).await
+    }
+
+    async fn set_email_verified(&self, user_id: Uuid, email: &str) -> bool {
+        db::set_email_verified(self, user_id, email).await
+    }
+}
+
+#[async_trait]
+impl TokenStore for deadpool_postgres::Client {
+    async fn validate_access_token(&self, access_token: String, client_db_id: Uuid) -> Option<Introspection> {
+        db::validate_access_token(self, access_token, client_db_id).await
+    }
+
+    async fn insert_token(
+        &self,
+        generated_token: String,
+        refresh_token: Option<String>,
+        grant: TokenGrant,
+        issuer: String,
+        client_info: &ClientInfo,
+    ) -> AccessToken {
+        db::insert_token(self, generated_token, refresh_token, grant, issuer, client_info).await
+    }
+
+    async fn find_refresh_token(&self, refresh_token: &str, client_db_id: Uuid) -> Option<TokenGrant> {
+        db::find_refresh_token(self, refresh_token, client_db_id).await
+    }
+
+    async fn revoke_tokens(&self, user_id: Option<Uuid>, client_db_id: Option<Uuid>, device: Option<&str>) -> u64 {
+        db::revoke_tokens(self, user_id, client_db_id, device).await
+    }
+
+    async fn get_active_token_scope(&self, access_token: &str) -> Option<String> {
+        db::get_active_token_scope(self, access_token).await
+    }
+
+    async fn get_active_token_user(&self, access_token: &str) -> Option<(Option<Uuid>, Option<String>)> {
+        db::get_active_token_user(self, access_token).await
+    }
+}
+
+#[async_trait]
+impl CodeStore for deadpool_postgres::Client {
+    async fn insert_code(
+        &self,
+        code: &str,
+        client_db_id: Uuid,
+        authorization: &AuthorizationCode,
+        request: &AuthorizationParams,
+    ) {
+        db::insert_code(self, code, client_db_id, authorization, request).await
+    }
+
+    async fn validate_code(
+        &self,
+        code: &str,
+        pcke: &str,
+        client_db_id: Uuid,
+        redirect_uri: &str,
+    ) -> Option<AuthorizationCode> {
+        db::validate_code(self, code, pcke, client_db_id, redirect_uri).await
+    }
+}
+
+#[async_trait]
+impl SessionStore for deadpool_postgres::Client {
+    async fn create_session(&self, session_hash: &str, sid: &str, user_id: Uuid, amr: &[String], lifetime: Duration) {
+        db::create_session(self, session_hash, sid, user_id, amr, lifetime).await
+    }
+
+    async fn find_session(&self, session_hash: &str) -> Option<LoginSession> {
+        db::find_session(self, session_hash).await
+    }
+
+    async fn set_session_amr(&self, session_id: i32, amr: &[String]) {
+        db::set_session_amr(self, session_id, amr).await
+    }
+
+    async fn delete_session(&self, session_id: i32) {
+        db::delete_session(self, session_id).await
+    }
+
+    async fn add_session_client(&self, session_id: i32, client_db_id: Uuid) {
+        db::add_session_client(self, session_id, client_db_id).await
+    }
+
+    async fn get_session_clients(&self, session_id: i32) -> Vec<LogoutClient> {
+        db::get_session_clients(self, session_id).await
+    }
+}
+
+#[async_trait]
+impl ScopeStore for deadpool_postgres::Client {
+    async fn get_scopes(&self) -> Vec<Scope> {
+        db::get_scopes(self).await
+    }
+
+    async fn get_scope_rules(&self) -> Vec<ScopeRule> {
+        db::get_scope_rules(self).await
+    }
+
+    async fn get_scope_policy(&self, client_db_id: Uuid, user_id: Option<Uuid>) -> ScopePolicy {
+        db::get_scope_policy(self, client_db_id, user_id).await
+    }
+
+    async fn get_consented_scopes(&self, user_id: Uuid, client_db_id: Uuid) -> Vec<String> {
+        db::get_consented_scopes(self, user_id, client_db_id).await
+    }
+
+    async fn insert_consents(&self, user_id: Uuid, client_db_id: Uuid, scopes: &[String]) {
+        db::insert_consents(self, user_id, client_db_id, scopes).await
+    }
+}
+
+#[async_trait]
+impl MfaStore for deadpool_postgres::Client {
+    async fn get_totp(&self, user_id: Uuid) -> Option<TotpCredential> {
+        db::get_totp(self, user_id).await
+    }
+
+    async fn set_pending_totp(&self, user_id: Uuid, secret: &str) {
+        db::set_pending_totp(self, user_id, secret).await
+    }
+
+    async fn confirm_totp(&self, user_id: Uuid, step: i64) {
+        db::confirm_totp(self, user_id, step).await
+    }
+
+    async fn use_totp_step(&self, user_id: Uuid, step: i64) -> bool {
+        db::use_totp_step(self, user_id, step).await
+    }
+
+    async fn set_recovery_codes(&self, user_id: Uuid, code_hashes: &[String]) {
+        db::set_recovery_codes(self, user_id, code_hashes).await
+    }
+
+    async fn use_recovery_code(&self, user_id: Uuid, code_hash: &str) -> bool {
+        db::use_recovery_code(self, user_id, code_hash).await
+    }
+
+    async fn delete_mfa(&self, user_id: Uuid) -> bool {
+        db::delete_mfa(self, user_id).await
+    }
+
+    async fn create_challenge(
+        &self,
+        challenge: &str,
+        purpose: ChallengePurpose,
+        user_id: Option<Uuid>,
+        lifetime: Duration,
+    ) {
+        db::create_challenge(self, challenge, purpose, user_id, lifetime).await
+    }
+
+    async fn use_challenge(&self, challenge: &str, purpose: ChallengePurpose) -> Option<Option<Uuid>> {
+        db::use_challenge(self, challenge, purpose).await
+    }
+
+    async fn add_passkey(&self, user_id: Uuid, credential: &NewCredential, name: &str) -> bool {
+        db::add_passkey(self, user_id, credential, name).await
+    }
+
+    async fn get_passkeys(&self, user_id: Uuid) -> Vec<Passkey> {
+        db::get_passkeys(self, user_id).await
+    }
+
+    async fn get_passkey_ids(&self, user_id: Uuid) -> Vec<String> {
+        db::get_passkey_ids(self, user_id).await
+    }
+
+    async fn find_passkey(&self, credential_id: &str) -> Option<PasskeyCredential> {
+        db::find_passkey(self, credential_id).await
+    }
+
+    async fn use_passkey(&self, passkey_id: i32, sign_count: i64) {
+        db::use_passkey(self, passkey_id, sign_count).await
+    }
+
+    async fn delete_passkey(&self, user_id: Uuid, passkey_id: i32) -> bool {
+        db::delete_passkey(self, user_id, passkey_id).await
+    }
+}
+
+#[async_trait]
+impl LockoutStore for deadpool_postgres::Client {
+    async fn is_locked(&self, kind: &str, subject: &str) -> bool {
+        db::is_locked(self, kind, subject).await
+    }
+
+    async fn add_login_failure(&self, kind: &str, subject: &str, window_start: DateTime<Local>) -> i32 {
+        db::add_login_failure(self, kind, subject, window_start).await
+    }
+
+    async fn lock_login(&self, kind: &str, subject: &str, locked_until: DateTime<Local>) {
+        db::lock_login(self, kind, subject, locked_until).await
+    }
+
+    async fn clear_login_failures(&self, kind: &str, subject: &str) -> bool {
+        db::clear_login_failures(self, kind, subject).await
+    }
+}
+
+#[async_trait]
+impl AttributeStore for deadpool_postgres::Client {
+    async fn get_attribute_definitions(&self) -> Vec<AttributeDefinition> {
+        db::get_attribute_definitions(self).await
+    }
+
+    async fn put_attribute_definition(&self, definition: &AttributeDefinition) {
+        db::put_attribute_definition(self, definition).await
+    }
+
+    async fn delete_attribute_definition(&mut self, name: &str) -> Result<bool, Error> {
+        db::delete_attribute_definition(self, name).await
+    }
+
+    async fn get_attribute_values(&self, name: &str) -> Vec<String> {
+        db::get_attribute_values(self, name).await
+    }
+
+    async fn get_user_attributes(&self, user_id: Uuid) -> Vec<(String, String)> {
+        db::get_user_attributes(self, user_id).await
+    }
+
+    async fn set_user_attributes(&mut self, user_id: Uuid, changes: &[(String, Option<String>)]) -> Result<(), Error> {
+        db::set_user_attributes(self, user_id, changes).await
+    }
+}
+
+#[async_trait]
+impl RoleStore for deadpool_postgres::Client {
+    async fn get_roles(&self, client_db_id: Option<Uuid>) -> Vec<Role> {
+        db::get_roles(self, client_db_id).await
+    }
+
+    async fn find_role(&self, client_db_id: Option<Uuid>, name: &str) -> Option<i32> {
+        db::find_role(self, client_db_id, name).await
+    }
+
+    async fn put_role(&self, client_db_id: Option<Uuid>, name: &str, description: &Option<String>) {
+        db::put_role(self, client_db_id, name, description).await
+    }
+
+    async fn delete_role(&self, client_db_id: Option<Uuid>, name: &str) -> bool {
+        db::delete_role(self, client_db_id, name).await
+    }
+
+    async fn get_groups(&self) -> Vec<Group> {
+        db::get_groups(self).await
+    }
+
+    async fn get_group(&self, name: &str) -> Option<(i32, Group)> {
+        db::get_group(self, name).await
+    }
+
+    async fn put_group(&self, name: &str, description: &Option<String>) {
+        db::put_group(self, name, description).await
+    }
+
+    async fn delete_group(&self, name: &str) -> bool {
+        db::delete_group(self, name).await
+    }
+
+    async fn add_group_member(&self, group_id: i32, user_id: Uuid) {
+        db::add_group_member(self, group_id, user_id).await
+    }
+
+    async fn remove_group_member(&self, group_id: i32, user_id: Uuid) -> bool {
+        db::remove_group_member(self, group_id, user_id).await
+    }
+
+    async fn add_group_role(&self, group_id: i32, role_id: i32) {
+        db::add_group_role(self, group_id, role_id).await
+    }
+
+    async fn remove_group_role(&self, group_id: i32, role_id: i32) -> bool {
+        db::remove_group_role(self, group_id, role_id).await
+    }
+
+    async fn add_user_role(&self, user_id: Uuid, role_id: i32) {
+        db::add_user_role(self, user_id, role_id).await
+    }
+
+    async fn remove_user_role(&self, user_id: Uuid, role_id: i32) -> bool {
+        db::remove_user_role(self, user_id, role_id).await
+    }
+
+    async fn get_user_roles(&self, user_id: Uuid) -> Vec<Role> {
+        db::get_user_roles(self, user_id).await
+    }
+
+    async fn get_user_groups(&self, user_id: Uuid) -> Vec<String> {
+        db::get_user_groups(self, user_id).await
+    }
+
+    async fn set_directory_groups(&mut self, user_id: Uuid, names: &[String]) {
+        db::set_directory_groups(self, user_id, names).await
+    }
+
+    async fn get_effective_roles(&self, user_id: Uuid) -> Vec<Role> {
+        db::get_effective_roles(self, user_id).await
+    }
+}
+
+#[async_trait]
+impl FederationStore for deadpool_postgres::Client {
+    async fn create_federation_state(&self, state: &str, params: &FederationState, lifetime: Duration) {
+        db::create_federation_state(self, state, params, lifetime).await
+    }
+
+    async fn use_federation_state(&self, state: &str) -> Option<FederationState> {
+        db::use_federation_state(self, state).await
+    }
+
+    async fn find_federated_user(&self, provider: &str, subject: &str) -> Option<Uuid> {
+        db::find_federated_user(self, provider, subject).await
+    }
+
+    async fn link_federated_identity(&self, provider: &str, subject: &str, user_id: Uuid, email: Option<&str>) {
+        db::link_federated_identity(self, provider, subject, user_id, email).await
+    }
+
+    async fn get_federated_identities(&self, user_id: Uuid) -> Vec<FederatedIdentity> {
+        db::get_federated_identities(self, user_id).await
+    }
+
+    async fn delete_federated_identities(&self, user_id: Uuid, provider: &str) -> bool {
+        db::delete_federated_identities(self, user_id, provider).await
+    }
+}
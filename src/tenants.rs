@@ -0,0 +1,195 @@
+use crate::db;
+use crate::errors::Error::{self, ConflictError};
+use crate::models::{Config, NewClient, ServerConfig};
+use config::ConfigError;
+use deadpool_postgres::Client;
+use std::collections::HashMap;
+
+// Tenants (realms) share the server process and nothing else. A tenant has its own schema in the
+// database, so its own users, clients, scopes and tokens, its own signing key and its own settings. A
+// request is served by the tenant whose host it was sent to, requests to other hosts by the default tenant
+// in the public schema. The connections of a tenant only see its schema, so a client of one tenant can't
+// find the tokens of another. Tenants are told apart by host and not by a path in the issuer, the pages
+// and redirects use absolute paths.
+
+// Where the demo data starts in database_init.sql, a new tenant only gets the tables
+pub const DEMO_DATA: &str = "-- demo data";
+
+pub struct Tenant {
+    pub name: String,
+    pub host: String,
+    pub schema: String,
+    // the server's configuration with the tenant's settings and schema
+    pub config: Config,
+}
+
+// Names end up in the schema name and the connection options
+fn valid_name(name: &str) -> bool {
+    name.len() <= 50
+        && name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+pub fn load(config: &Config) -> Result<Vec<Tenant>, ConfigError> {
+    if config.sqlite.is_some() && !config.tenants.is_empty() {
+        return Err(ConfigError::Message("Tenants are schemas of a Postgres database, not of SQLite".to_string()));
+    }
+    let mut tenants: Vec<Tenant> = Vec::new();
+    for (name, tenant) in &config.tenants {
+        let schema = tenant.schema.clone().unwrap_or_else(|| format!("tenant_{}", name));
+        if !valid_name(name) || !valid_name(&schema) {
+            return Err(ConfigError::Message(format!(
+                "Tenant {}: names and schemas have lowercase letters, digits and _",
+                name
+            )));
+        }
+        if tenants.iter().any(|t| t.host.eq_ignore_ascii_case(&tenant.host) || t.schema == schema) {
+            return Err(ConfigError::Message(format!("Tenant {} shares its host or schema", name)));
+        }
+        tenants.push(Tenant {
+            name: name.clone(),
+            host: tenant.host.clone(),
+            config: tenant_config(config, name, &tenant.host, &schema)?,
+            schema,
+        });
+    }
+    tenants.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(tenants)
+}
+
+// The server settings with what TENANTS.{NAME}.SERVER.* changes. The issuer and the public url are the
+// tenant's host unless they are set.
+fn tenant_config(config: &Config, name: &str, host: &str, schema: &str) -> Result<Config, ConfigError> {
+    let mut cfg = config::Config::new();
+    cfg.merge(config::Environment::new())?;
+    let prefix = format!("tenants.{}.server.", name);
+    let mut changed = Vec::new();
+    for (key, value) in std::env::vars() {
+        if let Some(setting) = key.to_lowercase().strip_prefix(&prefix) {
+            cfg.set(&format!("server.{}", setting), value)?;
+            changed.push(setting.to_string());
+        }
+    }
+
+    let mut server: ServerConfig = cfg.get("server")?;
+    if !changed.iter().any(|s| s == "name") {
+        server.name = host.to_string();
+    }
+    if !changed.iter().any(|s| s == "public_url") {
+        let scheme = if server.secure_cookies { "https" } else { "http" };
+        server.public_url = Some(format!("{}://{}", scheme, host));
+    }
+
+    let mut pg = config.pg.clone();
+    let search_path = format!("-c search_path={}", schema);
+    pg.options = Some(match &config.pg.options {
+        Some(options) => format!("{} {}", options, search_path),
+        None => search_path,
+    });
+    Ok(Config {
+        server,
+        bootstrap: false,
+        pg,
+        sqlite: None,
+        tenants: HashMap::new(),
+    })
+}
+
+// Creates the tenant's schema with the tables of the bootstrap script and an admin client for the admin
+// api. Returns the admin client's secret.
+pub async fn init(client: &mut Client, tenant: &Tenant, script: &str) -> Result<String, Error> {
+    if db::schema_exists(client, &tenant.schema).await {
+        return Err(ConflictError(format!("Schema {} already exists", tenant.schema)));
+    }
+    let tables = script.split(DEMO_DATA).next().unwrap_or(script);
+    db::create_schema(client, &tenant.schema, tables).await?;
+
+    let admin_scope = &tenant.config.server.admin_scope;
+    db::add_scope(client, "openid", "Sign you in", false).await;
+    db::add_scope(client, admin_scope, "Manage users and clients", true).await;
+    let params = NewClient {
+        display_name: Some("Admin".to_string()),
+        redirect_uris: Vec::new(),
+        post_logout_redirect_uris: Vec::new(),
+        frontchannel_logout_uri: None,
+        backchannel_logout_uri: None,
+        grant_types: Some(vec!["client_credentials".to_string()]),
+        scopes: vec![admin_scope.clone()],
+        default_scopes: vec![admin_scope.clone()],
+        access_token_lifetime: None,
+        refresh_token_lifetime: None,
+        first_party: true,
+        mfa_required: false,
+        rate_limit: None,
+        jwt_access_tokens: false,
+    };
+    let client_db_id = db::create_client(client, "admin", &params).await?;
+    let (_, secret) = db::add_client_secret(client, client_db_id, None).await;
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::Memory;
+    use crate::testing::{self, add_client, add_user, basic_auth, CLIENT_ID, PASSWORD, USERNAME};
+    use serde_json::Value;
+    use warp::filters::BoxedFilter;
+    use warp::test::RequestBuilder;
+
+    type App = BoxedFilter<(warp::reply::Response,)>;
+
+    const ACME: &str = "acme.example";
+
+    async fn reply(app: &App, request: RequestBuilder) -> (u16, Value) {
+        let response = request.reply(app).await;
+        (response.status().as_u16(), serde_json::from_slice(response.body()).unwrap_or_default())
+    }
+
+    fn post(host: &str, path: &str, authorization: &str, form: &str) -> RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("host", host)
+            .header("authorization", authorization)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(form)
+    }
+
+    #[tokio::test]
+    async fn a_token_of_one_tenant_is_not_accepted_by_another() {
+        // both tenants have a client and a user of the same name
+        let mut default = Memory::new();
+        let (_, default_secret) = add_client(&mut default, CLIENT_ID, &["openid"]).await;
+        add_user(&mut default, USERNAME, PASSWORD).await;
+        let mut acme = Memory::new();
+        let (_, acme_secret) = add_client(&mut acme, CLIENT_ID, &["openid"]).await;
+        add_user(&mut acme, USERNAME, PASSWORD).await;
+        let mut acme_config = testing::config();
+        acme_config.server.name = format!("http://{}", ACME);
+        let app = crate::with_tenant(
+            ACME.to_string(),
+            testing::app(&acme, acme_config),
+            testing::app(&default, testing::config()),
+        );
+        let default_client = basic_auth(CLIENT_ID, &default_secret);
+        let acme_client = basic_auth(CLIENT_ID, &acme_secret);
+
+        let login = format!("grant_type=password&username={}&password={}&scope=openid", USERNAME, PASSWORD);
+        let (status, token) = reply(&app, post("localhost:8080", "/oauth2/token", &default_client, &login)).await;
+        assert_eq!(status, 200);
+        let form = format!("token={}", token["access_token"].as_str().unwrap());
+        let bearer = format!("Bearer {}", token["access_token"].as_str().unwrap());
+
+        let introspect = post("localhost:8080", "/oauth2/introspect", &default_client, &form);
+        assert_eq!(reply(&app, introspect).await.1["active"], true);
+        // the other tenant doesn't know the token
+        let (status, _) = reply(&app, post(ACME, "/oauth2/introspect", &acme_client, &form)).await;
+        assert_eq!(status, 404);
+        let (status, _) = reply(&app, post(ACME, "/oauth2/userinfo", &bearer, "")).await;
+        assert_eq!(status, 401);
+
+        // nor are the client's credentials
+        let (status, error) = reply(&app, post(ACME, "/oauth2/token", &default_client, &login)).await;
+        assert_eq!((status, error["error"].as_str()), (401, Some("invalid_client")));
+    }
+}
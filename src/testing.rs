@@ -0,0 +1,229 @@
+// Helpers for the tests of the handlers: the routes of a server that keeps its data in memory, with a
+// client and a user to log in with
+use crate::jwt::SigningKeys;
+use crate::mail::{Mail, MailError, Mailer, StdoutMailer};
+use crate::memory::Memory;
+use crate::models::{Config, NewClient, User};
+use crate::password;
+use crate::storage::{ClientStore, UserStore};
+use crate::webauthn::{self, RelyingParty};
+use ciborium::value::Value;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use sha2::{Digest, Sha256};
+use serde_json::json;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use uuid::Uuid;
+use warp::filters::BoxedFilter;
+
+pub const CLIENT_ID: &str = "top";
+pub const USERNAME: &str = "test";
+pub const PASSWORD: &str = "test_password";
+
+pub fn config() -> Config {
+    serde_json::from_value(json!({
+        "server": {
+            "host": "localhost",
+            "port": 8080,
+            "name": "http://localhost:8080",
+            "cert_dir": "/nonexistent",
+            "secure_cookies": false,
+            "password": { "memory_cost": 1024, "time_cost": 1 },
+        },
+        "bootstrap": false,
+    }))
+    .expect("Invalid test configuration")
+}
+
+// Generating a key is slow, the tests share one
+pub fn keys() -> Arc<SigningKeys> {
+    static KEYS: OnceLock<Arc<SigningKeys>> = OnceLock::new();
+    KEYS.get_or_init(|| Arc::new(SigningKeys::load("/nonexistent"))).clone()
+}
+
+pub fn app(memory: &Memory, config: Config) -> BoxedFilter<(warp::reply::Response,)> {
+    app_with_mailer(memory, config, Arc::new(StdoutMailer))
+}
+
+pub fn app_with_mailer(
+    memory: &Memory,
+    config: Config,
+    mailer: Arc<dyn Mailer>,
+) -> BoxedFilter<(warp::reply::Response,)> {
+    crate::routes(Arc::new(memory.clone()), config, keys(), mailer)
+}
+
+// Keeps the mails the server sends so a test can follow their links
+#[derive(Default)]
+pub struct Outbox(Mutex<Vec<Mail>>);
+
+impl Mailer for Outbox {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        self.0.lock().unwrap().push(Mail {
+            to: mail.to.clone(),
+            subject: mail.subject.clone(),
+            body: mail.body.clone(),
+        });
+        Ok(())
+    }
+}
+
+impl Outbox {
+    // Takes the link out of the first mail to the address, mails are sent in the background so it waits a
+    // second for one
+    pub async fn link(&self, to: &str) -> Option<String> {
+        for _ in 0..20 {
+            if let Some(mail) = self.take(to) {
+                return mail.body.split_whitespace().find(|word| word.starts_with("http")).map(String::from);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        None
+    }
+
+    fn take(&self, to: &str) -> Option<Mail> {
+        let mut mails = self.0.lock().unwrap();
+        let position = mails.iter().position(|mail| mail.to == to)?;
+        Some(mails.remove(position))
+    }
+}
+
+// A client with the given scopes, all of them default, that may use every grant, returns its id and secret
+pub async fn add_client(memory: &mut Memory, client_id: &str, scopes: &[&str]) -> (Uuid, String) {
+    for scope in scopes {
+        memory.add_scope(scope, false);
+    }
+    let params: NewClient = serde_json::from_value(json!({
+        "redirect_uris": ["http://localhost:3000/callback"],
+        "grant_types": ["authorization_code", "refresh_token", "password", "client_credentials"],
+        "scopes": scopes,
+        "default_scopes": scopes,
+        "refresh_token_lifetime": 3600,
+    }))
+    .unwrap();
+    let id = memory.create_client(client_id, &params).await.expect("Could not create client");
+    let (_, secret) = memory.add_client_secret(id, None).await;
+    (id, secret)
+}
+
+pub async fn add_user(memory: &mut Memory, username: &str, password: &str) -> User {
+    let hash = password::hash(password, &config().server.password);
+    memory
+        .register_user(username, &format!("{}@example.com", username), &hash, true, None)
+        .await
+        .expect("Could not register user")
+}
+
+pub fn basic_auth(client_id: &str, secret: &str) -> String {
+    format!("Basic {}", base64::encode(format!("{}:{}", client_id, secret)))
+}
+
+// Posts the form to the token endpoint, answers the status and the response as json
+pub async fn token(
+    app: &BoxedFilter<(warp::reply::Response,)>,
+    authorization: &str,
+    form: &str,
+) -> (u16, serde_json::Value) {
+    let response = warp::test::request()
+        .method("POST")
+        .path("/oauth2/token")
+        .header("authorization", authorization)
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(form)
+        .reply(app)
+        .await;
+    (response.status().as_u16(), serde_json::from_slice(response.body()).unwrap_or_default())
+}
+
+pub async fn password_grant(
+    app: &BoxedFilter<(warp::reply::Response,)>,
+    authorization: &str,
+    username: &str,
+    password: &str,
+) -> (u16, serde_json::Value) {
+    token(app, authorization, &format!("grant_type=password&username={}&password={}", username, password)).await
+}
+
+// A passkey authenticator with a P-256 key. It answers for the origin and rp id it was made for and with the
+// flags and counter it has, tests change those to get a response the relying party should refuse.
+pub struct Authenticator {
+    key: SigningKey,
+    pub credential_id: Vec<u8>,
+    pub origin: String,
+    pub rp_id: String,
+    pub flags: u8,
+    pub sign_count: u32,
+}
+
+impl Authenticator {
+    // user present and verified
+    pub fn new(rp: &RelyingParty) -> Self {
+        Authenticator {
+            key: SigningKey::random(&mut rand::thread_rng()),
+            credential_id: b"credential".to_vec(),
+            origin: rp.origin.clone(),
+            rp_id: rp.id.clone(),
+            flags: 0x05,
+            sign_count: 1,
+        }
+    }
+
+    pub fn cose_key(&self) -> Vec<u8> {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let key = Value::Map(vec![
+            (1.into(), 2.into()),
+            (3.into(), (-7).into()),
+            ((-1).into(), 1.into()),
+            ((-2).into(), point.x().unwrap().to_vec().into()),
+            ((-3).into(), point.y().unwrap().to_vec().into()),
+        ]);
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&key, &mut bytes).unwrap();
+        bytes
+    }
+
+    pub fn client_data(&self, kind: &str, challenge: &str) -> Vec<u8> {
+        serde_json::to_vec(&json!({ "type": kind, "challenge": challenge, "origin": self.origin })).unwrap()
+    }
+
+    // with the attested credential for a registration
+    pub fn authenticator_data(&self, attested: bool) -> Vec<u8> {
+        let mut data = Sha256::digest(self.rp_id.as_bytes()).to_vec();
+        data.push(if attested { self.flags | 0x40 } else { self.flags });
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        if attested {
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            data.extend_from_slice(&self.credential_id);
+            data.extend_from_slice(&self.cose_key());
+        }
+        data
+    }
+
+    // The client data and attestation object of navigator.credentials.create()
+    pub fn register(&self, challenge: &str) -> (Vec<u8>, Vec<u8>) {
+        let attestation = Value::Map(vec![
+            ("fmt".into(), "none".into()),
+            ("attStmt".into(), Value::Map(vec![])),
+            ("authData".into(), self.authenticator_data(true).into()),
+        ]);
+        let mut attestation_object = vec![];
+        ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+        (self.client_data("webauthn.create", challenge), attestation_object)
+    }
+
+    // The client data, authenticator data and signature of navigator.credentials.get()
+    pub fn assert(&self, challenge: &str) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let client_data = self.client_data("webauthn.get", challenge);
+        let authenticator_data = self.authenticator_data(false);
+        let mut message = authenticator_data.clone();
+        message.extend_from_slice(&Sha256::digest(&client_data));
+        let signature: Signature = self.key.sign(&message);
+        (client_data, authenticator_data, signature.to_der().as_bytes().to_vec())
+    }
+
+    pub fn encoded_id(&self) -> String {
+        webauthn::encode(&self.credential_id)
+    }
+}
@@ -0,0 +1,65 @@
+use chrono::{DateTime, Local};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rand::RngCore;
+use sha1::Sha1;
+use url::form_urlencoded;
+
+// Time-based one-time passwords (RFC 6238) as understood by the common authenticator apps: HMAC-SHA1,
+// 30 second steps and 6 digits.
+
+const STEP: i64 = 30;
+const DIGITS: u32 = 6;
+
+// A new secret of 160 bits, base32 encoded the way authenticators expect it
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+// The otpauth:// uri authenticator apps read from the QR code
+pub fn provisioning_uri(secret: &str, issuer: &str, account: &str) -> String {
+    let query: String = form_urlencoded::Serializer::new(String::new())
+        .append_pair("secret", secret)
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP.to_string())
+        .finish();
+    let label: String = form_urlencoded::byte_serialize(format!("{}:{}", issuer, account).as_bytes()).collect();
+    format!("otpauth://totp/{}?{}", label.replace('+', "%20"), query)
+}
+
+pub fn qr_svg(uri: &str) -> String {
+    QrCode::new(uri.as_bytes())
+        .expect("Provisioning uri does not fit in a QR code")
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build()
+}
+
+// RFC 4226 section 5.3, dynamic truncation of the HMAC of the counter
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    binary % 10u32.pow(DIGITS)
+}
+
+// Checks a code against the current step and the ones next to it, to allow for clock drift. Returns the
+// step that matched so the caller can refuse to accept it twice.
+pub fn verify(secret: &str, code: &str, now: DateTime<Local>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let current = now.timestamp() / STEP;
+    (current - 1..=current + 1).find(|step| hotp(&key, *step as u64) == code)
+}
@@ -0,0 +1,376 @@
+use crate::models::ServerConfig;
+use ciborium::value::Value;
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::io::Cursor;
+use thiserror::Error;
+use url::Url;
+
+// The relying party side of WebAuthn (https://www.w3.org/TR/webauthn-2/) for passkeys. Attestation is not
+// asked for, so registration only checks the client data and reads the credential's public key. Keys
+// are kept as the COSE key the authenticator sent, ES256 and RS256 keys are supported.
+
+const ES256: i128 = -7;
+const RS256: i128 = -257;
+
+// authenticator data flags
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL: u8 = 0x40;
+
+#[derive(Error, Debug)]
+pub enum WebauthnError {
+    #[error("invalid credential: {0}")]
+    Invalid(&'static str),
+    #[error("the signature counter went back, the authenticator may have been cloned")]
+    CloneDetected,
+}
+
+use WebauthnError::Invalid;
+
+// Who the credentials are for: the rp id is a domain, the origin where the pages are served
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    pub origin: String,
+}
+
+impl RelyingParty {
+    pub fn new(server_config: &ServerConfig) -> Self {
+        let origin = Url::parse(&server_config.public_url()).expect("SERVER.PUBLIC_URL is not a valid url");
+        RelyingParty {
+            id: server_config
+                .webauthn
+                .rp_id
+                .clone()
+                .unwrap_or_else(|| origin.host_str().unwrap_or("localhost").to_string()),
+            name: server_config.name.clone(),
+            origin: origin.origin().ascii_serialization(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct NewCredential {
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode(value: &str) -> Result<Vec<u8>, WebauthnError> {
+    base64::decode_config(value.trim_end_matches('='), base64::URL_SAFE_NO_PAD).map_err(|_| Invalid("not base64url"))
+}
+
+pub fn generate_challenge() -> String {
+    let mut challenge = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    encode(&challenge)
+}
+
+// The challenge the browser signed, the caller looks it up before verifying the rest
+pub fn client_challenge(client_data_json: &[u8]) -> Result<String, WebauthnError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json).map_err(|_| Invalid("client data"))?;
+    Ok(client_data.challenge)
+}
+
+fn check_client_data(rp: &RelyingParty, client_data_json: &[u8], kind: &str) -> Result<(), WebauthnError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json).map_err(|_| Invalid("client data"))?;
+    if client_data.kind != kind {
+        return Err(Invalid("wrong ceremony"));
+    }
+    if client_data.origin != rp.origin {
+        return Err(Invalid("wrong origin"));
+    }
+    Ok(())
+}
+
+struct AuthenticatorData {
+    flags: u8,
+    sign_count: u32,
+    // credential id and COSE key, only after registration
+    credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+// rpIdHash (32) flags (1) signCount (4) [aaguid (16) credentialIdLength (2) credentialId credentialPublicKey]
+fn parse_authenticator_data(rp: &RelyingParty, data: &[u8]) -> Result<AuthenticatorData, WebauthnError> {
+    if data.len() < 37 {
+        return Err(Invalid("authenticator data too short"));
+    }
+    if data[..32] != Sha256::digest(rp.id.as_bytes())[..] {
+        return Err(Invalid("wrong rp id"));
+    }
+    let flags = data[32];
+    if flags & USER_PRESENT == 0 {
+        return Err(Invalid("user not present"));
+    }
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let credential = if flags & ATTESTED_CREDENTIAL != 0 {
+        let rest = data.get(37 + 16..).ok_or(Invalid("attested credential data"))?;
+        if rest.len() < 2 {
+            return Err(Invalid("attested credential data"));
+        }
+        let id_length = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        let id = rest.get(2..2 + id_length).ok_or(Invalid("credential id"))?;
+        // the key is followed by extensions when there are any, only read the key itself
+        let key_data = &rest[2 + id_length..];
+        let mut cursor = Cursor::new(key_data);
+        let _: Value = ciborium::de::from_reader(&mut cursor).map_err(|_| Invalid("credential public key"))?;
+        let key = &key_data[..cursor.position() as usize];
+        Some((id.to_vec(), key.to_vec()))
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData {
+        flags,
+        sign_count,
+        credential,
+    })
+}
+
+fn cose_int(map: &[(Value, Value)], label: i128) -> Option<i128> {
+    map.iter()
+        .find(|(k, _)| k.as_integer().map(i128::from) == Some(label))
+        .and_then(|(_, v)| v.as_integer())
+        .map(i128::from)
+}
+
+fn cose_bytes(map: &[(Value, Value)], label: i128) -> Option<&Vec<u8>> {
+    map.iter()
+        .find(|(k, _)| k.as_integer().map(i128::from) == Some(label))
+        .and_then(|(_, v)| v.as_bytes())
+}
+
+enum PublicKey {
+    Es256(p256::ecdsa::VerifyingKey),
+    Rs256(rsa::pkcs1v15::VerifyingKey<Sha256>),
+}
+
+// RFC 8152 section 13, EC2 keys (kty 2) on P-256 and RSA keys (kty 3)
+fn public_key(cose_key: &[u8]) -> Result<PublicKey, WebauthnError> {
+    let value: Value = ciborium::de::from_reader(cose_key).map_err(|_| Invalid("credential public key"))?;
+    let map = value.as_map().ok_or(Invalid("credential public key"))?;
+    match (cose_int(map, 1), cose_int(map, 3)) {
+        (Some(2), Some(ES256)) => {
+            let (x, y) = match (cose_bytes(map, -2), cose_bytes(map, -3)) {
+                (Some(x), Some(y)) if cose_int(map, -1) == Some(1) => (x, y),
+                _ => return Err(Invalid("unsupported curve")),
+            };
+            let mut point = vec![0x04];
+            point.extend_from_slice(x);
+            point.extend_from_slice(y);
+            p256::ecdsa::VerifyingKey::from_sec1_bytes(&point)
+                .map(PublicKey::Es256)
+                .map_err(|_| Invalid("credential public key"))
+        }
+        (Some(3), Some(RS256)) => {
+            let (n, e) = match (cose_bytes(map, -1), cose_bytes(map, -2)) {
+                (Some(n), Some(e)) => (n, e),
+                _ => return Err(Invalid("credential public key")),
+            };
+            let key = rsa::RsaPublicKey::new(rsa::BigUint::from_bytes_be(n), rsa::BigUint::from_bytes_be(e))
+                .map_err(|_| Invalid("credential public key"))?;
+            Ok(PublicKey::Rs256(rsa::pkcs1v15::VerifyingKey::new(key)))
+        }
+        _ => Err(Invalid("unsupported algorithm")),
+    }
+}
+
+fn verify_signature(cose_key: &[u8], message: &[u8], signature: &[u8]) -> Result<(), WebauthnError> {
+    use p256::ecdsa::signature::Verifier;
+    let valid = match public_key(cose_key)? {
+        PublicKey::Es256(key) => p256::ecdsa::DerSignature::try_from(signature)
+            .is_ok_and(|s| key.verify(message, &s).is_ok()),
+        PublicKey::Rs256(key) => rsa::pkcs1v15::Signature::try_from(signature)
+            .is_ok_and(|s| key.verify(message, &s).is_ok()),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(Invalid("bad signature"))
+    }
+}
+
+// Checks the response to navigator.credentials.create() and returns the new credential
+pub fn verify_registration(
+    rp: &RelyingParty,
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+) -> Result<NewCredential, WebauthnError> {
+    check_client_data(rp, client_data_json, "webauthn.create")?;
+
+    let attestation: Value =
+        ciborium::de::from_reader(attestation_object).map_err(|_| Invalid("attestation object"))?;
+    let auth_data = attestation
+        .as_map()
+        .and_then(|m| m.iter().find(|(k, _)| k.as_text() == Some("authData")))
+        .and_then(|(_, v)| v.as_bytes())
+        .ok_or(Invalid("attestation object"))?;
+
+    let data = parse_authenticator_data(rp, auth_data)?;
+    if data.flags & USER_VERIFIED == 0 {
+        return Err(Invalid("user not verified"));
+    }
+    let (id, key) = data.credential.ok_or(Invalid("no credential"))?;
+    public_key(&key)?;
+    Ok(NewCredential {
+        credential_id: encode(&id),
+        public_key: key,
+        sign_count: data.sign_count.into(),
+    })
+}
+
+// Checks the response to navigator.credentials.get() against a stored credential and returns the new
+// signature counter. Authenticators that count never go back, a lower counter means a copy of the key.
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+    public_key: &[u8],
+    sign_count: i64,
+    user_verification: bool,
+) -> Result<i64, WebauthnError> {
+    check_client_data(rp, client_data_json, "webauthn.get")?;
+    let data = parse_authenticator_data(rp, authenticator_data)?;
+    if user_verification && data.flags & USER_VERIFIED == 0 {
+        return Err(Invalid("user not verified"));
+    }
+
+    let mut message = authenticator_data.to_vec();
+    message.extend_from_slice(&Sha256::digest(client_data_json));
+    verify_signature(public_key, &message, signature)?;
+
+    let count = i64::from(data.sign_count);
+    if (count != 0 || sign_count != 0) && count <= sign_count {
+        return Err(WebauthnError::CloneDetected);
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Authenticator;
+
+    fn rp() -> RelyingParty {
+        RelyingParty {
+            id: "localhost".to_string(),
+            name: "http://localhost:8080".to_string(),
+            origin: "http://localhost:8080".to_string(),
+        }
+    }
+
+    fn register(authenticator: &Authenticator) -> Result<NewCredential, WebauthnError> {
+        let (client_data, attestation_object) = authenticator.register("challenge");
+        verify_registration(&rp(), &client_data, &attestation_object)
+    }
+
+    fn assert(authenticator: &Authenticator, public_key: &[u8], sign_count: i64) -> Result<i64, WebauthnError> {
+        let (client_data, authenticator_data, signature) = authenticator.assert("challenge");
+        verify_assertion(&rp(), &client_data, &authenticator_data, &signature, public_key, sign_count, true)
+    }
+
+    #[test]
+    fn a_registered_passkey_signs_in() {
+        let mut authenticator = Authenticator::new(&rp());
+        let credential = register(&authenticator).unwrap();
+        assert_eq!(credential.credential_id, authenticator.encoded_id());
+        assert_eq!(credential.sign_count, 1);
+
+        authenticator.sign_count = 2;
+        assert_eq!(assert(&authenticator, &credential.public_key, credential.sign_count).unwrap(), 2);
+        let (client_data, _, _) = authenticator.assert("challenge");
+        assert_eq!(client_challenge(&client_data).unwrap(), "challenge");
+    }
+
+    #[test]
+    fn responses_for_another_origin_or_rp_are_refused() {
+        let authenticator = Authenticator::new(&rp());
+        let credential = register(&authenticator).unwrap();
+
+        let mut elsewhere = Authenticator::new(&rp());
+        elsewhere.origin = "http://localhost:8081".to_string();
+        assert!(matches!(register(&elsewhere), Err(Invalid("wrong origin"))));
+        let mut other_rp = Authenticator::new(&rp());
+        other_rp.rp_id = "example.com".to_string();
+        assert!(matches!(register(&other_rp), Err(Invalid("wrong rp id"))));
+
+        let mut authenticator = authenticator;
+        authenticator.sign_count = 2;
+        authenticator.origin = "http://localhost:8081".to_string();
+        assert!(matches!(assert(&authenticator, &credential.public_key, 1), Err(Invalid("wrong origin"))));
+        authenticator.origin = rp().origin;
+        authenticator.rp_id = "example.com".to_string();
+        assert!(matches!(assert(&authenticator, &credential.public_key, 1), Err(Invalid("wrong rp id"))));
+    }
+
+    #[test]
+    fn responses_of_the_wrong_ceremony_or_key_are_refused() {
+        let authenticator = Authenticator::new(&rp());
+        let credential = register(&authenticator).unwrap();
+        let (client_data, authenticator_data, signature) = authenticator.assert("challenge");
+        let (_, attestation_object) = authenticator.register("challenge");
+        assert!(matches!(
+            verify_registration(&rp(), &client_data, &attestation_object),
+            Err(Invalid("wrong ceremony"))
+        ));
+
+        let other = Authenticator::new(&rp()).cose_key();
+        let result = verify_assertion(&rp(), &client_data, &authenticator_data, &signature, &other, 0, true);
+        assert!(matches!(result, Err(Invalid("bad signature"))));
+        // the signature covers the client data, so also the challenge in it
+        let signed_elsewhere = authenticator.client_data("webauthn.get", "another challenge");
+        let key = &credential.public_key;
+        let result = verify_assertion(&rp(), &signed_elsewhere, &authenticator_data, &signature, key, 0, true);
+        assert!(matches!(result, Err(Invalid("bad signature"))));
+    }
+
+    #[test]
+    fn user_verification_is_required_where_asked_for() {
+        let mut authenticator = Authenticator::new(&rp());
+        let credential = register(&authenticator).unwrap();
+        authenticator.flags = USER_PRESENT;
+        authenticator.sign_count = 2;
+        assert!(matches!(register(&authenticator), Err(Invalid("user not verified"))));
+        assert!(matches!(assert(&authenticator, &credential.public_key, 1), Err(Invalid("user not verified"))));
+
+        // as a second factor after the password the user only has to be present
+        let (client_data, authenticator_data, signature) = authenticator.assert("challenge");
+        let key = &credential.public_key;
+        let result = verify_assertion(&rp(), &client_data, &authenticator_data, &signature, key, 1, false);
+        assert_eq!(result.unwrap(), 2);
+        authenticator.flags = 0;
+        let (client_data, authenticator_data, signature) = authenticator.assert("challenge");
+        let result = verify_assertion(&rp(), &client_data, &authenticator_data, &signature, key, 1, false);
+        assert!(matches!(result, Err(Invalid("user not present"))));
+    }
+
+    #[test]
+    fn a_counter_that_goes_back_means_a_cloned_key() {
+        let mut authenticator = Authenticator::new(&rp());
+        let credential = register(&authenticator).unwrap();
+        for count in [1, 0] {
+            authenticator.sign_count = count;
+            let result = assert(&authenticator, &credential.public_key, 1);
+            assert!(matches!(result, Err(WebauthnError::CloneDetected)));
+        }
+        // authenticators without a counter always send 0
+        assert_eq!(assert(&authenticator, &credential.public_key, 0).unwrap(), 0);
+    }
+}